@@ -1,4 +1,13 @@
-use clap::{Parser, Subcommand};
+use std::{path::PathBuf, time::Duration};
+
+use clap::{Args, Parser, Subcommand};
+use ipnet::IpNet;
+
+use crate::{
+    bandwidth::LimitPolicy,
+    messages::{Compression, SessionOptions, Transport},
+    policy::DestinationRule,
+};
 
 /// Root of all command line arguments
 #[derive(Debug, Parser)]
@@ -16,25 +25,357 @@ pub enum Commands {
     Server {
         /// Where should data be forwarded
         forward: String,
-        /// The address of TURN server
+        /// The address of TURN server. Can be a comma separated list of addresses. Append @ and
+        /// the public key of a TURN server to encrypt and authenticate the signalling with it
         turn: String,
         /// The name of current service
         service: String,
+        #[command(flatten)]
+        secret: ServiceSecret,
+        #[command(flatten)]
+        tunnel: TunnelOptions,
+        #[command(flatten)]
+        session: ServiceOptions,
+        #[command(flatten)]
+        bandwidth: BandwidthLimits,
+        #[command(flatten)]
+        policy: DestinationPolicy,
+        /// File of the public keys of clients which can connect. Each line has a key and the name
+        /// of the client. Every client can connect if it's not set
+        #[arg(long)]
+        authorized_keys: Option<PathBuf>,
+        /// File of the private key which the server proves its identity with to the clients
+        /// which pin its public key. Create one with the keygen command
+        #[arg(long)]
+        identity: Option<PathBuf>,
+        /// How many seconds to wait for current sessions to finish on shutdown
+        #[arg(long, default_value_t = 10)]
+        drain_timeout: u64,
     },
     /// Work as a client connecting to remote server
     #[command(arg_required_else_help = true)]
     Client {
         /// Listen on this address
         listen: String,
-        /// The address of TURN server
+        /// The address of TURN server. Can be a comma separated list of addresses. Append @ and
+        /// the public key of a TURN server to encrypt and authenticate the signalling with it
         turn: String,
-        /// The name of current service
+        /// The name of current service. Pin the identity of the server by appending @ and its
+        /// public key, like test@KEY; the server must then prove that it has the key
         service: String,
+        #[command(flatten)]
+        secret: ServiceSecret,
+        /// Ask all TURN servers at once and use the fastest one instead of trying them in order
+        #[arg(long)]
+        race: bool,
+        #[command(flatten)]
+        tunnel: TunnelOptions,
+        /// Compression which is accepted if the server uses it for the service
+        #[arg(long, value_enum, default_value_t = Compression::Lz4)]
+        compression: Compression,
+        #[command(flatten)]
+        bandwidth: BandwidthLimits,
+        /// File of the private key which the client proves its identity with.
+        /// Create one with the keygen command
+        #[arg(long)]
+        identity: Option<PathBuf>,
+        /// Serve SOCKS5 (CONNECT and UDP ASSOCIATE) on the listen address instead of forwarding
+        /// UDP. The server dials the destinations. It must use the QUIC transport.
+        #[arg(long)]
+        socks: bool,
+        /// How many seconds to wait for current connections to finish on shutdown
+        #[arg(long, default_value_t = 10)]
+        drain_timeout: u64,
+    },
+    /// Connect peers with a layer 3 VPN over TUN devices
+    #[command(subcommand)]
+    Vpn(VpnCommands),
+    /// Join a mesh network and punch a path to every other node of it
+    #[command(arg_required_else_help = true)]
+    Mesh {
+        /// The address of TURN server. Can be a comma separated list of addresses. Append @ and
+        /// the public key of a TURN server to encrypt and authenticate the signalling with it
+        turn: String,
+        /// The name of the network
+        network: String,
+        #[command(flatten)]
+        secret: ServiceSecret,
+        /// Address of this node and the prefix of the network, like 10.201.0.1/24
+        #[arg(long)]
+        address: IpNet,
+        /// Send a heartbeat to each node every this many seconds
+        #[arg(long, default_value_t = 5)]
+        keep_alive: u64,
+        #[command(flatten)]
+        tun: TunOptions,
+    },
+    /// Create an identity and print its public key
+    #[command(arg_required_else_help = true)]
+    Keygen {
+        /// Save the private key in this file. The file must not exist
+        path: PathBuf,
     },
     /// Work as TURN server
     #[command(arg_required_else_help = true)]
+    #[allow(clippy::upper_case_acronyms)]
     TURN {
         /// Listen on this address
         listen: String,
+        /// Run the classic single threaded server with blocking sockets.
+        /// Good for tiny deployments.
+        #[arg(long)]
+        single_threaded: bool,
+        /// Number of worker threads and sockets. Defaults to the number of CPUs
+        #[arg(long, conflicts_with = "single_threaded")]
+        workers: Option<usize>,
+        #[command(flatten)]
+        cluster: TurnCluster,
+        #[command(flatten)]
+        limits: TurnLimits,
+        /// File of the private key which peers that pin the public key of this server
+        /// open secure channels with. Create one with the keygen command
+        #[arg(long)]
+        identity: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum VpnCommands {
+    /// Accept VPN clients. Each client gets an address of the VPN network.
+    #[command(arg_required_else_help = true)]
+    Server {
+        /// The address of TURN server. Can be a comma separated list of addresses. Append @ and
+        /// the public key of a TURN server to encrypt and authenticate the signalling with it
+        turn: String,
+        /// The name of current service
+        service: String,
+        #[command(flatten)]
+        secret: ServiceSecret,
+        #[command(flatten)]
+        tunnel: TunnelOptions,
+        #[command(flatten)]
+        session: ServiceOptions,
+        #[command(flatten)]
+        bandwidth: BandwidthLimits,
+        #[command(flatten)]
+        vpn: VpnServerOptions,
+        #[command(flatten)]
+        tun: TunOptions,
+        /// File of the public keys of clients which can connect. Each line has a key and the name
+        /// of the client. Every client can connect if it's not set
+        #[arg(long)]
+        authorized_keys: Option<PathBuf>,
+        /// File of the private key which the server proves its identity with to the clients
+        /// which pin its public key. Create one with the keygen command
+        #[arg(long)]
+        identity: Option<PathBuf>,
+        /// How many seconds to wait for current sessions to finish on shutdown
+        #[arg(long, default_value_t = 10)]
+        drain_timeout: u64,
+    },
+    /// Connect to a VPN server. A new session is started whenever the session is over.
+    #[command(arg_required_else_help = true)]
+    Client {
+        /// The address of TURN server. Can be a comma separated list of addresses. Append @ and
+        /// the public key of a TURN server to encrypt and authenticate the signalling with it
+        turn: String,
+        /// The name of current service. Pin the identity of the server by appending @ and its
+        /// public key, like test@KEY; the server must then prove that it has the key
+        service: String,
+        #[command(flatten)]
+        secret: ServiceSecret,
+        /// Ask all TURN servers at once and use the fastest one instead of trying them in order
+        #[arg(long)]
+        race: bool,
+        #[command(flatten)]
+        tunnel: TunnelOptions,
+        /// Compression which is accepted if the server uses it for the service
+        #[arg(long, value_enum, default_value_t = Compression::Lz4)]
+        compression: Compression,
+        #[command(flatten)]
+        bandwidth: BandwidthLimits,
+        #[command(flatten)]
+        tun: TunOptions,
+        /// File of the private key which the client proves its identity with.
+        /// Create one with the keygen command
+        #[arg(long)]
+        identity: Option<PathBuf>,
+        /// How many seconds to wait for the current session to finish on shutdown
+        #[arg(long, default_value_t = 10)]
+        drain_timeout: u64,
     },
 }
+
+/// Options of the punched tunnel between client and server
+#[derive(Debug, Clone, Args)]
+pub struct TunnelOptions {
+    /// Send a heartbeat over the punched path every this many seconds.
+    /// Keeps NAT mappings alive and detects dead paths.
+    #[arg(long, default_value_t = 5)]
+    pub keep_alive: u64,
+    /// Close a session if no data is sent or received for this many seconds
+    #[arg(long, default_value_t = 60)]
+    pub idle_timeout: u64,
+}
+
+impl TunnelOptions {
+    pub fn keep_alive_interval(&self) -> Duration {
+        Duration::from_secs(self.keep_alive.max(1))
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout.max(1))
+    }
+}
+
+/// Options of a service. The server dictates the options of its sessions to clients.
+#[derive(Debug, Clone, Args)]
+pub struct ServiceOptions {
+    /// Send a parity packet after every this many packets in order to rebuild
+    /// one lost packet of each group without retransmission. Zero disables it.
+    #[arg(long, default_value_t = 0, conflicts_with = "transport")]
+    pub fec: u8,
+    /// Compress the packets of clients which accept this compression
+    #[arg(long, value_enum, default_value_t = Compression::None, conflicts_with = "transport")]
+    pub compression: Compression,
+    /// Deliver the packets reliably and in order. Lost packets are retransmitted.
+    #[arg(long, conflicts_with = "transport")]
+    pub reliable: bool,
+    /// Carry the packets over this protocol. The other options only apply to datagram sessions.
+    #[arg(long, value_enum, default_value_t = Transport::Datagram)]
+    pub transport: Transport,
+    /// Bytes per second which all sessions of the service can send together
+    #[arg(long)]
+    pub service_bandwidth: Option<f64>,
+    /// Packets per second which all sessions of the service can send together
+    #[arg(long)]
+    pub service_packet_rate: Option<f64>,
+    /// Bytes which all sessions of the service can send in a burst. Defaults to one second of service bandwidth
+    #[arg(long, requires = "service_bandwidth")]
+    pub service_burst: Option<f64>,
+}
+
+impl ServiceOptions {
+    pub fn session_options(&self) -> SessionOptions {
+        SessionOptions {
+            fec_group_size: self.fec,
+            compression: self.compression,
+            reliable: self.reliable,
+            transport: self.transport,
+        }
+    }
+}
+
+/// Limits of the datagrams which are sent to the peers. Unlimited by default.
+#[derive(Debug, Clone, Default, Args)]
+pub struct BandwidthLimits {
+    /// Bytes per second which each session can send
+    #[arg(long)]
+    pub session_bandwidth: Option<f64>,
+    /// Packets per second which each session can send
+    #[arg(long)]
+    pub session_packet_rate: Option<f64>,
+    /// Bytes per second which all sessions together can send
+    #[arg(long)]
+    pub total_bandwidth: Option<f64>,
+    /// Packets per second which all sessions together can send
+    #[arg(long)]
+    pub total_packet_rate: Option<f64>,
+    /// Bytes which each session can send in a burst. Defaults to one second of session bandwidth
+    #[arg(long, requires = "session_bandwidth")]
+    pub session_burst: Option<f64>,
+    /// Bytes which all sessions together can send in a burst. Defaults to one second of total bandwidth
+    #[arg(long, requires = "total_bandwidth")]
+    pub total_burst: Option<f64>,
+    /// What to do with packets which exceed the limits
+    #[arg(long, value_enum, default_value_t = LimitPolicy::Drop)]
+    pub limit_policy: LimitPolicy,
+}
+
+/// Destinations which SOCKS clients can reach through the server
+#[derive(Debug, Clone, Args)]
+pub struct DestinationPolicy {
+    /// Allow clients to reach this network or host name with an optional port or port range, such as
+    /// 192.168.1.0/24, 10.0.0.5:22, 10.0.0.0/8:8000-8100 or *.example.com:443. Can be used multiple times.
+    /// Nothing is allowed by default.
+    #[arg(long)]
+    pub allow: Vec<DestinationRule>,
+    /// Deny clients to reach this network or host name, even if it is allowed.
+    /// Takes the same rules as --allow. Can be used multiple times.
+    #[arg(long)]
+    pub deny: Vec<DestinationRule>,
+}
+
+/// Addresses of a VPN server
+#[derive(Debug, Clone, Args)]
+pub struct VpnServerOptions {
+    /// The virtual address of the server and the prefix of the VPN network, such as 10.200.0.1/24.
+    /// Clients get the other addresses of the network.
+    #[arg(long)]
+    pub address: IpNet,
+    /// Push a route of this network over the VPN to clients. Can be used multiple times
+    #[arg(long = "route")]
+    pub routes: Vec<IpNet>,
+}
+
+/// Options of the TUN devices of VPN sessions
+#[derive(Debug, Clone, Args)]
+pub struct TunOptions {
+    /// Name of the TUN devices. %d is replaced with a number.
+    #[arg(long, default_value = "punch%d")]
+    pub tun_name: String,
+    /// MTU of the TUN devices. Larger packets are fragmented over the punched path.
+    #[arg(long, default_value_t = 1400)]
+    pub mtu: u16,
+}
+
+/// Options to hide the service name from TURN server
+#[derive(Debug, Args)]
+pub struct ServiceSecret {
+    /// A secret shared between client and server. If set, the service name is never sent to
+    /// TURN server and a key derived from this secret and the service name is used instead.
+    #[arg(long, env = "P2P_PUNCHER_SECRET", hide_env_values = true)]
+    pub secret: Option<String>,
+    /// Derived keys are rotated every this many seconds
+    #[arg(long, default_value_t = 3600)]
+    pub secret_epoch: u64,
+}
+
+/// Options of the TURN nodes which share their registered servers
+#[derive(Debug, Args)]
+pub struct TurnCluster {
+    /// Address of another TURN node of the cluster. Registered servers are shared
+    /// between the nodes. Can be used multiple times
+    #[arg(long = "peer", requires = "cluster_key")]
+    pub peers: Vec<String>,
+    /// A secret shared between the nodes of the cluster. Messages of other nodes
+    /// are authenticated with it. Required if any peer is set
+    #[arg(long, env = "P2P_PUNCHER_CLUSTER_KEY", hide_env_values = true)]
+    pub cluster_key: Option<String>,
+    /// Packets per second which each peer node can send
+    #[arg(long, default_value_t = 1000.0)]
+    pub peer_rate: f64,
+    /// Number of packets which each peer node can send in a burst
+    #[arg(long, default_value_t = 2000.0)]
+    pub peer_burst: f64,
+}
+
+/// Abuse protection options of TURN server
+#[derive(Debug, Args)]
+pub struct TurnLimits {
+    /// Packets per second which each source IP can send
+    #[arg(long, default_value_t = 10.0)]
+    pub source_rate: f64,
+    /// Number of packets which each source IP can send in a burst
+    #[arg(long, default_value_t = 20.0)]
+    pub source_burst: f64,
+    /// Lookups per second which can be done for each service. Leaves of each mesh network have the same limit.
+    #[arg(long, default_value_t = 5.0)]
+    pub service_rate: f64,
+    /// Number of lookups which can be done for each service in a burst
+    #[arg(long, default_value_t = 10.0)]
+    pub service_burst: f64,
+    /// Drop every packet from this IP or CIDR. Can be used multiple times
+    #[arg(long)]
+    pub ban: Vec<IpNet>,
+}
@@ -0,0 +1,333 @@
+use std::{sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+use tokio::time;
+
+use crate::{
+    arguments::{BandwidthLimits, ServiceOptions},
+    ratelimit::TokenBucket,
+};
+
+/// What to do with datagrams which exceed the bandwidth limits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LimitPolicy {
+    /// Drop the datagram
+    #[default]
+    Drop,
+    /// Hold the datagram until the limits allow it. Datagrams which arrive meanwhile are queued
+    /// in their session and dropped if the queue is full.
+    Queue,
+}
+
+/// A token bucket and its rate. The bucket holds one second worth of tokens by default.
+#[derive(Debug)]
+struct Limit {
+    rate: f64,
+    burst: f64,
+    bucket: TokenBucket,
+}
+
+impl Limit {
+    fn new(rate: f64, burst: Option<f64>) -> Self {
+        let burst = burst.unwrap_or(rate);
+        Self {
+            rate,
+            burst,
+            bucket: TokenBucket::new(burst),
+        }
+    }
+
+    /// Amounts which are larger than the bucket are allowed when the bucket is full;
+    /// otherwise they could never be taken. Taking them puts the bucket in debt.
+    fn has(&mut self, amount: f64) -> bool {
+        self.bucket
+            .has(amount.min(self.burst), self.rate, self.burst)
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.bucket.reserve(amount, self.rate, self.burst);
+    }
+
+    fn reserve(&mut self, amount: f64) -> Duration {
+        self.bucket.reserve(amount, self.rate, self.burst)
+    }
+}
+
+/// Limits the bytes and the datagrams which are sent per second
+#[derive(Debug, Default)]
+struct Limiter {
+    bytes: Option<Limit>,
+    datagrams: Option<Limit>,
+}
+
+impl Limiter {
+    fn new(
+        bytes_per_second: Option<f64>,
+        burst: Option<f64>,
+        datagrams_per_second: Option<f64>,
+    ) -> Self {
+        Self {
+            bytes: bytes_per_second.map(|rate| Limit::new(rate, burst)),
+            datagrams: datagrams_per_second.map(|rate| Limit::new(rate, None)),
+        }
+    }
+
+    /// Returns true if a datagram of this size can be sent now
+    fn has(&mut self, bytes: usize) -> bool {
+        self.bytes
+            .as_mut()
+            .is_none_or(|limit| limit.has(bytes as f64))
+            && self.datagrams.as_mut().is_none_or(|limit| limit.has(1.0))
+    }
+
+    /// Account a datagram which is sent now
+    fn take(&mut self, bytes: usize) {
+        self.bytes
+            .iter_mut()
+            .for_each(|limit| limit.take(bytes as f64));
+        self.datagrams.iter_mut().for_each(|limit| limit.take(1.0));
+    }
+
+    /// Account a datagram which is sent in the future. Returns how long it must wait.
+    fn reserve(&mut self, bytes: usize) -> Duration {
+        let bytes = self
+            .bytes
+            .as_mut()
+            .map_or(Duration::ZERO, |limit| limit.reserve(bytes as f64));
+        let datagrams = self
+            .datagrams
+            .as_mut()
+            .map_or(Duration::ZERO, |limit| limit.reserve(1.0));
+        bytes.max(datagrams)
+    }
+}
+
+/// Bandwidth limits of all sessions of a server or a client
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    limits: BandwidthLimits,
+    /// Shared between the sessions of the service
+    service: Arc<Mutex<Limiter>>,
+    /// Shared between all sessions
+    total: Arc<Mutex<Limiter>>,
+}
+
+impl Bandwidth {
+    pub fn new(limits: BandwidthLimits) -> Self {
+        let total = Limiter::new(
+            limits.total_bandwidth,
+            limits.total_burst,
+            limits.total_packet_rate,
+        );
+        Self {
+            limits,
+            service: Arc::default(),
+            total: Arc::new(Mutex::new(total)),
+        }
+    }
+
+    /// Limit all sessions of the service together as its options say
+    pub fn with_service(mut self, service: &ServiceOptions) -> Self {
+        self.service = Arc::new(Mutex::new(Limiter::new(
+            service.service_bandwidth,
+            service.service_burst,
+            service.service_packet_rate,
+        )));
+        self
+    }
+
+    /// Create the limits of a new session
+    pub fn session(&self) -> SessionBandwidth {
+        SessionBandwidth {
+            session: Limiter::new(
+                self.limits.session_bandwidth,
+                self.limits.session_burst,
+                self.limits.session_packet_rate,
+            ),
+            service: self.service.clone(),
+            total: self.total.clone(),
+            policy: self.limits.limit_policy,
+        }
+    }
+}
+
+/// Bandwidth limits of the datagrams which a session sends to the peer
+#[derive(Debug)]
+pub struct SessionBandwidth {
+    session: Limiter,
+    service: Arc<Mutex<Limiter>>,
+    total: Arc<Mutex<Limiter>>,
+    policy: LimitPolicy,
+}
+
+impl SessionBandwidth {
+    pub fn policy(&self) -> LimitPolicy {
+        self.policy
+    }
+
+    /// Account a datagram which is about to be sent. Returns None if the datagram must be dropped;
+    /// otherwise, how long it must wait before it's sent.
+    pub fn admit(&mut self, bytes: usize) -> Option<Duration> {
+        match self.policy {
+            LimitPolicy::Drop => {
+                let mut service = self.service.lock();
+                let mut total = self.total.lock();
+                if !self.session.has(bytes) || !service.has(bytes) || !total.has(bytes) {
+                    return None;
+                }
+                self.session.take(bytes);
+                service.take(bytes);
+                total.take(bytes);
+                Some(Duration::ZERO)
+            }
+            LimitPolicy::Queue => Some(self.reserve(bytes)),
+        }
+    }
+
+    /// Account a chunk of a stream which is about to be sent. Streams can't drop their data;
+    /// so they wait for the limits whatever the policy is. Returns how long the chunk must wait.
+    pub fn reserve(&mut self, bytes: usize) -> Duration {
+        let session = self.session.reserve(bytes);
+        let service = self.service.lock().reserve(bytes);
+        session.max(service).max(self.total.lock().reserve(bytes))
+    }
+
+    /// Wait until a datagram of this size can be sent. Returns false if it must be dropped.
+    pub async fn acquire(&mut self, bytes: usize) -> bool {
+        let Some(wait) = self.admit(bytes) else {
+            return false;
+        };
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+        true
+    }
+}
+
+/// Wait until a datagram of this size can be sent by a session whose limits are shared
+/// by several tasks. Returns false if it must be dropped.
+pub async fn acquire_shared(bandwidth: &Mutex<SessionBandwidth>, bytes: usize) -> bool {
+    let Some(wait) = bandwidth.lock().admit(bytes) else {
+        return false;
+    };
+    if !wait.is_zero() {
+        time::sleep(wait).await;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        messages::{Compression, Transport},
+        util::FORWARD_BUFFER_SIZE,
+    };
+
+    fn bandwidth(limits: BandwidthLimits) -> Bandwidth {
+        Bandwidth::new(limits)
+    }
+
+    fn service(packet_rate: Option<f64>) -> ServiceOptions {
+        ServiceOptions {
+            fec: 0,
+            compression: Compression::None,
+            reliable: false,
+            transport: Transport::Datagram,
+            service_bandwidth: None,
+            service_packet_rate: packet_rate,
+            service_burst: None,
+        }
+    }
+
+    #[test]
+    fn datagrams_over_the_session_limit_are_dropped() {
+        let bandwidth = bandwidth(BandwidthLimits {
+            session_bandwidth: Some(1000.0),
+            ..BandwidthLimits::default()
+        });
+        let (mut first, mut second) = (bandwidth.session(), bandwidth.session());
+        assert_eq!(first.admit(600), Some(Duration::ZERO));
+        assert_eq!(first.admit(600), None);
+        // Other sessions have their own limits
+        assert_eq!(second.admit(600), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn all_sessions_share_the_total_limit() {
+        let bandwidth = bandwidth(BandwidthLimits {
+            total_packet_rate: Some(2.0),
+            ..BandwidthLimits::default()
+        });
+        let (mut first, mut second) = (bandwidth.session(), bandwidth.session());
+        assert!(first.admit(10).is_some());
+        assert!(second.admit(10).is_some());
+        assert!(first.admit(10).is_none());
+        assert!(second.admit(10).is_none());
+    }
+
+    #[test]
+    fn sessions_of_a_service_share_its_limit() {
+        let service = service(Some(3.0));
+        let bandwidth = bandwidth(BandwidthLimits::default()).with_service(&service);
+        let (mut first, mut second) = (bandwidth.session(), bandwidth.session());
+        assert!(first.admit(10).is_some());
+        assert!(second.admit(10).is_some());
+        assert!(first.admit(10).is_some());
+        assert!(second.admit(10).is_none());
+        // Limits of other services are separate
+        let other = Bandwidth::new(BandwidthLimits::default()).with_service(&service);
+        assert!(other.session().admit(10).is_some());
+    }
+
+    #[test]
+    fn services_are_unlimited_by_default() {
+        let service = service(None);
+        let bandwidth = bandwidth(BandwidthLimits::default()).with_service(&service);
+        let mut session = bandwidth.session();
+        for _ in 0..1000 {
+            assert_eq!(session.admit(FORWARD_BUFFER_SIZE), Some(Duration::ZERO));
+        }
+    }
+
+    #[test]
+    fn streams_wait_for_the_limits_even_if_datagrams_are_dropped() {
+        let bandwidth = bandwidth(BandwidthLimits {
+            session_bandwidth: Some(1000.0),
+            ..BandwidthLimits::default()
+        });
+        let mut session = bandwidth.session();
+        assert_eq!(session.reserve(1000), Duration::ZERO);
+        let wait = session.reserve(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+        assert_eq!(session.admit(1), None);
+    }
+
+    #[test]
+    fn datagrams_larger_than_the_burst_need_a_full_bucket() {
+        let bandwidth = bandwidth(BandwidthLimits {
+            session_bandwidth: Some(1000.0),
+            session_burst: Some(500.0),
+            ..BandwidthLimits::default()
+        });
+        let mut session = bandwidth.session();
+        assert_eq!(session.admit(1200), Some(Duration::ZERO));
+        // The bucket is in debt for more than a second now
+        assert_eq!(session.admit(1), None);
+    }
+
+    #[test]
+    fn queued_datagrams_wait_for_the_limits() {
+        let bandwidth = bandwidth(BandwidthLimits {
+            session_bandwidth: Some(1000.0),
+            limit_policy: LimitPolicy::Queue,
+            ..BandwidthLimits::default()
+        });
+        let mut session = bandwidth.session();
+        assert_eq!(session.admit(1000), Some(Duration::ZERO));
+        let wait = session.admit(500).unwrap();
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+        let wait = session.admit(500).unwrap();
+        assert!(wait > Duration::from_millis(950) && wait <= Duration::from_secs(1));
+    }
+}
@@ -1,24 +1,62 @@
 use std::{
     collections::HashMap,
-    net::{SocketAddr, SocketAddrV4, ToSocketAddrs},
-    sync::{atomic::AtomicBool, atomic::Ordering, Arc},
+    fmt, io,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    sync::{atomic::AtomicBool, atomic::Ordering, Arc, Weak},
     time::{Duration, Instant},
 };
 
-use parking_lot::Mutex;
-use tokio::{net::UdpSocket, select, time};
+use anyhow::{anyhow, bail};
+use ipnet::IpNet;
+use parking_lot::{Mutex, RwLock};
+use quinn::{ConnectionError, Endpoint};
+use tokio::{net::UdpSocket, select, sync::mpsc, time};
 
 use crate::{
-    messages::{PunchMessage, UDPMessage},
-    util::{die, FORWARD_BUFFER_SIZE, LOCAL_UDP_BIND_ADDRESS, SOCKET_TIMEOUT, TURN_BUFFER_SIZE},
+    arguments::{TunOptions, TunnelOptions},
+    bandwidth::{Bandwidth, LimitPolicy, SessionBandwidth},
+    fragment::{Fragmenter, Reassembler, DATAGRAM_OFFSET, DEFAULT_MAX_FRAME_SIZE},
+    identity::{self, Exchange, ResumeSecret},
+    messages::{
+        signed_session, CertificateHash, Challenge, Compression, Cookie, PunchError, PunchMessage,
+        Resume, SessionOptions, SessionToken, Transport, UDPMessage, VpnConfig,
+    },
+    pmtud::{set_dont_fragment, PathMtu},
+    quic::{self, QuicTunnel},
+    secure::{Received, Turn, TurnChannel},
+    service::Service,
+    shutdown::{Shutdown, Stage},
+    stats::SessionStats,
+    tun::Tun,
+    tunnel::{Frame, Liveness, PathDead, CONTROL_BUFFER_SIZE, FRAME_HEADER_SIZE},
+    util::{
+        parse_turn_addresses, FORWARD_BUFFER_SIZE, LOCAL_UDP_BIND_ADDRESS, PUNCH_BUFFER_SIZE,
+        SEALED_TURN_BUFFER_SIZE, SOCKET_TIMEOUT,
+    },
 };
 
 use crate::defer::{defer, ScopeCall};
 
+/// While shutting down, check if all connections are closed every this often
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// How long to wait before starting a new VPN session after one has failed
+const VPN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Maximum number of datagrams of each connection which wait for the bandwidth limits.
+/// New datagrams are dropped when the queue is full.
+const MAX_QUEUED_DATAGRAMS: usize = 256;
+
+/// A datagram which waits for the bandwidth limits and when it can be sent
+type QueuedDatagram = (Box<[u8]>, Instant);
+
 /// Active socket is a client socket which is active and data can be sent into and from
 struct ActiveSocket {
-    /// The socket
-    socket: UdpSocket,
+    /// Carries the datagrams to the server
+    tunnel: Tunnel,
+    /// Limits the datagrams which are sent to the server
+    bandwidth: Mutex<SessionBandwidth>,
+    /// Datagrams which are held back by the bandwidth limits are sent by a task of the connection;
+    /// so they never hold back the listener or other connections. Only if the limits queue datagrams.
+    queue: Option<mpsc::Sender<QueuedDatagram>>,
     /// When was the last time we have seen something go into this socket
     /// (Not read, write)
     last_write: Mutex<Instant>,
@@ -26,18 +64,192 @@ struct ActiveSocket {
     slate: AtomicBool,
 }
 
-/// Spawn a client which connects to a server which is punched via a TURN server
-pub async fn spawn_client(listen: &str, turn: &str, service: &str) -> ! {
+/// How the datagrams of a connection are carried to the server.
+/// Each connection has only one tunnel; so the size of variants does not matter.
+#[allow(clippy::large_enum_variant)]
+enum Tunnel {
+    /// Frames over the punched socket
+    Datagram {
+        /// The socket. It's replaced when the session is resumed over a new path.
+        socket: RwLock<Arc<UdpSocket>>,
+        /// Fragments the datagrams which are sent to the server
+        fragmenter: tokio::sync::Mutex<Fragmenter>,
+    },
+    /// A QUIC connection over the punched socket
+    Quic(QuicTunnel),
+}
+
+impl Tunnel {
+    /// Open the tunnel of a new session over its punched socket
+    async fn open(
+        server_socket: UdpSocket,
+        session_options: SessionOptions,
+        certificate: Option<CertificateHash>,
+        options: &TunnelOptions,
+        stats: &Arc<SessionStats>,
+    ) -> anyhow::Result<(Self, Option<Endpoint>)> {
+        match session_options.transport {
+            Transport::Datagram => {
+                let fragmenter =
+                    Fragmenter::new(DEFAULT_MAX_FRAME_SIZE, session_options, stats.clone());
+                let tunnel = Tunnel::Datagram {
+                    socket: RwLock::new(Arc::new(server_socket)),
+                    fragmenter: tokio::sync::Mutex::new(fragmenter),
+                };
+                Ok((tunnel, None))
+            }
+            Transport::Quic => {
+                let server_address = server_socket.peer_addr()?;
+                let certificate = certificate
+                    .ok_or_else(|| anyhow!("server did not announce its QUIC certificate"))?;
+                let (endpoint, connection) = quic::connect(
+                    server_socket.into_std()?,
+                    server_address,
+                    certificate,
+                    options,
+                )
+                .await?;
+                let tunnel = QuicTunnel::new(connection, stats.clone());
+                Ok((Tunnel::Quic(tunnel), Some(endpoint)))
+            }
+        }
+    }
+}
+
+/// Where the datagrams of a connection are delivered
+#[derive(Clone)]
+enum LocalPeer {
+    /// A peer of the listener socket
+    Socket(&'static UdpSocket, SocketAddr),
+    /// The TUN device of a VPN session
+    Tun(Arc<Tun>),
+}
+
+impl LocalPeer {
+    /// Packets which the TUN device refuses are dropped
+    async fn send(&self, datagram: &[u8]) -> io::Result<usize> {
+        match self {
+            LocalPeer::Socket(socket, address) => socket.send_to(datagram, address).await,
+            LocalPeer::Tun(tun) => match tun.send(datagram).await {
+                Err(err) => {
+                    log::debug!("Dropping a packet which {} refuses: {}", tun.name(), err);
+                    Ok(0)
+                }
+                result => result,
+            },
+        }
+    }
+}
+
+impl fmt::Display for LocalPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalPeer::Socket(_, address) => write!(f, "{}", address),
+            LocalPeer::Tun(tun) => write!(f, "{}", tun.name()),
+        }
+    }
+}
+
+impl ActiveSocket {
+    fn new(tunnel: Tunnel, bandwidth: SessionBandwidth) -> Arc<Self> {
+        let (queue, queued) = match bandwidth.policy() {
+            LimitPolicy::Drop => (None, None),
+            LimitPolicy::Queue => {
+                let (queue, queued) = mpsc::channel(MAX_QUEUED_DATAGRAMS);
+                (Some(queue), Some(queued))
+            }
+        };
+        let active_socket = Arc::new(Self {
+            tunnel,
+            bandwidth: Mutex::new(bandwidth),
+            queue,
+            last_write: Mutex::new(Instant::now()),
+            slate: AtomicBool::new(false),
+        });
+        if let Some(queued) = queued {
+            tokio::task::spawn(send_queued(Arc::downgrade(&active_socket), queued));
+        }
+        active_socket
+    }
+
+    /// Send the datagram which is in buffer[DATAGRAM_OFFSET..][..length] to the server within the
+    /// bandwidth limits. Datagrams over the limits are dropped or queued as the limits say.
+    async fn forward(&self, buffer: &mut [u8], length: usize) -> anyhow::Result<()> {
+        if self
+            .queue
+            .as_ref()
+            .is_some_and(|queue| queue.capacity() == 0)
+        {
+            log::trace!("Dropping a datagram because the bandwidth queue is full");
+            return Ok(());
+        }
+        let Some(wait) = self.bandwidth.lock().admit(length) else {
+            log::trace!("Dropping a datagram over the bandwidth limits");
+            return Ok(());
+        };
+        match &self.queue {
+            // Even datagrams which can be sent now are queued; so the datagrams are never reordered
+            Some(queue) => {
+                let datagram = buffer[DATAGRAM_OFFSET..][..length].into();
+                let _ = queue.try_send((datagram, Instant::now() + wait));
+                Ok(())
+            }
+            None => self.send(buffer, length).await,
+        }
+    }
+
+    /// Send the datagram which is in buffer[DATAGRAM_OFFSET..][..length] to the server
+    async fn send(&self, buffer: &mut [u8], length: usize) -> anyhow::Result<()> {
+        match &self.tunnel {
+            Tunnel::Datagram { socket, fragmenter } => {
+                let socket = socket.read().clone();
+                fragmenter
+                    .lock()
+                    .await
+                    .send(&socket, buffer, length)
+                    .await?;
+            }
+            Tunnel::Quic(tunnel) => tunnel.send(&buffer[DATAGRAM_OFFSET..][..length]).await?,
+        }
+        Ok(())
+    }
+}
+
+/// Send the datagrams which wait for the bandwidth limits of a connection in order.
+/// Returns when the connection is dropped.
+async fn send_queued(
+    active_socket: Weak<ActiveSocket>,
+    mut queued: mpsc::Receiver<QueuedDatagram>,
+) {
+    let mut buffer = vec![0; DATAGRAM_OFFSET + FORWARD_BUFFER_SIZE];
+    while let Some((datagram, deadline)) = queued.recv().await {
+        time::sleep_until(deadline.into()).await;
+        let Some(active_socket) = active_socket.upgrade() else {
+            return;
+        };
+        buffer[DATAGRAM_OFFSET..][..datagram.len()].copy_from_slice(&datagram);
+        // Errors of the path are not fatal. The pump decides if the path is dead.
+        if let Err(err) = active_socket.send(&mut buffer, datagram.len()).await {
+            log::debug!("Cannot send a queued datagram to the server: {}", err);
+        }
+    }
+}
+
+/// Spawn a client which connects to a server which is punched via a TURN server.
+/// Returns when the client is shutting down and all connections are closed.
+#[allow(clippy::too_many_arguments)]
+pub async fn spawn_client(
+    listen: &str,
+    turn: &str,
+    service: &Service,
+    race: bool,
+    options: &TunnelOptions,
+    compression: Compression,
+    bandwidth: Bandwidth,
+    mut shutdown: Shutdown,
+) {
     // Parse socket addresses
-    let turn_address = turn
-        .to_socket_addrs()
-        .expect("cannot parse TURN address")
-        .next()
-        .expect("cannot parse TURN address");
-    let turn_address = match turn_address {
-        SocketAddr::V4(v4) => v4,
-        SocketAddr::V6(_) => die("TURN address cannot be IPv6"),
-    };
+    let turn_addresses = parse_turn_addresses(turn);
     // Listen for incoming connections. We leak this socket because its open until the end of program
     let listener_socket: &'static UdpSocket = Box::leak(Box::new(
         UdpSocket::bind(listen)
@@ -45,17 +257,30 @@ pub async fn spawn_client(listen: &str, turn: &str, service: &str) -> ! {
             .expect("cannot bind to local address"),
     ));
     log::info!("Listening on {}", listener_socket.local_addr().unwrap());
-    let mut buffer = [0; FORWARD_BUFFER_SIZE];
+    let mut buffer = [0; DATAGRAM_OFFSET + FORWARD_BUFFER_SIZE];
     // A map from remote address to outbound sockets
     let mut connection_map: HashMap<SocketAddr, Arc<ActiveSocket>> = HashMap::new();
     let mut last_connection_map_cleanup = Instant::now();
     // In a loop wait for connections and forward them
     loop {
         // Wait for packets...
-        let (read_bytes, addr) = listener_socket
-            .recv_from(&mut buffer)
-            .await
-            .expect("cannot read data from socket");
+        // While shutting down, wake up from time to time to check if all connections are closed.
+        let draining = shutdown.is_draining();
+        let received = select! {
+            read = listener_socket.recv_from(&mut buffer[DATAGRAM_OFFSET..]) => Some(read.expect("cannot read data from socket")),
+            () = shutdown.reached(Stage::Draining), if !draining => None,
+            () = time::sleep(DRAIN_CHECK_INTERVAL), if draining => None,
+        };
+        if draining {
+            connection_map.retain(|_, conn| !conn.slate.load(Ordering::Relaxed));
+            if connection_map.is_empty() {
+                log::info!("All connections are closed");
+                return;
+            }
+        }
+        let Some((read_bytes, addr)) = received else {
+            continue;
+        };
         // Check connection_map from time to time
         if last_connection_map_cleanup.elapsed() > SOCKET_TIMEOUT {
             log::trace!("Cleaning up the servers map");
@@ -66,21 +291,14 @@ pub async fn spawn_client(listen: &str, turn: &str, service: &str) -> ! {
         if let Some(active_socket) = connection_map.get(&addr) {
             // Check slate socket
             if active_socket.slate.load(Ordering::Relaxed) {
-                log::info!(
-                    "Deleting slate socket {}",
-                    active_socket.socket.local_addr().unwrap()
-                );
+                log::info!("Deleting slate connection {}", addr);
                 connection_map.remove(&addr);
                 continue;
             }
             // Send data
-            if let Err(err) = active_socket.socket.send(&buffer[..read_bytes]).await {
+            if let Err(err) = active_socket.forward(&mut buffer, read_bytes).await {
                 // Delete this entry from map
-                log::warn!(
-                    "cannot send udp packet to {}: {}",
-                    active_socket.socket.peer_addr().unwrap(),
-                    err
-                );
+                log::warn!("cannot send udp packet of {} to server: {}", addr, err);
                 active_socket.slate.store(true, Ordering::Relaxed);
                 connection_map.remove(&addr);
             } else {
@@ -88,151 +306,957 @@ pub async fn spawn_client(listen: &str, turn: &str, service: &str) -> ! {
             }
             continue;
         }
-        // Otherwise, we need to punch!
+        // Otherwise, we need to punch! Unless we are shutting down.
+        if draining {
+            log::debug!(
+                "Dropping packet of new connection {} while shutting down",
+                addr
+            );
+            continue;
+        }
         log::info!("New connection from {}", addr);
-        let server_socket = punch(&turn_address, service).await;
+        let Punched {
+            socket: server_socket,
+            session,
+            secret,
+            options: session_options,
+            certificate,
+            ..
+        } = match punch(&turn_addresses, service, race, compression, None).await {
+            Ok(punched) => punched,
+            Err(err) => {
+                log::error!("Cannot punch for {}: {}", addr, err);
+                continue;
+            }
+        };
         log::info!(
             "{} now is sending packets to {}",
             addr,
             server_socket.peer_addr().unwrap()
         );
+        let stats = Arc::new(SessionStats::default());
+        let (tunnel, endpoint) = match Tunnel::open(
+            server_socket,
+            session_options,
+            certificate,
+            options,
+            &stats,
+        )
+        .await
+        {
+            Ok(opened) => opened,
+            Err(err) => {
+                log::error!("Cannot connect to the QUIC server for {}: {}", addr, err);
+                continue;
+            }
+        };
+        // Create the active socket
+        let active_socket = ActiveSocket::new(tunnel, bandwidth.session());
         // Send the first packet we just got
-        let _ = server_socket.send(&buffer[..read_bytes]).await; // fuck errors
-                                                                 // Create the active socket
-        let active_socket = Arc::new(ActiveSocket {
-            socket: server_socket,
-            last_write: Mutex::new(Instant::now()),
-            slate: AtomicBool::new(false),
-        });
+        let _ = active_socket.forward(&mut buffer, read_bytes).await; // fuck errors
         connection_map.insert(addr, active_socket.clone());
         // Create a thread to watch incoming packets
-        tokio::task::spawn(async move {
-            let mut buffer = [0; FORWARD_BUFFER_SIZE];
-            defer!(active_socket.slate.store(true, Ordering::Relaxed));
-            while !active_socket.slate.load(Ordering::Relaxed) {
-                select! {
-                    // Either there is something in the socket
-                    read = active_socket.socket.recv(&mut buffer) => {
-                        let read = read?;
-                        listener_socket.send_to(&buffer[..read], addr).await?;
-                        tokio::task::yield_now().await;
-                    },
-                    // Or there is a timeout in read
-                    () = time::sleep(SOCKET_TIMEOUT) => {
-                        // However, this socket might get outgoing data... Check it
-                        if active_socket.last_write.lock().elapsed() > SOCKET_TIMEOUT {
-                            // Slate connection...
-                            log::info!("Detected slate connection {}", active_socket.socket.local_addr().unwrap());
-                            break;
+        let shutdown = shutdown.clone();
+        let turn_addresses = turn_addresses.clone();
+        let service = service.clone();
+        let options = options.clone();
+        if let Some(endpoint) = endpoint {
+            tokio::task::spawn(pump_quic(
+                LocalPeer::Socket(listener_socket, addr),
+                active_socket,
+                endpoint,
+                options,
+                stats,
+                shutdown,
+            ));
+            continue;
+        }
+        tokio::task::spawn(pump_datagram(
+            LocalPeer::Socket(listener_socket, addr),
+            active_socket,
+            turn_addresses,
+            service,
+            race,
+            compression,
+            (session, secret),
+            session_options,
+            options,
+            stats,
+            shutdown,
+        ));
+    }
+}
+
+/// Spawn a VPN client which carries the packets of a TUN device to a VPN server.
+/// A new session is started whenever the session is over.
+/// Returns when the client is shutting down and its session is closed.
+#[allow(clippy::too_many_arguments)]
+pub async fn spawn_vpn_client(
+    turn: &str,
+    service: &Service,
+    race: bool,
+    options: &TunnelOptions,
+    compression: Compression,
+    bandwidth: Bandwidth,
+    tun: &TunOptions,
+    mut shutdown: Shutdown,
+) {
+    let turn_addresses = parse_turn_addresses(turn);
+    while !shutdown.is_draining() {
+        let session = vpn_session(
+            &turn_addresses,
+            service,
+            race,
+            options,
+            compression,
+            bandwidth.session(),
+            tun,
+            shutdown.clone(),
+        );
+        if let Err(err) = session.await {
+            log::error!("VPN session failed: {}", err);
+            select! {
+                () = time::sleep(VPN_RETRY_INTERVAL) => {},
+                () = shutdown.reached(Stage::Draining) => {},
+            }
+        }
+    }
+}
+
+/// Punch a new VPN session, create its TUN device and send the packets of the device to the server
+/// until the session is over
+#[allow(clippy::too_many_arguments)]
+async fn vpn_session(
+    turn_addresses: &[Turn],
+    service: &Service,
+    race: bool,
+    options: &TunnelOptions,
+    compression: Compression,
+    bandwidth: SessionBandwidth,
+    tun: &TunOptions,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let Punched {
+        socket: server_socket,
+        session,
+        secret,
+        options: session_options,
+        vpn,
+        certificate,
+    } = punch(turn_addresses, service, race, compression, None).await?;
+    let Some(vpn) = vpn else {
+        let _ = server_socket
+            .send(Frame::Close.encode(&mut [0; CONTROL_BUFFER_SIZE]))
+            .await;
+        bail!("service is not a VPN");
+    };
+    // Routes which cover the server or TURN servers would send the tunnel into itself
+    let endpoints: Vec<IpAddr> = turn_addresses
+        .iter()
+        .map(|turn| IpAddr::V4(*turn.address.ip()))
+        .chain([server_socket.peer_addr()?.ip()])
+        .collect();
+    let routes: Vec<IpNet> = vpn
+        .routes
+        .iter()
+        .filter(
+            |route| match endpoints.iter().find(|ip| route.contains(*ip)) {
+                Some(ip) => {
+                    log::warn!("Refusing the pushed route {} which covers {}", route, ip);
+                    false
+                }
+                None => true,
+            },
+        )
+        .copied()
+        .collect();
+    let tun =
+        Arc::new(Tun::open(&tun.tun_name, vpn.address, Some(vpn.peer), tun.mtu, &routes).await?);
+    log::info!(
+        "VPN address is {} on {} and the server is {}",
+        vpn.address,
+        tun.name(),
+        vpn.peer
+    );
+    let stats = Arc::new(SessionStats::default());
+    let (tunnel, endpoint) =
+        Tunnel::open(server_socket, session_options, certificate, options, &stats).await?;
+    let active_socket = ActiveSocket::new(tunnel, bandwidth);
+    let peer = LocalPeer::Tun(tun.clone());
+    let mut pump = match endpoint {
+        Some(endpoint) => tokio::task::spawn(pump_quic(
+            peer,
+            active_socket.clone(),
+            endpoint,
+            options.clone(),
+            stats,
+            shutdown,
+        )),
+        None => tokio::task::spawn(pump_datagram(
+            peer,
+            active_socket.clone(),
+            turn_addresses.to_vec(),
+            service.clone(),
+            race,
+            compression,
+            (session, secret),
+            session_options,
+            options.clone(),
+            stats,
+            shutdown,
+        )),
+    };
+    // The pump must not outlive the session
+    let pump_handle = pump.abort_handle();
+    defer!(pump_handle.abort());
+    let mut buffer = [0; DATAGRAM_OFFSET + FORWARD_BUFFER_SIZE];
+    loop {
+        let read = select! {
+            read = tun.recv(&mut buffer[DATAGRAM_OFFSET..]) => read?,
+            result = &mut pump => return result?,
+        };
+        // Errors of the path are not fatal. The pump decides if the path is dead.
+        if let Err(err) = active_socket.forward(&mut buffer, read).await {
+            log::debug!("Cannot send to the VPN server: {}", err);
+        }
+        *active_socket.last_write.lock() = Instant::now();
+    }
+}
+
+/// Forward the frames of the punched socket to the local peer until the connection is closed or idle.
+/// If the path is dead, a new path is punched and the session is resumed over it.
+#[allow(clippy::too_many_arguments)]
+async fn pump_datagram(
+    peer: LocalPeer,
+    active_socket: Arc<ActiveSocket>,
+    turn_addresses: Vec<Turn>,
+    service: Service,
+    race: bool,
+    compression: Compression,
+    (mut session, mut secret): (SessionToken, ResumeSecret),
+    session_options: SessionOptions,
+    options: TunnelOptions,
+    stats: Arc<SessionStats>,
+    mut shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let Tunnel::Datagram {
+        socket: path,
+        fragmenter,
+    } = &active_socket.tunnel
+    else {
+        unreachable!()
+    };
+    let mut socket = path.read().clone();
+    let mut buffer = [0; FRAME_HEADER_SIZE + FORWARD_BUFFER_SIZE];
+    let mut control_buffer = [0; CONTROL_BUFFER_SIZE];
+    let mut reassembler = Reassembler::new(session_options, stats.clone());
+    let mut timer = fragmenter.lock().await.timer();
+    let mut liveness = Liveness::new(options.keep_alive_interval());
+    let mut path_mtu = PathMtu::default();
+    let mut last_read = Instant::now();
+    defer!({
+        active_socket.slate.store(true, Ordering::Relaxed);
+        log::info!("Connection {} is over: {}", peer, stats);
+    });
+    while !active_socket.slate.load(Ordering::Relaxed) {
+        // The connection is idle if no data is read or written in the idle timeout
+        let last_data = last_read.max(*active_socket.last_write.lock());
+        let fragmenter_deadline = fragmenter.lock().await.deadline();
+        select! {
+            // Either there is something in the socket
+            read = socket.recv(&mut buffer) => {
+                // Errors of the path are not fatal. Liveness decides if the path is dead.
+                let read = match read {
+                    Ok(read) => read,
+                    Err(err) => {
+                        log::debug!("Cannot read from server of {}: {}", peer, err);
+                        continue;
+                    }
+                };
+                match Frame::decode(&buffer[..read]) {
+                    Some(Frame::Close) => {
+                        log::info!("Server closed the connection {}", socket.local_addr().unwrap());
+                        break;
+                    }
+                    Some(Frame::Heartbeat(id)) => {
+                        let _ = socket.send(Frame::HeartbeatAck(id).encode(&mut control_buffer)).await;
+                    }
+                    Some(Frame::HeartbeatAck(id)) => liveness.on_ack(id),
+                    Some(Frame::Probe { id, size }) => {
+                        let _ = socket.send(Frame::ProbeAck { id, size: size as u16 }.encode(&mut control_buffer)).await;
+                    }
+                    Some(Frame::ProbeAck { id, size }) => {
+                        if let Some(mtu) = path_mtu.on_ack(id, size) {
+                            log::info!("Path MTU of connection {} is {} bytes", socket.local_addr().unwrap(), mtu);
+                            fragmenter.lock().await.set_max_frame_size(mtu);
+                        }
+                    }
+                    Some(frame @ (Frame::Data(_) | Frame::Fragment { .. } | Frame::Fec { .. } | Frame::FecParity { .. } | Frame::Reliable { .. })) => {
+                        if let Some(data) = reassembler.receive(frame) {
+                            peer.send(data).await?;
+                            last_read = Instant::now();
+                        }
+                        // The frame might release the frames after it even if it yields no datagram itself
+                        while let Some(data) = reassembler.pop() {
+                            peer.send(data).await?;
+                            last_read = Instant::now();
+                        }
+                        if let Some(ack) = reassembler.ack() {
+                            let _ = socket.send(ack.encode(&mut control_buffer)).await;
+                        }
+                    }
+                    Some(Frame::Ack { next, received }) => {
+                        if let Err(err) = fragmenter.lock().await.on_ack(&socket, next, received).await {
+                            log::debug!("Cannot send to server of {}: {}", peer, err);
                         }
-                        // If we reach here, it means that the socket is not read in the time
-                        // but data was written to it.
-                        // So just continue!
                     }
+                    None => log::trace!("Dropping invalid frame from server of {}", peer),
+                }
+                tokio::task::yield_now().await;
+            },
+            // Or we are shutting down
+            () = shutdown.reached(Stage::Closing) => {
+                log::info!("Closing connection {}", socket.local_addr().unwrap());
+                socket.send(Frame::Close.encode(&mut control_buffer)).await?;
+                break;
+            },
+            // Or it's time to probe the path MTU
+            () = time::sleep_until(path_mtu.deadline()) => {
+                if let Some(mtu) = path_mtu.on_timer(&socket).await {
+                    log::info!("Path MTU of connection {} is {} bytes", socket.local_addr().unwrap(), mtu);
+                    fragmenter.lock().await.set_max_frame_size(mtu);
+                }
+            },
+            // Or it's time to retransmit the lost frames of a reliable session or to flush the FEC parity
+            () = time::sleep_until(fragmenter_deadline.unwrap_or(last_data.into())), if fragmenter_deadline.is_some() => {
+                if let Err(err) = fragmenter.lock().await.on_timer(&socket).await {
+                    log::debug!("Cannot send to server of {}: {}", peer, err);
+                }
+            },
+            // Or the deadline of the fragmenter has moved earlier
+            () = timer.notified() => {},
+            // Or it's time to check if the server is still there
+            () = time::sleep_until(liveness.deadline()) => {
+                match liveness.on_timer() {
+                    Ok(heartbeat) => {
+                        let _ = socket.send(heartbeat.encode(&mut control_buffer)).await;
+                        continue;
+                    }
+                    Err(PathDead) => log::warn!("Path of connection {} is dead (last RTT {:?})", socket.local_addr().unwrap(), liveness.rtt()),
+                }
+                // Punch a new path and move the session to it
+                if shutdown.is_draining() {
+                    break;
+                }
+                let Punched { socket: new_socket, session: new_session, secret: new_secret, options: new_options, vpn: new_vpn, .. } = match punch(&turn_addresses, &service, race, compression, Some((session, &secret))).await {
+                    Ok(punched) => punched,
+                    Err(err) => {
+                        log::error!("Cannot punch a new path for {}: {}", peer, err);
+                        break;
+                    }
+                };
+                if new_session == session {
+                    log::info!("Session of {} is resumed over {}", peer, new_socket.local_addr().unwrap());
+                } else {
+                    log::warn!("Server could not resume the session of {}. A new session is started.", peer);
+                    // The new VPN session has new addresses; so its device must be created again
+                    if new_vpn.is_some() {
+                        let _ = new_socket.send(Frame::Close.encode(&mut control_buffer)).await;
+                        break;
+                    }
+                    if new_options.transport != Transport::Datagram {
+                        log::error!("Server has switched the transport of {}", peer);
+                        break;
+                    }
+                    // The new session might use other options
+                    let new_fragmenter = Fragmenter::new(DEFAULT_MAX_FRAME_SIZE, new_options, stats.clone());
+                    timer = new_fragmenter.timer();
+                    *fragmenter.lock().await = new_fragmenter;
+                    reassembler = Reassembler::new(new_options, stats.clone());
+                }
+                session = new_session;
+                secret = new_secret;
+                socket = Arc::new(new_socket);
+                *path.write() = socket.clone();
+                liveness = Liveness::new(options.keep_alive_interval());
+                // The new path might have a different MTU
+                path_mtu = PathMtu::default();
+                fragmenter.lock().await.set_max_frame_size(path_mtu.max_frame_size());
+            },
+            // Or the connection is idle. The deadline is checked again in the next iteration
+            // because data might have been written to the socket meanwhile.
+            () = time::sleep_until((last_data + options.idle_timeout()).into()) => {
+                if active_socket.last_write.lock().elapsed() >= options.idle_timeout() {
+                    log::info!("Detected slate connection {}", socket.local_addr().unwrap());
+                    let _ = socket.send(Frame::Close.encode(&mut control_buffer)).await;
+                    break;
                 }
             }
-            // Read here: https://rust-lang.github.io/async-book/07_workarounds/02_err_in_async_blocks.html
-            tokio::io::Result::Ok(())
-        });
+        }
+    }
+    Ok(())
+}
+
+/// Forward the datagrams of a QUIC connection to the local peer until the connection is closed or idle.
+/// QUIC detects dead paths itself; so these connections are never resumed over a new path.
+async fn pump_quic(
+    peer: LocalPeer,
+    active_socket: Arc<ActiveSocket>,
+    endpoint: Endpoint,
+    options: TunnelOptions,
+    stats: Arc<SessionStats>,
+    mut shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let Tunnel::Quic(tunnel) = &active_socket.tunnel else {
+        unreachable!()
+    };
+    let mut last_read = Instant::now();
+    defer!({
+        active_socket.slate.store(true, Ordering::Relaxed);
+        log::info!("Connection {} is over: {}", peer, stats);
+    });
+    while !active_socket.slate.load(Ordering::Relaxed) {
+        // The connection is idle if no data is read or written in the idle timeout
+        let last_data = last_read.max(*active_socket.last_write.lock());
+        select! {
+            received = tunnel.receive() => match received {
+                Ok(datagram) => {
+                    peer.send(&datagram).await?;
+                    last_read = Instant::now();
+                }
+                Err(ConnectionError::ApplicationClosed(_)) => {
+                    log::info!("Server closed the connection {}", peer);
+                    break;
+                }
+                Err(err) => {
+                    log::warn!("QUIC connection of {} is lost: {}", peer, err);
+                    break;
+                }
+            },
+            () = shutdown.reached(Stage::Closing) => {
+                log::info!("Closing connection {}", peer);
+                break;
+            },
+            () = time::sleep_until((last_data + options.idle_timeout()).into()) => {
+                if active_socket.last_write.lock().elapsed() >= options.idle_timeout() {
+                    log::info!("Detected slate connection {}", peer);
+                    break;
+                }
+            }
+        }
     }
+    // Tell the server and wait until it knows
+    tunnel.connection().close(0u32.into(), b"");
+    endpoint.wait_idle().await;
+    Ok(())
+}
+
+/// A path which is punched to the server and the session which the server has assigned to it
+pub struct Punched {
+    pub socket: UdpSocket,
+    pub session: SessionToken,
+    /// The secret which resumes the session
+    pub secret: ResumeSecret,
+    pub options: SessionOptions,
+    /// The addresses of a new VPN session
+    pub vpn: Option<VpnConfig>,
+    /// The hash of the certificate which the QUIC server of a new QUIC session presents
+    pub certificate: Option<CertificateHash>,
 }
 
-async fn punch(turn: &SocketAddrV4, service: &str) -> UdpSocket {
-    let mut buffer = [0; TURN_BUFFER_SIZE];
+/// Punch a path to the server. If resume is set, the server is asked to move that session to the new path
+/// and we prove that we hold the secret of the session.
+/// The server compresses the session with the given compression if its service uses it.
+pub async fn punch(
+    turns: &[Turn],
+    service: &Service,
+    race: bool,
+    compression: Compression,
+    resume: Option<(SessionToken, &ResumeSecret)>,
+) -> anyhow::Result<Punched> {
+    let mut buffer = [0; PUNCH_BUFFER_SIZE];
     // At first create a socket
-    let socket = UdpSocket::bind(LOCAL_UDP_BIND_ADDRESS)
-        .await
-        .expect("Cannot bind new socket");
+    let socket = UdpSocket::bind(LOCAL_UDP_BIND_ADDRESS).await?;
     log::debug!("Bound local socket on {}", socket.local_addr().unwrap());
-    // Now send the TURN hello to server.
+    if let Err(err) = set_dont_fragment(&socket) {
+        log::warn!("Cannot set the don't fragment bit of socket: {}", err);
+    }
+    // Now get the server address from TURN servers
+    let server_address = lookup_server(&socket, turns, service, race).await?;
+    // Before punching, wait one second in order to let the server punch its NAT
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    // Now punch! (handshake step 2)
+    socket.connect(server_address).await?;
+    // TURN server might give us the address of someone else; so a pinned server must prove its identity
+    let challenge = service.server_key().map(|_| identity::challenge());
+    // A new session derives its secret from our key and the key of the server
+    let exchange = Exchange::generate();
+    // Our identity and the secret of the resumed session are proved with the nonce of the server
+    let handshake2 = |nonce: Option<&Challenge>| {
+        postcard::to_stdvec(&UDPMessage::Punch(PunchMessage::PeerHandshake2 {
+            resume: resume.zip(nonce).map(|((session, secret), nonce)| {
+                Box::new(Resume {
+                    session,
+                    proof: identity::prove_resume(secret, session, nonce),
+                })
+            }),
+            exchange: exchange.public_key(),
+            compression,
+            identity: service
+                .identity()
+                .zip(nonce)
+                .map(|(identity, nonce)| Box::new(identity.prove(service.name(), nonce))),
+            challenge,
+        }))
+        .unwrap()
+    };
+    // Clients with an identity or a session to resume ask for the nonce first
+    let mut answered = service.identity().is_none() && resume.is_none();
+    if answered {
+        socket.send(&handshake2(None)).await?;
+    } else {
+        let handshake1 = UDPMessage::Punch(PunchMessage::PeerHandshake1 { nonce: None });
+        socket
+            .send(&postcard::to_stdvec(&handshake1).unwrap())
+            .await?;
+    }
+    log::debug!("Punched own NAT");
+    // Wait for server
+    loop {
+        let read_bytes = time::timeout(SOCKET_TIMEOUT, socket.recv(&mut buffer))
+            .await
+            .map_err(|_| anyhow!("server did not answer the punch"))??;
+        let server_punch = postcard::from_bytes::<UDPMessage<'_>>(&buffer[..read_bytes])
+            .map_err(|err| anyhow!("got invalid packet from server: {}", err))?;
+        if let UDPMessage::Punch(PunchMessage::PeerHandshake1 { nonce }) = server_punch {
+            match nonce.filter(|_| !answered) {
+                Some(nonce) => {
+                    socket.send(&handshake2(Some(&nonce))).await?;
+                    answered = true;
+                }
+                // NAT already punched
+                // ... but we need to wait for last packet from server as well
+                None => log::trace!(
+                    "First handshake packet went through the NAT! A full-cone nat or no nat"
+                ),
+            }
+            continue;
+        }
+        if let UDPMessage::Punch(PunchMessage::PeerHandshake3 {
+            session,
+            options,
+            vpn,
+            certificate,
+            exchange: server_exchange,
+            proof,
+        }) = server_punch
+        {
+            if let Some((server_key, challenge)) = service.server_key().zip(challenge) {
+                let verified = match proof {
+                    Some(proof) => server_key.verify_server(
+                        service.name(),
+                        &challenge,
+                        &signed_session(
+                            session,
+                            &options,
+                            &vpn,
+                            &certificate,
+                            (&exchange.public_key(), &server_exchange),
+                        ),
+                        &proof,
+                    ),
+                    None => Err(anyhow!("server did not prove that it is {}", server_key)),
+                };
+                if let Err(err) = verified {
+                    // Whoever it is should not keep the session
+                    let _ = socket
+                        .send(Frame::Close.encode(&mut [0; CONTROL_BUFFER_SIZE]))
+                        .await;
+                    return Err(err);
+                }
+                log::debug!(
+                    "Server {} has proved that it is {}",
+                    server_address,
+                    server_key
+                );
+            }
+            // A resumed session keeps its secret and a new one derives its own
+            let secret = match (server_exchange, resume) {
+                (Some(server_exchange), _) => exchange
+                    .resume_secret(&server_exchange)
+                    .ok_or_else(|| anyhow!("server has sent an invalid exchange key"))?,
+                (None, Some((resumed, secret))) if resumed == session => *secret,
+                (None, _) => bail!("server did not send its exchange key"),
+            };
+            // Last packet. Done!
+            return Ok(Punched {
+                socket,
+                session,
+                secret,
+                options,
+                vpn,
+                certificate,
+            });
+        }
+        if matches!(server_punch, UDPMessage::Punch(PunchMessage::Unauthorized)) {
+            bail!("server did not authorize our identity");
+        }
+        bail!("server response is not ok: {:?}", server_punch);
+    }
+}
+
+/// Ask TURN servers for the address of the server.
+/// TURN servers are tried in order unless race is true; In that case the fastest one is used.
+/// The next TURN server is used if one of them does not answer.
+async fn lookup_server(
+    socket: &UdpSocket,
+    turns: &[Turn],
+    service: &Service,
+    race: bool,
+) -> anyhow::Result<SocketAddrV4> {
+    let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut plain = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut channels: Vec<TurnChannel> = turns.iter().copied().map(TurnChannel::new).collect();
     // Server might not be ready. In this case we implement a retry mechanism.
-    let server_address: SocketAddrV4;
     let mut retry_counter = 0;
+    let mut lookup_key = service.lookup_key();
+    let (mut turn_index, mut cookie) = if race && turns.len() > 1 {
+        race_turns(socket, &mut channels, &lookup_key).await?
+    } else {
+        (0, None)
+    };
+    let mut previous_lookup_key = service.previous_lookup_key();
     loop {
-        let write_buffer = postcard::to_slice(
+        let channel = &mut channels[turn_index % turns.len()];
+        let turn = channel.address();
+        let write_buffer = channel.seal(
             &UDPMessage::Client {
-                service_name: service,
+                service_name: &lookup_key,
+                cookie,
             },
             &mut buffer,
-        )
-        .unwrap();
-        socket
-            .send_to(write_buffer, turn)
-            .await
-            .expect("Cannot send TURN hello to TURN server");
-        // This should send back either server address or a error which server does exists (yet)
-        let (read_bytes, _) = socket
-            .recv_from(&mut buffer)
-            .await
-            .expect("Cannot get TURN hello answer");
-        let turn_punch = match postcard::from_bytes::<UDPMessage<'_>>(&buffer[..read_bytes]) {
-            Err(err) => die(format!("Got invalid packet from TURN server: {}", err)),
-            Ok(pkt) => pkt,
+        );
+        socket.send_to(write_buffer, turn).await?;
+        // This should send back either server address or a error which server does exists (yet).
+        // TURN server might drop our packet if we are rate limited; so we need a timeout here.
+        // Forged packets are dropped while we wait.
+        let deadline = time::Instant::now() + SOCKET_TIMEOUT;
+        let turn_punch = loop {
+            let read_bytes =
+                match time::timeout_at(deadline, recv_from(socket, &turn, &mut buffer)).await {
+                    Ok(read) => read?,
+                    Err(_) => break None,
+                };
+            if let Some(received) = channel.open(&buffer[..read_bytes], &mut plain)? {
+                break Some(received);
+            }
         };
         // Check status
-        if let UDPMessage::Punch(p) = turn_punch {
-            if let PunchMessage::TURN(peer) = p {
-                log::info!("Got {} as server address", peer);
-                server_address = peer;
-                break;
+        match turn_punch {
+            // The secure channel has moved on. Send the lookup again over it.
+            Some(Received::Resend) => continue,
+            Some(Received::Message(UDPMessage::Punch(PunchMessage::TURN(peer)))) => {
+                log::info!("Got {} as server address from {}", peer, turn);
+                return Ok(peer);
+            }
+            // TURN server wants a cookie. Send the hello again with it without waiting.
+            // If we get the same cookie again, something is wrong and we should retry.
+            Some(Received::Message(UDPMessage::Cookie(new_cookie)))
+                if cookie != Some(new_cookie) =>
+            {
+                log::trace!("Got cookie from TURN server");
+                cookie = Some(new_cookie);
+                continue;
+            }
+            // The server might still be registered with the key of last epoch
+            Some(Received::Message(UDPMessage::Error(PunchError::NoServer)))
+                if previous_lookup_key.is_some() =>
+            {
+                log::debug!("Trying the lookup key of previous epoch");
+                lookup_key = previous_lookup_key.take().unwrap();
+                continue;
+            }
+            // Fuck up. Retry
+            Some(Received::Message(turn_punch)) => log::warn!(
+                "Cannot get the server address from TURN server. Got {:?}",
+                turn_punch
+            ),
+            // Fail over to the next TURN server. It might have forgotten our channel when we come back.
+            None => {
+                log::warn!("TURN server {} did not answer", turn);
+                channel.reset();
+                turn_index += 1;
             }
         }
-        // Fuck up. Retry
-        log::warn!(
-            "Cannot get the server address from TURN server. Got {:?}",
-            turn_punch
-        );
         if retry_counter == 5 {
-            die("Out of reties. RIP");
+            bail!("out of retries");
         }
         retry_counter += 1;
         tokio::time::sleep(Duration::from_secs(retry_counter)).await;
         log::warn!("Retrying...");
+        lookup_key = service.lookup_key();
+        previous_lookup_key = service.previous_lookup_key();
     }
-    // Before punching, wait one second in order to let the server punch its NAT
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    // Now punch! (handshake step 2)
-    socket
-        .connect(server_address)
-        .await
-        .expect("cannot do the connect to server ip");
-    let write_buffer = postcard::to_slice(
-        &UDPMessage::Punch {
-            0: PunchMessage::PeerHandshake2,
-        },
-        &mut buffer,
-    )
-    .unwrap();
-    socket.send(write_buffer).await.expect("cannot punch");
-    log::debug!("Punched own NAT");
-    // Wait for server]
+}
+
+/// Receive a packet from the given address. Packets from other addresses are dropped.
+async fn recv_from(
+    socket: &UdpSocket,
+    from: &SocketAddrV4,
+    buffer: &mut [u8],
+) -> std::io::Result<usize> {
     loop {
-        let read_bytes = socket
-            .recv(&mut buffer)
-            .await
-            .expect("cannot do third stage of punch");
-        let server_punch = match postcard::from_bytes::<UDPMessage<'_>>(&buffer[..read_bytes]) {
-            Err(err) => die(format!("Got invalid packet from server: {}", err)),
-            Ok(pkt) => pkt,
+        let (read_bytes, addr) = socket.recv_from(buffer).await?;
+        if addr == SocketAddr::V4(*from) {
+            return Ok(read_bytes);
+        }
+        log::trace!("Dropping packet from {}", addr);
+    }
+}
+
+/// Find the fastest TURN server. We send a hello without cookie to every TURN server.
+/// Such hellos are answered with a cookie and never cause any lookup; so we can safely
+/// send them to all TURN servers. The hellos of secure channels are answered with a cookie too.
+/// Returns the index of fastest TURN server and its cookie, unless its channel keeps the cookie.
+async fn race_turns(
+    socket: &UdpSocket,
+    channels: &mut [TurnChannel],
+    lookup_key: &str,
+) -> anyhow::Result<(usize, Option<Cookie>)> {
+    let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut plain = [0; SEALED_TURN_BUFFER_SIZE];
+    let hello = UDPMessage::Client {
+        service_name: lookup_key,
+        cookie: None,
+    };
+    for channel in channels.iter_mut() {
+        let write_buffer = channel.seal(&hello, &mut buffer);
+        socket.send_to(write_buffer, channel.address()).await?;
+    }
+    let deadline = time::Instant::now() + SOCKET_TIMEOUT;
+    loop {
+        let (read_bytes, from) =
+            match time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+                Ok(read) => read?,
+                // No one answered. Just try them in order.
+                Err(_) => return Ok((0, None)),
+            };
+        let SocketAddr::V4(from) = from else {
+            continue;
         };
-        if matches!(
-            server_punch,
-            UDPMessage::Punch(PunchMessage::PeerHandshake1)
-        ) {
-            // NAT already punched
-            // ... but we need to wait for last packet from server as well
-            log::trace!("First handshake packet went through the NAT! A full-cone nat or no nat");
+        let Some(turn_index) = channels
+            .iter()
+            .position(|channel| channel.address() == from)
+        else {
             continue;
+        };
+        match channels[turn_index].open(&buffer[..read_bytes], &mut plain) {
+            Ok(Some(Received::Message(UDPMessage::Cookie(cookie)))) => {
+                log::debug!("TURN server {} won the race", from);
+                return Ok((turn_index, Some(cookie)));
+            }
+            Ok(Some(Received::Resend)) => {
+                log::debug!("TURN server {} won the race", from);
+                return Ok((turn_index, None));
+            }
+            _ => {}
         }
-        if matches!(
-            server_punch,
-            UDPMessage::Punch(PunchMessage::PeerHandshake3)
-        ) {
-            // Last packet
-            break;
-        }
-        die(format!("Server response is not ok: {:?}", server_punch));
     }
-    // Done!
-    return socket;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::identity::{Identity, PublicKey};
+
+    use super::*;
+
+    const COOKIE: Cookie = 7;
+
+    /// A fake TURN server over a plain channel. It gives a cookie to hellos and answers the lookups
+    /// with answer after delay. Returns the lookups which it got.
+    async fn fake_turn(
+        delay: Duration,
+        answer: impl Fn(&str) -> Option<UDPMessage<'static>> + Send + 'static,
+    ) -> (Turn, Arc<Mutex<Vec<String>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(address) = socket.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let lookups = Arc::new(Mutex::new(Vec::new()));
+        let received = lookups.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+            loop {
+                let (length, from) = socket.recv_from(&mut buffer).await.unwrap();
+                let Ok(UDPMessage::Client {
+                    service_name,
+                    cookie,
+                }) = postcard::from_bytes(&buffer[..length])
+                else {
+                    continue;
+                };
+                let reply = match cookie {
+                    None => Some(UDPMessage::Cookie(COOKIE)),
+                    Some(_) => {
+                        received.lock().push(service_name.to_owned());
+                        answer(service_name)
+                    }
+                };
+                if let Some(reply) = reply {
+                    time::sleep(delay).await;
+                    let reply = postcard::to_stdvec(&reply).unwrap();
+                    socket.send_to(&reply, from).await.unwrap();
+                }
+            }
+        });
+        (Turn { address, key: None }, lookups)
+    }
+
+    fn server(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new([192, 0, 2, 1].into(), port)
+    }
+
+    fn found(port: u16) -> Option<UDPMessage<'static>> {
+        Some(UDPMessage::Punch(PunchMessage::TURN(server(port))))
+    }
+
+    async fn client_socket() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn turn_servers_which_do_not_answer_are_skipped() {
+        let (silent, silent_lookups) = fake_turn(Duration::ZERO, |_| None).await;
+        let (answering, _) = fake_turn(Duration::ZERO, |_| found(1)).await;
+        let service = Service::new("test".to_owned(), None, Duration::from_secs(60));
+        let socket = client_socket().await;
+        let address = lookup_server(&socket, &[silent, answering], &service, false)
+            .await
+            .unwrap();
+        assert_eq!(address, server(1));
+        assert_eq!(*silent_lookups.lock(), ["test"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn servers_of_previous_epoch_are_looked_up_if_there_is_no_server() {
+        let service = Service::new(
+            "test".to_owned(),
+            Some("secret".to_owned()),
+            Duration::from_secs(3600),
+        );
+        let previous = service.previous_lookup_key().unwrap();
+        let registered = previous.clone();
+        let (turn, lookups) = fake_turn(Duration::ZERO, move |key| {
+            if key == registered {
+                found(1)
+            } else {
+                Some(UDPMessage::Error(PunchError::NoServer))
+            }
+        })
+        .await;
+        let socket = client_socket().await;
+        let address = lookup_server(&socket, &[turn], &service, false)
+            .await
+            .unwrap();
+        assert_eq!(address, server(1));
+        assert_eq!(*lookups.lock(), [service.lookup_key(), previous]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_fastest_turn_server_wins_the_race() {
+        let (slow, slow_lookups) = fake_turn(Duration::from_millis(100), |_| found(1)).await;
+        let (fast, _) = fake_turn(Duration::from_millis(10), |_| found(2)).await;
+        let service = Service::new("test".to_owned(), None, Duration::from_secs(60));
+        let socket = client_socket().await;
+        let mut channels = [TurnChannel::new(slow), TurnChannel::new(fast)];
+        let (index, cookie) = race_turns(&socket, &mut channels, "test").await.unwrap();
+        assert_eq!((index, cookie), (1, Some(COOKIE)));
+        let socket = client_socket().await;
+        let address = lookup_server(&socket, &[slow, fast], &service, true)
+            .await
+            .unwrap();
+        assert_eq!(address, server(2));
+        assert!(slow_lookups.lock().is_empty());
+    }
+
+    /// A new identity. Its key file is removed right away.
+    fn identity() -> Identity {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "client-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let identity = Identity::generate(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        identity
+    }
+
+    /// Punch a server which is pinned to the key but proves its session with signer.
+    /// Returns the result of the punch and whether the client has closed the session.
+    async fn punch_pinned(signer: &Identity, pinned: PublicKey) -> (anyhow::Result<Punched>, bool) {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(peer_address) = peer.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let (turn, _) = fake_turn(Duration::ZERO, move |_| {
+            Some(UDPMessage::Punch(PunchMessage::TURN(peer_address)))
+        })
+        .await;
+        let service = Service::new("test".to_owned(), None, Duration::from_secs(60))
+            .with_server_key(Some(pinned));
+        let peer = async {
+            let mut buffer = [0; PUNCH_BUFFER_SIZE];
+            let (length, client) = peer.recv_from(&mut buffer).await.unwrap();
+            let Ok(UDPMessage::Punch(PunchMessage::PeerHandshake2 {
+                exchange,
+                challenge: Some(challenge),
+                ..
+            })) = postcard::from_bytes(&buffer[..length])
+            else {
+                panic!("client did not send a challenge");
+            };
+            let options = SessionOptions::default();
+            let server_exchange = Some(Exchange::generate().public_key());
+            let session = signed_session(1, &options, &None, &None, (&exchange, &server_exchange));
+            let handshake3 = UDPMessage::Punch(PunchMessage::PeerHandshake3 {
+                session: 1,
+                options,
+                vpn: None,
+                certificate: None,
+                exchange: server_exchange,
+                proof: Some(Box::new(signer.prove_server("test", &challenge, &session))),
+            });
+            let handshake3 = postcard::to_stdvec(&handshake3).unwrap();
+            peer.send_to(&handshake3, client).await.unwrap();
+            match time::timeout(SOCKET_TIMEOUT, peer.recv(&mut buffer)).await {
+                Ok(length) => Frame::decode(&buffer[..length.unwrap()]) == Some(Frame::Close),
+                Err(_) => false,
+            }
+        };
+        let turns = [turn];
+        tokio::join!(
+            punch(&turns, &service, false, Compression::None, None),
+            peer
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pinned_clients_accept_the_proof_of_their_server() {
+        let server = identity();
+        let (punched, closed) = punch_pinned(&server, server.public_key()).await;
+        assert_eq!(punched.unwrap().session, 1);
+        assert!(!closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pinned_clients_abort_when_another_key_proves_the_session() {
+        // Such as a rendezvous server which gives out its own address
+        let impostor = identity();
+        let (punched, closed) = punch_pinned(&impostor, identity().public_key()).await;
+        assert!(punched.is_err());
+        assert!(closed);
+    }
 }
@@ -0,0 +1,150 @@
+use crate::{messages::Compression, util::FORWARD_BUFFER_SIZE};
+
+/// Every datagram of a compressed session starts with a byte which says how it's encoded
+pub const COMPRESSION_HEADER_SIZE: usize = 1;
+
+/// Size of the buffer which any datagram can be compressed into
+pub const COMPRESS_BUFFER_SIZE: usize =
+    COMPRESSION_HEADER_SIZE + lz4_flex::block::get_maximum_output_size(FORWARD_BUFFER_SIZE);
+
+/// Encodings of datagrams in compressed sessions
+mod encoding {
+    pub const RAW: u8 = 0;
+    pub const LZ4: u8 = 1;
+}
+
+/// Compress the data into output[COMPRESSION_HEADER_SIZE..] and write its encoding before it.
+/// Output must have room for COMPRESS_BUFFER_SIZE bytes. Returns the length of the compressed datagram or None if the data does not shrink.
+pub fn compress(compression: Compression, data: &[u8], output: &mut [u8]) -> Option<usize> {
+    let (header, body) = output.split_at_mut(COMPRESSION_HEADER_SIZE);
+    let length = match compression {
+        Compression::None => return None,
+        Compression::Lz4 => {
+            header[0] = encoding::LZ4;
+            lz4_flex::block::compress_into(data, body).ok()?
+        }
+    };
+    // Only compressed data which is smaller than the raw datagram is useful
+    (length < data.len()).then_some(COMPRESSION_HEADER_SIZE + length)
+}
+
+/// Mark the data which is in buffer[COMPRESSION_HEADER_SIZE..] as not compressed in place.
+/// Returns the length of the datagram.
+pub fn raw(buffer: &mut [u8], length: usize) -> usize {
+    buffer[0] = encoding::RAW;
+    COMPRESSION_HEADER_SIZE + length
+}
+
+/// Decompresses the datagrams of a compressed session
+#[derive(Debug)]
+pub struct Decompressor {
+    buffer: Vec<u8>,
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Self {
+            buffer: vec![0; FORWARD_BUFFER_SIZE],
+        }
+    }
+}
+
+impl Decompressor {
+    /// Decode a datagram of a compressed session. Returns None if the datagram is invalid.
+    pub fn decompress<'a>(&'a mut self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let (&encoding, data) = datagram.split_first()?;
+        match encoding {
+            encoding::RAW => Some(data),
+            encoding::LZ4 => match lz4_flex::block::decompress_into(data, &mut self.buffer) {
+                Ok(length) => Some(&self.buffer[..length]),
+                Err(err) => {
+                    log::debug!("Cannot decompress datagram: {}", err);
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compressible text of the given length
+    fn text(length: usize) -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog. "
+            .iter()
+            .copied()
+            .cycle()
+            .take(length)
+            .collect()
+    }
+
+    #[test]
+    fn compressed_datagrams_are_decompressed() {
+        let mut decompressor = Decompressor::default();
+        let mut output = vec![0; COMPRESS_BUFFER_SIZE];
+        for length in [100, 1400, FORWARD_BUFFER_SIZE] {
+            let data = text(length);
+            let compressed = compress(Compression::Lz4, &data, &mut output).unwrap();
+            assert!(compressed < data.len());
+            assert_eq!(
+                decompressor.decompress(&output[..compressed]),
+                Some(&data[..])
+            );
+        }
+    }
+
+    #[test]
+    fn datagrams_which_do_not_shrink_are_sent_raw() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut output = vec![0; COMPRESS_BUFFER_SIZE];
+        assert_eq!(compress(Compression::Lz4, &data, &mut output), None);
+        assert_eq!(compress(Compression::None, &text(1400), &mut output), None);
+        output[COMPRESSION_HEADER_SIZE..][..data.len()].copy_from_slice(&data);
+        let length = raw(&mut output, data.len());
+        assert_eq!(
+            Decompressor::default().decompress(&output[..length]),
+            Some(&data[..])
+        );
+    }
+
+    #[test]
+    fn invalid_datagrams_are_rejected() {
+        let mut decompressor = Decompressor::default();
+        assert_eq!(decompressor.decompress(&[]), None);
+        assert_eq!(decompressor.decompress(&[7, 1, 2, 3]), None);
+        assert_eq!(decompressor.decompress(&[encoding::LZ4, 0xff, 0xff]), None);
+        // Truncated compressed data
+        let data = text(1400);
+        let mut output = vec![0; COMPRESS_BUFFER_SIZE];
+        let compressed = compress(Compression::Lz4, &data, &mut output).unwrap();
+        assert_eq!(decompressor.decompress(&output[..compressed - 1]), None);
+        // Matches which point before the start of the data
+        assert_eq!(
+            decompressor.decompress(&[encoding::LZ4, 0x0f, 0xff, 0xff, 0xff]),
+            None
+        );
+    }
+
+    #[test]
+    fn datagrams_which_decompress_larger_than_the_buffer_are_rejected() {
+        let mut datagram = vec![encoding::LZ4];
+        datagram.extend(lz4_flex::block::compress(&vec![0; FORWARD_BUFFER_SIZE + 1]));
+        assert_eq!(Decompressor::default().decompress(&datagram), None);
+    }
+
+    #[test]
+    fn data_which_does_not_fit_the_output_is_not_compressed() {
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..FORWARD_BUFFER_SIZE * 2)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 24) as u8
+            })
+            .collect();
+        let mut output = vec![0; COMPRESS_BUFFER_SIZE];
+        assert_eq!(compress(Compression::Lz4, &noise, &mut output), None);
+    }
+}
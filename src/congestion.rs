@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Congestion window of new sessions in frames
+const INITIAL_WINDOW: f64 = 10.0;
+/// Congestion window never shrinks below this many frames
+const MIN_WINDOW: f64 = 2.0;
+/// Frames are paced a bit faster than one window per RTT; so the pacer never limits the window
+const PACING_GAIN: f64 = 1.25;
+/// Frames which are not sent in time because of the timer resolution can be sent in a burst
+/// of up to this long
+const MAX_PACING_BURST: Duration = Duration::from_millis(2);
+
+/// A NewReno-like congestion controller with pacing. The window grows by one frame for each
+/// acknowledged frame in slow start and by one frame per RTT afterwards. It's halved when a frame
+/// is lost and falls to the minimum when the retransmission timer expires.
+#[derive(Debug)]
+pub struct Congestion {
+    /// Number of frames which can be in flight
+    window: f64,
+    /// The window never grows beyond this many frames
+    max_window: f64,
+    slow_start_threshold: f64,
+    /// Losses of frames which are sent before this time belong to the last congestion event
+    recovery_start: Instant,
+    /// The next new frame must not be sent before this time
+    next_send: Instant,
+}
+
+impl Congestion {
+    pub fn new(max_window: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            window: INITIAL_WINDOW,
+            max_window: max_window as f64,
+            slow_start_threshold: f64::INFINITY,
+            recovery_start: now,
+            next_send: now,
+        }
+    }
+
+    /// Number of frames which can be in flight
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// When can the next new frame be sent
+    pub fn next_send(&self) -> Instant {
+        self.next_send
+    }
+
+    /// Must be called when a new frame is sent. The frames of each window are spread over the RTT.
+    /// Frames are not paced until the RTT is measured.
+    pub fn on_send(&mut self, rtt: Option<Duration>) {
+        let Some(rtt) = rtt else {
+            return;
+        };
+        let now = Instant::now();
+        let interval = rtt.div_f64(self.window * PACING_GAIN);
+        let earliest = now.checked_sub(MAX_PACING_BURST).unwrap_or(now);
+        self.next_send = self.next_send.max(earliest) + interval;
+    }
+
+    /// Must be called when frames are acknowledged
+    pub fn on_ack(&mut self, acknowledged: usize) {
+        for _ in 0..acknowledged {
+            if self.window < self.slow_start_threshold {
+                self.window += 1.0;
+            } else {
+                self.window += 1.0 / self.window;
+            }
+        }
+        self.window = self.window.min(self.max_window);
+    }
+
+    /// Must be called when a frame which was sent at the given time is lost.
+    /// Timeout is true if the loss is detected by the retransmission timer.
+    pub fn on_loss(&mut self, sent: Instant, timeout: bool) {
+        // The window is already reduced for this congestion event
+        if sent < self.recovery_start {
+            return;
+        }
+        self.recovery_start = Instant::now();
+        self.slow_start_threshold = (self.window / 2.0).max(MIN_WINDOW);
+        self.window = if timeout {
+            MIN_WINDOW
+        } else {
+            self.slow_start_threshold
+        };
+        log::trace!("Congestion window is reduced to {:.1} frames", self.window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
+
+    #[tokio::test(start_paused = true)]
+    async fn window_grows_by_a_frame_per_ack_in_slow_start() {
+        let mut congestion = Congestion::new(256);
+        assert_eq!(congestion.window(), INITIAL_WINDOW as usize);
+        congestion.on_ack(5);
+        assert_eq!(congestion.window(), INITIAL_WINDOW as usize + 5);
+        // But never beyond the maximum window
+        congestion.on_ack(1000);
+        assert_eq!(congestion.window(), 256);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn losses_halve_the_window_once_per_congestion_event() {
+        let mut congestion = Congestion::new(256);
+        congestion.on_ack(10);
+        let before_loss = Instant::now();
+        time::advance(Duration::from_millis(10)).await;
+        congestion.on_loss(Instant::now(), false);
+        assert_eq!(congestion.window(), 10);
+        // Frames which are sent before the window is reduced belong to the same event
+        congestion.on_loss(before_loss, false);
+        assert_eq!(congestion.window(), 10);
+        // The window grows by about one frame per window of acks afterwards
+        congestion.on_ack(9);
+        assert_eq!(congestion.window(), 10);
+        congestion.on_ack(2);
+        assert_eq!(congestion.window(), 11);
+        // A frame which is sent after the reduction starts a new event
+        time::advance(Duration::from_millis(10)).await;
+        congestion.on_loss(Instant::now(), false);
+        assert_eq!(congestion.window(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_drop_the_window_to_the_minimum() {
+        let mut congestion = Congestion::new(256);
+        time::advance(Duration::from_millis(10)).await;
+        congestion.on_loss(Instant::now(), true);
+        assert_eq!(congestion.window(), MIN_WINDOW as usize);
+        // The threshold is half of the window before the timeout; so slow start ends there
+        congestion.on_ack(3);
+        assert_eq!(congestion.window(), INITIAL_WINDOW as usize / 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn frames_are_paced_over_the_rtt() {
+        let mut congestion = Congestion::new(256);
+        let start = Instant::now();
+        // No pacing until the RTT is known
+        congestion.on_send(None);
+        assert_eq!(congestion.next_send(), start);
+        let rtt = Duration::from_millis(100);
+        let interval = rtt.div_f64(INITIAL_WINDOW * PACING_GAIN);
+        congestion.on_send(Some(rtt));
+        congestion.on_send(Some(rtt));
+        assert_eq!(congestion.next_send(), start + interval * 2);
+        // Time which the sender was idle is not saved up beyond a short burst
+        time::advance(Duration::from_secs(1)).await;
+        congestion.on_send(Some(rtt));
+        assert_eq!(
+            congestion.next_send(),
+            Instant::now() - MAX_PACING_BURST + interval
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::{io, sync::Arc, time::Duration};
+
+use tokio::{net::UdpSocket, sync::Notify, time::Instant};
+
+use crate::tunnel::{Frame, FEC_PARITY_HEADER_SIZE};
+
+/// Number of recent groups which the decoder keeps the frames of
+const FEC_WINDOW: usize = 16;
+/// The parity of a group which is not complete is sent if no frame is sent in this time;
+/// so the last frames of a burst are protected too
+const FEC_FLUSH_DELAY: Duration = Duration::from_millis(50);
+
+/// Protects the frames which are sent to the peer with XOR parity.
+/// After every group of frames, a parity frame is sent which can rebuild any one lost frame of the group.
+/// If the sender goes quiet before a group is complete, the parity of the partial group is sent.
+#[derive(Debug)]
+pub struct FecEncoder {
+    /// Number of frames in each group
+    group_size: u8,
+    group: u32,
+    /// Number of frames which are sent in the current group
+    index: u8,
+    /// XOR of the frames of current group
+    parity: Vec<u8>,
+    /// Length of the longest frame of current group
+    parity_length: usize,
+    /// XOR of the lengths of frames of current group
+    length: u16,
+    /// Frames are encoded in this buffer
+    buffer: Vec<u8>,
+    /// When was the last frame of current group sent
+    last_sent: Instant,
+    /// Notified when a group is started; so its parity is flushed in time
+    timer: Arc<Notify>,
+}
+
+impl FecEncoder {
+    pub fn new(group_size: u8, timer: Arc<Notify>) -> Self {
+        Self {
+            group_size: group_size.max(1),
+            group: 0,
+            index: 0,
+            parity: Vec::new(),
+            parity_length: 0,
+            length: 0,
+            buffer: Vec::new(),
+            last_sent: Instant::now(),
+            timer,
+        }
+    }
+
+    /// When should the parity of the partial group be flushed. None if no group is started.
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        (self.index > 0).then(|| self.last_sent + FEC_FLUSH_DELAY)
+    }
+
+    /// Send the parity of the current group even if it's not complete
+    pub async fn flush(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        if self.index == 0 {
+            return Ok(());
+        }
+        log::trace!(
+            "Flushing the parity of group {} after {} frames",
+            self.group,
+            self.index
+        );
+        self.send_parity(socket).await
+    }
+
+    /// Send a frame to the peer. The parity of the group is sent after its last frame.
+    pub async fn send(&mut self, socket: &UdpSocket, frame: &[u8]) -> io::Result<()> {
+        if self.buffer.len() < FEC_PARITY_HEADER_SIZE + frame.len() {
+            self.buffer.resize(FEC_PARITY_HEADER_SIZE + frame.len(), 0);
+        }
+        if self.parity.len() < frame.len() {
+            self.parity.resize(frame.len(), 0);
+        }
+        for (parity, byte) in self.parity.iter_mut().zip(frame) {
+            *parity ^= byte;
+        }
+        self.parity_length = self.parity_length.max(frame.len());
+        self.length ^= frame.len() as u16;
+        let index = self.index;
+        self.index += 1;
+        self.last_sent = Instant::now();
+        if index == 0 {
+            // The flush timer must be started
+            self.timer.notify_one();
+        }
+        let fec_frame = Frame::Fec {
+            group: self.group,
+            index,
+            frame,
+        };
+        socket.send(fec_frame.encode(&mut self.buffer)).await?;
+        if self.index < self.group_size {
+            return Ok(());
+        }
+        // The group is complete
+        self.send_parity(socket).await
+    }
+
+    /// Send the parity of the frames of current group and start the next group
+    async fn send_parity(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        let parity_frame = Frame::FecParity {
+            group: self.group,
+            count: self.index,
+            length: self.length,
+            data: &self.parity[..self.parity_length],
+        };
+        let parity_frame = parity_frame.encode(&mut self.buffer);
+        self.parity[..self.parity_length].fill(0);
+        self.parity_length = 0;
+        self.length = 0;
+        self.index = 0;
+        self.group = self.group.wrapping_add(1);
+        socket.send(parity_frame).await?;
+        Ok(())
+    }
+}
+
+/// Frames of a group which are received
+#[derive(Debug)]
+struct FecGroup {
+    group: u32,
+    frames: Vec<Option<Box<[u8]>>>,
+    /// Number of received frames
+    received: usize,
+    /// True if the parity of this group is already used
+    done: bool,
+}
+
+/// Rebuilds the lost frames with the parity frames which the peer sends
+#[derive(Debug, Default)]
+pub struct FecDecoder {
+    /// Recent groups. Each group is in the slot of its number modulo the window.
+    groups: [Option<FecGroup>; FEC_WINDOW],
+    /// Rebuilt frames are written in this buffer
+    recovered: Vec<u8>,
+}
+
+impl FecDecoder {
+    /// The received frames of a group. None if the group is too old.
+    fn group(groups: &mut [Option<FecGroup>; FEC_WINDOW], group: u32) -> Option<&mut FecGroup> {
+        let slot = &mut groups[group as usize % FEC_WINDOW];
+        match slot {
+            Some(current) if current.group == group => {}
+            // Frames of old groups which arrive late are useless
+            Some(current) if (group.wrapping_sub(current.group) as i32) < 0 => return None,
+            _ => {
+                *slot = Some(FecGroup {
+                    group,
+                    frames: Vec::new(),
+                    received: 0,
+                    done: false,
+                })
+            }
+        }
+        slot.as_mut()
+    }
+
+    /// Keep a copy of a received frame in order to rebuild the lost frames of its group
+    pub fn push(&mut self, group: u32, index: u8, frame: &[u8]) {
+        let Some(group) = Self::group(&mut self.groups, group) else {
+            return;
+        };
+        let index = index as usize;
+        if group.done {
+            return;
+        }
+        if group.frames.len() <= index {
+            group.frames.resize(index + 1, None);
+        }
+        if group.frames[index].is_none() {
+            group.frames[index] = Some(frame.into());
+            group.received += 1;
+        }
+    }
+
+    /// Rebuild the lost frame of a group with its parity.
+    /// Returns the frame if exactly one frame of the group is lost.
+    pub fn recover(&mut self, group: u32, count: u8, length: u16, data: &[u8]) -> Option<&[u8]> {
+        let count = count as usize;
+        let group = Self::group(&mut self.groups, group)?;
+        if group.done || group.frames.len() > count || group.received + 1 != count {
+            return None;
+        }
+        group.done = true;
+        group.frames.resize(count, None);
+        let mut length = length as usize;
+        self.recovered.clear();
+        self.recovered.extend_from_slice(data);
+        for frame in group.frames.iter().flatten() {
+            length ^= frame.len();
+            for (recovered, byte) in self.recovered.iter_mut().zip(frame.iter()) {
+                *recovered ^= byte;
+            }
+        }
+        if length > self.recovered.len() {
+            return None;
+        }
+        log::trace!("Rebuilt a lost frame of group {}", group.group);
+        Some(&self.recovered[..length])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An encoder which sends to the returned socket
+    async fn encoder(group_size: u8) -> (FecEncoder, UdpSocket, UdpSocket) {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender
+            .connect(receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+        let encoder = FecEncoder::new(group_size, Arc::new(Notify::new()));
+        (encoder, sender, receiver)
+    }
+
+    /// Receive the frames which are sent until now as (group, index, frame) and the parity frames
+    /// as (group, count, length, data)
+    #[allow(clippy::type_complexity)]
+    async fn receive(
+        socket: &UdpSocket,
+        packets: usize,
+    ) -> (Vec<(u32, u8, Vec<u8>)>, Vec<(u32, u8, u16, Vec<u8>)>) {
+        let (mut frames, mut parities) = (Vec::new(), Vec::new());
+        let mut buffer = [0; 256];
+        for _ in 0..packets {
+            let read = socket.recv(&mut buffer).await.unwrap();
+            match Frame::decode(&buffer[..read]).unwrap() {
+                Frame::Fec {
+                    group,
+                    index,
+                    frame,
+                } => frames.push((group, index, frame.to_vec())),
+                Frame::FecParity {
+                    group,
+                    count,
+                    length,
+                    data,
+                } => parities.push((group, count, length, data.to_vec())),
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        }
+        (frames, parities)
+    }
+
+    fn frame(i: usize) -> Vec<u8> {
+        vec![i as u8; 10 + i * 3]
+    }
+
+    #[tokio::test]
+    async fn one_lost_frame_of_each_group_is_rebuilt() {
+        let (mut encoder, sender, receiver) = encoder(3).await;
+        for i in 0..6 {
+            encoder.send(&sender, &frame(i)).await.unwrap();
+        }
+        assert!(encoder.flush_deadline().is_none());
+        let (frames, parities) = receive(&receiver, 8).await;
+        assert_eq!(parities.len(), 2);
+        let mut decoder = FecDecoder::default();
+        // The second frame of the first group and the last frame of the second group are lost
+        for (group, index, frame) in &frames {
+            if (*group, *index) != (0, 1) && (*group, *index) != (1, 2) {
+                decoder.push(*group, *index, frame);
+            }
+        }
+        for ((group, count, length, data), lost) in parities.iter().zip([1, 5]) {
+            let recovered = decoder.recover(*group, *count, *length, data);
+            assert_eq!(recovered, Some(&frame(lost)[..]));
+        }
+    }
+
+    #[tokio::test]
+    async fn two_lost_frames_of_a_group_are_not_rebuilt() {
+        let (mut encoder, sender, receiver) = encoder(3).await;
+        for i in 0..3 {
+            encoder.send(&sender, &frame(i)).await.unwrap();
+        }
+        let (frames, parities) = receive(&receiver, 4).await;
+        let mut decoder = FecDecoder::default();
+        let (group, index, frame) = &frames[0];
+        decoder.push(*group, *index, frame);
+        let (group, count, length, data) = &parities[0];
+        assert!(decoder.recover(*group, *count, *length, data).is_none());
+        // Nothing is lost either
+        let mut decoder = FecDecoder::default();
+        for (group, index, frame) in &frames {
+            decoder.push(*group, *index, frame);
+        }
+        assert!(decoder.recover(*group, *count, *length, data).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn parity_of_a_partial_group_is_flushed_when_the_sender_goes_quiet() {
+        let (mut encoder, sender, receiver) = encoder(4).await;
+        let timer = encoder.timer.clone();
+        assert!(encoder.flush_deadline().is_none());
+        encoder.send(&sender, &frame(0)).await.unwrap();
+        // The flush timer is started by the first frame of the group
+        tokio::time::timeout(Duration::from_millis(1), timer.notified())
+            .await
+            .unwrap();
+        tokio::time::advance(FEC_FLUSH_DELAY / 2).await;
+        encoder.send(&sender, &frame(1)).await.unwrap();
+        assert_eq!(
+            encoder.flush_deadline(),
+            Some(Instant::now() + FEC_FLUSH_DELAY)
+        );
+        encoder.flush(&sender).await.unwrap();
+        assert!(encoder.flush_deadline().is_none());
+        let (frames, parities) = receive(&receiver, 3).await;
+        let (group, count, length, data) = &parities[0];
+        assert_eq!((*group, *count), (0, 2));
+        let mut decoder = FecDecoder::default();
+        let (group, index, received) = &frames[1];
+        decoder.push(*group, *index, received);
+        assert_eq!(
+            decoder.recover(0, *count, *length, data),
+            Some(&frame(0)[..])
+        );
+        // The next group starts after the flushed one
+        encoder.send(&sender, &frame(2)).await.unwrap();
+        let (frames, _) = receive(&receiver, 1).await;
+        assert_eq!((frames[0].0, frames[0].1), (1, 0));
+    }
+}
@@ -0,0 +1,618 @@
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+use tokio::{net::UdpSocket, sync::Notify, time::Instant};
+
+use crate::{
+    compress::{compress, raw, Decompressor, COMPRESSION_HEADER_SIZE, COMPRESS_BUFFER_SIZE},
+    fec::{FecDecoder, FecEncoder},
+    messages::{Compression, SessionOptions},
+    reliable::{ReliableReceiver, ReliableSender},
+    stats::SessionStats,
+    tunnel::{
+        data_frame, Frame, FEC_PARITY_HEADER_SIZE, FRAGMENT_HEADER_SIZE, FRAME_HEADER_SIZE,
+        RELIABLE_HEADER_SIZE,
+    },
+    util::FORWARD_BUFFER_SIZE,
+};
+
+/// Datagrams which are sent with a [Fragmenter] must be read at this offset of the buffer.
+/// The room before them is used for the headers.
+pub const DATAGRAM_OFFSET: usize = FRAME_HEADER_SIZE + COMPRESSION_HEADER_SIZE;
+
+/// Largest frame which is sent over the punched path. Larger datagrams are fragmented.
+/// It's small enough to pass through almost every path without IP fragmentation.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1200;
+/// Frames are never smaller than this; so every datagram fits in 255 fragments
+pub const MIN_MAX_FRAME_SIZE: usize = FRAGMENT_HEADER_SIZE
+    + (COMPRESSION_HEADER_SIZE + FORWARD_BUFFER_SIZE).div_ceil(u8::MAX as usize);
+
+/// Fragments of a datagram are dropped if the datagram is not complete in this time
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Maximum bytes of incomplete datagrams which each session keeps
+const MAX_REASSEMBLY_MEMORY: usize = 1024 * 1024;
+/// Maximum number of incomplete datagrams which each session keeps
+const MAX_INCOMPLETE_DATAGRAMS: usize = 64;
+
+/// Sends datagrams of the application to the peer.
+/// Datagrams which don't fit in one frame of the path are fragmented.
+#[derive(Debug)]
+pub struct Fragmenter {
+    /// ID of the next fragmented datagram
+    next_id: u32,
+    /// Largest data or fragment frame which is sent
+    max_frame_size: usize,
+    /// Fragment frames are encoded in this buffer
+    frame_buffer: Vec<u8>,
+    /// Protects the frames with forward error correction if enabled
+    fec: Option<FecEncoder>,
+    /// Retransmits the lost frames if the session is reliable
+    reliable: Option<ReliableSender>,
+    compression: Compression,
+    /// Compressed datagrams are written in this buffer
+    compress_buffer: Vec<u8>,
+    /// Notified when the deadline of on_timer moves earlier
+    timer: Arc<Notify>,
+    stats: Arc<SessionStats>,
+}
+
+impl Fragmenter {
+    pub fn new(max_frame_size: usize, options: SessionOptions, stats: Arc<SessionStats>) -> Self {
+        let timer = Arc::new(Notify::new());
+        let mut fragmenter = Self {
+            next_id: 0,
+            max_frame_size: 0,
+            frame_buffer: Vec::new(),
+            fec: (options.fec_group_size > 0)
+                .then(|| FecEncoder::new(options.fec_group_size, timer.clone())),
+            reliable: options
+                .reliable
+                .then(|| ReliableSender::new(timer.clone(), stats.clone())),
+            compression: options.compression,
+            compress_buffer: match options.compression {
+                Compression::None => Vec::new(),
+                _ => vec![0; FRAME_HEADER_SIZE + COMPRESS_BUFFER_SIZE],
+            },
+            timer,
+            stats,
+        };
+        fragmenter.set_max_frame_size(max_frame_size);
+        fragmenter
+    }
+
+    /// Change the largest frame which is sent over the path
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.stats.set_mtu(max_frame_size);
+        // FEC and reliable frames wrap the data frames; so there must be room for their headers
+        let mut overhead = 0;
+        if self.fec.is_some() {
+            overhead += FEC_PARITY_HEADER_SIZE;
+        }
+        if self.reliable.is_some() {
+            overhead += RELIABLE_HEADER_SIZE;
+        }
+        self.max_frame_size = (max_frame_size.saturating_sub(overhead)).max(MIN_MAX_FRAME_SIZE);
+        if self.frame_buffer.len() < self.max_frame_size {
+            self.frame_buffer.resize(self.max_frame_size, 0);
+        }
+    }
+
+    /// When should on_timer be called. None if nothing is waiting for a timer.
+    pub fn deadline(&self) -> Option<Instant> {
+        let retransmit = self.reliable.as_ref().and_then(ReliableSender::deadline);
+        let flush = self.fec.as_ref().and_then(FecEncoder::flush_deadline);
+        retransmit.into_iter().chain(flush).min()
+    }
+
+    /// Returns a handle which is notified when the deadline moves earlier.
+    /// The deadline must be checked again when it's notified.
+    pub fn timer(&self) -> Arc<Notify> {
+        self.timer.clone()
+    }
+
+    /// Retransmit the lost frames, send the frames which the congestion controller allows now
+    /// and flush the parity of a partial FEC group if the sender has gone quiet.
+    /// Must be called when the deadline is reached.
+    pub async fn on_timer(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        if let Some(reliable) = &mut self.reliable {
+            while let Some(frame) = reliable.poll_transmit() {
+                Self::transmit(&mut self.fec, socket, frame).await?;
+            }
+        }
+        if let Some(fec) = &mut self.fec {
+            if fec
+                .flush_deadline()
+                .is_some_and(|deadline| deadline <= Instant::now())
+            {
+                fec.flush(socket).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Must be called when an ack of reliable frames is received.
+    /// The queued frames which fit in the send window now are sent.
+    pub async fn on_ack(&mut self, socket: &UdpSocket, next: u32, received: u32) -> io::Result<()> {
+        if let Some(reliable) = &mut self.reliable {
+            reliable.on_ack(next, received);
+        }
+        self.on_timer(socket).await
+    }
+
+    /// Send a data or fragment frame to the peer. Frames of reliable sessions are numbered first.
+    async fn send_frame(
+        fec: &mut Option<FecEncoder>,
+        reliable: &mut Option<ReliableSender>,
+        socket: &UdpSocket,
+        frame: &[u8],
+    ) -> io::Result<()> {
+        let frame = match reliable {
+            Some(reliable) => match reliable.push(frame) {
+                Some(frame) => frame,
+                None => return Ok(()),
+            },
+            None => frame,
+        };
+        Self::transmit(fec, socket, frame).await
+    }
+
+    /// Send a frame over the path. It's protected by forward error correction if enabled.
+    async fn transmit(
+        fec: &mut Option<FecEncoder>,
+        socket: &UdpSocket,
+        frame: &[u8],
+    ) -> io::Result<()> {
+        match fec {
+            Some(fec) => fec.send(socket, frame).await,
+            None => socket.send(frame).await.map(|_| ()),
+        }
+    }
+
+    /// Send the datagram which is read into buffer[DATAGRAM_OFFSET..DATAGRAM_OFFSET + length].
+    /// The datagram is compressed if the session is compressed and it shrinks.
+    pub async fn send(
+        &mut self,
+        socket: &UdpSocket,
+        buffer: &mut [u8],
+        length: usize,
+    ) -> io::Result<()> {
+        self.stats.on_sent(length);
+        if self.compression == Compression::None {
+            return self
+                .send_datagram(socket, &mut buffer[COMPRESSION_HEADER_SIZE..], length)
+                .await;
+        }
+        let data = &buffer[DATAGRAM_OFFSET..DATAGRAM_OFFSET + length];
+        let mut compressed = std::mem::take(&mut self.compress_buffer);
+        let result = match compress(self.compression, data, &mut compressed[FRAME_HEADER_SIZE..]) {
+            Some(length) => self.send_datagram(socket, &mut compressed, length).await,
+            None => {
+                let length = raw(&mut buffer[FRAME_HEADER_SIZE..], length);
+                self.send_datagram(socket, buffer, length).await
+            }
+        };
+        self.compress_buffer = compressed;
+        result
+    }
+
+    /// Send the datagram which is in buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length]
+    async fn send_datagram(
+        &mut self,
+        socket: &UdpSocket,
+        buffer: &mut [u8],
+        length: usize,
+    ) -> io::Result<()> {
+        if FRAME_HEADER_SIZE + length <= self.max_frame_size {
+            let frame = data_frame(buffer, length);
+            return Self::send_frame(&mut self.fec, &mut self.reliable, socket, frame).await;
+        }
+        let data = &buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length];
+        let chunk_size = self.max_frame_size - FRAGMENT_HEADER_SIZE;
+        let count = data.len().div_ceil(chunk_size);
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        log::trace!("Sending datagram {} in {} fragments", id, count);
+        for (index, chunk) in data.chunks(chunk_size).enumerate() {
+            let frame = Frame::Fragment {
+                id,
+                index: index as u8,
+                count: count as u8,
+                data: chunk,
+            };
+            let frame = frame.encode(&mut self.frame_buffer);
+            Self::send_frame(&mut self.fec, &mut self.reliable, socket, frame).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A datagram which some of its fragments are received
+#[derive(Debug)]
+struct IncompleteDatagram {
+    fragments: Vec<Option<Box<[u8]>>>,
+    /// Number of received fragments
+    received: usize,
+    /// Total bytes of received fragments
+    size: usize,
+    /// When was the first fragment received
+    started: Instant,
+}
+
+/// Turns the frames which the peer sends back into datagrams. Lost frames are rebuilt with
+/// forward error correction, frames of reliable sessions are put in order, fragmented datagrams
+/// are reassembled and compressed datagrams are decompressed.
+#[derive(Debug)]
+pub struct Reassembler {
+    fragments: Fragments,
+    fec: FecDecoder,
+    /// Orders the frames if the session is reliable
+    reliable: Option<ReliableReceiver>,
+    /// Decompresses the datagrams if the session is compressed
+    decompressor: Option<Decompressor>,
+    /// Datagrams which pop decompresses are copied here
+    popped: Vec<u8>,
+    stats: Arc<SessionStats>,
+}
+
+impl Reassembler {
+    pub fn new(options: SessionOptions, stats: Arc<SessionStats>) -> Self {
+        Self {
+            fragments: Fragments::default(),
+            fec: FecDecoder::default(),
+            reliable: options.reliable.then(ReliableReceiver::default),
+            decompressor: (options.compression != Compression::None).then(Decompressor::default),
+            popped: Vec::new(),
+            stats,
+        }
+    }
+
+    /// Handle a frame which carries data. Returns the datagram which must be delivered to the application.
+    /// In reliable sessions, the frame might release more datagrams which are returned by pop.
+    pub fn receive<'a>(&'a mut self, frame: Frame<'a>) -> Option<&'a [u8]> {
+        let frame = match frame {
+            Frame::Fec {
+                group,
+                index,
+                frame,
+            } => {
+                self.fec.push(group, index, frame);
+                Frame::decode(frame)?
+            }
+            Frame::FecParity {
+                group,
+                count,
+                length,
+                data,
+            } => Frame::decode(self.fec.recover(group, count, length, data)?)?,
+            frame => frame,
+        };
+        let frame = match (frame, &mut self.reliable) {
+            (Frame::Reliable { seq, frame }, Some(reliable)) => {
+                if !reliable.accept(seq, frame) {
+                    return None;
+                }
+                Frame::decode(frame)?
+            }
+            (Frame::Reliable { .. }, None) => return None,
+            (frame, _) => frame,
+        };
+        let datagram = match frame {
+            Frame::Data(data) => data,
+            Frame::Fragment {
+                id,
+                index,
+                count,
+                data,
+            } => {
+                if !self.fragments.push(id, index, count, data) {
+                    return None;
+                }
+                &self.fragments.datagram
+            }
+            _ => return None,
+        };
+        Self::deliver(&mut self.decompressor, &self.stats, datagram)
+    }
+
+    /// Returns the next datagram of a reliable session which was waiting for the frames before it.
+    /// Must be called until it returns None after each received frame.
+    pub fn pop(&mut self) -> Option<&[u8]> {
+        loop {
+            let frame = self.reliable.as_mut()?.pop()?;
+            match Frame::decode(&frame) {
+                Some(Frame::Data(data)) => {
+                    self.fragments.datagram.clear();
+                    self.fragments.datagram.extend_from_slice(data);
+                }
+                Some(Frame::Fragment {
+                    id,
+                    index,
+                    count,
+                    data,
+                }) if self.fragments.push(id, index, count, data) => {}
+                // Frames which don't complete a datagram yield nothing; try the next one
+                _ => continue,
+            }
+            let Some(decompressor) = &mut self.decompressor else {
+                break;
+            };
+            // Datagrams which cannot be decompressed are skipped as well
+            if let Some(datagram) = decompressor.decompress(&self.fragments.datagram) {
+                self.popped.clear();
+                self.popped.extend_from_slice(datagram);
+                break;
+            }
+        }
+        let datagram = match self.decompressor {
+            Some(_) => &self.popped,
+            None => &self.fragments.datagram,
+        };
+        self.stats.on_received(datagram.len());
+        Some(datagram)
+    }
+
+    /// The ack which must be sent to the peer after receiving frames of a reliable session
+    pub fn ack(&mut self) -> Option<Frame<'static>> {
+        self.reliable.as_mut()?.ack()
+    }
+
+    /// Decompress a complete datagram if the session is compressed
+    fn deliver<'a>(
+        decompressor: &'a mut Option<Decompressor>,
+        stats: &SessionStats,
+        datagram: &'a [u8],
+    ) -> Option<&'a [u8]> {
+        let datagram = match decompressor {
+            Some(decompressor) => decompressor.decompress(datagram)?,
+            None => datagram,
+        };
+        stats.on_received(datagram.len());
+        Some(datagram)
+    }
+}
+
+/// Fragments of the datagrams which are not complete yet
+#[derive(Debug, Default)]
+struct Fragments {
+    incomplete: HashMap<u32, IncompleteDatagram>,
+    /// Total bytes of incomplete datagrams
+    memory: usize,
+    /// Complete datagrams are assembled in this buffer
+    datagram: Vec<u8>,
+}
+
+impl Fragments {
+    /// Add a fragment. Returns true if the datagram is complete and assembled in the datagram buffer.
+    fn push(&mut self, id: u32, index: u8, count: u8, data: &[u8]) -> bool {
+        if index >= count {
+            return false;
+        }
+        self.remove_expired();
+        // A datagram with an id which is reused with a different count is garbage
+        if self
+            .incomplete
+            .get(&id)
+            .is_some_and(|datagram| datagram.fragments.len() != count as usize)
+        {
+            self.remove(id);
+        }
+        if !self.incomplete.contains_key(&id) {
+            // Make room for the new datagram by dropping the oldest ones
+            while !self.incomplete.is_empty()
+                && (self.incomplete.len() >= MAX_INCOMPLETE_DATAGRAMS
+                    || self.memory + data.len() > MAX_REASSEMBLY_MEMORY)
+            {
+                let oldest = *self
+                    .incomplete
+                    .iter()
+                    .min_by_key(|(_, datagram)| datagram.started)
+                    .unwrap()
+                    .0;
+                log::debug!("Dropping incomplete datagram {} to free memory", oldest);
+                self.remove(oldest);
+            }
+            self.incomplete.insert(
+                id,
+                IncompleteDatagram {
+                    fragments: vec![None; count as usize],
+                    received: 0,
+                    size: 0,
+                    started: Instant::now(),
+                },
+            );
+        }
+        if self.memory + data.len() > MAX_REASSEMBLY_MEMORY {
+            log::debug!("Dropping datagram {} because reassembly memory is full", id);
+            self.remove(id);
+            return false;
+        }
+        let datagram = self.incomplete.get_mut(&id).unwrap();
+        if datagram.fragments[index as usize].is_some() {
+            return false;
+        }
+        // No datagram which the peer sends is larger than the forward buffer
+        if datagram.size + data.len() > FORWARD_BUFFER_SIZE {
+            log::debug!("Dropping datagram {} because it's too large", id);
+            self.remove(id);
+            return false;
+        }
+        let fragment = &mut datagram.fragments[index as usize];
+        *fragment = Some(data.into());
+        datagram.received += 1;
+        datagram.size += data.len();
+        self.memory += data.len();
+        if datagram.received < datagram.fragments.len() {
+            return false;
+        }
+        // Complete!
+        let datagram = self.remove(id).unwrap();
+        self.datagram.clear();
+        for fragment in datagram.fragments {
+            self.datagram.extend_from_slice(&fragment.unwrap());
+        }
+        true
+    }
+
+    fn remove(&mut self, id: u32) -> Option<IncompleteDatagram> {
+        let datagram = self.incomplete.remove(&id)?;
+        self.memory -= datagram.size;
+        Some(datagram)
+    }
+
+    /// Drop the datagrams which are not completed in time
+    fn remove_expired(&mut self) {
+        let memory = &mut self.memory;
+        self.incomplete.retain(|id, datagram| {
+            let expired = datagram.started.elapsed() > REASSEMBLY_TIMEOUT;
+            if expired {
+                log::debug!("Reassembly of datagram {} timed out", id);
+                *memory -= datagram.size;
+            }
+            !expired
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a frame as the reliable frame with the given sequence number
+    fn reliable(seq: u32, frame: Frame<'_>) -> Vec<u8> {
+        let mut inner = [0; 64];
+        let inner = frame.encode(&mut inner);
+        let mut buffer = [0; 128];
+        Frame::Reliable { seq, frame: inner }
+            .encode(&mut buffer)
+            .to_vec()
+    }
+
+    fn reassembler(compression: Compression) -> Reassembler {
+        let options = SessionOptions {
+            reliable: true,
+            compression,
+            ..SessionOptions::default()
+        };
+        Reassembler::new(options, Arc::new(SessionStats::default()))
+    }
+
+    #[test]
+    fn frames_which_yield_no_datagram_release_the_frames_after_them() {
+        let mut reassembler = reassembler(Compression::None);
+        let second = reliable(1, Frame::Data(b"second"));
+        let first = reliable(
+            0,
+            Frame::Fragment {
+                id: 7,
+                index: 0,
+                count: 2,
+                data: b"half",
+            },
+        );
+        assert!(reassembler
+            .receive(Frame::decode(&second).unwrap())
+            .is_none());
+        assert!(reassembler.pop().is_none());
+        // The first frame is only a part of a datagram, but the second one is complete
+        assert!(reassembler
+            .receive(Frame::decode(&first).unwrap())
+            .is_none());
+        assert_eq!(reassembler.pop(), Some(&b"second"[..]));
+        assert!(reassembler.pop().is_none());
+    }
+
+    #[test]
+    fn pop_skips_datagrams_which_cannot_be_decompressed() {
+        let mut reassembler = reassembler(Compression::Lz4);
+        let garbage = reliable(1, Frame::Data(&[0xFF, 1, 2, 3]));
+        let valid = reliable(2, Frame::Data(&[0, b'o', b'k']));
+        let first = reliable(0, Frame::Data(&[0, b'h', b'i']));
+        for frame in [&garbage, &valid] {
+            assert!(reassembler.receive(Frame::decode(frame).unwrap()).is_none());
+        }
+        assert_eq!(
+            reassembler.receive(Frame::decode(&first).unwrap()),
+            Some(&b"hi"[..])
+        );
+        assert_eq!(reassembler.pop(), Some(&b"ok"[..]));
+        assert!(reassembler.pop().is_none());
+    }
+
+    #[test]
+    fn fragments_are_reassembled_in_any_order() {
+        let mut fragments = Fragments::default();
+        assert!(!fragments.push(1, 2, 3, b"!"));
+        assert!(!fragments.push(1, 0, 3, b"hello"));
+        // Duplicates are ignored
+        assert!(!fragments.push(1, 0, 3, b"hello"));
+        assert!(!fragments.push(1, 2, 3, b"!"));
+        assert!(fragments.push(1, 1, 3, b" world"));
+        assert_eq!(fragments.datagram, b"hello world!");
+        assert!(fragments.incomplete.is_empty());
+        assert_eq!(fragments.memory, 0);
+    }
+
+    #[test]
+    fn invalid_fragments_are_dropped() {
+        let mut fragments = Fragments::default();
+        assert!(!fragments.push(1, 2, 2, b"out of range"));
+        assert!(fragments.incomplete.is_empty());
+        // An id which is reused with another count starts over
+        assert!(!fragments.push(1, 0, 3, b"old"));
+        assert!(!fragments.push(1, 0, 2, b"new"));
+        assert_eq!(fragments.memory, 3);
+        assert!(fragments.push(1, 1, 2, b"er"));
+        assert_eq!(fragments.datagram, b"newer");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn incomplete_datagrams_time_out() {
+        let mut fragments = Fragments::default();
+        assert!(!fragments.push(1, 0, 2, b"first"));
+        tokio::time::advance(REASSEMBLY_TIMEOUT / 2).await;
+        assert!(!fragments.push(2, 0, 2, b"second"));
+        tokio::time::advance(REASSEMBLY_TIMEOUT / 2 + Duration::from_millis(1)).await;
+        // The first datagram has timed out; so its other half starts a new datagram
+        assert!(!fragments.push(1, 1, 2, b" half"));
+        assert_eq!(fragments.incomplete.len(), 2);
+        assert_eq!(fragments.memory, b"second half".len());
+        assert!(fragments.push(2, 1, 2, b" half"));
+        assert_eq!(fragments.datagram, b"second half");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oldest_datagrams_are_dropped_over_the_count_limit() {
+        let mut fragments = Fragments::default();
+        for id in 0..=MAX_INCOMPLETE_DATAGRAMS as u32 {
+            assert!(!fragments.push(id, 0, 2, b"half"));
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(fragments.incomplete.len(), MAX_INCOMPLETE_DATAGRAMS);
+        assert!(!fragments.incomplete.contains_key(&0));
+        assert_eq!(fragments.memory, MAX_INCOMPLETE_DATAGRAMS * 4);
+        assert!(fragments.push(1, 1, 2, b"!"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oldest_datagrams_are_dropped_over_the_memory_limit() {
+        let mut fragments = Fragments::default();
+        let fragment = vec![0; 60_000];
+        let fit = MAX_REASSEMBLY_MEMORY / fragment.len();
+        for id in 0..=fit as u32 {
+            assert!(!fragments.push(id, 0, 2, &fragment));
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(fragments.incomplete.len(), fit);
+        assert!(!fragments.incomplete.contains_key(&0));
+        assert_eq!(fragments.memory, fit * fragment.len());
+    }
+
+    #[test]
+    fn datagrams_larger_than_the_forward_buffer_are_dropped() {
+        let mut fragments = Fragments::default();
+        let fragment = vec![0; FORWARD_BUFFER_SIZE / 2 + 1];
+        assert!(!fragments.push(1, 0, 3, &fragment));
+        assert!(!fragments.push(1, 1, 3, &fragment));
+        assert!(fragments.incomplete.is_empty());
+        assert_eq!(fragments.memory, 0);
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use curve25519_dalek::MontgomeryPoint;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    messages::{Challenge, ClientIdentity, ExchangeKey, ResumeProof, SessionToken},
+    util::unix_micros,
+};
+
+/// Proofs of identity are refused if they are older than this. This also bounds the clock skew between peers.
+const MAX_PROOF_AGE: Duration = Duration::from_secs(120);
+/// Signatures of client identities are bound to this context; so they can't be used for anything else
+const CLIENT_PROOF_CONTEXT: &[u8] = b"p2p-puncher client identity";
+/// Signatures of server identities are bound to this context
+const SERVER_PROOF_CONTEXT: &[u8] = b"p2p-puncher server identity";
+/// Secrets of sessions are derived in this context
+const RESUME_SECRET_CONTEXT: &[u8] = b"p2p-puncher resume secret";
+
+/// The secret which peers of a session share. Only its holder can resume the session.
+pub type ResumeSecret = [u8; 32];
+
+/// The ed25519 key pair which this peer is known by
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    /// Create a new identity and save its private key in a new file which only the owner can read
+    pub fn generate(path: &Path) -> anyhow::Result<Self> {
+        let identity = Self {
+            key: SigningKey::generate(&mut OsRng),
+        };
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("cannot create {}", path.display()))?;
+        writeln!(file, "{}", STANDARD.encode(identity.key.to_bytes()))?;
+        Ok(identity)
+    }
+
+    /// Load the private key which is saved in the file as base64
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let encoded =
+            fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let key = STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| anyhow!("{} does not have a valid private key", path.display()))?;
+        Ok(Self {
+            key: SigningKey::from_bytes(&key),
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.key.verifying_key())
+    }
+
+    /// The X25519 private key of this identity which is used in key exchanges
+    pub fn dh_private(&self) -> [u8; 32] {
+        self.key.to_scalar_bytes()
+    }
+
+    /// Prove to the server of the service that the client has this identity.
+    /// Nonce is what the server has sent in its first handshake packet.
+    pub fn prove(&self, service: &str, nonce: &Challenge) -> ClientIdentity {
+        let timestamp = unix_micros();
+        ClientIdentity {
+            key: self.key.verifying_key(),
+            timestamp,
+            signature: self.key.sign(&proof_message(
+                CLIENT_PROOF_CONTEXT,
+                &[service.as_bytes(), &timestamp.to_be_bytes(), nonce],
+            )),
+        }
+    }
+
+    /// Prove to a client which has sent the challenge that the server has this identity.
+    /// The session which the server gives to the client is signed too; so it can't be changed on the way.
+    pub fn prove_server(&self, service: &str, challenge: &Challenge, session: &[u8]) -> Signature {
+        self.key.sign(&proof_message(
+            SERVER_PROOF_CONTEXT,
+            &[service.as_bytes(), challenge, session],
+        ))
+    }
+}
+
+/// Create a random challenge which the server must sign
+pub fn challenge() -> Challenge {
+    let mut challenge = Challenge::default();
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+/// An ephemeral X25519 key pair which each peer creates for a punch. Peers exchange the public
+/// keys in the handshake and derive the secret of their session; so the secret is never sent.
+pub struct Exchange {
+    private: [u8; 32],
+}
+
+impl Exchange {
+    pub fn generate() -> Self {
+        let mut private = [0; 32];
+        OsRng.fill_bytes(&mut private);
+        Self { private }
+    }
+
+    pub fn public_key(&self) -> ExchangeKey {
+        MontgomeryPoint::mul_base_clamped(self.private).to_bytes()
+    }
+
+    /// Derive the secret of a session from the key of the other peer as
+    /// SHA-256(context || X25519(private key, key of peer)).
+    /// Returns None if the key of the peer is a low order point.
+    pub fn resume_secret(&self, peer: &ExchangeKey) -> Option<ResumeSecret> {
+        let shared = MontgomeryPoint(*peer).mul_clamped(self.private).to_bytes();
+        if shared == [0; 32] {
+            return None;
+        }
+        let mut hash = Sha256::new();
+        hash.update(RESUME_SECRET_CONTEXT);
+        hash.update(shared);
+        Some(hash.finalize().into())
+    }
+}
+
+/// Prove that we hold the secret of a session in order to resume it over the path
+/// which the server has sent the nonce on. The proof is HMAC-SHA256(secret, session || nonce).
+pub fn prove_resume(
+    secret: &ResumeSecret,
+    session: SessionToken,
+    nonce: &Challenge,
+) -> ResumeProof {
+    resume_mac(secret, session, nonce)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// Check the proof of a client which wants to resume the session over the path of the nonce
+pub fn verify_resume(
+    secret: &ResumeSecret,
+    session: SessionToken,
+    nonce: &Challenge,
+    proof: &ResumeProof,
+) -> bool {
+    resume_mac(secret, session, nonce)
+        .verify_slice(proof)
+        .is_ok()
+}
+
+fn resume_mac(secret: &ResumeSecret, session: SessionToken, nonce: &Challenge) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take key of any size");
+    mac.update(&session.to_be_bytes());
+    mac.update(nonce);
+    mac
+}
+
+/// Only the public key is shown; so private keys never end up in logs
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Identity").field(&self.public_key()).finish()
+    }
+}
+
+/// The public key of an identity. Written as base64.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PublicKey(VerifyingKey);
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&STANDARD.encode(self.0.as_bytes()))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl PublicKey {
+    /// The X25519 public key of this identity which is used in key exchanges
+    pub fn dh_public(&self) -> [u8; 32] {
+        self.0.to_montgomery().to_bytes()
+    }
+
+    /// Check the proof of a server which has answered our challenge
+    pub fn verify_server(
+        &self,
+        service: &str,
+        challenge: &Challenge,
+        session: &[u8],
+        signature: &Signature,
+    ) -> anyhow::Result<()> {
+        self.0
+            .verify_strict(
+                &proof_message(
+                    SERVER_PROOF_CONTEXT,
+                    &[service.as_bytes(), challenge, session],
+                ),
+                signature,
+            )
+            .map_err(|_| anyhow!("server could not prove that it is {}", self))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let key: [u8; 32] = STANDARD
+            .decode(key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| anyhow!("invalid public key {}", key))?;
+        Ok(Self(VerifyingKey::from_bytes(&key)?))
+    }
+}
+
+/// Clients which can open sessions of a service and their names
+pub struct AuthorizedKeys {
+    keys: HashMap<PublicKey, String>,
+    /// Proofs which are already used and are not too old. A proof can't be used twice.
+    used: Mutex<HashSet<(PublicKey, u64)>>,
+}
+
+impl AuthorizedKeys {
+    /// Load the keys from a file. Each line has a public key and the name of the client.
+    /// Empty lines and lines which start with # are ignored.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let mut keys = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let key: PublicKey = key
+                .parse()
+                .with_context(|| format!("line {} of {}", number + 1, path.display()))?;
+            let name = match name.trim() {
+                "" => key.to_string(),
+                name => name.to_owned(),
+            };
+            keys.insert(key, name);
+        }
+        Ok(Self {
+            keys,
+            used: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Check the proof of a client for the service and the nonce which the server has sent to it.
+    /// Returns the key and the name of the client.
+    pub fn verify(
+        &self,
+        service: &str,
+        nonce: &Challenge,
+        identity: &ClientIdentity,
+    ) -> anyhow::Result<(PublicKey, &str)> {
+        let key = PublicKey(identity.key);
+        let Some(name) = self.keys.get(&key) else {
+            bail!("{} is not authorized", key);
+        };
+        let now = unix_micros();
+        let max_age = MAX_PROOF_AGE.as_micros() as u64;
+        if identity.timestamp.abs_diff(now) > max_age {
+            bail!(
+                "proof of {} is too old; the clocks might be out of sync",
+                name
+            );
+        }
+        identity
+            .key
+            .verify_strict(
+                &proof_message(
+                    CLIENT_PROOF_CONTEXT,
+                    &[service.as_bytes(), &identity.timestamp.to_be_bytes(), nonce],
+                ),
+                &identity.signature,
+            )
+            .map_err(|_| anyhow!("proof of {} has an invalid signature", name))?;
+        let mut used = self.used.lock();
+        used.retain(|(_, timestamp)| timestamp.abs_diff(now) <= max_age);
+        if !used.insert((key, identity.timestamp)) {
+            bail!("proof of {} is replayed", name);
+        }
+        Ok((key, name))
+    }
+}
+
+/// The message which is signed to prove an identity. Each part is prefixed with its length.
+fn proof_message(context: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut message = context.to_vec();
+    for part in parts {
+        message.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        message.extend_from_slice(part);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: Challenge = [7; 32];
+
+    fn identity() -> Identity {
+        Identity {
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    fn authorized(identities: &[&Identity]) -> AuthorizedKeys {
+        AuthorizedKeys {
+            keys: identities
+                .iter()
+                .map(|identity| (identity.public_key(), "alice".to_owned()))
+                .collect(),
+            used: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// A proof which is signed at the timestamp
+    fn prove_at(identity: &Identity, service: &str, timestamp: u64) -> ClientIdentity {
+        ClientIdentity {
+            key: identity.key.verifying_key(),
+            timestamp,
+            signature: identity.key.sign(&proof_message(
+                CLIENT_PROOF_CONTEXT,
+                &[service.as_bytes(), &timestamp.to_be_bytes(), &NONCE],
+            )),
+        }
+    }
+
+    #[test]
+    fn authorized_keys_are_verified() {
+        let client = identity();
+        let keys = authorized(&[&client]);
+        let (key, name) = keys
+            .verify("test", &NONCE, &client.prove("test", &NONCE))
+            .unwrap();
+        assert_eq!(key, client.public_key());
+        assert_eq!(name, "alice");
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        let keys = authorized(&[&identity()]);
+        let proof = identity().prove("test", &NONCE);
+        assert!(keys.verify("test", &NONCE, &proof).is_err());
+    }
+
+    #[test]
+    fn proofs_are_bound_to_the_service_and_the_nonce() {
+        let client = identity();
+        let keys = authorized(&[&client]);
+        let proof = client.prove("other", &NONCE);
+        assert!(keys.verify("test", &NONCE, &proof).is_err());
+        let proof = client.prove("test", &[8; 32]);
+        assert!(keys.verify("test", &NONCE, &proof).is_err());
+    }
+
+    #[test]
+    fn proofs_which_are_too_old_or_too_new_are_refused() {
+        let client = identity();
+        let keys = authorized(&[&client]);
+        let max_age = MAX_PROOF_AGE.as_micros() as u64;
+        let expired = prove_at(&client, "test", unix_micros() - max_age - 1_000_000);
+        assert!(keys.verify("test", &NONCE, &expired).is_err());
+        let future = prove_at(&client, "test", unix_micros() + max_age + 1_000_000);
+        assert!(keys.verify("test", &NONCE, &future).is_err());
+        let skewed = prove_at(&client, "test", unix_micros() - max_age / 2);
+        assert!(keys.verify("test", &NONCE, &skewed).is_ok());
+    }
+
+    #[test]
+    fn proofs_can_not_be_replayed() {
+        let client = identity();
+        let keys = authorized(&[&client]);
+        let proof = client.prove("test", &NONCE);
+        assert!(keys.verify("test", &NONCE, &proof).is_ok());
+        assert!(keys.verify("test", &NONCE, &proof).is_err());
+        // A new proof is fine
+        let proof = prove_at(&client, "test", proof.timestamp + 1);
+        assert!(keys.verify("test", &NONCE, &proof).is_ok());
+    }
+
+    #[test]
+    fn server_proofs_are_bound_to_the_challenge_and_the_session() {
+        let server = identity();
+        let challenge = challenge();
+        let proof = server.prove_server("test", &challenge, b"session");
+        let key = server.public_key();
+        assert!(key
+            .verify_server("test", &challenge, b"session", &proof)
+            .is_ok());
+        assert!(key
+            .verify_server("test", &challenge, b"other", &proof)
+            .is_err());
+        assert!(key
+            .verify_server("test", &[0; 32], b"session", &proof)
+            .is_err());
+        assert!(identity()
+            .public_key()
+            .verify_server("test", &challenge, b"session", &proof)
+            .is_err());
+    }
+
+    #[test]
+    fn both_peers_derive_the_same_resume_secret() {
+        let (client, server) = (Exchange::generate(), Exchange::generate());
+        let secret = client.resume_secret(&server.public_key()).unwrap();
+        assert_eq!(server.resume_secret(&client.public_key()), Some(secret));
+        assert_ne!(
+            Exchange::generate().resume_secret(&server.public_key()),
+            Some(secret)
+        );
+        // Low order keys would make the secret known to everyone
+        assert!(client.resume_secret(&[0; 32]).is_none());
+    }
+}
@@ -1,12 +1,35 @@
+use std::{path::PathBuf, time::Duration};
+
 use clap::Parser;
 
 mod arguments;
+mod bandwidth;
 mod client;
+mod compress;
+mod congestion;
 mod defer;
+mod fec;
+mod fragment;
+mod identity;
+mod mesh;
 mod messages;
+mod pmtud;
+mod policy;
+mod quic;
+mod ratelimit;
+mod relay;
+mod reliable;
+mod secure;
 mod server;
+mod service;
+mod shutdown;
+mod socks;
+mod stats;
+mod tun;
+mod tunnel;
 mod turn;
 mod util;
+mod vpn;
 
 fn main() {
     env_logger::init();
@@ -16,26 +39,248 @@ fn main() {
             forward,
             turn,
             service,
+            secret,
+            tunnel,
+            session,
+            bandwidth,
+            policy,
+            authorized_keys,
+            identity,
+            drain_timeout,
         } => tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap()
             .block_on(async {
-                server::spawn_server(&forward, &turn, &service).await;
+                let service = new_service(service, secret, identity);
+                let (shutdown, shutdown_done) =
+                    shutdown::listen(Duration::from_secs(drain_timeout));
+                tokio::join!(
+                    server::spawn_server(
+                        server::Forward::address(&forward),
+                        &turn,
+                        &service,
+                        &tunnel,
+                        session.session_options(),
+                        bandwidth::Bandwidth::new(bandwidth).with_service(&session),
+                        policy::Policy::new(policy.allow, policy.deny),
+                        authorized_keys.map(load_authorized_keys),
+                        shutdown
+                    ),
+                    shutdown_done
+                );
             }),
         arguments::Commands::Client {
             listen,
             turn,
             service,
+            secret,
+            race,
+            tunnel,
+            compression,
+            bandwidth,
+            identity,
+            socks,
+            drain_timeout,
         } => tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap()
             .block_on(async {
-                client::spawn_client(&listen, &turn, &service).await;
+                let service = new_service(service, secret, identity);
+                let (shutdown, shutdown_done) =
+                    shutdown::listen(Duration::from_secs(drain_timeout));
+                let client = async {
+                    if socks {
+                        socks::spawn_socks(&listen, &turn, &service, race, &tunnel, shutdown).await
+                    } else {
+                        client::spawn_client(
+                            &listen,
+                            &turn,
+                            &service,
+                            race,
+                            &tunnel,
+                            compression,
+                            bandwidth::Bandwidth::new(bandwidth),
+                            shutdown,
+                        )
+                        .await
+                    }
+                };
+                tokio::join!(client, shutdown_done);
             }),
-        arguments::Commands::TURN { listen } => {
-            turn::spawn_turn(&listen);
+        arguments::Commands::Vpn(arguments::VpnCommands::Server {
+            turn,
+            service,
+            secret,
+            tunnel,
+            session,
+            bandwidth,
+            vpn,
+            tun,
+            authorized_keys,
+            identity,
+            drain_timeout,
+        }) => tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let service = new_service(service, secret, identity);
+                let (shutdown, shutdown_done) =
+                    shutdown::listen(Duration::from_secs(drain_timeout));
+                let vpn = std::sync::Arc::new(vpn::Vpn::new(vpn, tun));
+                tokio::join!(
+                    server::spawn_server(
+                        server::Forward::Vpn(vpn),
+                        &turn,
+                        &service,
+                        &tunnel,
+                        session.session_options(),
+                        bandwidth::Bandwidth::new(bandwidth).with_service(&session),
+                        policy::Policy::default(),
+                        authorized_keys.map(load_authorized_keys),
+                        shutdown
+                    ),
+                    shutdown_done
+                );
+            }),
+        arguments::Commands::Vpn(arguments::VpnCommands::Client {
+            turn,
+            service,
+            secret,
+            race,
+            tunnel,
+            compression,
+            bandwidth,
+            tun,
+            identity,
+            drain_timeout,
+        }) => tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let service = new_service(service, secret, identity);
+                let (shutdown, shutdown_done) =
+                    shutdown::listen(Duration::from_secs(drain_timeout));
+                tokio::join!(
+                    client::spawn_vpn_client(
+                        &turn,
+                        &service,
+                        race,
+                        &tunnel,
+                        compression,
+                        bandwidth::Bandwidth::new(bandwidth),
+                        &tun,
+                        shutdown
+                    ),
+                    shutdown_done
+                );
+            }),
+        arguments::Commands::Mesh {
+            turn,
+            network,
+            secret,
+            address,
+            keep_alive,
+            tun,
+        } => tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let network = new_service(network, secret, None);
+                // Nodes have no sessions to drain. They leave the network at once.
+                let (shutdown, shutdown_done) = shutdown::listen(Duration::ZERO);
+                tokio::join!(
+                    mesh::spawn_mesh(
+                        &turn,
+                        &network,
+                        address,
+                        Duration::from_secs(keep_alive),
+                        &tun,
+                        shutdown
+                    ),
+                    shutdown_done
+                );
+            }),
+        arguments::Commands::Keygen { path } => match identity::Identity::generate(&path) {
+            Ok(identity) => println!("{}", identity.public_key()),
+            Err(err) => util::die(err),
+        },
+        arguments::Commands::TURN {
+            listen,
+            single_threaded: true,
+            cluster,
+            limits,
+            identity,
+            ..
+        } => {
+            turn::spawn_turn(&listen, &cluster, &limits, load_identity(identity));
+        }
+        arguments::Commands::TURN {
+            listen,
+            workers,
+            cluster,
+            limits,
+            identity,
+            ..
+        } => {
+            let identity = load_identity(identity);
+            let workers = workers.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |workers| workers.get())
+            });
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(workers)
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    turn::spawn_turn_multi_threaded(&listen, workers, &cluster, &limits, identity)
+                        .await;
+                })
         }
     };
 }
+
+/// Create the service from its name and secret arguments and the file of our identity.
+/// The name might end with @ and the public key of the server.
+fn new_service(
+    name: String,
+    secret: arguments::ServiceSecret,
+    identity: Option<PathBuf>,
+) -> service::Service {
+    let identity = load_identity(identity);
+    let (name, server_key) = match name.rsplit_once('@') {
+        Some((name, server_key)) => match server_key.parse() {
+            Ok(server_key) => (name.to_owned(), Some(server_key)),
+            Err(err) => util::die(err),
+        },
+        None => (name, None),
+    };
+    service::Service::new(
+        name,
+        secret.secret,
+        Duration::from_secs(secret.secret_epoch),
+    )
+    .with_identity(identity)
+    .with_server_key(server_key)
+}
+
+fn load_identity(path: Option<PathBuf>) -> Option<identity::Identity> {
+    path.map(|path| match identity::Identity::load(&path) {
+        Ok(identity) => {
+            log::info!("Our identity is {}", identity.public_key());
+            identity
+        }
+        Err(err) => util::die(err),
+    })
+}
+
+fn load_authorized_keys(path: PathBuf) -> identity::AuthorizedKeys {
+    match identity::AuthorizedKeys::load(&path) {
+        Ok(keys) => keys,
+        Err(err) => util::die(err),
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use tokio::{net::UdpSocket, select, time};
+
+use crate::{
+    arguments::TunOptions,
+    messages::{Cookie, MeshMessage, PunchError, UDPMessage},
+    secure::{Received, TurnChannel},
+    service::Service,
+    shutdown::{Shutdown, Stage},
+    tun::Tun,
+    tunnel::{data_frame, Frame, Liveness, PathDead, CONTROL_BUFFER_SIZE, FRAME_HEADER_SIZE},
+    util::{
+        die, parse_turn_addresses, FORWARD_BUFFER_SIZE, LOCAL_UDP_BIND_ADDRESS,
+        SEALED_TURN_BUFFER_SIZE,
+    },
+};
+
+/// Join the network again every this often. This keeps the membership in TURN servers and
+/// gives another chance to punch the nodes which we could not reach.
+const JOIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Another node of the mesh network
+struct Peer {
+    /// Virtual address of the node
+    address: IpAddr,
+    liveness: Liveness,
+    /// True if the node has answered any heartbeat
+    punched: bool,
+}
+
+/// Join a mesh network with a virtual address and punch a path to every other node of it.
+/// TURN servers and all nodes are reached from one socket; so the public endpoint which TURN
+/// servers see is the one which other nodes punch. Packets of the TUN device are sent to the
+/// node which owns their destination.
+pub async fn spawn_mesh(
+    turn: &str,
+    network: &Service,
+    address: IpNet,
+    keep_alive: Duration,
+    tun: &TunOptions,
+    mut shutdown: Shutdown,
+) {
+    let mut channels: Vec<TurnChannel> = parse_turn_addresses(turn)
+        .into_iter()
+        .map(TurnChannel::new)
+        .collect();
+    let socket = match UdpSocket::bind(LOCAL_UDP_BIND_ADDRESS).await {
+        Ok(socket) => socket,
+        Err(err) => die(err),
+    };
+    let tun = match Tun::open(
+        &tun.tun_name,
+        address.addr(),
+        None,
+        tun.mtu,
+        &[address.trunc()],
+    )
+    .await
+    {
+        Ok(tun) => tun,
+        Err(err) => die(err),
+    };
+    log::info!(
+        "Joining {} as {} on {} from {}",
+        network.name(),
+        address.addr(),
+        tun.name(),
+        socket.local_addr().unwrap()
+    );
+    let mut cookies: HashMap<SocketAddrV4, Cookie> = HashMap::new();
+    let mut joined = vec![false; channels.len()];
+    // TURN servers forget our secure channels when they restart. Channels of the servers
+    // which have not answered since the last join are opened again.
+    let mut answered = vec![true; channels.len()];
+    let mut peers: HashMap<SocketAddrV4, Peer> = HashMap::new();
+    // Which node owns each virtual address
+    let mut routes: HashMap<IpAddr, SocketAddrV4> = HashMap::new();
+    let mut join_interval = time::interval(JOIN_INTERVAL);
+    let mut lookup_key = network.lookup_key();
+    let mut turn_buffer = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut turn_plain = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut control_buffer = [0; CONTROL_BUFFER_SIZE];
+    let mut tun_buffer = vec![0; FORWARD_BUFFER_SIZE];
+    let mut socket_buffer = vec![0; FORWARD_BUFFER_SIZE];
+    loop {
+        let heartbeat_deadline = peers.values().map(|peer| peer.liveness.deadline()).min();
+        select! {
+            () = shutdown.reached(Stage::Draining) => {
+                log::info!("Leaving {}", network.name());
+                for channel in &mut channels {
+                    let leave = channel.seal(
+                        &UDPMessage::Leave {
+                            network: &lookup_key,
+                            cookie: cookies.get(&channel.address()).copied(),
+                        },
+                        &mut turn_buffer,
+                    );
+                    let _ = socket.send_to(leave, channel.address()).await;
+                }
+                let close = Frame::Close.encode(&mut control_buffer);
+                for endpoint in peers.keys() {
+                    let _ = socket.send_to(close, endpoint).await;
+                }
+                return;
+            }
+            _ = join_interval.tick() => {
+                lookup_key = network.lookup_key();
+                for (channel, answered) in channels.iter_mut().zip(&mut answered) {
+                    if !*answered {
+                        channel.reset();
+                    }
+                    *answered = false;
+                    log::trace!("Joining {} in {}", network.name(), channel.address());
+                    let cookie = cookies.get(&channel.address()).copied();
+                    if let Err(err) = join(&socket, channel, &lookup_key, address.addr(), cookie).await {
+                        log::warn!("Cannot join {} in {}: {}", network.name(), channel.address(), err);
+                    }
+                }
+            }
+            () = time::sleep_until(heartbeat_deadline.unwrap_or_else(time::Instant::now)), if heartbeat_deadline.is_some() => {
+                let now = time::Instant::now();
+                let mut dead = Vec::new();
+                for (endpoint, peer) in peers.iter_mut().filter(|(_, peer)| peer.liveness.deadline() <= now) {
+                    match peer.liveness.on_timer() {
+                        Ok(heartbeat) => {
+                            let _ = socket.send_to(heartbeat.encode(&mut control_buffer), endpoint).await;
+                        }
+                        Err(PathDead) => dead.push(*endpoint),
+                    }
+                }
+                for endpoint in dead {
+                    // The node is punched again if TURN servers still have it
+                    log::warn!("Path to {} is dead", endpoint);
+                    remove_peer(&mut peers, &mut routes, endpoint);
+                }
+            }
+            result = tun.recv(&mut tun_buffer[FRAME_HEADER_SIZE..]) => {
+                let length = match result {
+                    Ok(length) => length,
+                    Err(err) => die(err),
+                };
+                let Some((_, destination)) = packet_addresses(&tun_buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + length]) else {
+                    continue;
+                };
+                let Some(endpoint) = routes.get(&destination) else {
+                    log::trace!("No node has {}", destination);
+                    continue;
+                };
+                if let Err(err) = socket.send_to(data_frame(&mut tun_buffer, length), endpoint).await {
+                    log::debug!("Cannot send to {}: {}", endpoint, err);
+                }
+            }
+            result = socket.recv_from(&mut socket_buffer) => {
+                let (length, from) = match result {
+                    Ok(result) => result,
+                    // Errors of paths (such as ICMP unreachable) are not fatal
+                    Err(err) => {
+                        log::debug!("Cannot receive from the socket: {}", err);
+                        continue;
+                    }
+                };
+                let SocketAddr::V4(from) = from else {
+                    continue;
+                };
+                let packet = &socket_buffer[..length];
+                if let Some(turn_index) = channels.iter().position(|channel| channel.address() == from) {
+                    let channel = &mut channels[turn_index];
+                    let message = match channel.open(packet, &mut turn_plain) {
+                        Ok(Some(Received::Message(message))) => message,
+                        // The secure channel has moved on. Join again over it without waiting.
+                        Ok(Some(Received::Resend)) => {
+                            let cookie = cookies.get(&from).copied();
+                            let _ = join(&socket, channel, &lookup_key, address.addr(), cookie).await;
+                            continue;
+                        }
+                        Ok(None) => continue,
+                        Err(err) => {
+                            log::debug!("Ignoring packet from {}: {}", from, err);
+                            continue;
+                        }
+                    };
+                    answered[turn_index] = true;
+                    match message {
+                        UDPMessage::Ok => {
+                            if !joined[turn_index] {
+                                log::info!("Joined {} in {}", network.name(), from);
+                                joined[turn_index] = true;
+                            }
+                        }
+                        // TURN server wants a cookie. Join again with it without waiting.
+                        UDPMessage::Cookie(cookie) if cookies.get(&from) != Some(&cookie) => {
+                            log::trace!("Got cookie from {}", from);
+                            cookies.insert(from, cookie);
+                            let _ = join(&socket, channel, &lookup_key, address.addr(), Some(cookie)).await;
+                        }
+                        UDPMessage::Error(PunchError::DuplicateKey) => {
+                            log::error!("Another node of {} has {} in {}", network.name(), address.addr(), from);
+                        }
+                        UDPMessage::Mesh(MeshMessage::Member { endpoint, address: member_address }) => {
+                            if !address.contains(&member_address) {
+                                log::warn!("Node {} has {} which is not in {}", endpoint, member_address, address.trunc());
+                                continue;
+                            }
+                            if let Some(peer) = peers.get_mut(&endpoint) {
+                                if peer.address != member_address {
+                                    log::info!("Node {} is now {}", endpoint, member_address);
+                                    routes.remove(&peer.address);
+                                    peer.address = member_address;
+                                }
+                                routes.insert(member_address, endpoint);
+                                continue;
+                            }
+                            // Both nodes start sending heartbeats when they learn about each other. This punches the path.
+                            log::info!("Punching {} ({})", endpoint, member_address);
+                            let mut liveness = Liveness::new(keep_alive);
+                            if let Ok(heartbeat) = liveness.on_timer() {
+                                let _ = socket.send_to(heartbeat.encode(&mut control_buffer), endpoint).await;
+                            }
+                            peers.insert(endpoint, Peer { address: member_address, liveness, punched: false });
+                            routes.insert(member_address, endpoint);
+                        }
+                        UDPMessage::Mesh(MeshMessage::Left { endpoint }) => {
+                            if remove_peer(&mut peers, &mut routes, endpoint) {
+                                log::info!("Node {} has left", endpoint);
+                            }
+                        }
+                        message => log::debug!("Unexpected packet from TURN server {}: {:?}", from, message),
+                    }
+                    continue;
+                }
+                let Some(peer) = peers.get_mut(&from) else {
+                    log::trace!("Ignoring packet from unknown node {}", from);
+                    continue;
+                };
+                match Frame::decode(packet) {
+                    Some(Frame::Data(data)) => {
+                        // Nodes can only send packets from their own address
+                        if packet_addresses(data).is_some_and(|(source, _)| source == peer.address) {
+                            if let Err(err) = tun.send(data).await {
+                                log::debug!("Cannot write to {}: {}", tun.name(), err);
+                            }
+                        } else {
+                            log::trace!("Dropping a spoofed packet from {}", from);
+                        }
+                    }
+                    Some(Frame::Heartbeat(id)) => {
+                        let _ = socket.send_to(Frame::HeartbeatAck(id).encode(&mut control_buffer), from).await;
+                    }
+                    Some(Frame::HeartbeatAck(id)) => {
+                        peer.liveness.on_ack(id);
+                        if !peer.punched {
+                            log::info!("Punched a path to {} ({}) with RTT {:?}", from, peer.address, peer.liveness.rtt());
+                            peer.punched = true;
+                        }
+                    }
+                    Some(Frame::Close) => {
+                        log::info!("Node {} has closed", from);
+                        remove_peer(&mut peers, &mut routes, from);
+                    }
+                    frame => log::trace!("Ignoring frame {:?} from {}", frame, from),
+                }
+            }
+        }
+    }
+}
+
+/// Send a join of the network to TURN server over its channel
+async fn join(
+    socket: &UdpSocket,
+    channel: &mut TurnChannel,
+    network: &str,
+    address: IpAddr,
+    cookie: Option<Cookie>,
+) -> io::Result<usize> {
+    let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+    let join = channel.seal(
+        &UDPMessage::Join {
+            network,
+            address,
+            cookie,
+        },
+        &mut buffer,
+    );
+    socket.send_to(join, channel.address()).await
+}
+
+/// Forget a node and its route. Returns false if the node was not known.
+fn remove_peer(
+    peers: &mut HashMap<SocketAddrV4, Peer>,
+    routes: &mut HashMap<IpAddr, SocketAddrV4>,
+    endpoint: SocketAddrV4,
+) -> bool {
+    let Some(peer) = peers.remove(&endpoint) else {
+        return false;
+    };
+    if routes.get(&peer.address) == Some(&endpoint) {
+        routes.remove(&peer.address);
+    }
+    true
+}
+
+/// Source and destination addresses of an IPv4 or IPv6 packet
+fn packet_addresses(packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let source: [u8; 4] = packet[12..16].try_into().unwrap();
+            let destination: [u8; 4] = packet[16..20].try_into().unwrap();
+            Some((source.into(), destination.into()))
+        }
+        6 if packet.len() >= 40 => {
+            let source: [u8; 16] = packet[8..24].try_into().unwrap();
+            let destination: [u8; 16] = packet[24..40].try_into().unwrap();
+            Some((source.into(), destination.into()))
+        }
+        _ => None,
+    }
+}
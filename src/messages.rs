@@ -1,5 +1,11 @@
-use std::{net::SocketAddrV4, str};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    str,
+};
 
+use ed25519_dalek::{Signature, VerifyingKey};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
 /// All possible messages which can be sent from or to all apps
@@ -8,11 +14,17 @@ pub enum UDPMessage<'a> {
     /// Client wants to connect to TURN server
     Client {
         service_name: &'a str,
+        /// The cookie which TURN server gave to this client earlier
+        cookie: Option<Cookie>,
     },
     /// Server advertising itself to TURN server
     Server {
         service_name: &'a str,
     },
+    /// Server is shutting down and does not want to get new clients
+    Deregister {
+        service_name: &'a str,
+    },
     // An error...
     Error(PunchError),
     // Punch packet
@@ -21,6 +33,70 @@ pub enum UDPMessage<'a> {
     KeepAlive,
     /// Something was ok. Client knows what it is
     Ok,
+    /// TURN server wants the client to prove it owns its address before looking up a service.
+    /// Client must send its request again with this cookie.
+    Cookie(Cookie),
+    /// Messages which TURN nodes of a cluster send to each other. Data is an encoded
+    /// FederationMessage and tag is HMAC-SHA256(cluster key, nonce || data).
+    /// Nonces are the microseconds since UNIX epoch and strictly increase on each node.
+    Federation {
+        nonce: u64,
+        data: &'a [u8],
+        tag: [u8; 32],
+    },
+    /// A node joins a mesh network with its virtual address or stays in it.
+    /// Like clients, nodes must prove that they own their address with a cookie.
+    Join {
+        network: &'a str,
+        address: IpAddr,
+        cookie: Option<Cookie>,
+    },
+    /// A node leaves a mesh network. Like joins, leaves must have the cookie of the node.
+    Leave {
+        network: &'a str,
+        cookie: Option<Cookie>,
+    },
+    /// TURN server tells a node about the other nodes of its mesh network
+    Mesh(MeshMessage),
+    /// Peer opens a secure channel with TURN server. Has the first message of the Noise handshake.
+    /// Like lookups, it must have a cookie before TURN server does any work for it.
+    SecureHello {
+        cookie: Option<Cookie>,
+        handshake: &'a [u8],
+    },
+    /// TURN server accepts the channel. Has the second message of the Noise handshake.
+    SecureAccept(&'a [u8]),
+    /// A message which is encrypted over a secure channel. Nonce counts the messages of each side.
+    Secure {
+        nonce: u64,
+        data: &'a [u8],
+    },
+}
+
+/// A stateless cookie which TURN server gives to clients
+pub type Cookie = u64;
+
+/// Identifies a session between a client and a server. Clients use it to resume their session over a new path.
+pub type SessionToken = u64;
+
+/// Random bytes which a client asks the server to sign in order to prove its identity
+pub type Challenge = [u8; 32];
+
+/// SHA-256 of the QUIC certificate of a session
+pub type CertificateHash = [u8; 32];
+
+/// An ephemeral X25519 public key which peers exchange to share the secret of a session
+pub type ExchangeKey = [u8; 32];
+
+/// HMAC of a new path with the secret of a session which proves that a client can resume it
+pub type ResumeProof = [u8; 32];
+
+/// A request of client to move its session to a new path
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Resume {
+    pub session: SessionToken,
+    /// HMAC of the session and the nonce of the new path keyed by the secret of the session
+    pub proof: ResumeProof,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -29,12 +105,199 @@ pub enum PunchError {
     DuplicateKey,
     /// No server is listening with this key
     NoServer,
+    /// Too many requests were sent for this key
+    RateLimited,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum PunchMessage {
-    PeerHandshake1,
-    PeerHandshake2,
-    PeerHandshake3,
+    /// The server punches with a fresh nonce which clients sign to prove their identity.
+    /// Clients which have an identity ask for the nonce with an empty one.
+    PeerHandshake1 { nonce: Option<Challenge> },
+    PeerHandshake2 {
+        /// The session which client wants to resume over this new path
+        resume: Option<Box<Resume>>,
+        /// The key of client which a new session derives its secret from
+        exchange: ExchangeKey,
+        /// The compression which client accepts if the service uses it
+        compression: Compression,
+        /// Proof of the identity of client if it has one
+        identity: Option<Box<ClientIdentity>>,
+        /// Set if client wants the server to prove its identity
+        challenge: Option<Challenge>,
+    },
+    PeerHandshake3 {
+        /// The session which this path belongs to
+        session: SessionToken,
+        options: SessionOptions,
+        /// The addresses of a new VPN session
+        vpn: Option<VpnConfig>,
+        /// The hash of the certificate of the QUIC server if the session uses QUIC
+        certificate: Option<CertificateHash>,
+        /// The key of server which a new session derives its secret from. Resumed sessions keep their secret.
+        exchange: Option<ExchangeKey>,
+        /// Signature of the challenge and this session if client has sent a challenge and the server has an identity
+        proof: Option<Box<Signature>>,
+    },
+    #[allow(clippy::upper_case_acronyms)]
     TURN(SocketAddrV4),
+    /// The server does not accept the identity of client
+    Unauthorized,
+}
+
+/// The parts of the last handshake packet which the server signs in its proof.
+/// The exchange keys of both peers are signed too; so nobody else can share the secret of the session.
+pub fn signed_session(
+    session: SessionToken,
+    options: &SessionOptions,
+    vpn: &Option<VpnConfig>,
+    certificate: &Option<CertificateHash>,
+    exchange: (&ExchangeKey, &Option<ExchangeKey>),
+) -> Vec<u8> {
+    postcard::to_stdvec(&(session, options, vpn, certificate, exchange)).unwrap()
+}
+
+/// A client proves its identity by signing the service name, the current time and the nonce
+/// of the server with its key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientIdentity {
+    pub key: VerifyingKey,
+    /// Microseconds since the Unix epoch
+    pub timestamp: u64,
+    pub signature: Signature,
+}
+
+/// Options of a session which the server chooses for its service and sends to the client in the handshake
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionOptions {
+    /// Send a parity frame after every this many frames. Zero disables forward error correction.
+    pub fec_group_size: u8,
+    /// Compression of the datagrams which are sent over the tunnel
+    pub compression: Compression,
+    /// Retransmit the lost datagrams and deliver them in order
+    pub reliable: bool,
+    /// How the datagrams are carried over the punched path
+    pub transport: Transport,
+}
+
+/// Algorithms which can compress the datagrams of a session
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+/// Protocols which can carry the datagrams of a session over the punched path
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Transport {
+    /// Frames of this program directly over UDP
+    #[default]
+    Datagram,
+    /// A QUIC connection. Datagrams are sent as QUIC datagrams or streams.
+    Quic,
+}
+
+/// Addresses of a VPN session which the server pushes to the client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VpnConfig {
+    /// Virtual address of the client
+    pub address: IpAddr,
+    /// Virtual address of the server
+    pub peer: IpAddr,
+    /// Networks which the client routes over the session
+    pub routes: Vec<IpNet>,
+}
+
+/// A destination which a SOCKS client asks the server to reach
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Destination {
+    Address(SocketAddr),
+    /// A host name which the server resolves and a port
+    Domain(String, u16),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Address(address) => write!(f, "{}", address),
+            Destination::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// The first message of each QUIC stream which a SOCKS client opens
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StreamRequest {
+    /// Open a TCP connection to the destination and copy the stream to it
+    Connect(Destination),
+    /// Relay UDP datagrams while the stream is open. The records are sent as QUIC datagrams
+    /// of the association whose id is the id of the stream.
+    Associate,
+}
+
+/// The answer of the server to a stream request. On success, it has the local address of the
+/// TCP connection or the UDP socket which the server has opened.
+pub type StreamReply = Result<SocketAddr, DialError>;
+
+/// Why the server could not open a stream request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialError {
+    /// The policy of the server does not allow the destination
+    NotAllowed,
+    /// The host name can't be resolved
+    Unresolved,
+    Unreachable,
+    Refused,
+    Failed,
+}
+
+/// A UDP datagram of an association. Peer is the destination of datagrams which the client
+/// sends and the source of datagrams which the server sends.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UdpRecord<'a> {
+    pub peer: Destination,
+    pub payload: &'a [u8],
+}
+
+/// A QUIC datagram of a session
+#[derive(Serialize, Deserialize, Debug)]
+pub enum QuicDatagram<'a> {
+    /// A datagram of the tunnel
+    Session(&'a [u8]),
+    /// A UDP datagram of the association which the stream of this id has opened
+    Udp {
+        association: u64,
+        record: UdpRecord<'a>,
+    },
+}
+
+/// Membership of a mesh network which TURN server sends to its nodes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum MeshMessage {
+    /// A node of the network with its public address and its virtual address
+    Member {
+        endpoint: SocketAddrV4,
+        address: IpAddr,
+    },
+    /// The node has left the network
+    Left { endpoint: SocketAddrV4 },
+}
+
+/// Messages which are exchanged between TURN nodes in order to share the registered servers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum FederationMessage<'a> {
+    /// A server is registered (or refreshed) in the sender node
+    Registered {
+        service_name: &'a str,
+        server: SocketAddrV4,
+    },
+    /// The server of this service is no longer available in the sender node
+    Removed { service_name: &'a str },
+    /// A client has asked for a server which is registered in the receiver node.
+    /// The receiver must notify the server about the client.
+    Match {
+        service_name: &'a str,
+        client: SocketAddrV4,
+    },
 }
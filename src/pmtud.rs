@@ -0,0 +1,415 @@
+use std::{io, time::Duration};
+
+use tokio::{net::UdpSocket, time::Instant};
+
+use crate::{
+    fragment::{DEFAULT_MAX_FRAME_SIZE, MIN_MAX_FRAME_SIZE},
+    tunnel::Frame,
+};
+
+/// Largest frame which is probed. This is the UDP payload of a full Ethernet frame over IPv4.
+pub const MAX_PROBE_SIZE: usize = 1500 - 20 - 8;
+/// Probing is done when the search range is smaller than this
+const SEARCH_PRECISION: usize = 8;
+/// How long to wait for the ack of a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// A size is considered too large if this many probes of it are lost in a row
+const MAX_PROBES: u32 = 3;
+/// Frame size which is used when probes of the default size are lost. IPv4 hosts must accept
+/// datagrams of 576 bytes; so most paths pass it.
+const BASE_FRAME_SIZE: usize = 576;
+/// Search for a larger MTU again every this often; the path might have changed
+const RAISE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Probe the confirmed size every this often; so a path which starts dropping
+/// frames of that size (a black hole) is detected
+const CONFIRM_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A probe which is waiting for its ack
+#[derive(Debug, Clone, Copy)]
+struct Probe {
+    id: u32,
+    size: usize,
+    sent: Instant,
+    /// Number of probes of this size which are sent
+    attempts: u32,
+}
+
+/// Discovers the largest frame which can pass the punched path without IP fragmentation.
+/// This is PLPMTUD (RFC 8899) with a binary search: Padded probe frames are sent and
+/// the largest acknowledged size is used as the maximum frame size of the session.
+/// Once the search is done, the confirmed size is probed from time to time and the size is
+/// lowered if those probes are lost.
+#[derive(Debug)]
+pub struct PathMtu {
+    /// Largest frame size which is confirmed
+    confirmed: usize,
+    /// Smallest frame size which is known to be too large
+    too_large: usize,
+    probe: Option<Probe>,
+    /// True if a search is in progress
+    searching: bool,
+    next_id: u32,
+    /// When should the next search start if probing is done
+    next_search: Instant,
+    /// When should the confirmed size be probed again if probing is done
+    next_confirm: Instant,
+    /// The result of the last search which was reported
+    reported: usize,
+    /// Probes are encoded in this buffer
+    buffer: Box<[u8]>,
+}
+
+impl Default for PathMtu {
+    fn default() -> Self {
+        Self {
+            confirmed: DEFAULT_MAX_FRAME_SIZE,
+            too_large: MAX_PROBE_SIZE + 1,
+            probe: None,
+            searching: false,
+            next_id: 0,
+            next_search: Instant::now(),
+            next_confirm: Instant::now() + CONFIRM_INTERVAL,
+            reported: 0,
+            buffer: vec![0; MAX_PROBE_SIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl PathMtu {
+    /// The largest frame which can be sent over the path
+    pub fn max_frame_size(&self) -> usize {
+        self.confirmed
+    }
+
+    /// Returns true if the search is done
+    fn searched(&self) -> bool {
+        self.too_large - self.confirmed <= SEARCH_PRECISION
+    }
+
+    /// When should on_timer be called
+    pub fn deadline(&self) -> Instant {
+        match self.probe {
+            Some(probe) => probe.sent + PROBE_TIMEOUT,
+            None if self.searching => self.next_search,
+            None => self.next_search.min(self.next_confirm),
+        }
+    }
+
+    /// Must be called when the deadline is reached. Sends the next probe if needed.
+    /// Returns the new maximum frame size if a search is done or a black hole is detected
+    /// and it's changed.
+    pub async fn on_timer(&mut self, socket: &UdpSocket) -> Option<usize> {
+        let now = Instant::now();
+        let size = match self.probe {
+            Some(probe) if probe.attempts < MAX_PROBES => probe.size,
+            // Lost all probes of the confirmed size. Frames of this size no longer pass the path.
+            Some(probe) if !self.searching => return self.on_black_hole(probe.size, now),
+            // Lost all probes of this size
+            Some(probe) => {
+                log::trace!("Probes of {} bytes are lost", probe.size);
+                self.too_large = probe.size;
+                self.next_probe_size()
+            }
+            // Continue the search or start a new one
+            None if self.searching => self.next_probe_size(),
+            // Most paths are Ethernet; so the largest size is probed first
+            None if now >= self.next_search => {
+                self.searching = true;
+                self.too_large = MAX_PROBE_SIZE + 1;
+                MAX_PROBE_SIZE
+            }
+            // Make sure that the path still passes frames of the confirmed size
+            None => self.confirmed,
+        };
+        if self.searching && self.searched() {
+            return self.finish_search(now);
+        }
+        let attempts = match self.probe {
+            Some(probe) if probe.size == size => probe.attempts + 1,
+            _ => 1,
+        };
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.probe = Some(Probe {
+            id,
+            size,
+            sent: now,
+            attempts,
+        });
+        // Probes which fail to send (for example because they are larger than the MTU of the interface)
+        // are considered lost right away
+        let frame = Frame::Probe { id, size }.encode(&mut self.buffer);
+        if let Err(err) = socket.send(frame).await {
+            log::trace!("Cannot send probe of {} bytes: {}", size, err);
+            self.probe = Some(Probe {
+                id,
+                size,
+                sent: now - PROBE_TIMEOUT,
+                attempts: MAX_PROBES,
+            });
+        }
+        None
+    }
+
+    /// Must be called when an ack of a probe is received.
+    /// Returns the new maximum frame size if a search is done and it's changed.
+    pub fn on_ack(&mut self, id: u32, size: u16) -> Option<usize> {
+        let probe = self.probe.filter(|probe| probe.id == id)?;
+        if size as usize != probe.size {
+            return None;
+        }
+        self.probe = None;
+        self.confirmed = self.confirmed.max(probe.size);
+        if !self.searching {
+            self.next_confirm = Instant::now() + CONFIRM_INTERVAL;
+            return None;
+        }
+        if self.searched() {
+            return self.finish_search(Instant::now());
+        }
+        // Probe the next size right away
+        self.next_search = Instant::now();
+        None
+    }
+
+    /// Size of the next probe in the binary search
+    fn next_probe_size(&self) -> usize {
+        (self.confirmed + self.too_large) / 2
+    }
+
+    /// Returns the result of the search if it's not reported before
+    fn finish_search(&mut self, now: Instant) -> Option<usize> {
+        log::trace!("Path MTU search is done: {} bytes", self.confirmed);
+        self.probe = None;
+        self.searching = false;
+        self.next_search = now + RAISE_INTERVAL;
+        self.next_confirm = now + CONFIRM_INTERVAL;
+        if self.reported == self.confirmed {
+            return None;
+        }
+        self.reported = self.confirmed;
+        Some(self.confirmed)
+    }
+
+    /// Lower the maximum frame size to a safe size and search between it and the lost size again.
+    /// Returns the lowered size. Frames can't be smaller than the minimum; so the size is kept
+    /// if probes of the minimum are lost.
+    fn on_black_hole(&mut self, size: usize, now: Instant) -> Option<usize> {
+        self.probe = None;
+        if size <= MIN_MAX_FRAME_SIZE {
+            log::warn!(
+                "Probes of {} bytes are lost. The path MTU is below the supported minimum.",
+                size
+            );
+            self.next_confirm = now + CONFIRM_INTERVAL;
+            return None;
+        }
+        self.confirmed = if size > DEFAULT_MAX_FRAME_SIZE {
+            DEFAULT_MAX_FRAME_SIZE
+        } else if size > BASE_FRAME_SIZE {
+            BASE_FRAME_SIZE
+        } else {
+            MIN_MAX_FRAME_SIZE
+        };
+        log::debug!(
+            "Probes of the path MTU ({} bytes) are lost. Lowering it to {} bytes.",
+            size,
+            self.confirmed
+        );
+        self.too_large = size;
+        self.searching = true;
+        self.next_search = now;
+        self.reported = self.confirmed;
+        Some(self.confirmed)
+    }
+}
+
+/// Set the don't fragment bit on the packets of the socket; so probes which are
+/// larger than the path MTU are dropped instead of being fragmented.
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // IP_PMTUDISC_PROBE sets the DF bit and ignores the path MTU which the kernel has cached
+    let value: libc::c_int = libc::IP_PMTUDISC_PROBE;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the don't fragment bit on the packets of the socket; so probes which are
+/// larger than the path MTU are dropped instead of being fragmented.
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_: &UdpSocket) -> io::Result<()> {
+    log::debug!(
+        "Cannot set the don't fragment bit on this platform. Path MTU might be overestimated."
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path which drops the frames which are larger than its MTU
+    struct Path {
+        socket: UdpSocket,
+        peer: std::net::UdpSocket,
+        mtu: usize,
+        /// Sizes of the probes which are sent over the path
+        probes: Vec<usize>,
+    }
+
+    impl Path {
+        async fn new(mtu: usize) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            peer.set_nonblocking(true).unwrap();
+            socket.connect(peer.local_addr().unwrap()).await.unwrap();
+            Self {
+                socket,
+                peer,
+                mtu,
+                probes: vec![],
+            }
+        }
+
+        /// Wait for the deadline, run the timer and ack the probe if it passes the path.
+        /// Returns the reported frame size.
+        async fn step(&mut self, pmtu: &mut PathMtu) -> Option<usize> {
+            tokio::time::sleep_until(pmtu.deadline()).await;
+            let reported = pmtu.on_timer(&self.socket).await;
+            let mut buffer = [0; MAX_PROBE_SIZE];
+            let Ok(length) = self.peer.recv(&mut buffer) else {
+                return reported;
+            };
+            let Some(Frame::Probe { id, size }) = Frame::decode(&buffer[..length]) else {
+                panic!("expected a probe");
+            };
+            self.probes.push(size);
+            if size > self.mtu {
+                return reported;
+            }
+            pmtu.on_ack(id, size as u16).or(reported)
+        }
+
+        /// Run PathMtu until it reports a frame size
+        async fn report(&mut self, pmtu: &mut PathMtu) -> usize {
+            for _ in 0..200 {
+                if let Some(size) = self.step(pmtu).await {
+                    return size;
+                }
+            }
+            panic!("frame size is never reported");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn largest_probe_is_tried_first() {
+        let mut path = Path::new(MAX_PROBE_SIZE).await;
+        let mut pmtu = PathMtu::default();
+        assert_eq!(pmtu.max_frame_size(), DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(path.report(&mut pmtu).await, MAX_PROBE_SIZE);
+        assert_eq!(path.probes, [MAX_PROBE_SIZE]);
+        assert_eq!(pmtu.max_frame_size(), MAX_PROBE_SIZE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn binary_search_finds_the_path_mtu() {
+        let mut path = Path::new(1400).await;
+        let mut pmtu = PathMtu::default();
+        let start = Instant::now();
+        assert_eq!(path.report(&mut pmtu).await, 1399);
+        // Each size is probed a few times before it's considered too large
+        assert_eq!(
+            path.probes,
+            [1472, 1472, 1472, 1336, 1404, 1404, 1404, 1370, 1387, 1395, 1399]
+        );
+        assert_eq!(start.elapsed(), 6 * PROBE_TIMEOUT);
+        // Nothing is reported again if the next search has the same result
+        tokio::time::sleep_until(start + RAISE_INTERVAL).await;
+        for _ in 0..20 {
+            assert_eq!(path.step(&mut pmtu).await, None);
+        }
+        assert_eq!(pmtu.max_frame_size(), 1399);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paths_smaller_than_the_default_are_found_by_confirm_probes() {
+        let mut path = Path::new(1000).await;
+        let mut pmtu = PathMtu::default();
+        // Sizes below the default are not searched unless its probes are lost
+        assert_eq!(path.report(&mut pmtu).await, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(path.report(&mut pmtu).await, BASE_FRAME_SIZE);
+        let size = path.report(&mut pmtu).await;
+        assert!((1000 - SEARCH_PRECISION..=1000).contains(&size));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paths_smaller_than_the_base_size_are_searched_down_to_the_minimum() {
+        let mut path = Path::new(400).await;
+        let mut pmtu = PathMtu::default();
+        assert_eq!(path.report(&mut pmtu).await, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(path.report(&mut pmtu).await, BASE_FRAME_SIZE);
+        assert_eq!(path.report(&mut pmtu).await, MIN_MAX_FRAME_SIZE);
+        let size = path.report(&mut pmtu).await;
+        assert!((400 - SEARCH_PRECISION..=400).contains(&size));
+        assert_eq!(pmtu.max_frame_size(), size);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn frame_size_is_never_lowered_below_the_minimum() {
+        let mut path = Path::new(MIN_MAX_FRAME_SIZE - 1).await;
+        let mut pmtu = PathMtu::default();
+        assert_eq!(path.report(&mut pmtu).await, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(path.report(&mut pmtu).await, BASE_FRAME_SIZE);
+        assert_eq!(path.report(&mut pmtu).await, MIN_MAX_FRAME_SIZE);
+        // Probes of the minimum are lost too, but there is nothing smaller to use
+        for _ in 0..20 {
+            assert_eq!(path.step(&mut pmtu).await, None);
+        }
+        assert_eq!(pmtu.max_frame_size(), MIN_MAX_FRAME_SIZE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn black_holes_lower_the_frame_size_and_larger_sizes_are_searched_again() {
+        let mut path = Path::new(MAX_PROBE_SIZE).await;
+        let mut pmtu = PathMtu::default();
+        assert_eq!(path.report(&mut pmtu).await, MAX_PROBE_SIZE);
+        // The path starts dropping frames of the confirmed size
+        let start = Instant::now();
+        path.mtu = 1300;
+        path.probes.clear();
+        assert_eq!(path.report(&mut pmtu).await, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(path.probes, [MAX_PROBE_SIZE; 3]);
+        assert_eq!(start.elapsed(), CONFIRM_INTERVAL + 3 * PROBE_TIMEOUT);
+        let size = path.report(&mut pmtu).await;
+        assert!((1300 - SEARCH_PRECISION..=1300).contains(&size));
+        // The path is restored and a larger size is found by the next search
+        path.mtu = MAX_PROBE_SIZE;
+        assert_eq!(path.report(&mut pmtu).await, MAX_PROBE_SIZE);
+        assert!(start.elapsed() >= RAISE_INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acks_of_other_probes_are_ignored() {
+        let path = Path::new(MAX_PROBE_SIZE).await;
+        let mut pmtu = PathMtu::default();
+        pmtu.on_timer(&path.socket).await;
+        let probe = pmtu.probe.unwrap();
+        assert_eq!(pmtu.on_ack(probe.id + 1, probe.size as u16), None);
+        assert_eq!(pmtu.on_ack(probe.id, 1300), None);
+        assert_eq!(pmtu.max_frame_size(), DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(pmtu.on_ack(probe.id, probe.size as u16), Some(probe.size));
+        assert_eq!(pmtu.on_ack(probe.id, probe.size as u16), None);
+    }
+}
@@ -0,0 +1,200 @@
+use std::{net::SocketAddr, ops::RangeInclusive, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use ipnet::IpNet;
+
+/// What a destination rule matches besides the ports
+#[derive(Debug, Clone)]
+enum Target {
+    Network(IpNet),
+    /// A host name, or all the subdomains of a name if it starts with `*.`. Always lowercase.
+    Host(String),
+}
+
+/// A rule which matches the destinations in a network or with a host name, and a range of ports.
+/// Written as CIDR, HOST, CIDR:PORT or HOST:FIRST-LAST. IPv6 networks with ports are written in brackets.
+#[derive(Debug, Clone)]
+pub struct DestinationRule {
+    target: Target,
+    ports: RangeInclusive<u16>,
+}
+
+impl DestinationRule {
+    /// Host is the name which the client asked for, if any. Host rules only match named destinations.
+    pub fn matches(&self, host: Option<&str>, address: SocketAddr) -> bool {
+        if !self.ports.contains(&address.port()) {
+            return false;
+        }
+        match &self.target {
+            Target::Network(network) => network.contains(&address.ip()),
+            Target::Host(pattern) => host.is_some_and(|host| {
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                match pattern.strip_prefix("*.") {
+                    Some(domain) => host
+                        .strip_suffix(domain)
+                        .is_some_and(|subdomain| subdomain.ends_with('.')),
+                    None => host == *pattern,
+                }
+            }),
+        }
+    }
+}
+
+impl FromStr for DestinationRule {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let parse_target = |target: &str| {
+            let network = target.trim_start_matches('[').trim_end_matches(']');
+            if let Ok(network) = network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<std::net::IpAddr>().map(IpNet::from))
+            {
+                return Ok(Target::Network(network));
+            }
+            let host = target.to_ascii_lowercase();
+            let labels = host.strip_prefix("*.").unwrap_or(&host);
+            let valid = !labels.is_empty()
+                && labels.split('.').all(|label| {
+                    !label.is_empty()
+                        && label
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                });
+            if !valid {
+                bail!("invalid network or host name {}", target);
+            }
+            Ok(Target::Host(host))
+        };
+        // A bare target has no port. Otherwise, the port is after the last colon.
+        let Some((target, ports)) = rule
+            .rsplit_once(':')
+            .filter(|(target, _)| !target.contains(':') || target.ends_with(']'))
+        else {
+            return Ok(Self {
+                target: parse_target(rule)?,
+                ports: 0..=u16::MAX,
+            });
+        };
+        let ports = match ports.split_once('-') {
+            Some((first, last)) => first.parse()?..=last.parse()?,
+            None => {
+                let port = ports.parse()?;
+                port..=port
+            }
+        };
+        if ports.is_empty() {
+            return Err(anyhow!("invalid port range in {}", rule));
+        }
+        Ok(Self {
+            target: parse_target(target)?,
+            ports,
+        })
+    }
+}
+
+/// Destinations which clients can ask the server to reach.
+/// Nothing is allowed by default and denied destinations are never allowed.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    allow: Vec<DestinationRule>,
+    deny: Vec<DestinationRule>,
+}
+
+impl Policy {
+    pub fn new(allow: Vec<DestinationRule>, deny: Vec<DestinationRule>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Host is the name which the client asked for and address is what it resolved to
+    pub fn allows(&self, host: Option<&str>, address: SocketAddr) -> bool {
+        let matches = |rule: &DestinationRule| rule.matches(host, address);
+        !self.deny.iter().any(matches) && self.allow.iter().any(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule: &str) -> DestinationRule {
+        rule.parse().unwrap()
+    }
+
+    fn policy(allow: &[&str], deny: &[&str]) -> Policy {
+        Policy::new(
+            allow.iter().map(|r| rule(r)).collect(),
+            deny.iter().map(|r| rule(r)).collect(),
+        )
+    }
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_and_ports_match() {
+        let network = rule("10.0.0.0/8:80-443");
+        assert!(network.matches(None, address("10.1.2.3:80")));
+        assert!(network.matches(None, address("10.1.2.3:443")));
+        assert!(!network.matches(None, address("10.1.2.3:444")));
+        assert!(!network.matches(None, address("11.1.2.3:80")));
+        let ip = rule("192.168.1.10");
+        assert!(ip.matches(None, address("192.168.1.10:22")));
+        assert!(!ip.matches(None, address("192.168.1.11:22")));
+        let v6 = rule("[fd00::/8]:53");
+        assert!(v6.matches(None, address("[fd12::1]:53")));
+        assert!(!v6.matches(None, address("[fd12::1]:54")));
+        assert!(rule("fd00::/8").matches(None, address("[fd12::1]:1")));
+    }
+
+    #[test]
+    fn hosts_match_names_and_subdomains() {
+        let any = address("1.2.3.4:443");
+        let host = rule("Example.com:443");
+        assert!(host.matches(Some("example.com"), any));
+        assert!(host.matches(Some("EXAMPLE.com."), any));
+        assert!(!host.matches(Some("www.example.com"), any));
+        // Host rules never match destinations without a name
+        assert!(!host.matches(None, any));
+        let wildcard = rule("*.example.com");
+        assert!(wildcard.matches(Some("www.example.com"), any));
+        assert!(wildcard.matches(Some("a.b.example.com"), any));
+        assert!(!wildcard.matches(Some("example.com"), any));
+        assert!(!wildcard.matches(Some("badexample.com"), any));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for invalid in [
+            "",
+            "exa mple.com",
+            "10.0.0.0/8:443-80",
+            "host:port",
+            "*.",
+            "a..b",
+        ] {
+            assert!(invalid.parse::<DestinationRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn nothing_is_allowed_by_default() {
+        assert!(!Policy::default().allows(None, address("127.0.0.1:80")));
+        assert!(!policy(&[], &["10.0.0.0/8"]).allows(None, address("127.0.0.1:80")));
+    }
+
+    #[test]
+    fn deny_beats_allow() {
+        let policy = policy(
+            &["0.0.0.0/0", "*.example.com"],
+            &["127.0.0.0/8", "evil.example.com"],
+        );
+        assert!(policy.allows(None, address("1.2.3.4:80")));
+        assert!(!policy.allows(None, address("127.0.0.1:80")));
+        assert!(policy.allows(Some("www.example.com"), address("1.2.3.4:80")));
+        assert!(!policy.allows(Some("evil.example.com"), address("1.2.3.4:80")));
+        // A name is denied by the addresses which it resolves to
+        assert!(!policy.allows(Some("www.example.com"), address("127.0.0.1:80")));
+    }
+}
@@ -0,0 +1,426 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::Context;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use quinn::{
+    crypto::rustls::QuicClientConfig,
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, CryptoProvider},
+        pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+        CertificateError, DigitallySignedStruct, SignatureScheme,
+    },
+    ClientConfig, Connection, ConnectionError, Endpoint, EndpointConfig, IdleTimeout,
+    SendDatagramError, ServerConfig, TokioRuntime, TransportConfig,
+};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use crate::{
+    arguments::TunnelOptions,
+    messages::{CertificateHash, Destination, QuicDatagram, UdpRecord},
+    stats::SessionStats,
+    util::FORWARD_BUFFER_SIZE,
+};
+
+/// The name of the server in its certificate
+const SERVER_NAME: &str = "p2p-puncher";
+/// A QUIC connection is lost if nothing is received for this many keep-alive intervals
+const MAX_MISSED_KEEP_ALIVES: u32 = 3;
+/// UDP records which wait for their association. More are dropped.
+const MAX_QUEUED_RECORDS: usize = 256;
+/// Datagrams which are read from streams and wait to be received. Streams wait for them.
+const MAX_QUEUED_STREAMS: usize = 64;
+
+/// Keep-alives of QUIC connections follow the keep-alive of the tunnel. QUIC's own idle timeout
+/// only detects dead paths; idle sessions are detected by their data like other sessions.
+fn transport_config(options: &TunnelOptions) -> Arc<TransportConfig> {
+    let keep_alive = options.keep_alive_interval();
+    let mut config = TransportConfig::default();
+    config
+        .keep_alive_interval(Some(keep_alive))
+        .max_idle_timeout(IdleTimeout::try_from(keep_alive * MAX_MISSED_KEEP_ALIVES).ok());
+    Arc::new(config)
+}
+
+/// The self signed certificate of the QUIC server of a session. Clients learn its hash in the
+/// punch handshake and accept no other certificate.
+pub struct Certificate {
+    certificate: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl Certificate {
+    pub fn generate() -> anyhow::Result<Self> {
+        let certificate = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])?;
+        Ok(Self {
+            certificate: certificate.cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(certificate.signing_key.serialize_der()),
+        })
+    }
+
+    pub fn hash(&self) -> CertificateHash {
+        certificate_hash(&self.certificate)
+    }
+}
+
+/// SHA-256 of a DER encoded certificate
+fn certificate_hash(certificate: &CertificateDer<'_>) -> CertificateHash {
+    Sha256::digest(certificate).into()
+}
+
+/// Create a QUIC server with the certificate of its session on the punched socket of the session
+pub fn server_endpoint(
+    socket: std::net::UdpSocket,
+    certificate: Certificate,
+    options: &TunnelOptions,
+) -> anyhow::Result<Endpoint> {
+    let mut config =
+        ServerConfig::with_single_cert(vec![certificate.certificate], certificate.key.into())?;
+    config.transport_config(transport_config(options));
+    Ok(Endpoint::new(
+        EndpointConfig::default(),
+        Some(config),
+        socket,
+        Arc::new(TokioRuntime),
+    )?)
+}
+
+/// Create a QUIC client on the punched socket and connect to the server over it.
+/// The server must present the certificate which it has announced in the punch handshake.
+pub async fn connect(
+    socket: std::net::UdpSocket,
+    server_address: SocketAddr,
+    certificate: CertificateHash,
+    options: &TunnelOptions,
+) -> anyhow::Result<(Endpoint, Connection)> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+            hash: certificate,
+            provider,
+        }))
+        .with_no_client_auth();
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+    config.transport_config(transport_config(options));
+    let endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        None,
+        socket,
+        Arc::new(TokioRuntime),
+    )?;
+    let connection = endpoint
+        .connect_with(config, server_address, SERVER_NAME)?
+        .await
+        .context("cannot connect to the QUIC server")?;
+    Ok((endpoint, connection))
+}
+
+/// The certificate of the server is self signed; so it's accepted only if its hash is the one
+/// which the server has sent in the punch handshake. If the server is pinned, the hash is signed
+/// in its proof; so nobody else can terminate the connection.
+#[derive(Debug)]
+struct PinnedCertificate {
+    hash: CertificateHash,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if certificate_hash(end_entity) != self.hash {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// A QUIC connection which carries the datagrams of a session.
+/// Datagrams are sent as QUIC datagrams if they fit in one; otherwise each one is sent on its own stream.
+/// The UDP records of SOCKS associations share the QUIC datagrams with the session.
+#[derive(Debug, Clone)]
+pub struct QuicTunnel {
+    connection: Connection,
+    stats: Arc<SessionStats>,
+    associations: Arc<Mutex<HashMap<u64, mpsc::Sender<Record>>>>,
+    /// Datagrams which are read from streams. Each stream is read by its own task; so a stream
+    /// which stalls holds back neither the QUIC datagrams nor the other streams.
+    streams: Arc<tokio::sync::Mutex<mpsc::Receiver<Bytes>>>,
+}
+
+impl QuicTunnel {
+    pub fn new(connection: Connection, stats: Arc<SessionStats>) -> Self {
+        let (sender, streams) = mpsc::channel(MAX_QUEUED_STREAMS);
+        tokio::spawn(read_streams(connection.clone(), sender));
+        Self {
+            connection,
+            stats,
+            associations: Default::default(),
+            streams: Arc::new(tokio::sync::Mutex::new(streams)),
+        }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Send a datagram of the application to the peer
+    pub async fn send(&self, datagram: &[u8]) -> anyhow::Result<()> {
+        self.stats.on_sent(datagram.len());
+        if self.send_datagram(&QuicDatagram::Session(datagram))? {
+            return Ok(());
+        }
+        let mut stream = self.connection.open_uni().await?;
+        stream.write_all(datagram).await?;
+        stream.finish()?;
+        Ok(())
+    }
+
+    /// Send the message as a QUIC datagram. Returns false if it does not fit in one.
+    fn send_datagram(&self, message: &QuicDatagram) -> anyhow::Result<bool> {
+        let encoded = postcard::to_allocvec(message)?;
+        let fits = self
+            .connection
+            .max_datagram_size()
+            .is_some_and(|max| encoded.len() <= max);
+        if !fits {
+            return Ok(false);
+        }
+        match self.connection.send_datagram(encoded.into()) {
+            // The path MTU might have shrunk meanwhile
+            Err(SendDatagramError::TooLarge) => Ok(false),
+            result => result.map(|()| true).map_err(Into::into),
+        }
+    }
+
+    /// Receive the next datagram of the peer. Returns an error when the connection is closed.
+    /// UDP records are passed to their associations meanwhile.
+    pub async fn receive(&self) -> Result<Bytes, ConnectionError> {
+        loop {
+            let datagram = tokio::select! {
+                datagram = self.connection.read_datagram() => {
+                    let datagram = datagram?;
+                    match postcard::from_bytes(&datagram) {
+                        Ok(QuicDatagram::Session(data)) => datagram.slice_ref(data),
+                        Ok(QuicDatagram::Udp { association, record }) => {
+                            let payload = datagram.slice_ref(record.payload);
+                            self.dispatch(association, record.peer, payload);
+                            continue;
+                        }
+                        Err(_) => {
+                            log::debug!("Dropping an invalid QUIC datagram");
+                            continue;
+                        }
+                    }
+                }
+                datagram = async { self.streams.lock().await.recv().await } => match datagram {
+                    Some(datagram) => datagram,
+                    // Streams are accepted until the connection is closed
+                    None => return Err(self.connection.closed().await),
+                },
+            };
+            self.stats.on_received(datagram.len());
+            return Ok(datagram);
+        }
+    }
+
+    /// Pass a UDP record to its association. Records of unknown associations and records
+    /// which the association can't keep up with are dropped.
+    fn dispatch(&self, association: u64, peer: Destination, payload: Bytes) {
+        let associations = self.associations.lock();
+        let Some(records) = associations.get(&association) else {
+            log::trace!(
+                "Dropping a UDP record of unknown association {}",
+                association
+            );
+            return;
+        };
+        if records.try_send((peer, payload)).is_err() {
+            log::trace!("Dropping a UDP record of busy association {}", association);
+        }
+    }
+
+    /// Start receiving the UDP records of an association
+    pub fn associate(&self, association: u64) -> Association {
+        let (sender, records) = mpsc::channel(MAX_QUEUED_RECORDS);
+        self.associations.lock().insert(association, sender);
+        Association {
+            tunnel: self.clone(),
+            id: association,
+            records: tokio::sync::Mutex::new(records),
+        }
+    }
+}
+
+/// Accept the streams of the peer until the connection is closed and read each one in its own task
+async fn read_streams(connection: Connection, datagrams: mpsc::Sender<Bytes>) {
+    while let Ok(mut stream) = connection.accept_uni().await {
+        let datagrams = datagrams.clone();
+        tokio::spawn(async move {
+            match stream.read_to_end(FORWARD_BUFFER_SIZE).await {
+                Ok(datagram) => {
+                    let _ = datagrams.send(datagram.into()).await;
+                }
+                Err(err) => log::debug!("Cannot read a datagram from QUIC stream: {}", err),
+            }
+        });
+    }
+}
+
+/// A UDP record: the peer and the payload
+pub type Record = (Destination, Bytes);
+
+/// The UDP records of one association on a QUIC tunnel. Nothing is received for the association
+/// after it is dropped.
+#[derive(Debug)]
+pub struct Association {
+    tunnel: QuicTunnel,
+    id: u64,
+    records: tokio::sync::Mutex<mpsc::Receiver<Record>>,
+}
+
+impl Association {
+    /// Send a UDP record to the peer. Records which don't fit in a QUIC datagram are dropped
+    /// like UDP datagrams which are too large for the path.
+    pub fn send(&self, record: UdpRecord) -> anyhow::Result<()> {
+        let length = record.payload.len();
+        let message = QuicDatagram::Udp {
+            association: self.id,
+            record,
+        };
+        if !self.tunnel.send_datagram(&message)? {
+            log::trace!(
+                "Dropping a UDP datagram of {} bytes which does not fit in a QUIC datagram",
+                length
+            );
+        }
+        Ok(())
+    }
+
+    /// Receive the next UDP record of the peer
+    pub async fn receive(&self) -> Option<Record> {
+        self.records.lock().await.recv().await
+    }
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        self.tunnel.associations.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const OPTIONS: TunnelOptions = TunnelOptions {
+        keep_alive: 1,
+        idle_timeout: 10,
+    };
+
+    fn socket() -> std::net::UdpSocket {
+        std::net::UdpSocket::bind("127.0.0.1:0").unwrap()
+    }
+
+    /// Serve one QUIC connection with a new certificate and connect to it with the given hash.
+    /// The hash of the certificate is used if it's None.
+    async fn connect_with(hash: Option<CertificateHash>) -> anyhow::Result<()> {
+        let certificate = Certificate::generate().unwrap();
+        let hash = hash.unwrap_or_else(|| certificate.hash());
+        let server_socket = socket();
+        let server_address = server_socket.local_addr().unwrap();
+        let server = server_endpoint(server_socket, certificate, &OPTIONS).unwrap();
+        tokio::spawn(async move {
+            if let Some(incoming) = server.accept().await {
+                let _ = incoming.await;
+            }
+        });
+        connect(socket(), server_address, hash, &OPTIONS).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_the_announced_certificate_is_accepted() {
+        connect_with(None).await.unwrap();
+        let other = Certificate::generate().unwrap().hash();
+        assert!(connect_with(Some(other)).await.is_err());
+    }
+
+    /// A client and a server tunnel over one QUIC connection
+    async fn tunnels() -> (QuicTunnel, QuicTunnel, Endpoint) {
+        let certificate = Certificate::generate().unwrap();
+        let hash = certificate.hash();
+        let server_socket = socket();
+        let server_address = server_socket.local_addr().unwrap();
+        let server = server_endpoint(server_socket, certificate, &OPTIONS).unwrap();
+        let accepted = tokio::spawn(async move { server.accept().await.unwrap().await.unwrap() });
+        let (endpoint, connection) = connect(socket(), server_address, hash, &OPTIONS)
+            .await
+            .unwrap();
+        let stats = Arc::new(SessionStats::default());
+        let client = QuicTunnel::new(connection, stats.clone());
+        let server = QuicTunnel::new(accepted.await.unwrap(), stats);
+        (client, server, endpoint)
+    }
+
+    #[tokio::test]
+    async fn stalled_streams_do_not_hold_back_datagrams() {
+        let (client, server, _endpoint) = tunnels().await;
+        let mut stalled = client.connection().open_uni().await.unwrap();
+        stalled.write_all(b"stalled ").await.unwrap();
+        client.send(b"datagram").await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), server.receive()).await;
+        assert_eq!(&received.unwrap().unwrap()[..], b"datagram");
+        stalled.write_all(b"stream").await.unwrap();
+        stalled.finish().unwrap();
+        assert_eq!(&server.receive().await.unwrap()[..], b"stalled stream");
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// Maximum number of keys which each shard of a rate limiter tracks.
+/// Old buckets are evicted when a shard is full; so new keys are never locked out.
+const MAX_KEYS_PER_SHARD: usize = 16 * 1024;
+/// A full shard is evicted down to this many keys; so the cost of eviction is shared by many new keys
+const EVICTED_KEYS_PER_SHARD: usize = MAX_KEYS_PER_SHARD - MAX_KEYS_PER_SHARD / 8;
+
+/// A classic token bucket. Tokens are refilled with a constant rate up to the burst size.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket
+    pub fn new(burst: f64) -> Self {
+        Self {
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Refill the bucket based on the time passed since the last refill
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last_refill = now;
+    }
+
+    /// Take the given amount of tokens from the bucket. Returns false if there is not enough tokens.
+    pub fn take(&mut self, amount: f64, rate: f64, burst: f64) -> bool {
+        self.refill(rate, burst);
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+
+    /// Returns true if the bucket has the given amount of tokens
+    pub fn has(&mut self, amount: f64, rate: f64, burst: f64) -> bool {
+        self.refill(rate, burst);
+        self.tokens >= amount
+    }
+
+    /// Take the given amount of tokens even if the bucket does not have them.
+    /// Returns how long the caller must wait until the borrowed tokens are refilled.
+    pub fn reserve(&mut self, amount: f64, rate: f64, burst: f64) -> Duration {
+        self.refill(rate, burst);
+        self.tokens -= amount;
+        Duration::from_secs_f64((-self.tokens).max(0.0) / rate)
+    }
+
+    /// Returns true if the bucket is full. Full buckets are useless to keep.
+    fn is_full(&self, rate: f64, burst: f64) -> bool {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.tokens + elapsed * rate >= burst
+    }
+}
+
+/// Rate limits any kind of key (source address, service name, ...) with a token bucket for each key.
+/// Keys are sharded in order to reduce lock contention between threads.
+pub struct RateLimiter<K> {
+    shards: Box<[Mutex<HashMap<K, TokenBucket>>]>,
+    hasher: RandomState,
+    /// Tokens added to each bucket per second
+    rate: f64,
+    /// Size of each bucket
+    burst: f64,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(rate: f64, burst: f64, shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+            rate,
+            burst,
+        }
+    }
+
+    /// Take one token from the bucket of the key. Returns false if the key must be limited.
+    pub fn check<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        let mut shard = self.shards[index].lock();
+        if let Some(bucket) = shard.get_mut(key) {
+            return bucket.take(1.0, self.rate, self.burst);
+        }
+        // New key
+        if shard.len() >= MAX_KEYS_PER_SHARD {
+            self.evict(&mut shard);
+        }
+        let mut bucket = TokenBucket::new(self.burst);
+        let allowed = bucket.take(1.0, self.rate, self.burst);
+        shard.insert(key.to_owned(), bucket);
+        allowed
+    }
+
+    /// Take one token from the bucket of the key if it has a bucket. Keys without a bucket are
+    /// allowed and don't get one; so keys which are not proven yet can't fill the limiter.
+    pub fn check_known<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        match self.shards[index].lock().get_mut(key) {
+            Some(bucket) => bucket.take(1.0, self.rate, self.burst),
+            None => true,
+        }
+    }
+
+    /// Make room in a full shard. Full buckets are removed first. If that's not enough,
+    /// the buckets which are not used for the longest time are removed too.
+    fn evict(&self, shard: &mut HashMap<K, TokenBucket>) {
+        shard.retain(|_, bucket| !bucket.is_full(self.rate, self.burst));
+        if shard.len() <= EVICTED_KEYS_PER_SHARD {
+            return;
+        }
+        let mut last_used: Vec<_> = shard.values().map(|bucket| bucket.last_refill).collect();
+        let (_, &mut newest_evicted, _) =
+            last_used.select_nth_unstable(shard.len() - EVICTED_KEYS_PER_SHARD - 1);
+        log::debug!("Rate limiter is full. Evicting the least recently used keys.");
+        shard.retain(|_, bucket| bucket.last_refill > newest_evicted);
+    }
+
+    /// Remove the buckets which are full. They are equal to a new bucket.
+    pub fn cleanup(&self) {
+        for shard in self.shards.iter() {
+            shard
+                .lock()
+                .retain(|_, bucket| !bucket.is_full(self.rate, self.burst));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_bursts_and_refills_with_the_rate() {
+        let (rate, burst) = (100.0, 10.0);
+        let mut bucket = TokenBucket::new(burst);
+        assert!(bucket.is_full(rate, burst));
+        for _ in 0..10 {
+            assert!(bucket.take(1.0, rate, burst));
+        }
+        assert!(!bucket.take(1.0, rate, burst));
+        std::thread::sleep(Duration::from_millis(50));
+        // At least 5 tokens are refilled, but never beyond the burst
+        assert!(bucket.take(5.0, rate, burst));
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(bucket.is_full(rate, burst));
+        assert!(!bucket.has(burst + 1.0, rate, burst));
+    }
+
+    #[test]
+    fn reserved_tokens_must_be_waited_for() {
+        let (rate, burst) = (10.0, 10.0);
+        let mut bucket = TokenBucket::new(burst);
+        assert_eq!(bucket.reserve(10.0, rate, burst), Duration::ZERO);
+        // The bucket is in debt now; so each token takes a tenth of a second
+        let wait = bucket.reserve(5.0, rate, burst);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+        let wait = bucket.reserve(5.0, rate, burst);
+        assert!(wait > Duration::from_millis(950) && wait <= Duration::from_secs(1));
+        assert!(!bucket.has(1.0, rate, burst));
+    }
+
+    #[test]
+    fn limiter_limits_each_key_on_its_own() {
+        let limiter = RateLimiter::new(1.0, 2.0, 4);
+        assert!(limiter.check("a"));
+        assert!(limiter.check("a"));
+        assert!(!limiter.check("a"));
+        assert!(limiter.check("b"));
+        // Only full buckets are forgotten
+        limiter.cleanup();
+        assert!(!limiter.check("a"));
+    }
+
+    #[test]
+    fn least_recently_used_keys_are_evicted_from_full_limiters() {
+        // Buckets are never refilled; so each key is allowed once while it's remembered
+        let limiter = RateLimiter::new(0.0, 1.0, 1);
+        for key in 0..MAX_KEYS_PER_SHARD {
+            assert!(limiter.check(&key));
+        }
+        assert!(limiter.check(&MAX_KEYS_PER_SHARD));
+        assert!(limiter.shards[0].lock().len() <= EVICTED_KEYS_PER_SHARD + 1);
+        assert!(limiter.check(&0));
+        assert!(!limiter.check(&(MAX_KEYS_PER_SHARD - 1)));
+        assert!(!limiter.check(&MAX_KEYS_PER_SHARD));
+    }
+
+    #[test]
+    fn unknown_keys_are_not_remembered() {
+        let limiter = RateLimiter::new(0.0, 1.0, 1);
+        for _ in 0..3 {
+            assert!(limiter.check_known("a"));
+        }
+        assert!(limiter.shards[0].lock().is_empty());
+        assert!(limiter.check("a"));
+        assert!(!limiter.check_known("a"));
+    }
+}
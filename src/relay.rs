@@ -0,0 +1,444 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::bail;
+use parking_lot::Mutex;
+use quinn::{ConnectionError, RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{self, TcpStream, UdpSocket},
+    select, task,
+    time::{self, Instant},
+};
+
+use crate::{
+    bandwidth::{acquire_shared, SessionBandwidth},
+    messages::{Destination, DialError, StreamReply, StreamRequest, UdpRecord},
+    policy::Policy,
+    quic::{Association, QuicTunnel},
+    util::{FORWARD_BUFFER_SIZE, LOCAL_UDP_BIND_ADDRESS, SOCKET_TIMEOUT},
+};
+
+/// Messages on streams are never larger than this
+const MAX_MESSAGE_SIZE: usize = FORWARD_BUFFER_SIZE + 1024;
+/// TCP connections are copied to the client in chunks of this size; each chunk waits for the bandwidth limits
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+/// An association forgets the destinations which it has resolved when it has resolved this many
+const MAX_RESOLVED_DESTINATIONS: usize = 1024;
+/// An association forgets the peer which it has not sent to for the longest time when it has sent to this many
+const MAX_PEERS: usize = 1024;
+
+/// Write a message on a stream. Each message is prefixed by its length.
+pub async fn write_message<T: Serialize>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    message: &T,
+) -> anyhow::Result<()> {
+    let encoded = postcard::to_allocvec(message)?;
+    stream
+        .write_all(&(encoded.len() as u32).to_be_bytes())
+        .await?;
+    stream.write_all(&encoded).await?;
+    Ok(())
+}
+
+/// Read the next message of a stream into the buffer. Returns None if the stream is finished.
+pub async fn read_message<'a, T: Deserialize<'a>>(
+    stream: &mut (impl AsyncRead + Unpin),
+    buffer: &'a mut Vec<u8>,
+) -> anyhow::Result<Option<T>> {
+    let mut length = [0; 4];
+    match stream.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        bail!("message of {} bytes is too large", length);
+    }
+    buffer.resize(length, 0);
+    stream.read_exact(buffer).await?;
+    Ok(Some(postcard::from_bytes(buffer)?))
+}
+
+/// Serve the streams which a SOCKS client opens on the QUIC tunnel of its session.
+/// The data which is relayed to the client is within the bandwidth limits of the session.
+/// Streams is the number of open streams; the session is never idle while it has any.
+/// Returns when the connection is closed.
+pub async fn serve_streams(
+    tunnel: QuicTunnel,
+    policy: Arc<Policy>,
+    bandwidth: Arc<Mutex<SessionBandwidth>>,
+    streams: Arc<AtomicUsize>,
+    last_data: Arc<Mutex<Instant>>,
+) -> anyhow::Result<()> {
+    let client = tunnel.connection().remote_address();
+    loop {
+        let (send, recv) = match tunnel.connection().accept_bi().await {
+            Ok(stream) => stream,
+            Err(ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let tunnel = tunnel.clone();
+        let policy = policy.clone();
+        let bandwidth = bandwidth.clone();
+        let streams = streams.clone();
+        let last_data = last_data.clone();
+        streams.fetch_add(1, Ordering::Relaxed);
+        task::spawn(async move {
+            if let Err(err) = serve_stream(send, recv, &tunnel, &policy, &bandwidth, client).await {
+                log::debug!("Stream of {} failed: {}", client, err);
+            }
+            *last_data.lock() = Instant::now();
+            streams.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Answer the request of a stream and relay its data
+async fn serve_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    tunnel: &QuicTunnel,
+    policy: &Policy,
+    bandwidth: &Mutex<SessionBandwidth>,
+    client: SocketAddr,
+) -> anyhow::Result<()> {
+    let mut buffer = Vec::new();
+    let Some(request) = read_message::<StreamRequest>(&mut recv, &mut buffer).await? else {
+        return Ok(());
+    };
+    match request {
+        StreamRequest::Connect(destination) => {
+            let stream = match connect(&destination, policy).await {
+                Ok(stream) => stream,
+                Err(DialError::NotAllowed) => {
+                    log::warn!(
+                        "Denied {} to connect to {} by the policy",
+                        client,
+                        destination
+                    );
+                    write_message(&mut send, &StreamReply::Err(DialError::NotAllowed)).await?;
+                    send.finish()?;
+                    return Ok(());
+                }
+                Err(err) => {
+                    log::warn!("Cannot connect {} to {}: {:?}", client, destination, err);
+                    write_message(&mut send, &StreamReply::Err(err)).await?;
+                    send.finish()?;
+                    return Ok(());
+                }
+            };
+            log::info!("{} is connected to {}", client, destination);
+            write_message(&mut send, &StreamReply::Ok(stream.local_addr()?)).await?;
+            let (mut tcp_read, mut tcp_write) = stream.into_split();
+            tokio::try_join!(
+                async {
+                    tokio::io::copy(&mut recv, &mut tcp_write).await?;
+                    tcp_write.shutdown().await
+                },
+                async {
+                    copy_limited(&mut tcp_read, &mut send, bandwidth).await?;
+                    send.shutdown().await
+                },
+            )?;
+            log::debug!("Connection of {} to {} is closed", client, destination);
+            Ok(())
+        }
+        StreamRequest::Associate => {
+            let socket = UdpSocket::bind(LOCAL_UDP_BIND_ADDRESS).await?;
+            write_message(&mut send, &StreamReply::Ok(socket.local_addr()?)).await?;
+            log::info!(
+                "Relaying UDP of {} from {}",
+                client,
+                socket.local_addr().unwrap()
+            );
+            let association = tunnel.associate(send.id().into());
+            associate(recv, association, socket, policy, bandwidth, client).await
+        }
+    }
+}
+
+/// Copy a stream to the client within the bandwidth limits of the session until it's finished
+async fn copy_limited(
+    from: &mut (impl AsyncRead + Unpin),
+    to: &mut (impl AsyncWrite + Unpin),
+    bandwidth: &Mutex<SessionBandwidth>,
+) -> io::Result<()> {
+    let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+    loop {
+        let read = from.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        let wait = bandwidth.lock().reserve(read);
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+        to.write_all(&buffer[..read]).await?;
+    }
+}
+
+/// Resolve the destination and check it against the policy.
+/// The first address of a host name which the policy allows is used.
+async fn resolve(destination: &Destination, policy: &Policy) -> Result<SocketAddr, DialError> {
+    let (host, addresses) = match destination {
+        Destination::Address(address) => (None, vec![*address]),
+        Destination::Domain(host, port) => {
+            let addresses = net::lookup_host((host.as_str(), *port))
+                .await
+                .map_err(|_| DialError::Unresolved)?;
+            (Some(host.as_str()), addresses.collect())
+        }
+    };
+    addresses
+        .into_iter()
+        .find(|address| policy.allows(host, *address))
+        .ok_or(DialError::NotAllowed)
+}
+
+/// Open a TCP connection to the destination if the policy allows it
+async fn connect(destination: &Destination, policy: &Policy) -> Result<TcpStream, DialError> {
+    let address = resolve(destination, policy).await?;
+    match time::timeout(SOCKET_TIMEOUT, TcpStream::connect(address)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(err)) => Err(match err.kind() {
+            io::ErrorKind::ConnectionRefused => DialError::Refused,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                DialError::Unreachable
+            }
+            _ => DialError::Failed,
+        }),
+        Err(_) => Err(DialError::Unreachable),
+    }
+}
+
+/// The peers which the client of an association has sent to. Only they can answer.
+#[derive(Debug, Default)]
+struct Peers(HashMap<SocketAddr, Instant>);
+
+impl Peers {
+    /// Remember that the client has sent to the peer
+    fn insert(&mut self, peer: SocketAddr) {
+        if self.0.len() >= MAX_PEERS && !self.0.contains_key(&peer) {
+            let oldest = self.0.iter().min_by_key(|(_, sent)| **sent);
+            if let Some((&oldest, _)) = oldest {
+                self.0.remove(&oldest);
+            }
+        }
+        self.0.insert(peer, Instant::now());
+    }
+
+    fn contains(&self, peer: &SocketAddr) -> bool {
+        self.0.contains_key(peer)
+    }
+}
+
+/// Relay the UDP records of the association to their destinations and the answers back
+/// until the client finishes the stream. Only the peers which the client has sent to can answer,
+/// and their answers are dropped or delayed as the bandwidth limits of the session say.
+/// Destinations are resolved once per association; denied ones are logged once.
+async fn associate(
+    mut recv: RecvStream,
+    association: Association,
+    socket: UdpSocket,
+    policy: &Policy,
+    bandwidth: &Mutex<SessionBandwidth>,
+    client: SocketAddr,
+) -> anyhow::Result<()> {
+    let peers = Mutex::new(Peers::default());
+    let outbound = async {
+        let mut resolved = HashMap::new();
+        while let Some((peer, payload)) = association.receive().await {
+            let address = match resolved.get(&peer) {
+                Some(address) => *address,
+                None => {
+                    let address = resolve(&peer, policy).await;
+                    match address {
+                        Err(DialError::NotAllowed) => {
+                            log::warn!("Denied {} to send to {} by the policy", client, peer)
+                        }
+                        Err(err) => log::debug!("Cannot resolve {}: {:?}", peer, err),
+                        Ok(_) => {}
+                    }
+                    if resolved.len() >= MAX_RESOLVED_DESTINATIONS {
+                        resolved.clear();
+                    }
+                    resolved.insert(peer, address);
+                    address
+                }
+            };
+            if let Ok(address) = address {
+                peers.lock().insert(address);
+                let _ = socket.send_to(&payload, address).await;
+            }
+        }
+        anyhow::Ok(())
+    };
+    let inbound = async {
+        let mut buffer = [0; FORWARD_BUFFER_SIZE];
+        loop {
+            let (length, from) = socket.recv_from(&mut buffer).await?;
+            if !peers.lock().contains(&from) {
+                log::trace!("Dropping datagram from unknown peer {}", from);
+                continue;
+            }
+            if !acquire_shared(bandwidth, length).await {
+                log::trace!("Dropping a datagram over the bandwidth limits");
+                continue;
+            }
+            association.send(UdpRecord {
+                peer: Destination::Address(from),
+                payload: &buffer[..length],
+            })?;
+        }
+    };
+    // The association is over when the client finishes the stream
+    let finished = async {
+        recv.read_to_end(0).await?;
+        anyhow::Ok(())
+    };
+    // Each direction runs until the association is over; so neither is cancelled midway
+    select! {
+        result = outbound => result,
+        result = inbound => result,
+        result = finished => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::{arguments::BandwidthLimits, bandwidth::Bandwidth};
+
+    #[tokio::test]
+    async fn messages_are_read_as_they_are_written() {
+        let (mut writer, mut reader) = duplex(1024);
+        let requests = [
+            StreamRequest::Connect(Destination::Domain("example.com".to_owned(), 443)),
+            StreamRequest::Associate,
+        ];
+        for request in &requests {
+            write_message(&mut writer, request).await.unwrap();
+        }
+        write_message(&mut writer, &StreamReply::Err(DialError::Refused))
+            .await
+            .unwrap();
+        drop(writer);
+        let mut buffer = Vec::new();
+        let request = read_message::<StreamRequest>(&mut reader, &mut buffer)
+            .await
+            .unwrap();
+        assert!(matches!(
+            request,
+            Some(StreamRequest::Connect(Destination::Domain(host, 443))) if host == "example.com"
+        ));
+        let request = read_message::<StreamRequest>(&mut reader, &mut buffer)
+            .await
+            .unwrap();
+        assert!(matches!(request, Some(StreamRequest::Associate)));
+        let reply = read_message::<StreamReply>(&mut reader, &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(reply, Some(Err(DialError::Refused)));
+        // The stream is finished
+        let reply = read_message::<StreamReply>(&mut reader, &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(reply, None);
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused_before_they_are_read() {
+        let mut buffer = Vec::new();
+        let length = (MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes();
+        let result = read_message::<StreamRequest>(&mut &length[..], &mut buffer).await;
+        assert!(result.is_err());
+        assert!(buffer.is_empty());
+        // The largest message is still read
+        let mut stream = (MAX_MESSAGE_SIZE as u32).to_be_bytes().to_vec();
+        stream.resize(4 + MAX_MESSAGE_SIZE, 0);
+        let result = read_message::<StreamReply>(&mut &stream[..], &mut buffer).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn truncated_and_invalid_messages_are_errors() {
+        let mut buffer = Vec::new();
+        let truncated = [0, 0, 0, 10, 1, 2, 3];
+        let result = read_message::<StreamRequest>(&mut &truncated[..], &mut buffer).await;
+        assert!(result.is_err());
+        let length = [0, 0];
+        let result = read_message::<StreamRequest>(&mut &length[..], &mut buffer).await;
+        assert!(matches!(result, Ok(None)));
+        let invalid = [0, 0, 0, 1, 9];
+        let result = read_message::<StreamRequest>(&mut &invalid[..], &mut buffer).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_which_are_not_sent_to_for_the_longest_time_are_forgotten() {
+        let peer = |port| SocketAddr::from(([192, 0, 2, 1], port));
+        let mut peers = Peers::default();
+        for port in 0..MAX_PEERS as u16 {
+            peers.insert(peer(port));
+            time::advance(Duration::from_millis(1)).await;
+        }
+        // Sending again refreshes a peer
+        peers.insert(peer(0));
+        time::advance(Duration::from_millis(1)).await;
+        peers.insert(peer(MAX_PEERS as u16));
+        assert_eq!(peers.0.len(), MAX_PEERS);
+        assert!(peers.contains(&peer(0)));
+        assert!(!peers.contains(&peer(1)));
+        assert!(peers.contains(&peer(2)));
+        assert!(peers.contains(&peer(MAX_PEERS as u16)));
+    }
+
+    #[tokio::test]
+    async fn streams_are_copied_within_the_bandwidth_limits() {
+        let bandwidth = Bandwidth::new(BandwidthLimits {
+            session_bandwidth: Some(100_000.0),
+            session_burst: Some(STREAM_CHUNK_SIZE as f64),
+            ..BandwidthLimits::default()
+        });
+        let session = Mutex::new(bandwidth.session());
+        let data = vec![7; 3 * STREAM_CHUNK_SIZE];
+        let mut copied = Vec::new();
+        let start = std::time::Instant::now();
+        copy_limited(&mut &data[..], &mut copied, &session)
+            .await
+            .unwrap();
+        assert_eq!(copied, data);
+        // The first chunk is in the bucket and the others wait for the rate
+        let elapsed = start.elapsed().as_secs_f64();
+        let expected = (2 * STREAM_CHUNK_SIZE) as f64 / 100_000.0;
+        assert!(elapsed >= expected * 0.9 && elapsed < expected * 2.0);
+    }
+
+    #[tokio::test]
+    async fn destinations_are_resolved_by_the_policy() {
+        let policy = Policy::new(vec!["192.0.2.0/24:53".parse().unwrap()], vec![]);
+        let allowed: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        assert_eq!(
+            resolve(&Destination::Address(allowed), &policy).await,
+            Ok(allowed)
+        );
+        let denied = Destination::Address("192.0.2.1:54".parse().unwrap());
+        assert_eq!(resolve(&denied, &policy).await, Err(DialError::NotAllowed));
+        let denied = Destination::Address("198.51.100.1:53".parse().unwrap());
+        assert_eq!(resolve(&denied, &policy).await, Err(DialError::NotAllowed));
+    }
+}
@@ -0,0 +1,430 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use tokio::{sync::Notify, time::Instant};
+
+use crate::{
+    congestion::Congestion,
+    stats::SessionStats,
+    tunnel::{Frame, RttEstimator, RELIABLE_HEADER_SIZE},
+};
+
+/// Maximum number of frames which are sent and not acknowledged yet
+const SEND_WINDOW: usize = 256;
+/// Maximum number of frames which wait for room in the send window.
+/// New frames are dropped when the queue is full.
+const MAX_QUEUED_FRAMES: usize = 1024;
+/// Maximum number of frames after a lost frame which the receiver keeps.
+/// It's as large as the send window; so the receiver never drops a frame which is in flight.
+const REORDER_WINDOW: usize = SEND_WINDOW;
+/// Retransmission timeout of a frame is doubled on each retransmission up to this many times
+const MAX_BACKOFF: u32 = 6;
+/// A frame is lost if a frame which is sent this much of RTT after it is acknowledged before it
+const REORDER_THRESHOLD: f64 = 0.25;
+
+/// A frame which is sent but not acknowledged yet
+#[derive(Debug)]
+struct InFlight {
+    frame: Box<[u8]>,
+    sent: Instant,
+    /// Number of times which the frame is retransmitted
+    retransmits: u32,
+    /// True if the frame is detected as lost and must be retransmitted now
+    lost: bool,
+}
+
+impl InFlight {
+    /// When should the frame be retransmitted if its ack does not arrive
+    fn deadline(&self, rto: Duration) -> Instant {
+        if self.lost {
+            return self.sent;
+        }
+        self.sent + rto * 2u32.pow(self.retransmits.min(MAX_BACKOFF))
+    }
+}
+
+/// Numbers the frames of a reliable session and keeps them until the peer acknowledges them.
+/// Frames are retransmitted if they are lost or their ack does not arrive in time.
+/// New frames are sent as the congestion window and the pacer allow.
+#[derive(Debug)]
+pub struct ReliableSender {
+    /// Sequence number of the first frame in flight
+    base: u32,
+    /// Frames from the base which are sent. Acknowledged frames are None.
+    in_flight: VecDeque<Option<InFlight>>,
+    /// Number of frames in flight which are not acknowledged
+    outstanding: usize,
+    /// Frames which wait for the congestion window or the pacer
+    queue: VecDeque<Box<[u8]>>,
+    rtt: RttEstimator,
+    congestion: Congestion,
+    /// The latest time which an acknowledged frame was sent at. Frames which are sent
+    /// well before it and are not acknowledged yet are lost.
+    latest_acknowledged: Option<Instant>,
+    /// Notified when the deadline moves earlier
+    timer: Arc<Notify>,
+    stats: Arc<SessionStats>,
+    /// Reliable frames are encoded in this buffer
+    buffer: Vec<u8>,
+}
+
+impl ReliableSender {
+    pub fn new(timer: Arc<Notify>, stats: Arc<SessionStats>) -> Self {
+        Self {
+            base: 0,
+            in_flight: VecDeque::new(),
+            outstanding: 0,
+            queue: VecDeque::new(),
+            rtt: RttEstimator::default(),
+            congestion: Congestion::new(SEND_WINDOW),
+            latest_acknowledged: None,
+            timer,
+            stats,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Returns true if there is room for a new frame in flight
+    fn window_open(&self) -> bool {
+        self.in_flight.len() < SEND_WINDOW && self.outstanding < self.congestion.window()
+    }
+
+    /// Send a data or fragment frame. Returns the reliable frame which must be sent now
+    /// or None if the frame is queued.
+    pub fn push(&mut self, frame: &[u8]) -> Option<&[u8]> {
+        if self.queue.is_empty()
+            && self.window_open()
+            && self.congestion.next_send() <= Instant::now()
+        {
+            if self.outstanding == 0 {
+                // The retransmission timer must be started
+                self.timer.notify_one();
+            }
+            return Some(self.send(frame.into()));
+        }
+        if self.queue.len() >= MAX_QUEUED_FRAMES {
+            log::debug!("Dropping a frame because the send queue is full");
+            self.stats.on_drop();
+            return None;
+        }
+        if self.queue.is_empty() {
+            // The pacer timer must be started
+            self.timer.notify_one();
+        }
+        self.queue.push_back(frame.into());
+        None
+    }
+
+    /// Put a frame in flight and return the reliable frame of it
+    fn send(&mut self, frame: Box<[u8]>) -> &[u8] {
+        let seq = self.base.wrapping_add(self.in_flight.len() as u32);
+        self.in_flight.push_back(Some(InFlight {
+            frame,
+            sent: Instant::now(),
+            retransmits: 0,
+            lost: false,
+        }));
+        self.outstanding += 1;
+        self.congestion.on_send(self.rtt.rtt());
+        let frame = &self.in_flight.back().unwrap().as_ref().unwrap().frame;
+        Self::encode(&mut self.buffer, seq, frame)
+    }
+
+    fn encode<'a>(buffer: &'a mut Vec<u8>, seq: u32, frame: &[u8]) -> &'a [u8] {
+        if buffer.len() < RELIABLE_HEADER_SIZE + frame.len() {
+            buffer.resize(RELIABLE_HEADER_SIZE + frame.len(), 0);
+        }
+        Frame::Reliable { seq, frame }.encode(buffer)
+    }
+
+    /// When should poll_transmit be called. None if nothing is waiting for a timer.
+    pub fn deadline(&self) -> Option<Instant> {
+        let rto = self.rtt.rto();
+        let retransmit = self
+            .in_flight
+            .iter()
+            .flatten()
+            .map(|in_flight| in_flight.deadline(rto))
+            .min();
+        // Queued frames wait for the pacer. If the window is full, they wait for acks instead.
+        let pacer =
+            (!self.queue.is_empty() && self.window_open()).then(|| self.congestion.next_send());
+        retransmit.into_iter().chain(pacer).min()
+    }
+
+    /// Returns the next frame which must be sent: Either a lost frame, a frame which its ack
+    /// has not arrived in time or a queued frame which the congestion controller allows now.
+    pub fn poll_transmit(&mut self) -> Option<&[u8]> {
+        let now = Instant::now();
+        let rto = self.rtt.rto();
+        let expired = self.in_flight.iter().position(|in_flight| {
+            in_flight
+                .as_ref()
+                .is_some_and(|in_flight| in_flight.deadline(rto) <= now)
+        });
+        if let Some(index) = expired {
+            let seq = self.base.wrapping_add(index as u32);
+            let in_flight = self.in_flight[index].as_mut().unwrap();
+            if !in_flight.lost {
+                self.congestion.on_loss(in_flight.sent, true);
+            }
+            in_flight.sent = now;
+            in_flight.retransmits += 1;
+            in_flight.lost = false;
+            self.stats.on_retransmit();
+            log::trace!("Retransmitting frame {}", seq);
+            return Some(Self::encode(&mut self.buffer, seq, &in_flight.frame));
+        }
+        if self.window_open() && self.congestion.next_send() <= now {
+            let frame = self.queue.pop_front()?;
+            return Some(self.send(frame));
+        }
+        None
+    }
+
+    /// Forget the frames which the peer has acknowledged and detect the lost ones
+    pub fn on_ack(&mut self, next: u32, received: u32) {
+        let acknowledged = next.wrapping_sub(self.base) as usize;
+        // Acks which arrive out of order are stale
+        if acknowledged > self.in_flight.len() {
+            return;
+        }
+        let now = Instant::now();
+        let mut count = 0;
+        for in_flight in self.in_flight.drain(..acknowledged).flatten() {
+            Self::acknowledge(
+                &mut self.rtt,
+                &mut self.latest_acknowledged,
+                &in_flight,
+                now,
+            );
+            count += 1;
+        }
+        self.base = next;
+        for bit in 0..u32::BITS as usize {
+            if received & (1 << bit) == 0 {
+                continue;
+            }
+            if let Some(in_flight) = self.in_flight.get_mut(bit + 1).and_then(Option::take) {
+                Self::acknowledge(
+                    &mut self.rtt,
+                    &mut self.latest_acknowledged,
+                    &in_flight,
+                    now,
+                );
+                count += 1;
+            }
+        }
+        self.outstanding -= count;
+        self.congestion.on_ack(count);
+        self.detect_losses();
+        self.stats
+            .set_congestion(self.rtt.rtt(), self.congestion.window());
+    }
+
+    fn acknowledge(
+        rtt: &mut RttEstimator,
+        latest_acknowledged: &mut Option<Instant>,
+        in_flight: &InFlight,
+        now: Instant,
+    ) {
+        // Retransmitted frames are ambiguous for measuring the RTT
+        if in_flight.retransmits == 0 {
+            rtt.on_sample(now.duration_since(in_flight.sent));
+        }
+        *latest_acknowledged = (*latest_acknowledged).max(Some(in_flight.sent));
+    }
+
+    /// Mark the frames which are sent well before an acknowledged frame as lost
+    fn detect_losses(&mut self) {
+        let Some(latest_acknowledged) = self.latest_acknowledged else {
+            return;
+        };
+        let threshold = self
+            .rtt
+            .rtt()
+            .unwrap_or_default()
+            .mul_f64(REORDER_THRESHOLD);
+        for in_flight in self.in_flight.iter_mut().flatten() {
+            if !in_flight.lost && in_flight.sent + threshold < latest_acknowledged {
+                in_flight.lost = true;
+                self.congestion.on_loss(in_flight.sent, false);
+            }
+        }
+    }
+}
+
+/// Puts the frames of a reliable session back in order and acknowledges them
+#[derive(Debug, Default)]
+pub struct ReliableReceiver {
+    /// Sequence number of the next frame which is delivered
+    next: u32,
+    /// Frames from next which are received out of order. Missing frames are None.
+    buffer: VecDeque<Option<Box<[u8]>>>,
+    /// True if a frame is received since the last ack
+    ack_pending: bool,
+}
+
+impl ReliableReceiver {
+    /// Handle a reliable frame. Returns true if it's the next frame and must be delivered now.
+    /// Frames which are received out of order are kept until the frames before them arrive.
+    pub fn accept(&mut self, seq: u32, frame: &[u8]) -> bool {
+        self.ack_pending = true;
+        let offset = seq.wrapping_sub(self.next) as usize;
+        if offset == 0 {
+            self.next = self.next.wrapping_add(1);
+            self.buffer.pop_front();
+            return true;
+        }
+        // Either a duplicate or too far ahead. The peer retransmits the latter.
+        if offset >= REORDER_WINDOW {
+            return false;
+        }
+        if self.buffer.len() <= offset {
+            self.buffer.resize(offset + 1, None);
+        }
+        self.buffer[offset].get_or_insert_with(|| frame.into());
+        false
+    }
+
+    /// Returns the next frame if it's received out of order earlier and must be delivered now
+    pub fn pop(&mut self) -> Option<Box<[u8]>> {
+        let frame = self.buffer.front_mut()?.take()?;
+        self.buffer.pop_front();
+        self.next = self.next.wrapping_add(1);
+        Some(frame)
+    }
+
+    /// The ack which must be sent to the peer. None if nothing is received since the last ack.
+    pub fn ack(&mut self) -> Option<Frame<'static>> {
+        if !std::mem::take(&mut self.ack_pending) {
+            return None;
+        }
+        let received = self
+            .buffer
+            .iter()
+            .skip(1)
+            .take(u32::BITS as usize)
+            .enumerate()
+            .filter(|(_, frame)| frame.is_some())
+            .fold(0, |received, (bit, _)| received | 1 << bit);
+        Some(Frame::Ack {
+            next: self.next,
+            received,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
+
+    fn sender() -> ReliableSender {
+        ReliableSender::new(Arc::new(Notify::new()), Arc::new(SessionStats::default()))
+    }
+
+    /// Sequence number and inner frame of a reliable frame
+    fn decode(packet: &[u8]) -> (u32, Vec<u8>) {
+        match Frame::decode(packet) {
+            Some(Frame::Reliable { seq, frame }) => (seq, frame.to_vec()),
+            frame => panic!("not a reliable frame: {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn receiver_puts_frames_back_in_order() {
+        let mut receiver = ReliableReceiver::default();
+        assert!(!receiver.accept(2, b"c"));
+        assert!(!receiver.accept(1, b"b"));
+        // Duplicates of buffered frames are ignored
+        assert!(!receiver.accept(2, b"x"));
+        assert!(receiver.pop().is_none());
+        assert!(receiver.accept(0, b"a"));
+        assert_eq!(receiver.pop().as_deref(), Some(&b"b"[..]));
+        assert_eq!(receiver.pop().as_deref(), Some(&b"c"[..]));
+        assert!(receiver.pop().is_none());
+        // Delivered frames are duplicates now
+        assert!(!receiver.accept(1, b"b"));
+        assert!(matches!(
+            receiver.ack(),
+            Some(Frame::Ack {
+                next: 3,
+                received: 0
+            })
+        ));
+        // Nothing is received since the last ack
+        assert!(receiver.ack().is_none());
+    }
+
+    #[test]
+    fn receiver_acknowledges_frames_after_a_gap() {
+        let mut receiver = ReliableReceiver::default();
+        assert!(receiver.accept(0, b"a"));
+        assert!(!receiver.accept(2, b"c"));
+        assert!(!receiver.accept(4, b"e"));
+        // Frames beyond the reorder window are dropped and retransmitted by the peer later
+        assert!(!receiver.accept(1 + REORDER_WINDOW as u32, b"z"));
+        // Bit i stands for frame next + 1 + i
+        assert!(matches!(
+            receiver.ack(),
+            Some(Frame::Ack {
+                next: 1,
+                received: 0b101
+            })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sender_retransmits_frames_which_are_lost() {
+        let mut sender = sender();
+        for i in 0..4u8 {
+            let (seq, frame) = decode(sender.push(&[i]).unwrap());
+            assert_eq!((seq, frame), (i as u32, vec![i]));
+            time::advance(Duration::from_millis(50)).await;
+        }
+        assert!(sender.poll_transmit().is_none());
+        // Frame 1 is lost; so the peer acknowledges 0 and selectively acknowledges 2 and 3
+        sender.on_ack(1, 0b11);
+        let (seq, frame) = decode(sender.poll_transmit().unwrap());
+        assert_eq!((seq, frame), (1, vec![1]));
+        assert!(sender.poll_transmit().is_none());
+        sender.on_ack(4, 0);
+        assert!(sender.deadline().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sender_retransmits_frames_which_are_not_acknowledged_in_time() {
+        let mut sender = sender();
+        assert!(sender.push(b"a").is_some());
+        let timeout = sender.deadline().unwrap() - Instant::now();
+        time::advance(timeout / 2).await;
+        assert!(sender.poll_transmit().is_none());
+        time::advance(timeout / 2).await;
+        let (seq, frame) = decode(sender.poll_transmit().unwrap());
+        assert_eq!((seq, frame), (0, b"a".to_vec()));
+        // The timeout is doubled for each retransmission
+        assert_eq!(sender.deadline().unwrap() - Instant::now(), timeout * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sender_queues_frames_when_the_window_is_full() {
+        let mut sender = sender();
+        let window = sender.congestion.window();
+        for i in 0..window {
+            assert!(sender.push(&[i as u8]).is_some());
+        }
+        assert!(sender.push(b"queued").is_none());
+        assert!(sender.poll_transmit().is_none());
+        // The queue is bounded too
+        for _ in 1..MAX_QUEUED_FRAMES {
+            assert!(sender.push(b"more").is_none());
+        }
+        assert!(sender.push(b"dropped").is_none());
+        assert_eq!(sender.queue.len(), MAX_QUEUED_FRAMES);
+        // An ack makes room in the window for the first queued frame
+        time::advance(Duration::from_millis(10)).await;
+        sender.on_ack(1, 0);
+        let (seq, frame) = decode(sender.poll_transmit().unwrap());
+        assert_eq!((seq, frame), (window as u32, b"queued".to_vec()));
+    }
+}
@@ -0,0 +1,583 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use snow::{Builder, HandshakeState, StatelessTransportState};
+
+use crate::{
+    identity::{Identity, PublicKey},
+    messages::{Cookie, UDPMessage},
+    util::{SEALED_TURN_BUFFER_SIZE, TURN_BUFFER_SIZE},
+};
+
+/// Peers know the static key of TURN server and TURN server does not know the peers
+const NOISE_PARAMS: &str = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+/// Channels of this program can't be confused with other Noise channels
+const PROLOGUE: &[u8] = b"p2p-puncher signalling";
+/// Size of the hello of peers: their ephemeral key and the tag of an empty payload
+const HELLO_SIZE: usize = 48;
+/// Size of the answer of TURN server: its ephemeral key and the tag of an empty payload
+const ACCEPT_SIZE: usize = 48;
+/// Size of the authentication tag of each sealed message
+const TAG_SIZE: usize = 16;
+/// TURN server forgets the channels which are not used in this time
+const SLATE_CHANNEL: Duration = Duration::from_secs(60 * 5);
+
+/// A TURN server and the public key which it must prove to have, if it's pinned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Turn {
+    pub address: SocketAddrV4,
+    pub key: Option<PublicKey>,
+}
+
+impl fmt::Display for Turn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.address.fmt(f)
+    }
+}
+
+/// Nonces of the messages which are received over a channel.
+/// Messages can be reordered a bit but a message is never accepted twice.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    /// One more than the largest nonce which is accepted
+    next: u64,
+    /// Bit i is set if nonce next - 1 - i is accepted
+    accepted: u64,
+}
+
+impl ReplayWindow {
+    pub fn is_new(&self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - nonce;
+        age < u64::BITS as u64 && self.accepted & (1 << age) == 0
+    }
+
+    pub fn accept(&mut self, nonce: u64) {
+        if nonce >= self.next {
+            let shift = nonce - self.next + 1;
+            self.accepted = self.accepted.checked_shl(shift as u32).unwrap_or(0) | 1;
+            self.next = nonce + 1;
+        } else {
+            self.accepted |= 1 << (self.next - 1 - nonce);
+        }
+    }
+}
+
+/// Keys of an established channel
+struct Keys {
+    transport: StatelessTransportState,
+    /// Nonce of the next message which is sent
+    next_nonce: u64,
+    received: ReplayWindow,
+}
+
+impl Keys {
+    fn new(handshake: HandshakeState) -> anyhow::Result<Self> {
+        Ok(Self {
+            transport: handshake.into_stateless_transport_mode()?,
+            next_nonce: 0,
+            received: ReplayWindow::default(),
+        })
+    }
+
+    /// Encrypt a message and write the sealed message of it into the buffer
+    fn seal<'b>(&mut self, plain: &[u8], buffer: &'b mut [u8]) -> Option<&'b [u8]> {
+        let mut sealed = [0; SEALED_TURN_BUFFER_SIZE];
+        let nonce = self.next_nonce;
+        let length = self
+            .transport
+            .write_message(nonce, plain, &mut sealed)
+            .ok()?;
+        self.next_nonce += 1;
+        postcard::to_slice(
+            &UDPMessage::Secure {
+                nonce,
+                data: &sealed[..length],
+            },
+            buffer,
+        )
+        .ok()
+        .map(|packet| &*packet)
+    }
+
+    /// Decrypt a message. Returns None if it's forged or replayed.
+    fn open<'b>(&mut self, nonce: u64, data: &[u8], buffer: &'b mut [u8]) -> Option<&'b [u8]> {
+        if !self.received.is_new(nonce) || data.len() < TAG_SIZE || buffer.len() < data.len() {
+            return None;
+        }
+        let length = self.transport.read_message(nonce, data, buffer).ok()?;
+        self.received.accept(nonce);
+        Some(&buffer[..length])
+    }
+}
+
+/// What a packet of TURN server was
+pub enum Received<'b> {
+    /// A message of TURN server
+    Message(UDPMessage<'b>),
+    /// The channel has moved on. The last message must be sent again now.
+    Resend,
+}
+
+enum State {
+    /// The key of TURN server is not pinned. Messages are sent as they are.
+    Plain,
+    /// Waiting for TURN server to accept our hello
+    Pending {
+        handshake: Box<HandshakeState>,
+        hello: [u8; HELLO_SIZE],
+        cookie: Option<Cookie>,
+    },
+    Established(Keys),
+}
+
+/// The signalling channel of a peer with a TURN server. If the key of TURN server is pinned,
+/// the messages are encrypted and forged packets are ignored.
+pub struct TurnChannel {
+    turn: Turn,
+    state: State,
+}
+
+impl TurnChannel {
+    pub fn new(turn: Turn) -> Self {
+        let mut channel = Self {
+            turn,
+            state: State::Plain,
+        };
+        channel.reset();
+        channel
+    }
+
+    pub fn address(&self) -> SocketAddrV4 {
+        self.turn.address
+    }
+
+    /// Start a new handshake. TURN servers forget the channels when they restart.
+    pub fn reset(&mut self) {
+        let Some(key) = self.turn.key else {
+            return;
+        };
+        let mut handshake = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .prologue(PROLOGUE)
+            .remote_public_key(&key.dh_public())
+            .build_initiator()
+            .expect("Noise parameters are valid");
+        let mut hello = [0; HELLO_SIZE];
+        handshake
+            .write_message(&[], &mut hello)
+            .expect("hello fits in its buffer");
+        self.state = State::Pending {
+            handshake: Box::new(handshake),
+            hello,
+            cookie: None,
+        };
+    }
+
+    /// Write the packet of a message for TURN server into the buffer. While the channel is not
+    /// established, this is our hello instead; the message must be sent again on `Received::Resend`.
+    pub fn seal<'b>(&mut self, msg: &UDPMessage<'_>, buffer: &'b mut [u8]) -> &'b [u8] {
+        match &mut self.state {
+            State::Plain => postcard::to_slice(msg, buffer).unwrap(),
+            State::Pending { hello, cookie, .. } => postcard::to_slice(
+                &UDPMessage::SecureHello {
+                    cookie: *cookie,
+                    handshake: hello,
+                },
+                buffer,
+            )
+            .unwrap(),
+            State::Established(keys) => {
+                let mut plain = [0; TURN_BUFFER_SIZE];
+                let plain = postcard::to_slice(msg, &mut plain).unwrap();
+                keys.seal(plain, buffer).unwrap()
+            }
+        }
+    }
+
+    /// Read a packet which is received from TURN server. The message might be decrypted into the buffer.
+    /// Returns None if the packet is not authentic and must be ignored. Invalid packets are errors
+    /// only if the channel is plain.
+    pub fn open<'b>(
+        &mut self,
+        packet: &'b [u8],
+        buffer: &'b mut [u8],
+    ) -> anyhow::Result<Option<Received<'b>>> {
+        let packet = match postcard::from_bytes::<UDPMessage<'_>>(packet) {
+            Ok(packet) => packet,
+            Err(err) if matches!(self.state, State::Plain) => {
+                return Err(anyhow!("got invalid packet from TURN server: {}", err))
+            }
+            Err(_) => return Ok(None),
+        };
+        let received = match (&mut self.state, packet) {
+            (State::Plain, packet) => Some(Received::Message(packet)),
+            (State::Pending { handshake, .. }, UDPMessage::SecureAccept(accept)) => {
+                if handshake.read_message(accept, &mut []).is_err() {
+                    return Ok(None);
+                }
+                let State::Pending { handshake, .. } =
+                    std::mem::replace(&mut self.state, State::Plain)
+                else {
+                    unreachable!()
+                };
+                self.state = State::Established(Keys::new(*handshake)?);
+                log::debug!("Established a secure channel with {}", self.turn);
+                Some(Received::Resend)
+            }
+            // TURN server wants a cookie before it does any work for our hello
+            (State::Pending { cookie, .. }, UDPMessage::Cookie(new_cookie))
+                if *cookie != Some(new_cookie) =>
+            {
+                *cookie = Some(new_cookie);
+                Some(Received::Resend)
+            }
+            (State::Established(keys), UDPMessage::Secure { nonce, data }) => keys
+                .open(nonce, data, buffer)
+                .and_then(|plain| postcard::from_bytes::<UDPMessage<'_>>(plain).ok())
+                .map(Received::Message),
+            (_, packet) => {
+                log::debug!(
+                    "Ignoring unauthenticated packet from {}: {:?}",
+                    self.turn,
+                    packet
+                );
+                None
+            }
+        };
+        Ok(received)
+    }
+}
+
+/// A channel of TURN server with a peer
+struct Channel {
+    /// The hello which has opened the channel. Hellos are sent again if the accept is lost.
+    hello: [u8; HELLO_SIZE],
+    accept: [u8; ACCEPT_SIZE],
+    keys: Keys,
+    last_seen: Instant,
+}
+
+/// The secure channels of TURN server with peers which know its key
+pub struct SecureChannels {
+    private_key: [u8; 32],
+    channels: Mutex<HashMap<SocketAddrV4, Channel>>,
+}
+
+impl SecureChannels {
+    pub fn new(identity: &Identity) -> Self {
+        Self {
+            private_key: identity.dh_private(),
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Open a channel for the hello of a peer and return our answer. A hello which opened the
+    /// current channel of the peer gets the same answer; so lost answers can be sent again.
+    pub fn accept(&self, addr: SocketAddrV4, hello: &[u8]) -> Option<[u8; ACCEPT_SIZE]> {
+        let mut channels = self.channels.lock();
+        if let Some(channel) = channels.get(&addr).filter(|channel| channel.hello == hello) {
+            return Some(channel.accept);
+        }
+        let mut handshake = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .prologue(PROLOGUE)
+            .local_private_key(&self.private_key)
+            .build_responder()
+            .ok()?;
+        handshake.read_message(hello, &mut []).ok()?;
+        let mut accept = [0; ACCEPT_SIZE];
+        handshake.write_message(&[], &mut accept).ok()?;
+        channels.insert(
+            addr,
+            Channel {
+                hello: hello.try_into().ok()?,
+                accept,
+                keys: Keys::new(handshake).ok()?,
+                last_seen: Instant::now(),
+            },
+        );
+        Some(accept)
+    }
+
+    /// True if the peer has a channel. Its plain packets are not trusted.
+    pub fn has_channel(&self, addr: SocketAddrV4) -> bool {
+        self.channels.lock().contains_key(&addr)
+    }
+
+    /// Decrypt a message of the channel of a peer. Returns None if it's forged or replayed.
+    pub fn open<'b>(
+        &self,
+        addr: SocketAddrV4,
+        nonce: u64,
+        data: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Option<&'b [u8]> {
+        let mut channels = self.channels.lock();
+        let channel = channels.get_mut(&addr)?;
+        let plain = channel.keys.open(nonce, data, buffer)?;
+        channel.last_seen = Instant::now();
+        Some(plain)
+    }
+
+    /// Write the sealed packet of a message into the buffer. Returns None if the peer has no channel.
+    pub fn seal<'b>(
+        &self,
+        to: SocketAddrV4,
+        plain: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Option<&'b [u8]> {
+        self.channels.lock().get_mut(&to)?.keys.seal(plain, buffer)
+    }
+
+    /// Forget the channels which are not used for a long time
+    pub fn cleanup(&self) {
+        log::trace!("Cleaning up the secure channels");
+        self.channels
+            .lock()
+            .retain(|_, channel| channel.last_seen.elapsed() < SLATE_CHANNEL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    const PEER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 5000);
+
+    /// A new identity. Its key file is removed right away.
+    fn identity() -> Identity {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "secure-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let identity = Identity::generate(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        identity
+    }
+
+    fn channel(identity: &Identity) -> TurnChannel {
+        TurnChannel::new(Turn {
+            address: SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 100), 3478),
+            key: Some(identity.public_key()),
+        })
+    }
+
+    fn message() -> UDPMessage<'static> {
+        UDPMessage::Server {
+            service_name: "service",
+        }
+    }
+
+    /// The hello which the peer sends instead of its messages
+    fn hello(channel: &mut TurnChannel) -> (Option<Cookie>, [u8; HELLO_SIZE]) {
+        let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+        match postcard::from_bytes(channel.seal(&message(), &mut buffer)).unwrap() {
+            UDPMessage::SecureHello { cookie, handshake } => {
+                (cookie, handshake.try_into().unwrap())
+            }
+            packet => panic!("expected a hello, got {:?}", packet),
+        }
+    }
+
+    /// Give an answer of TURN server to the peer. Returns true if the peer must send again.
+    fn answer(channel: &mut TurnChannel, msg: &UDPMessage<'_>) -> Option<bool> {
+        let mut packet = [0; SEALED_TURN_BUFFER_SIZE];
+        let packet = postcard::to_slice(msg, &mut packet).unwrap();
+        let mut plain = [0; SEALED_TURN_BUFFER_SIZE];
+        channel
+            .open(packet, &mut plain)
+            .unwrap()
+            .map(|received| matches!(received, Received::Resend))
+    }
+
+    fn handshake(channel: &mut TurnChannel, server: &SecureChannels) {
+        let (_, hello) = hello(channel);
+        let accept = server.accept(PEER, &hello).unwrap();
+        assert_eq!(
+            answer(channel, &UDPMessage::SecureAccept(&accept)),
+            Some(true)
+        );
+    }
+
+    /// The nonce and the sealed data of the next message of the peer
+    fn sealed(channel: &mut TurnChannel) -> (u64, Vec<u8>) {
+        let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+        match postcard::from_bytes(channel.seal(&message(), &mut buffer)).unwrap() {
+            UDPMessage::Secure { nonce, data } => (nonce, data.to_vec()),
+            packet => panic!("expected a sealed message, got {:?}", packet),
+        }
+    }
+
+    /// True if TURN server can open a sealed message of the peer
+    fn opens(server: &SecureChannels, (nonce, data): &(u64, Vec<u8>)) -> bool {
+        let mut plain = [0; SEALED_TURN_BUFFER_SIZE];
+        server
+            .open(PEER, *nonce, data, &mut plain)
+            .and_then(|plain| postcard::from_bytes::<UDPMessage<'_>>(plain).ok())
+            .is_some_and(|msg| {
+                matches!(
+                    msg,
+                    UDPMessage::Server {
+                        service_name: "service"
+                    }
+                )
+            })
+    }
+
+    #[test]
+    fn peers_open_channels_with_the_pinned_key() {
+        let identity = identity();
+        let server = SecureChannels::new(&identity);
+        let mut channel = channel(&identity);
+        handshake(&mut channel, &server);
+        assert!(server.has_channel(PEER));
+        assert!(opens(&server, &sealed(&mut channel)));
+        // Answers of TURN server are sealed too
+        let mut plain = [0; TURN_BUFFER_SIZE];
+        let plain = postcard::to_slice(&UDPMessage::KeepAlive, &mut plain).unwrap();
+        let mut packet = [0; SEALED_TURN_BUFFER_SIZE];
+        let packet = server.seal(PEER, plain, &mut packet).unwrap();
+        let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+        assert!(matches!(
+            channel.open(packet, &mut buffer).unwrap(),
+            Some(Received::Message(UDPMessage::KeepAlive))
+        ));
+    }
+
+    #[test]
+    fn lost_accepts_are_sent_again() {
+        let identity = identity();
+        let server = SecureChannels::new(&identity);
+        let mut channel = channel(&identity);
+        let (_, hello) = hello(&mut channel);
+        let accept = server.accept(PEER, &hello).unwrap();
+        assert_eq!(server.accept(PEER, &hello), Some(accept));
+    }
+
+    #[test]
+    fn hellos_for_other_keys_are_rejected() {
+        let server = SecureChannels::new(&identity());
+        let mut channel = channel(&identity());
+        let (_, hello) = hello(&mut channel);
+        assert_eq!(server.accept(PEER, &hello), None);
+        assert!(!server.has_channel(PEER));
+    }
+
+    #[test]
+    fn forged_accepts_are_ignored() {
+        let identity = identity();
+        let mut channel = channel(&identity);
+        hello(&mut channel);
+        assert_eq!(
+            answer(&mut channel, &UDPMessage::SecureAccept(&[7; ACCEPT_SIZE])),
+            None
+        );
+        assert_eq!(answer(&mut channel, &message()), None);
+        // The channel still waits for TURN server
+        let server = SecureChannels::new(&identity);
+        handshake(&mut channel, &server);
+        assert!(opens(&server, &sealed(&mut channel)));
+    }
+
+    #[test]
+    fn tampered_and_forged_packets_are_dropped() {
+        let identity = identity();
+        let server = SecureChannels::new(&identity);
+        let mut channel = channel(&identity);
+        handshake(&mut channel, &server);
+        let (nonce, mut data) = sealed(&mut channel);
+        data[0] ^= 1;
+        assert!(!opens(&server, &(nonce, data.clone())));
+        data[0] ^= 1;
+        assert!(!opens(&server, &(nonce + 1, data.clone())));
+        assert!(!opens(&server, &(nonce, data[..TAG_SIZE - 1].to_vec())));
+        // Failed attempts don't burn the nonce of the real message
+        assert!(opens(&server, &(nonce, data)));
+        // Established channels ignore plain and invalid packets of others
+        assert_eq!(answer(&mut channel, &message()), None);
+        let mut plain = [0; SEALED_TURN_BUFFER_SIZE];
+        assert!(channel.open(&[0xff; 8], &mut plain).unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_packets_are_errors_on_plain_channels() {
+        let mut channel = TurnChannel::new(Turn {
+            address: SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 100), 3478),
+            key: None,
+        });
+        let mut plain = [0; SEALED_TURN_BUFFER_SIZE];
+        assert!(channel.open(&[0xff; 8], &mut plain).is_err());
+        assert_eq!(answer(&mut channel, &message()), Some(false));
+    }
+
+    #[test]
+    fn replayed_packets_are_dropped() {
+        let identity = identity();
+        let server = SecureChannels::new(&identity);
+        let mut channel = channel(&identity);
+        handshake(&mut channel, &server);
+        let first = sealed(&mut channel);
+        let second = sealed(&mut channel);
+        // Reordered messages are accepted once
+        assert!(opens(&server, &second));
+        assert!(opens(&server, &first));
+        assert!(!opens(&server, &first));
+        assert!(!opens(&server, &second));
+    }
+
+    #[test]
+    fn replay_window_accepts_each_nonce_once() {
+        let mut window = ReplayWindow::default();
+        for nonce in [0, 5, 3, 100] {
+            assert!(window.is_new(nonce));
+            window.accept(nonce);
+            assert!(!window.is_new(nonce));
+        }
+        assert!(!window.is_new(5));
+        assert!(window.is_new(99));
+        assert!(window.is_new(100 - 64 + 1));
+        // Nonces which are older than the window can't be told from replays
+        assert!(!window.is_new(100 - 64));
+        assert!(!window.is_new(0));
+    }
+
+    #[test]
+    fn channels_are_opened_again_after_turn_server_restarts() {
+        let identity = identity();
+        let mut channel = channel(&identity);
+        handshake(&mut channel, &SecureChannels::new(&identity));
+        // The restarted server does not know the channel and drops its messages
+        let server = SecureChannels::new(&identity);
+        assert!(!opens(&server, &sealed(&mut channel)));
+        assert!(!server.has_channel(PEER));
+        // The peer does not get answers and starts a new handshake
+        channel.reset();
+        let (cookie, _) = hello(&mut channel);
+        assert_eq!(cookie, None);
+        assert_eq!(answer(&mut channel, &UDPMessage::Cookie(42)), Some(true));
+        let (cookie, hello) = hello(&mut channel);
+        assert_eq!(cookie, Some(42));
+        // The same cookie again means that something else is wrong
+        assert_eq!(answer(&mut channel, &UDPMessage::Cookie(42)), None);
+        let accept = server.accept(PEER, &hello).unwrap();
+        assert_eq!(
+            answer(&mut channel, &UDPMessage::SecureAccept(&accept)),
+            Some(true)
+        );
+        assert!(opens(&server, &sealed(&mut channel)));
+    }
+}
@@ -1,34 +1,284 @@
-use std::{net::{SocketAddr, SocketAddrV4, ToSocketAddrs}, time::Duration};
+use std::{
+    collections::{
+        hash_map::{Entry, RandomState},
+        HashMap,
+    },
+    fmt, future,
+    hash::BuildHasher,
+    io,
+    net::{SocketAddr, SocketAddrV4, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use anyhow::bail;
-use tokio::{net::UdpSocket, select, task, time};
+use anyhow::{anyhow, bail};
+use parking_lot::Mutex;
+use tokio::{
+    net::UdpSocket,
+    select,
+    sync::{self, mpsc, watch},
+    task,
+    time::{self, Instant},
+};
 
 use crate::{
-    messages::{PunchMessage, UDPMessage},
-    util::{die, FORWARD_BUFFER_SIZE, LOCAL_UDP_BIND_ADDRESS, SOCKET_TIMEOUT, TURN_BUFFER_SIZE},
+    arguments::TunnelOptions,
+    bandwidth::{acquire_shared, Bandwidth, SessionBandwidth},
+    fragment::{Fragmenter, Reassembler, DATAGRAM_OFFSET},
+    identity::{self, AuthorizedKeys, Exchange, PublicKey, ResumeSecret},
+    messages::{
+        signed_session, Challenge, Compression, PunchMessage, Resume, SessionOptions, SessionToken,
+        Transport, UDPMessage,
+    },
+    pmtud::{set_dont_fragment, PathMtu},
+    policy::Policy,
+    quic::{self, QuicTunnel},
+    relay,
+    secure::{Received, Turn, TurnChannel},
+    service::Service,
+    shutdown::{Shutdown, Stage},
+    stats::SessionStats,
+    tun::Tun,
+    tunnel::{Frame, Liveness, PathDead, CONTROL_BUFFER_SIZE, FRAME_HEADER_SIZE},
+    util::{
+        die, parse_turn_addresses, FORWARD_BUFFER_SIZE, LOCAL_UDP_BIND_ADDRESS, PUNCH_BUFFER_SIZE,
+        SEALED_TURN_BUFFER_SIZE, SOCKET_TIMEOUT,
+    },
+    vpn::{Lease, Vpn},
 };
 
+use crate::defer::{defer, ScopeCall};
+
 const KEEP_ALIVE_INTERVAL: Duration = time::Duration::from_secs(1);
+/// How often the server registers itself again in TURN server
+const REGISTER_INTERVAL: Duration = time::Duration::from_secs(30);
+/// How long to wait before registering again in a TURN server which has failed
+const REGISTER_RETRY_INTERVAL: Duration = time::Duration::from_secs(5);
+
+/// How long a session waits for its client to resume it after its path is dead
+const RESUME_TIMEOUT: Duration = time::Duration::from_secs(30);
+
+/// A path of a session: The punched socket and the address of client on it
+type NewPath = (UdpSocket, SocketAddrV4);
+
+/// Where the server forwards the packets of its sessions
+#[derive(Clone)]
+pub enum Forward {
+    /// A new UDP socket of each session sends to this address
+    Address(SocketAddr),
+    /// Each session has its own TUN device
+    Vpn(Arc<Vpn>),
+}
+
+impl Forward {
+    /// Forward to the address which is parsed from the argument
+    pub fn address(forward: &str) -> Self {
+        Self::Address(
+            forward
+                .to_socket_addrs()
+                .expect("cannot parse forward address")
+                .next()
+                .expect("cannot parse forward address"),
+        )
+    }
+}
+
+/// The local side of a session, where the packets of its client are forwarded to
+enum Local {
+    /// A UDP socket which is connected to the forward address
+    Socket(UdpSocket),
+    /// The TUN device of a VPN session and the address of its client
+    Tun(Tun, Lease),
+}
+
+impl Local {
+    /// Packets which the TUN device refuses and packets which a VPN client sends from addresses
+    /// other than its own are dropped
+    async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        match self {
+            Local::Socket(socket) => socket.send(packet).await,
+            Local::Tun(_, lease) if !lease.owns(packet) => {
+                log::trace!(
+                    "Dropping a packet which {} sends from another address",
+                    lease.address()
+                );
+                Ok(0)
+            }
+            Local::Tun(tun, _) => match tun.send(packet).await {
+                Err(err) => {
+                    log::debug!("Dropping a packet which {} refuses: {}", tun.name(), err);
+                    Ok(0)
+                }
+                result => result,
+            },
+        }
+    }
+
+    async fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Local::Socket(socket) => socket.recv(buffer).await,
+            Local::Tun(tun, _) => tun.recv(buffer).await,
+        }
+    }
+}
+
+impl fmt::Display for Local {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Local::Socket(socket) => match (socket.local_addr(), socket.peer_addr()) {
+                (Ok(local), Ok(peer)) => write!(f, "{} to {}", local, peer),
+                _ => write!(f, "closed socket"),
+            },
+            Local::Tun(tun, lease) => write!(f, "{} of {}", tun.name(), lease.address()),
+        }
+    }
+}
 
-/// Spawn a webserver which gets incoming connections from TURN server
-pub async fn spawn_server(forward: &str, turn: &str, service: &str) -> ! {
+/// A session which its client can resume over new paths
+struct Session {
+    new_paths: mpsc::Sender<NewPath>,
+    /// The key of the client which has opened the session, if the server authorizes clients
+    key: Option<PublicKey>,
+    /// The secret which the client has derived with us in the handshake
+    secret: ResumeSecret,
+}
+
+/// Sessions of the server which clients can resume over new paths
+struct Sessions {
+    sessions: Mutex<HashMap<SessionToken, Session>>,
+    /// Options of every session of the service
+    options: SessionOptions,
+    bandwidth: Bandwidth,
+    /// Destinations which SOCKS clients of QUIC sessions can reach
+    policy: Arc<Policy>,
+    /// Clients which can open sessions. Every client can if it's not set.
+    authorized: Option<AuthorizedKeys>,
+    /// Tokens are generated by hashing a counter with a random key; so they can't be guessed
+    hasher: RandomState,
+    counter: AtomicU64,
+}
+
+impl Sessions {
+    fn new(
+        options: SessionOptions,
+        bandwidth: Bandwidth,
+        policy: Policy,
+        authorized: Option<AuthorizedKeys>,
+    ) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            options,
+            bandwidth,
+            policy: Arc::new(policy),
+            authorized,
+            hasher: RandomState::new(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Create a new session of the client with the key and the secret which it can resume the session with.
+    /// Returns its token and the receiver of its new paths.
+    fn create(
+        &self,
+        key: Option<PublicKey>,
+        secret: ResumeSecret,
+    ) -> (SessionToken, mpsc::Receiver<NewPath>) {
+        let (sender, receiver) = mpsc::channel(1);
+        let mut sessions = self.sessions.lock();
+        loop {
+            let token = self
+                .hasher
+                .hash_one(self.counter.fetch_add(1, Ordering::Relaxed));
+            if let Entry::Vacant(entry) = sessions.entry(token) {
+                entry.insert(Session {
+                    new_paths: sender,
+                    key,
+                    secret,
+                });
+                return (token, receiver);
+            }
+        }
+    }
+
+    /// Get the sender of new paths of a session if the client with the key has opened it and has
+    /// proved the secret of the session for the path which the nonce is sent on
+    fn get(
+        &self,
+        resume: &Resume,
+        key: Option<PublicKey>,
+        nonce: &Challenge,
+    ) -> Option<mpsc::Sender<NewPath>> {
+        self.sessions
+            .lock()
+            .get(&resume.session)
+            .filter(|session| {
+                session.key == key
+                    && identity::verify_resume(
+                        &session.secret,
+                        resume.session,
+                        nonce,
+                        &resume.proof,
+                    )
+            })
+            .map(|session| session.new_paths.clone())
+    }
+
+    fn remove(&self, token: SessionToken) {
+        self.sessions.lock().remove(&token);
+    }
+}
+
+/// Spawn a webserver which gets incoming connections from TURN server.
+/// Returns when the server is shutting down and no longer accepts clients.
+#[allow(clippy::too_many_arguments)]
+pub async fn spawn_server(
+    forward: Forward,
+    turn: &str,
+    service: &Service,
+    options: &TunnelOptions,
+    session_options: SessionOptions,
+    bandwidth: Bandwidth,
+    policy: Policy,
+    authorized: Option<AuthorizedKeys>,
+    mut shutdown: Shutdown,
+) {
     // Parse socket addresses
-    let forward_address = forward
-        .to_socket_addrs()
-        .expect("cannot parse forward address")
-        .next()
-        .expect("cannot parse forward address");
-    let turn_address = turn
-        .to_socket_addrs()
-        .expect("cannot parse TURN address")
-        .next()
-        .expect("cannot parse TURN address");
-    let turn_address = match turn_address {
-        SocketAddr::V4(v4) => v4,
-        SocketAddr::V6(_) => die("TURN address cannot be IPv6"),
-    };
+    let turn_addresses = parse_turn_addresses(turn);
+    // Sessions are shared between TURN servers; clients may resume their session through any of them
+    let sessions = Arc::new(Sessions::new(
+        session_options,
+        bandwidth,
+        policy,
+        authorized,
+    ));
+    // Register in all TURN servers so losing one of them doesn't break connectivity
+    for turn in turn_addresses {
+        let forward = forward.clone();
+        let service = service.clone();
+        let sessions = sessions.clone();
+        let options = options.clone();
+        let shutdown = shutdown.clone();
+        task::spawn(async move {
+            serve_turn(turn, forward, &service, &sessions, &options, shutdown).await
+        });
+    }
+    shutdown.reached(Stage::Draining).await;
+}
+
+/// Register in a TURN server and accept the clients which it sends to us until shutdown
+async fn serve_turn(
+    turn: Turn,
+    forward: Forward,
+    service: &Service,
+    sessions: &Arc<Sessions>,
+    options: &TunnelOptions,
+    mut shutdown: Shutdown,
+) {
     // In a loop, we must connect to TURN server and advertise ourselves
-    loop {
+    while !shutdown.is_draining() {
         // Spawn a client
         let socket = match UdpSocket::bind(LOCAL_UDP_BIND_ADDRESS).await {
             Ok(socket) => socket,
@@ -36,180 +286,931 @@ pub async fn spawn_server(forward: &str, turn: &str, service: &str) -> ! {
         };
         log::debug!("Started a socket on {}", socket.local_addr().unwrap());
         // Connect to TURN server and get the client address
-        let client_addr = turn_handshake(&socket, &turn_address, service).await;
+        let client_addr = match turn_handshake(&socket, turn, service, &mut shutdown).await {
+            Ok(Some(client_addr)) => client_addr,
+            // Lookup key is rotated or we are shutting down. Register again with a new socket if needed.
+            Ok(None) => continue,
+            // Other TURN servers are still serving. Try this one again later.
+            Err(err) => {
+                log::error!("Cannot register in TURN server {}: {}", turn, err);
+                time::sleep(REGISTER_RETRY_INTERVAL).await;
+                continue;
+            }
+        };
         // Now punch!
+        let forward = forward.clone();
+        let service = service.clone();
+        let sessions = sessions.clone();
+        let options = options.clone();
+        let shutdown = shutdown.clone();
         tokio::task::spawn(async move {
-            if let Err(err) = punch(socket, client_addr, forward_address).await {
+            if let Err(err) = punch(
+                socket,
+                client_addr,
+                forward,
+                service,
+                sessions,
+                options,
+                shutdown,
+            )
+            .await
+            {
                 log::error!("Cannot punch: {}", err);
             }
         });
     }
 }
 
-async fn turn_handshake(socket: &UdpSocket, turn: &SocketAddrV4, service: &str) -> SocketAddrV4 {
-    let mut buf = [0; TURN_BUFFER_SIZE];
-    // Send server hello
-    log::debug!("Sending server hello");
-    let write_buffer = postcard::to_slice(
-        &UDPMessage::Server {
-            service_name: service,
-        },
-        &mut buf,
-    )
-    .unwrap();
-    socket.send_to(&write_buffer, turn).await.unwrap();
-    // Get the answer
-    log::debug!("Waiting for TURN ack");
-    let (read_len, _) = socket.recv_from(&mut buf).await.unwrap();
-    let turn_ack = match postcard::from_bytes::<UDPMessage<'_>>(&buf[..read_len]) {
-        Err(err) => die(format!("Got invalid packet from TURN server: {}", err)),
-        Ok(pkt) => pkt,
+/// Register the socket in TURN server and wait for a client.
+/// Returns None if the lookup key of service is rotated or the server is shutting down
+/// before any client connects; the socket is deregistered from TURN server in both cases.
+/// Returns an error if TURN server does not answer or refuses the registration.
+async fn turn_handshake(
+    socket: &UdpSocket,
+    turn: Turn,
+    service: &Service,
+    shutdown: &mut Shutdown,
+) -> anyhow::Result<Option<SocketAddrV4>> {
+    let mut buf = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut plain = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut write_buf = [0; SEALED_TURN_BUFFER_SIZE];
+    // Each socket is a new peer for TURN server; so it gets its own channel
+    let mut channel = TurnChannel::new(turn);
+    let lookup_key = service.lookup_key();
+    let hello = UDPMessage::Server {
+        service_name: &lookup_key,
     };
-    // Check status
-    if !matches!(turn_ack, UDPMessage::Ok) {
-        die(format!(
-            "Got non successful ack packet from TURN server: {:?}",
-            turn_ack
-        ));
-    }
-    log::info!("Server registered {}", socket.local_addr().unwrap());
+    // Send server hello
+    log::debug!("Sending server hello to {}", turn);
+    socket
+        .send_to(channel.seal(&hello, &mut write_buf), turn.address)
+        .await?;
+    let mut registered = false;
+    // When should TURN server acknowledge our last hello
+    let mut ack_deadline = Some(Instant::now() + SOCKET_TIMEOUT);
     // Keep alive to tell the NAT to keep the state.
-    // This method should never return
-    let keep_alive = async {
-        let keep_alive_buffer =
-            postcard::to_vec::<UDPMessage<'_>, TURN_BUFFER_SIZE>(&UDPMessage::KeepAlive).unwrap();
-        loop {
-            time::sleep(KEEP_ALIVE_INTERVAL).await;
-            log::trace!("Sending keep alive from {}", socket.local_addr().unwrap());
-            if socket.send_to(&keep_alive_buffer, turn).await.is_err() {
-                break;
-            }
+    let mut keep_alive_interval =
+        time::interval_at(Instant::now() + KEEP_ALIVE_INTERVAL, KEEP_ALIVE_INTERVAL);
+    // Registering again refreshes our registration and makes sure that TURN server is still alive
+    let mut register_interval =
+        time::interval_at(Instant::now() + REGISTER_INTERVAL, REGISTER_INTERVAL);
+    // Derived lookup keys are rotated. We must register again after that.
+    let rotation = async {
+        match service.until_rotation() {
+            Some(duration) => time::sleep(duration).await,
+            None => future::pending().await,
         }
     };
+    tokio::pin!(rotation);
     // Wait for punch and poll the keep alive
-    let turn_punch;
-    select! {
-        () = keep_alive => unreachable!(),
-        recv_result = socket.recv_from(&mut buf) => {
-            let (read_len, _) = recv_result.unwrap();
-            turn_punch = match postcard::from_bytes::<UDPMessage<'_>>(&buf[..read_len]) {
-                Err(err) => die(format!("Got invalid packet from TURN server: {}", err)),
-                Ok(pkt) => pkt,
-            };
-        },
-    };
-    // Parse packet
-    if let UDPMessage::Punch(p) = &turn_punch {
-        if let PunchMessage::TURN(other) = &p {
-            log::info!("Client peer is {}", other);
-            return *other;
+    loop {
+        select! {
+            () = &mut rotation => {
+                log::debug!("Lookup key of {} is rotated", service.name());
+                break;
+            },
+            () = shutdown.reached(Stage::Draining) => break,
+            _ = keep_alive_interval.tick() => {
+                log::trace!("Sending keep alive from {}", socket.local_addr().unwrap());
+                socket.send_to(channel.seal(&UDPMessage::KeepAlive, &mut write_buf), turn.address).await?;
+            },
+            _ = register_interval.tick() => {
+                log::trace!("Refreshing registration of {}", socket.local_addr().unwrap());
+                socket.send_to(channel.seal(&hello, &mut write_buf), turn.address).await?;
+                ack_deadline.get_or_insert(Instant::now() + SOCKET_TIMEOUT);
+            },
+            () = time::sleep_until(ack_deadline.unwrap_or_else(Instant::now)), if ack_deadline.is_some() => {
+                bail!("TURN server did not acknowledge the registration");
+            },
+            recv_result = socket.recv_from(&mut buf) => {
+                let (read_len, from) = recv_result?;
+                if from != SocketAddr::V4(turn.address) {
+                    log::debug!("Ignoring packet from {} while waiting for client", from);
+                    continue;
+                }
+                let Some(received) = channel.open(&buf[..read_len], &mut plain)? else {
+                    continue;
+                };
+                let msg = match received {
+                    Received::Message(msg) => msg,
+                    // The secure channel has moved on. Send the hello again over it.
+                    Received::Resend => {
+                        socket.send_to(channel.seal(&hello, &mut write_buf), turn.address).await?;
+                        continue;
+                    }
+                };
+                match msg {
+                    UDPMessage::Ok => {
+                        if !registered {
+                            log::info!("Server registered {} in {}", socket.local_addr().unwrap(), turn);
+                            registered = true;
+                        }
+                        ack_deadline = None;
+                    }
+                    UDPMessage::Punch(PunchMessage::TURN(other)) => {
+                        log::info!("Client peer is {}", other);
+                        return Ok(Some(other));
+                    }
+                    // Something went south
+                    turn_packet => bail!("Got non successful packet from TURN server: {:?}", turn_packet),
+                }
+            },
         }
     }
-    // Something went south
-    die(format!(
-        "Got invalid packet from TURN server while waiting for client: {:?}",
-        turn_punch
-    ));
+    // Clients must not be matched with this socket anymore; neither by the old key nor after shutdown
+    log::info!(
+        "Deregistering {} from {}",
+        socket.local_addr().unwrap(),
+        turn
+    );
+    let goodbye = channel.seal(
+        &UDPMessage::Deregister {
+            service_name: &lookup_key,
+        },
+        &mut write_buf,
+    );
+    socket.send_to(goodbye, turn.address).await?;
+    Ok(None)
 }
 
 async fn punch(
     socket: UdpSocket,
     other_peer: SocketAddrV4,
-    forward_address: SocketAddr,
+    forward: Forward,
+    service: Service,
+    sessions: Arc<Sessions>,
+    options: TunnelOptions,
+    shutdown: Shutdown,
 ) -> anyhow::Result<()> {
-    let mut punch_buffer = [0; 4]; // very very small buffer. The packet is 2 bytes only
+    let mut punch_buffer = [0; PUNCH_BUFFER_SIZE];
     log::info!(
         "Punching {} from {}",
         other_peer,
         socket.local_addr().unwrap()
     );
-    // Step 1: Punch the NAT
-    let to_write_punch_buffer = postcard::to_slice(
-        &UDPMessage::Punch {
-            0: PunchMessage::PeerHandshake1,
-        },
-        &mut punch_buffer,
-    )
+    if let Err(err) = set_dont_fragment(&socket) {
+        log::warn!("Cannot set the don't fragment bit of socket: {}", err);
+    }
+    // Step 1: Punch the NAT. Clients sign the nonce to prove their identity.
+    let nonce = identity::challenge();
+    let handshake1 = postcard::to_stdvec(&UDPMessage::Punch(PunchMessage::PeerHandshake1 {
+        nonce: Some(nonce),
+    }))
     .unwrap();
-    socket.send_to(to_write_punch_buffer, other_peer).await?;
-    // Step 2: Wait for client to send something back
+    socket.send_to(&handshake1, other_peer).await?;
+    // Step 2: Wait for client to send something back. Nothing is allocated for the session
+    // before the client has answered from the punched address.
     log::debug!("Waiting for client step 2 handshake");
-    let (packet_length, _) = socket.recv_from(&mut punch_buffer).await?;
+    let deadline = Instant::now() + SOCKET_TIMEOUT;
+    let packet_length = loop {
+        let (length, from) = time::timeout_at(deadline, socket.recv_from(&mut punch_buffer))
+            .await
+            .map_err(|_| anyhow!("client did not answer the punch"))??;
+        if from != SocketAddr::V4(other_peer) {
+            log::debug!("Ignoring a punch of {} which is not the client", from);
+            continue;
+        }
+        // Our first packet might not have gone through the NAT of the client
+        if let Ok(UDPMessage::Punch(PunchMessage::PeerHandshake1 { .. })) =
+            postcard::from_bytes(&punch_buffer[..length])
+        {
+            log::trace!("Sending the nonce to {} again", other_peer);
+            socket.send_to(&handshake1, other_peer).await?;
+            continue;
+        }
+        break length;
+    };
     let client_punch = postcard::from_bytes::<UDPMessage<'_>>(&punch_buffer[..packet_length])?;
-    if !matches!(client_punch, UDPMessage::Punch(PunchMessage::PeerHandshake2)) {
+    let UDPMessage::Punch(PunchMessage::PeerHandshake2 {
+        resume,
+        exchange,
+        compression,
+        identity,
+        challenge,
+    }) = client_punch
+    else {
         bail!(
             "Invalid packet received from client peer: {:?}",
             client_punch
         );
+    };
+    // Only authorized clients can open or resume sessions
+    let mut client_key = None;
+    if let Some(authorized) = &sessions.authorized {
+        let verified = match &identity {
+            Some(identity) => authorized.verify(service.name(), &nonce, identity),
+            None => Err(anyhow!("client has no identity")),
+        };
+        match verified {
+            Ok((key, name)) => {
+                log::info!("{} is authorized as {}", other_peer, name);
+                client_key = Some(key);
+            }
+            Err(err) => {
+                let to_write_punch_buffer = postcard::to_slice(
+                    &UDPMessage::Punch(PunchMessage::Unauthorized),
+                    &mut punch_buffer,
+                )
+                .unwrap();
+                socket.send_to(to_write_punch_buffer, other_peer).await?;
+                bail!("{} is refused: {}", other_peer, err);
+            }
+        }
     }
+    // Check if client wants to move an existing session to this path.
+    // Only the client which has opened a session and holds its secret can resume it.
+    let resumed = resume
+        .as_ref()
+        .and_then(|resume| Some((resume.session, sessions.get(resume, client_key, &nonce)?)));
+    if resume.is_some() && resumed.is_none() {
+        log::warn!(
+            "{} wants to resume an unknown session or the session of another client. Starting a new one.",
+            other_peer
+        );
+    }
+    // New sessions derive a secret with the client which only the client can resume them with
+    let new_secret = match &resumed {
+        Some(_) => None,
+        None => {
+            let ours = Exchange::generate();
+            let secret = ours
+                .resume_secret(&exchange)
+                .ok_or_else(|| anyhow!("{} has sent an invalid exchange key", other_peer))?;
+            Some((ours.public_key(), secret))
+        }
+    };
+    let server_exchange = new_secret.map(|(key, _)| key);
+    // New sessions need their local side before the client is told about them
+    let (local, vpn) = match (&resumed, &forward) {
+        (Some(_), _) => (None, None),
+        (None, Forward::Address(address)) => {
+            let local_socket = UdpSocket::bind(LOCAL_UDP_BIND_ADDRESS).await?;
+            local_socket.connect(address).await?;
+            (Some(Local::Socket(local_socket)), None)
+        }
+        (None, Forward::Vpn(vpn)) => {
+            let (lease, config) = vpn.lease()?;
+            let tun = vpn.open(&lease).await?;
+            log::info!(
+                "VPN address of {} is {} on {}",
+                other_peer,
+                config.address,
+                tun.name()
+            );
+            (Some(Local::Tun(tun, lease)), Some(config))
+        }
+    };
+    let (token, new_paths) = match (&resumed, new_secret) {
+        (Some((token, _)), _) => (*token, None),
+        (None, Some((_, secret))) => {
+            let (token, new_paths) = sessions.create(client_key, secret);
+            (token, Some(new_paths))
+        }
+        (None, None) => unreachable!("new sessions always have a secret"),
+    };
+    // The session is compressed only if the client accepts the compression of the service
+    let session_options = SessionOptions {
+        compression: if compression == sessions.options.compression {
+            compression
+        } else {
+            Compression::None
+        },
+        ..sessions.options
+    };
+    // Clients of a new QUIC session accept only the certificate which we announce here
+    let certificate = match (&resumed, session_options.transport) {
+        (None, Transport::Quic) => Some(quic::Certificate::generate()?),
+        _ => None,
+    };
+    let certificate_hash = certificate.as_ref().map(quic::Certificate::hash);
+    // Prove our identity if client has asked for it
+    let proof = challenge
+        .zip(service.identity())
+        .map(|(challenge, identity)| {
+            Box::new(identity.prove_server(
+                service.name(),
+                &challenge,
+                &signed_session(
+                    token,
+                    &session_options,
+                    &vpn,
+                    &certificate_hash,
+                    (&exchange, &server_exchange),
+                ),
+            ))
+        });
     // Send back a packet (handshake step 3)
     log::debug!("Sending handshake step 3");
     let to_write_punch_buffer = postcard::to_slice(
-        &UDPMessage::Punch {
-            0: PunchMessage::PeerHandshake3,
-        },
+        &UDPMessage::Punch(PunchMessage::PeerHandshake3 {
+            session: token,
+            options: session_options,
+            vpn,
+            certificate: certificate_hash,
+            exchange: server_exchange,
+            proof,
+        }),
         &mut punch_buffer,
     )
     .unwrap();
     socket.send_to(to_write_punch_buffer, other_peer).await?;
-    // Now dial the destination
-    let local_socket = UdpSocket::bind(LOCAL_UDP_BIND_ADDRESS).await?;
+    // Hand the path to the session if it's resumed
+    if let Some((_, session)) = resumed {
+        log::info!("Resuming a session over the path to {}", other_peer);
+        return session
+            .send((socket, other_peer))
+            .await
+            .map_err(|_| anyhow!("session is closed before it could be resumed"));
+    }
     // Now proxy data
+    let local = local.unwrap();
+    let bandwidth = sessions.bandwidth.session();
     task::spawn(async move {
-        if let Err(err) = forward_udp(socket, local_socket, other_peer, forward_address).await {
+        let forwarded = match session_options.transport {
+            Transport::Datagram => {
+                forward_udp(
+                    (socket, other_peer),
+                    local,
+                    new_paths.unwrap(),
+                    session_options,
+                    bandwidth,
+                    options,
+                    shutdown,
+                )
+                .await
+            }
+            Transport::Quic => {
+                forward_quic(
+                    (socket, other_peer),
+                    certificate.unwrap(),
+                    local,
+                    bandwidth,
+                    sessions.policy.clone(),
+                    options,
+                    shutdown,
+                )
+                .await
+            }
+        };
+        if let Err(err) = forwarded {
             log::error!("Cannot forward: {}", err);
         }
+        sessions.remove(token);
     });
     // Done
-    return Ok(());
+    Ok(())
 }
 
-/// Copy UDP diagrams from one socket to another bidirectionally and a timeout
+/// Copy UDP diagrams from one socket to another bidirectionally and a timeout.
+/// Each direction is pumped by its own task; so packets are never lost because of the other
+/// direction and a slow direction never blocks the other one.
+/// The remote peer is told when the session is closed.
+/// If the path to the remote peer dies, the session waits for the client to resume it over a new path.
+#[allow(clippy::too_many_arguments)]
 async fn forward_udp(
-    remote_socket: tokio::net::UdpSocket,
-    local_socket: tokio::net::UdpSocket,
-    remote_address: SocketAddrV4,
-    local_address: SocketAddr,
+    (remote_socket, mut remote_address): NewPath,
+    local: Local,
+    mut new_paths: mpsc::Receiver<NewPath>,
+    session_options: SessionOptions,
+    bandwidth: SessionBandwidth,
+    options: TunnelOptions,
+    mut shutdown: Shutdown,
 ) -> anyhow::Result<()> {
     log::info!(
-        "Proxying from {} to {} and {} to {}",
+        "Proxying from {} to {} and {}",
         remote_socket.local_addr().unwrap(),
         remote_address,
-        local_socket.local_addr().unwrap(),
-        local_address
+        local
     );
-    // Connect to hosts from each socket
+    // Connect to the client from the punched socket
     remote_socket.connect(remote_address).await?;
-    local_socket.connect(local_address).await?;
-    // Wait for either sockets to get something
-    let mut buffer1 = [0; FORWARD_BUFFER_SIZE];
-    let mut buffer2 = [0; FORWARD_BUFFER_SIZE];
-    // In a loop, get the packets
-    /*
-     * BUG:
-     * However, here we can encounter a bug. What if two handlers of remote and local
-     * socket fire up at the same time? I really don't know what happens. I spoke to some
-     * of my friends and no one had any idea.
-     * I speculate that because the task is canceled, the buffer will be lost. So a packet loss
-     * is going to happen. But this does not matter because this is a UDP socket. And packet
-     * losses happen in UDP.
-     * I could have fixed it by going into the effort to spawn two tasks, one for each socket.
-     * Then continuously poll the sockets and send the result in a channel. The data in the
-     * channel won't get lost.
-     */
+    let local = Arc::new(local);
+    // The pumps always use the socket of current path
+    let (path_sender, path) = watch::channel(Arc::new(remote_socket));
+    let (events_sender, mut events) = mpsc::unbounded_channel();
+    let last_data = Arc::new(Mutex::new(Instant::now()));
+    let mut path_mtu = PathMtu::default();
+    let stats = Arc::new(SessionStats::default());
+    let client_address = remote_address;
+    defer!(log::info!(
+        "Session of {} is over: {}",
+        client_address,
+        stats
+    ));
+    // Both the local pump and the session use the fragmenter; the session changes its frame size
+    // and retransmits the lost frames of reliable sessions
+    let fragmenter = Arc::new(sync::Mutex::new(Fragmenter::new(
+        path_mtu.max_frame_size(),
+        session_options,
+        stats.clone(),
+    )));
+    let timer = fragmenter.lock().await.timer();
+    let mut remote_pump = task::spawn(pump_remote(
+        path.clone(),
+        local.clone(),
+        Reassembler::new(session_options, stats.clone()),
+        fragmenter.clone(),
+        events_sender,
+        last_data.clone(),
+    ));
+    let mut local_pump = task::spawn(pump_local(
+        local.clone(),
+        path,
+        fragmenter.clone(),
+        bandwidth,
+        last_data.clone(),
+    ));
+    // Pumps must not outlive the session
+    let (remote_pump_handle, local_pump_handle) =
+        (remote_pump.abort_handle(), local_pump.abort_handle());
+    defer!({
+        remote_pump_handle.abort();
+        local_pump_handle.abort();
+    });
+    let mut control_buffer = [0; CONTROL_BUFFER_SIZE];
+    let mut liveness = Liveness::new(options.keep_alive_interval());
+    // When the path is dead, this is when we give up waiting for the client to resume the session
+    let mut resume_deadline: Option<Instant> = None;
     loop {
+        let remote_socket = path_sender.borrow().clone();
+        let liveness_deadline = resume_deadline.unwrap_or_else(|| liveness.deadline());
+        let idle_deadline = *last_data.lock() + options.idle_timeout();
+        let fragmenter_deadline = fragmenter.lock().await.deadline();
         select! {
-            () = time::sleep(SOCKET_TIMEOUT) => {
-                log::info!("Sockets {} and {} timed out", local_address, remote_address);
+            () = time::sleep_until(idle_deadline) => {
+                // Pumps might have moved data meanwhile
+                if last_data.lock().elapsed() < options.idle_timeout() {
+                    continue;
+                }
+                log::info!("Session of {} and {} timed out", remote_address, local);
+                let _ = remote_socket.send(Frame::Close.encode(&mut control_buffer)).await;
                 anyhow::bail!("timeout");
             }
-            read = remote_socket.recv(&mut buffer1) => {
-                local_socket.send(&buffer1[..read?]).await?;
-            },
-            read = local_socket.recv(&mut buffer2) => {
-                remote_socket.send(&buffer2[..read?]).await?;
-            },
+            () = time::sleep_until(liveness_deadline) => {
+                if resume_deadline.is_some() {
+                    anyhow::bail!("path is dead and client did not resume the session");
+                }
+                match liveness.on_timer() {
+                    Ok(heartbeat) => {
+                        let _ = remote_socket.send(heartbeat.encode(&mut control_buffer)).await;
+                    }
+                    Err(PathDead) => {
+                        log::warn!("Path to {} is dead (last RTT {:?}). Waiting for the client to resume the session.", remote_address, liveness.rtt());
+                        resume_deadline = Some(Instant::now() + RESUME_TIMEOUT);
+                    }
+                }
+            }
+            () = time::sleep_until(path_mtu.deadline()), if resume_deadline.is_none() => {
+                if let Some(mtu) = path_mtu.on_timer(&remote_socket).await {
+                    log::info!("Path MTU to {} is {} bytes", remote_address, mtu);
+                    fragmenter.lock().await.set_max_frame_size(mtu);
+                }
+            }
+            () = time::sleep_until(fragmenter_deadline.unwrap_or(idle_deadline)), if fragmenter_deadline.is_some() => {
+                if let Err(err) = fragmenter.lock().await.on_timer(&remote_socket).await {
+                    log::debug!("Cannot send to {}: {}", remote_address, err);
+                }
+            }
+            // The deadline of the fragmenter has moved earlier
+            () = timer.notified() => {}
+            Some(event) = events.recv() => {
+                match event {
+                    PathEvent::Heartbeat => {}
+                    PathEvent::HeartbeatAck(id) => liveness.on_ack(id),
+                    PathEvent::ProbeAck { id, size } => {
+                        if let Some(mtu) = path_mtu.on_ack(id, size) {
+                            log::info!("Path MTU to {} is {} bytes", remote_address, mtu);
+                            fragmenter.lock().await.set_max_frame_size(mtu);
+                        }
+                    }
+                }
+                // The old path has come back to life
+                if resume_deadline.take().is_some() {
+                    log::info!("Path to {} is alive again", remote_address);
+                    liveness = Liveness::new(options.keep_alive_interval());
+                }
+            }
+            Some((socket, address)) = new_paths.recv() => {
+                socket.connect(address).await?;
+                log::info!("Session of {} is moved to {} on {}", remote_address, address, socket.local_addr().unwrap());
+                path_sender.send_replace(Arc::new(socket));
+                remote_address = address;
+                liveness = Liveness::new(options.keep_alive_interval());
+                resume_deadline = None;
+                // The new path might have a different MTU
+                path_mtu = PathMtu::default();
+                fragmenter.lock().await.set_max_frame_size(path_mtu.max_frame_size());
+            }
+            () = shutdown.reached(Stage::Closing) => {
+                log::info!("Closing the session of {}", remote_address);
+                remote_socket.send(Frame::Close.encode(&mut control_buffer)).await?;
+                return Ok(());
+            }
+            result = &mut remote_pump => {
+                result??;
+                log::info!("{} closed the session", remote_address);
+                return Ok(());
+            }
+            result = &mut local_pump => return result?,
         }
-        task::yield_now().await;
+    }
+}
+
+/// Accept the QUIC connection of the client on the punched socket and copy the datagrams
+/// between it and the local side. The streams of SOCKS clients are served as the policy allows.
+/// QUIC detects dead paths itself; so these sessions are never resumed.
+async fn forward_quic(
+    (remote_socket, remote_address): NewPath,
+    certificate: quic::Certificate,
+    local: Local,
+    bandwidth: SessionBandwidth,
+    policy: Arc<Policy>,
+    options: TunnelOptions,
+    mut shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let endpoint = quic::server_endpoint(remote_socket.into_std()?, certificate, &options)?;
+    let incoming = time::timeout(SOCKET_TIMEOUT, endpoint.accept())
+        .await
+        .map_err(|_| anyhow!("client did not connect over QUIC"))?
+        .ok_or_else(|| anyhow!("QUIC endpoint is closed"))?;
+    let connection = incoming.await?;
+    log::info!(
+        "Proxying QUIC connection of {} and {}",
+        remote_address,
+        local
+    );
+    let local = Arc::new(local);
+    let stats = Arc::new(SessionStats::default());
+    defer!(log::info!(
+        "Session of {} is over: {}",
+        remote_address,
+        stats
+    ));
+    let tunnel = QuicTunnel::new(connection, stats.clone());
+    let last_data = Arc::new(Mutex::new(Instant::now()));
+    // Datagrams and the streams of SOCKS clients share the limits of the session
+    let bandwidth = Arc::new(Mutex::new(bandwidth));
+    let mut remote_pump = task::spawn(pump_quic_remote(
+        tunnel.clone(),
+        local.clone(),
+        last_data.clone(),
+    ));
+    let mut local_pump = task::spawn(pump_quic_local(
+        local,
+        tunnel.clone(),
+        bandwidth.clone(),
+        last_data.clone(),
+    ));
+    let streams = Arc::new(AtomicUsize::new(0));
+    let stream_pump = task::spawn(relay::serve_streams(
+        tunnel.clone(),
+        policy,
+        bandwidth,
+        streams.clone(),
+        last_data.clone(),
+    ));
+    // Pumps must not outlive the session
+    let (remote_pump_handle, local_pump_handle) =
+        (remote_pump.abort_handle(), local_pump.abort_handle());
+    defer!({
+        remote_pump_handle.abort();
+        local_pump_handle.abort();
+        stream_pump.abort();
+    });
+    let result = loop {
+        let idle_deadline = *last_data.lock() + options.idle_timeout();
+        select! {
+            () = time::sleep_until(idle_deadline) => {
+                // Pumps might have moved data meanwhile and open streams are never idle
+                if last_data.lock().elapsed() < options.idle_timeout()
+                    || streams.load(Ordering::Relaxed) > 0
+                {
+                    continue;
+                }
+                log::info!("QUIC session of {} timed out", remote_address);
+                break Err(anyhow!("timeout"));
+            }
+            () = shutdown.reached(Stage::Closing) => {
+                log::info!("Closing the session of {}", remote_address);
+                break Ok(());
+            }
+            result = &mut remote_pump => {
+                result??;
+                log::info!("{} closed the session", remote_address);
+                break Ok(());
+            }
+            result = &mut local_pump => break result?,
+        }
+    };
+    // Tell the client and wait until it knows
+    tunnel.connection().close(0u32.into(), b"");
+    endpoint.wait_idle().await;
+    result
+}
+
+/// Receive the datagrams of the QUIC connection and forward them to the local side.
+/// Returns Ok when the client closes the connection.
+async fn pump_quic_remote(
+    tunnel: QuicTunnel,
+    local: Arc<Local>,
+    last_data: Arc<Mutex<Instant>>,
+) -> anyhow::Result<()> {
+    loop {
+        let datagram = match tunnel.receive().await {
+            Ok(datagram) => datagram,
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        local.send(&datagram).await?;
+        *last_data.lock() = Instant::now();
+    }
+}
+
+/// Read packets from the local side and send them over the QUIC connection
+async fn pump_quic_local(
+    local: Arc<Local>,
+    tunnel: QuicTunnel,
+    bandwidth: Arc<Mutex<SessionBandwidth>>,
+    last_data: Arc<Mutex<Instant>>,
+) -> anyhow::Result<()> {
+    let mut buffer = [0; FORWARD_BUFFER_SIZE];
+    loop {
+        let read = local.recv(&mut buffer).await?;
+        if !acquire_shared(&bandwidth, read).await {
+            log::trace!("Dropping a datagram over the bandwidth limits");
+            continue;
+        }
+        tunnel.send(&buffer[..read]).await?;
+        *last_data.lock() = Instant::now();
+    }
+}
+
+/// Events of the path which the remote pump reports to its session
+#[derive(Debug, Clone, Copy)]
+enum PathEvent {
+    /// The remote peer has sent a heartbeat; so the path works
+    Heartbeat,
+    /// The remote peer has answered one of our heartbeats
+    HeartbeatAck(u32),
+    /// The remote peer has answered one of our path MTU probes
+    ProbeAck { id: u32, size: u16 },
+}
+
+/// Receive frames from the remote peer over the current path and forward their data to the local side.
+/// Returns when the remote peer closes the session.
+async fn pump_remote(
+    mut path: watch::Receiver<Arc<UdpSocket>>,
+    local: Arc<Local>,
+    mut reassembler: Reassembler,
+    fragmenter: Arc<sync::Mutex<Fragmenter>>,
+    events: mpsc::UnboundedSender<PathEvent>,
+    last_data: Arc<Mutex<Instant>>,
+) -> anyhow::Result<()> {
+    let mut buffer = [0; FRAME_HEADER_SIZE + FORWARD_BUFFER_SIZE];
+    let mut control_buffer = [0; CONTROL_BUFFER_SIZE];
+    let mut remote_socket = path.borrow_and_update().clone();
+    loop {
+        // recv is cancel safe; so changing the path never loses a packet
+        let read = select! {
+            read = remote_socket.recv(&mut buffer) => read,
+            changed = path.changed() => {
+                changed?;
+                remote_socket = path.borrow_and_update().clone();
+                continue;
+            }
+        };
+        // Errors of the path (such as ICMP unreachable) are not fatal. Liveness decides if the path is dead.
+        let read = match read {
+            Ok(read) => read,
+            Err(err) => {
+                log::debug!("Cannot read from {:?}: {}", remote_socket.peer_addr(), err);
+                continue;
+            }
+        };
+        match Frame::decode(&buffer[..read]) {
+            Some(
+                frame @ (Frame::Data(_)
+                | Frame::Fragment { .. }
+                | Frame::Fec { .. }
+                | Frame::FecParity { .. }
+                | Frame::Reliable { .. }),
+            ) => {
+                if let Some(data) = reassembler.receive(frame) {
+                    local.send(data).await?;
+                    *last_data.lock() = Instant::now();
+                }
+                // The frame might release the frames after it even if it yields no datagram itself
+                while let Some(data) = reassembler.pop() {
+                    local.send(data).await?;
+                    *last_data.lock() = Instant::now();
+                }
+                if let Some(ack) = reassembler.ack() {
+                    let _ = remote_socket.send(ack.encode(&mut control_buffer)).await;
+                }
+            }
+            Some(Frame::Ack { next, received }) => {
+                let mut fragmenter = fragmenter.lock().await;
+                if let Err(err) = fragmenter.on_ack(&remote_socket, next, received).await {
+                    log::debug!("Cannot send to {:?}: {}", remote_socket.peer_addr(), err);
+                }
+            }
+            Some(Frame::Close) => return Ok(()),
+            Some(Frame::Heartbeat(id)) => {
+                let _ = remote_socket
+                    .send(Frame::HeartbeatAck(id).encode(&mut control_buffer))
+                    .await;
+                let _ = events.send(PathEvent::Heartbeat);
+            }
+            Some(Frame::HeartbeatAck(id)) => {
+                let _ = events.send(PathEvent::HeartbeatAck(id));
+            }
+            Some(Frame::Probe { id, size }) => {
+                let _ = remote_socket
+                    .send(
+                        Frame::ProbeAck {
+                            id,
+                            size: size as u16,
+                        }
+                        .encode(&mut control_buffer),
+                    )
+                    .await;
+            }
+            Some(Frame::ProbeAck { id, size }) => {
+                let _ = events.send(PathEvent::ProbeAck { id, size });
+            }
+            None => log::trace!(
+                "Dropping invalid frame from {:?}",
+                remote_socket.peer_addr()
+            ),
+        }
+    }
+}
+
+/// Read packets from the local side and send them to the remote peer over the current path.
+/// Packets which are larger than the maximum frame size of the path are fragmented.
+/// Packets over the bandwidth limits are dropped or delayed as the limits say.
+async fn pump_local(
+    local: Arc<Local>,
+    path: watch::Receiver<Arc<UdpSocket>>,
+    fragmenter: Arc<sync::Mutex<Fragmenter>>,
+    mut bandwidth: SessionBandwidth,
+    last_data: Arc<Mutex<Instant>>,
+) -> anyhow::Result<()> {
+    let mut buffer = [0; DATAGRAM_OFFSET + FORWARD_BUFFER_SIZE];
+    loop {
+        let read = local.recv(&mut buffer[DATAGRAM_OFFSET..]).await?;
+        if !bandwidth.acquire(read).await {
+            log::trace!("Dropping a datagram over the bandwidth limits");
+            continue;
+        }
+        let remote_socket = path.borrow().clone();
+        let sent = fragmenter
+            .lock()
+            .await
+            .send(&remote_socket, &mut buffer, read)
+            .await;
+        if let Err(err) = sent {
+            log::debug!("Cannot send to {:?}: {}", remote_socket.peer_addr(), err);
+        }
+        *last_data.lock() = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{shutdown, tunnel::data_frame};
+
+    /// Number of packets which are sent in each direction
+    const PACKETS: usize = 2000;
+
+    #[test]
+    fn sessions_are_resumed_only_with_their_secret() {
+        let sessions = Sessions::new(
+            SessionOptions::default(),
+            Bandwidth::new(Default::default()),
+            Policy::new(Vec::new(), Vec::new()),
+            None,
+        );
+        let secret = Exchange::generate()
+            .resume_secret(&Exchange::generate().public_key())
+            .unwrap();
+        let (session, _new_paths) = sessions.create(None, secret);
+        let nonce = identity::challenge();
+        let resume = |proof| Resume { session, proof };
+        // The token alone is not enough
+        assert!(sessions.get(&resume([0; 32]), None, &nonce).is_none());
+        // Neither is a proof of another path or another secret
+        let other_path = identity::prove_resume(&secret, session, &identity::challenge());
+        assert!(sessions.get(&resume(other_path), None, &nonce).is_none());
+        let other_secret = identity::prove_resume(&[1; 32], session, &nonce);
+        assert!(sessions.get(&resume(other_secret), None, &nonce).is_none());
+        let proof = identity::prove_resume(&secret, session, &nonce);
+        assert!(sessions.get(&resume(proof), None, &nonce).is_some());
+        // Other sessions can't be resumed with the proof
+        let other = Resume {
+            session: session.wrapping_add(1),
+            proof,
+        };
+        assert!(sessions.get(&other, None, &nonce).is_none());
+    }
+
+    /// Send numbered packets on the socket. If framed is true, they are sent as data frames.
+    async fn send_packets(socket: Arc<UdpSocket>, framed: bool) {
+        let mut buffer = [0; FRAME_HEADER_SIZE + 8];
+        for i in 0..PACKETS {
+            buffer[FRAME_HEADER_SIZE..].copy_from_slice(&(i as u64).to_be_bytes());
+            let packet = if framed {
+                data_frame(&mut buffer, 8)
+            } else {
+                &buffer[FRAME_HEADER_SIZE..]
+            };
+            socket.send(packet).await.unwrap();
+            // Don't overflow the receive buffers of the sockets
+            if i % 64 == 0 {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+    }
+
+    /// Receive the numbered packets and return how many of them are received
+    async fn receive_packets(socket: Arc<UdpSocket>, framed: bool) -> usize {
+        let mut buffer = [0; FRAME_HEADER_SIZE + FORWARD_BUFFER_SIZE];
+        let mut received = 0;
+        while let Ok(read) = time::timeout(Duration::from_secs(1), socket.recv(&mut buffer)).await {
+            let packet = &buffer[..read.unwrap()];
+            let packet = if framed {
+                match Frame::decode(packet) {
+                    Some(Frame::Data(data)) => data,
+                    _ => continue,
+                }
+            } else {
+                packet
+            };
+            assert_eq!(packet, (received as u64).to_be_bytes());
+            received += 1;
+            if received == PACKETS {
+                break;
+            }
+        }
+        received
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn forward_udp_does_not_lose_packets() {
+        let bind = || UdpSocket::bind("127.0.0.1:0");
+        // The client peer and the application which the server forwards to
+        let (peer, application) = (bind().await.unwrap(), bind().await.unwrap());
+        let (remote_socket, local_socket) = (bind().await.unwrap(), bind().await.unwrap());
+        let SocketAddr::V4(peer_address) = peer.local_addr().unwrap() else {
+            unreachable!()
+        };
+        peer.connect(remote_socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        application
+            .connect(local_socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        local_socket
+            .connect(application.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (peer, application) = (Arc::new(peer), Arc::new(application));
+        // The shutdown controller is never polled; so the session is never closed by it
+        let (shutdown, _controller) = shutdown::listen(Duration::from_secs(1));
+        let (_new_paths, new_paths) = mpsc::channel(1);
+        let options = TunnelOptions {
+            keep_alive: 5,
+            idle_timeout: 60,
+        };
+        let forwarder = task::spawn(forward_udp(
+            (remote_socket, peer_address),
+            Local::Socket(local_socket),
+            new_paths,
+            SessionOptions::default(),
+            Bandwidth::new(Default::default()).session(),
+            options,
+            shutdown,
+        ));
+        // Push traffic in both directions at the same time
+        let uplink = task::spawn(receive_packets(application.clone(), false));
+        let downlink = task::spawn(receive_packets(peer.clone(), true));
+        tokio::join!(
+            send_packets(peer.clone(), true),
+            send_packets(application.clone(), false)
+        );
+        assert_eq!(uplink.await.unwrap(), PACKETS);
+        assert_eq!(downlink.await.unwrap(), PACKETS);
+        // Closing the session from the peer stops the forwarder
+        let mut control_buffer = [0; CONTROL_BUFFER_SIZE];
+        peer.send(Frame::Close.encode(&mut control_buffer))
+            .await
+            .unwrap();
+        time::timeout(Duration::from_secs(1), forwarder)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
     }
 }
@@ -0,0 +1,158 @@
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::identity::{Identity, PublicKey};
+
+/// Number of bytes of HMAC which is used as the lookup key
+const HASHED_KEY_LENGTH: usize = 16;
+
+/// The name of a service which servers register and clients look up in TURN server
+#[derive(Debug, Clone)]
+pub struct Service {
+    name: String,
+    /// If set, the lookup key is derived from this secret and the name is never sent to TURN server
+    secret: Option<String>,
+    /// Length of each epoch of derived keys
+    epoch: Duration,
+    /// The identity which this peer proves in the punch handshake
+    identity: Option<Identity>,
+    /// The identity which the server must prove to clients
+    server_key: Option<PublicKey>,
+}
+
+impl Service {
+    pub fn new(name: String, secret: Option<String>, epoch: Duration) -> Self {
+        Self {
+            name,
+            secret,
+            epoch: epoch.max(Duration::from_secs(1)),
+            identity: None,
+            server_key: None,
+        }
+    }
+
+    pub fn with_identity(mut self, identity: Option<Identity>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    pub fn with_server_key(mut self, server_key: Option<PublicKey>) -> Self {
+        self.server_key = server_key;
+        self
+    }
+
+    pub fn server_key(&self) -> Option<&PublicKey> {
+        self.server_key.as_ref()
+    }
+
+    /// The human readable name of the service
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Current epoch number of derived keys
+    fn current_epoch(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_secs() / self.epoch.as_secs()
+    }
+
+    /// The time left until the current key is rotated. None if keys are never rotated.
+    pub fn until_rotation(&self) -> Option<Duration> {
+        self.secret.as_ref()?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let next_epoch = Duration::from_secs((self.current_epoch() + 1) * self.epoch.as_secs());
+        Some(next_epoch.saturating_sub(now))
+    }
+
+    /// The key which should be used to register or look up this service right now
+    pub fn lookup_key(&self) -> String {
+        self.key_of_epoch(self.current_epoch())
+    }
+
+    /// The key of the last epoch. Servers might have registered with this key if
+    /// their clock is a bit behind or the key was just rotated. None if keys are not derived.
+    pub fn previous_lookup_key(&self) -> Option<String> {
+        self.secret.as_ref()?;
+        Some(self.key_of_epoch(self.current_epoch().saturating_sub(1)))
+    }
+
+    /// Derive the key of an epoch as hex(HMAC-SHA256(secret, name || epoch))
+    fn key_of_epoch(&self, epoch: u64) -> String {
+        let Some(secret) = &self.secret else {
+            return self.name.clone();
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(self.name.as_bytes());
+        mac.update(&epoch.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+        digest[..HASHED_KEY_LENGTH].iter().fold(
+            String::with_capacity(HASHED_KEY_LENGTH * 2),
+            |mut hex, byte| {
+                let _ = write!(hex, "{:02x}", byte);
+                hex
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+    fn service(name: &str, secret: &str) -> Service {
+        Service::new(name.to_owned(), Some(secret.to_owned()), DAY)
+    }
+
+    #[test]
+    fn keys_are_stable_within_an_epoch_and_differ_across_epochs() {
+        let service = service("web", "secret");
+        let key = service.key_of_epoch(1000);
+        assert_eq!(key.len(), HASHED_KEY_LENGTH * 2);
+        assert_eq!(service.key_of_epoch(1000), key);
+        assert_ne!(service.key_of_epoch(1001), key);
+        assert_ne!(service.key_of_epoch(999), key);
+        // Another secret or name has other keys
+        assert_ne!(self::service("web", "other").key_of_epoch(1000), key);
+        assert_ne!(self::service("mail", "secret").key_of_epoch(1000), key);
+    }
+
+    #[test]
+    fn key_of_previous_epoch_is_still_offered() {
+        let service = service("web", "secret");
+        let epoch = service.current_epoch();
+        let (key, previous) = (service.lookup_key(), service.previous_lookup_key());
+        // The epoch might have just rolled over
+        if service.current_epoch() != epoch {
+            return;
+        }
+        assert_eq!(key, service.key_of_epoch(epoch));
+        assert_eq!(previous, Some(service.key_of_epoch(epoch - 1)));
+        assert_ne!(previous, Some(key));
+        assert!(service.until_rotation().unwrap() <= DAY);
+    }
+
+    #[test]
+    fn names_are_the_keys_without_a_secret() {
+        let service = Service::new("web".to_owned(), None, DAY);
+        assert_eq!(service.lookup_key(), "web");
+        assert_eq!(service.key_of_epoch(1000), "web");
+        assert_eq!(service.previous_lookup_key(), None);
+        assert_eq!(service.until_rotation(), None);
+    }
+}
@@ -0,0 +1,181 @@
+use std::{future::Future, io, time::Duration};
+
+use tokio::{
+    select, signal,
+    sync::{mpsc, watch},
+    time,
+};
+
+/// How long sessions have to send their close messages after they are told to close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Stages of the shutdown of the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Everything is normal
+    Running,
+    /// No new sessions should be accepted. Current sessions can continue.
+    Draining,
+    /// Every session must be closed now
+    Closing,
+}
+
+/// A handle which tasks use to watch the shutdown of the program.
+/// Every clone of this handle keeps the program alive until it's dropped or the drain timeout is reached.
+#[derive(Clone)]
+pub struct Shutdown {
+    stage: watch::Receiver<Stage>,
+    _alive: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    /// Returns true if the program is shutting down and no new session should be accepted
+    pub fn is_draining(&self) -> bool {
+        *self.stage.borrow() >= Stage::Draining
+    }
+
+    /// Wait until the shutdown reaches the given stage
+    pub async fn reached(&mut self, stage: Stage) {
+        if self
+            .stage
+            .wait_for(|current| *current >= stage)
+            .await
+            .is_err()
+        {
+            // The controller is gone. This only happens when the program is exiting.
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Listen for Ctrl-C and shut down the program gracefully when it's pressed.
+/// Returns a handle to watch the shutdown and a future which is resolved when the program can exit.
+/// The program exits when all handles are dropped or the drain timeout is reached.
+/// Pressing Ctrl-C again skips the draining.
+pub fn listen(drain_timeout: Duration) -> (Shutdown, impl Future<Output = ()>) {
+    listen_for(drain_timeout, signal::ctrl_c)
+}
+
+/// Like listen, but the program is shut down when the future of signal is resolved
+fn listen_for<F>(
+    drain_timeout: Duration,
+    mut signal: impl FnMut() -> F,
+) -> (Shutdown, impl Future<Output = ()>)
+where
+    F: Future<Output = io::Result<()>>,
+{
+    let (stage_sender, stage) = watch::channel(Stage::Running);
+    let (alive, mut alive_receiver) = mpsc::channel::<()>(1);
+    let controller = async move {
+        // Nothing is ever sent in the alive channel. recv returns when all senders are dropped.
+        select! {
+            _ = alive_receiver.recv() => return,
+            result = signal() => {
+                if let Err(err) = result {
+                    log::error!("Cannot listen for shutdown signal: {}", err);
+                    std::future::pending::<()>().await;
+                }
+            },
+        }
+        log::info!(
+            "Shutting down. Draining sessions for {:?}. Press Ctrl-C again to force.",
+            drain_timeout
+        );
+        let _ = stage_sender.send(Stage::Draining);
+        select! {
+            _ = alive_receiver.recv() => return,
+            () = time::sleep(drain_timeout) => log::info!("Drain timeout reached"),
+            _ = signal() => log::info!("Forcing shutdown"),
+        }
+        let _ = stage_sender.send(Stage::Closing);
+        let _ = time::timeout(CLOSE_TIMEOUT, alive_receiver.recv()).await;
+    };
+    (
+        Shutdown {
+            stage,
+            _alive: alive,
+        },
+        controller,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{sync::Notify, time::Instant};
+
+    use super::*;
+
+    const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Starts the controller with a signal which is raised by notifying the returned handle
+    fn start() -> (Shutdown, Arc<Notify>, tokio::task::JoinHandle<()>) {
+        let signals = Arc::new(Notify::new());
+        let raised = signals.clone();
+        let (shutdown, controller) = listen_for(DRAIN_TIMEOUT, move || {
+            let raised = raised.clone();
+            async move {
+                raised.notified().await;
+                Ok(())
+            }
+        });
+        (shutdown, signals, tokio::spawn(controller))
+    }
+
+    fn assert_waited(start: Instant, duration: Duration) {
+        let waited = start.elapsed();
+        assert!(
+            waited >= duration && waited <= duration + Duration::from_millis(1),
+            "waited {:?} instead of {:?}",
+            waited,
+            duration
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_are_drained_until_the_drain_timeout() {
+        let (mut shutdown, signals, controller) = start();
+        time::sleep(DRAIN_TIMEOUT * 2).await;
+        assert!(!shutdown.is_draining());
+        signals.notify_one();
+        shutdown.reached(Stage::Draining).await;
+        assert!(shutdown.is_draining());
+        let draining = Instant::now();
+        shutdown.reached(Stage::Closing).await;
+        assert_waited(draining, DRAIN_TIMEOUT);
+        // Sessions which do not close in time do not keep the program alive
+        let closing = Instant::now();
+        controller.await.unwrap();
+        assert_waited(closing, CLOSE_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_second_signal_skips_the_draining() {
+        let (mut shutdown, signals, controller) = start();
+        signals.notify_one();
+        shutdown.reached(Stage::Draining).await;
+        let draining = Instant::now();
+        signals.notify_one();
+        shutdown.reached(Stage::Closing).await;
+        assert_waited(draining, Duration::ZERO);
+        drop(shutdown);
+        controller.await.unwrap();
+        assert_waited(draining, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn program_exits_when_every_session_is_drained() {
+        let (mut shutdown, signals, controller) = start();
+        let session = shutdown.clone();
+        signals.notify_one();
+        shutdown.reached(Stage::Draining).await;
+        let draining = Instant::now();
+        drop(shutdown);
+        time::sleep(DRAIN_TIMEOUT / 2).await;
+        assert!(!controller.is_finished());
+        drop(session);
+        controller.await.unwrap();
+        assert_waited(draining, DRAIN_TIMEOUT / 2);
+    }
+}
@@ -0,0 +1,684 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use parking_lot::Mutex;
+use quinn::{Endpoint, RecvStream, SendStream};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    select, task, time,
+};
+
+use crate::{
+    arguments::TunnelOptions,
+    client::{self, Punched},
+    messages::{
+        Compression, Destination, DialError, StreamReply, StreamRequest, Transport, UdpRecord,
+    },
+    quic::{self, QuicTunnel},
+    relay::{read_message, write_message},
+    secure::Turn,
+    service::Service,
+    shutdown::{Shutdown, Stage},
+    stats::SessionStats,
+    util::{parse_turn_addresses, FORWARD_BUFFER_SIZE},
+};
+
+/// While shutting down, check if all SOCKS clients are gone every this often
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+const VERSION: u8 = 5;
+
+/// Both halves of a QUIC stream
+type Opened = (SendStream, RecvStream);
+
+/// Authentication methods of SOCKS5
+mod method {
+    pub const NO_AUTHENTICATION: u8 = 0;
+    pub const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+}
+
+/// Commands of SOCKS5 requests
+mod command {
+    pub const CONNECT: u8 = 1;
+    pub const UDP_ASSOCIATE: u8 = 3;
+}
+
+/// Address types of SOCKS5
+mod address_type {
+    pub const IPV4: u8 = 1;
+    pub const DOMAIN: u8 = 3;
+    pub const IPV6: u8 = 4;
+}
+
+/// Reply codes of SOCKS5
+mod reply {
+    pub const SUCCEEDED: u8 = 0;
+    pub const GENERAL_FAILURE: u8 = 1;
+    pub const NOT_ALLOWED: u8 = 2;
+    pub const HOST_UNREACHABLE: u8 = 4;
+    pub const CONNECTION_REFUSED: u8 = 5;
+    pub const COMMAND_NOT_SUPPORTED: u8 = 7;
+}
+
+/// The QUIC connection to the server which all SOCKS clients share.
+/// It's punched when the first SOCKS client arrives and punched again if it's lost.
+struct Tunnel {
+    turns: Vec<Turn>,
+    service: Service,
+    race: bool,
+    options: TunnelOptions,
+    connection: tokio::sync::Mutex<Option<(Endpoint, QuicTunnel)>>,
+}
+
+impl Tunnel {
+    /// The current connection to the server. A new one is punched if there is none.
+    async fn connection(&self) -> anyhow::Result<QuicTunnel> {
+        let mut current = self.connection.lock().await;
+        if let Some((_, tunnel)) = current.as_ref() {
+            if tunnel.connection().close_reason().is_none() {
+                return Ok(tunnel.clone());
+            }
+        }
+        let Punched {
+            socket,
+            options,
+            certificate,
+            ..
+        } = client::punch(
+            &self.turns,
+            &self.service,
+            self.race,
+            Compression::None,
+            None,
+        )
+        .await?;
+        if options.transport != Transport::Quic {
+            bail!("the server does not use QUIC transport");
+        }
+        let certificate =
+            certificate.ok_or_else(|| anyhow!("server did not announce its QUIC certificate"))?;
+        let server_address = socket.peer_addr()?;
+        let (endpoint, connection) = quic::connect(
+            socket.into_std()?,
+            server_address,
+            certificate,
+            &self.options,
+        )
+        .await?;
+        log::info!("Connected to the server at {} over QUIC", server_address);
+        let tunnel = QuicTunnel::new(connection, Arc::new(SessionStats::default()));
+        // Nothing else reads the datagrams of the connection; so they are read here to pass
+        // UDP records to their associations
+        let reader = tunnel.clone();
+        task::spawn(async move {
+            while reader.receive().await.is_ok() {
+                log::trace!("Dropping a datagram of the session which SOCKS does not use");
+            }
+        });
+        *current = Some((endpoint, tunnel.clone()));
+        Ok(tunnel)
+    }
+
+    /// Open a stream to the server for the request.
+    /// Returns the tunnel of the stream, the stream and the reply of the server.
+    async fn open(
+        &self,
+        request: &StreamRequest,
+    ) -> anyhow::Result<(QuicTunnel, Opened, StreamReply)> {
+        let tunnel = self.connection().await?;
+        let (mut send, mut recv) = tunnel.connection().open_bi().await?;
+        write_message(&mut send, request).await?;
+        let mut buffer = Vec::new();
+        let reply = read_message(&mut recv, &mut buffer)
+            .await?
+            .ok_or_else(|| anyhow!("server closed the stream"))?;
+        Ok((tunnel, (send, recv), reply))
+    }
+
+    /// Close the connection and wait until the server knows
+    async fn close(&self) {
+        if let Some((endpoint, tunnel)) = self.connection.lock().await.take() {
+            tunnel.connection().close(0u32.into(), b"");
+            endpoint.wait_idle().await;
+        }
+    }
+}
+
+/// Serve a SOCKS5 server which sends the requests of its clients to the server through a punched
+/// QUIC connection. Returns when the client is shutting down and all SOCKS clients are gone.
+pub async fn spawn_socks(
+    listen: &str,
+    turn: &str,
+    service: &Service,
+    race: bool,
+    options: &TunnelOptions,
+    mut shutdown: Shutdown,
+) {
+    let listener = TcpListener::bind(listen)
+        .await
+        .expect("cannot bind to local address");
+    log::info!(
+        "SOCKS5 server is listening on {}",
+        listener.local_addr().unwrap()
+    );
+    let tunnel = Arc::new(Tunnel {
+        turns: parse_turn_addresses(turn),
+        service: service.clone(),
+        race,
+        options: options.clone(),
+        connection: tokio::sync::Mutex::new(None),
+    });
+    let clients = Arc::new(AtomicUsize::new(0));
+    loop {
+        let (stream, address) = select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::warn!("Cannot accept SOCKS client: {}", err);
+                    continue;
+                }
+            },
+            () = shutdown.reached(Stage::Draining) => break,
+        };
+        let tunnel = tunnel.clone();
+        let clients = clients.clone();
+        clients.fetch_add(1, Ordering::Relaxed);
+        task::spawn(async move {
+            if let Err(err) = serve_client(stream, &tunnel).await {
+                log::debug!("SOCKS client {} failed: {}", address, err);
+            }
+            clients.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+    // Let current clients finish until the connections must be closed
+    while clients.load(Ordering::Relaxed) > 0 {
+        select! {
+            () = time::sleep(DRAIN_CHECK_INTERVAL) => {}
+            () = shutdown.reached(Stage::Closing) => break,
+        }
+    }
+    tunnel.close().await;
+    log::info!("All SOCKS clients are gone");
+}
+
+/// Negotiate with a SOCKS5 client and serve its request
+async fn serve_client(mut stream: TcpStream, tunnel: &Tunnel) -> anyhow::Result<()> {
+    let (command, destination) = negotiate(&mut stream).await?;
+    match command {
+        command::CONNECT => connect(stream, destination, tunnel).await,
+        command::UDP_ASSOCIATE => associate(stream, tunnel).await,
+        command => {
+            send_reply(&mut stream, reply::COMMAND_NOT_SUPPORTED, unspecified()).await?;
+            bail!("command {} is not supported", command);
+        }
+    }
+}
+
+/// Agree on the authentication method with a SOCKS5 client and read its request.
+/// Returns the command and the destination of the request.
+async fn negotiate(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> anyhow::Result<(u8, Destination)> {
+    // Greeting: version, number of methods and the methods
+    let mut greeting = [0; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        bail!("client does not speak SOCKS5");
+    }
+    let mut methods = vec![0; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&method::NO_AUTHENTICATION) {
+        stream
+            .write_all(&[VERSION, method::NO_ACCEPTABLE_METHODS])
+            .await?;
+        bail!("client needs authentication");
+    }
+    stream
+        .write_all(&[VERSION, method::NO_AUTHENTICATION])
+        .await?;
+    // Request: version, command, reserved and the destination
+    let mut request = [0; 3];
+    stream.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        send_reply(stream, reply::GENERAL_FAILURE, unspecified()).await?;
+        bail!("request of version {} is not SOCKS5", request[0]);
+    }
+    let destination = read_address(stream).await?;
+    Ok((request[1], destination))
+}
+
+/// Connect the client to the destination through a stream to the server
+async fn connect(
+    mut stream: TcpStream,
+    destination: Destination,
+    tunnel: &Tunnel,
+) -> anyhow::Result<()> {
+    let request = StreamRequest::Connect(destination.clone());
+    let (mut send, mut recv) = match open(&mut stream, tunnel, &request).await? {
+        Some((_, opened)) => opened,
+        None => bail!("server cannot connect to {}", destination),
+    };
+    log::info!("{} is connected to {}", stream.peer_addr()?, destination);
+    let (mut tcp_read, mut tcp_write) = stream.into_split();
+    tokio::try_join!(
+        async {
+            tokio::io::copy(&mut recv, &mut tcp_write).await?;
+            tcp_write.shutdown().await
+        },
+        async {
+            tokio::io::copy(&mut tcp_read, &mut send).await?;
+            send.shutdown().await
+        },
+    )?;
+    Ok(())
+}
+
+/// Relay the UDP datagrams of the client as QUIC datagrams of an association until the client
+/// closes its TCP connection or the server finishes the stream of the association
+async fn associate(mut stream: TcpStream, tunnel: &Tunnel) -> anyhow::Result<()> {
+    let relay = UdpSocket::bind((stream.local_addr()?.ip(), 0)).await?;
+    let Some((quic, (send, mut recv))) =
+        open(&mut stream, tunnel, &StreamRequest::Associate).await?
+    else {
+        bail!("server cannot relay UDP");
+    };
+    // The stream stays open while the association lives
+    let association = quic.associate(send.id().into());
+    send_reply(&mut stream, reply::SUCCEEDED, relay.local_addr()?).await?;
+    // Datagrams are only accepted from the host of the client and the answers go to where
+    // its first datagram came from
+    let client_ip = stream.peer_addr()?.ip();
+    let client = Mutex::new(None);
+    let outbound = async {
+        let mut buffer = [0; FORWARD_BUFFER_SIZE];
+        loop {
+            let (length, from) = relay.recv_from(&mut buffer).await?;
+            if from.ip() != client_ip {
+                log::trace!(
+                    "Dropping datagram from {} which is not the SOCKS client",
+                    from
+                );
+                continue;
+            }
+            client.lock().get_or_insert(from);
+            let Some((destination, payload)) = parse_udp_header(&buffer[..length]) else {
+                log::trace!("Dropping invalid or fragmented datagram of {}", from);
+                continue;
+            };
+            association.send(UdpRecord {
+                peer: destination,
+                payload,
+            })?;
+        }
+    };
+    let inbound = async {
+        let mut datagram = Vec::new();
+        while let Some((peer, payload)) = association.receive().await {
+            let (Some(client), Destination::Address(peer)) = (*client.lock(), peer) else {
+                continue;
+            };
+            datagram.clear();
+            datagram.extend_from_slice(&[0, 0, 0]);
+            encode_address(&mut datagram, peer);
+            datagram.extend_from_slice(&payload);
+            relay.send_to(&datagram, client).await?;
+        }
+        anyhow::Ok(())
+    };
+    // The association is over when the client closes its TCP connection
+    let closed = async {
+        let mut buffer = [0; 64];
+        while stream.read(&mut buffer).await? > 0 {}
+        anyhow::Ok(())
+    };
+    let finished = async {
+        recv.read_to_end(0).await?;
+        anyhow::Ok(())
+    };
+    select! {
+        result = outbound => result,
+        result = inbound => result,
+        result = closed => result,
+        result = finished => result,
+    }
+}
+
+/// Open a stream for the request and send the reply of the server to the client.
+/// Returns None if the server has refused the request.
+async fn open(
+    stream: &mut TcpStream,
+    tunnel: &Tunnel,
+    request: &StreamRequest,
+) -> anyhow::Result<Option<(QuicTunnel, Opened)>> {
+    let (quic, opened, bound) = match tunnel.open(request).await {
+        Ok((quic, opened, Ok(bound))) => (quic, opened, bound),
+        Ok((_, _, Err(err))) => {
+            send_reply(stream, reply_code(err), unspecified()).await?;
+            log::warn!("Server refused {:?}: {:?}", request, err);
+            return Ok(None);
+        }
+        Err(err) => {
+            send_reply(stream, reply::GENERAL_FAILURE, unspecified()).await?;
+            return Err(err);
+        }
+    };
+    if matches!(request, StreamRequest::Connect(_)) {
+        send_reply(stream, reply::SUCCEEDED, bound).await?;
+    }
+    Ok(Some((quic, opened)))
+}
+
+/// The reply code of SOCKS5 for an error of the server
+fn reply_code(err: DialError) -> u8 {
+    match err {
+        DialError::NotAllowed => reply::NOT_ALLOWED,
+        DialError::Unresolved | DialError::Unreachable => reply::HOST_UNREACHABLE,
+        DialError::Refused => reply::CONNECTION_REFUSED,
+        DialError::Failed => reply::GENERAL_FAILURE,
+    }
+}
+
+fn unspecified() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+}
+
+/// Send a reply with the bound address to the client
+async fn send_reply(
+    stream: &mut (impl AsyncWrite + Unpin),
+    code: u8,
+    bound: SocketAddr,
+) -> anyhow::Result<()> {
+    let mut reply = vec![VERSION, code, 0];
+    encode_address(&mut reply, bound);
+    stream.write_all(&reply).await?;
+    Ok(())
+}
+
+/// Read an address type, an address and a port from the stream
+async fn read_address(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Destination> {
+    let mut address = vec![stream.read_u8().await?];
+    let length = match address[0] {
+        address_type::IPV4 => 4,
+        address_type::IPV6 => 16,
+        address_type::DOMAIN => {
+            let length = stream.read_u8().await?;
+            address.push(length);
+            length as usize
+        }
+        address_type => bail!("address type {} is not supported", address_type),
+    };
+    let start = address.len();
+    address.resize(start + length + 2, 0);
+    stream.read_exact(&mut address[start..]).await?;
+    parse_address(&address)
+        .map(|(destination, _)| destination)
+        .ok_or_else(|| anyhow!("invalid address"))
+}
+
+/// Parse an address type, an address and a port. Returns the destination and its length.
+fn parse_address(buffer: &[u8]) -> Option<(Destination, usize)> {
+    let (&address_type, rest) = buffer.split_first()?;
+    let (ip, length): (IpAddr, _) = match address_type {
+        address_type::IPV4 => (<[u8; 4]>::try_from(rest.get(..4)?).ok()?.into(), 4),
+        address_type::IPV6 => (<[u8; 16]>::try_from(rest.get(..16)?).ok()?.into(), 16),
+        address_type::DOMAIN => {
+            let (&length, rest) = rest.split_first()?;
+            let length = length as usize;
+            let host = std::str::from_utf8(rest.get(..length)?).ok()?;
+            let port = u16::from_be_bytes(rest.get(length..length + 2)?.try_into().ok()?);
+            return Some((
+                Destination::Domain(host.to_owned(), port),
+                1 + 1 + length + 2,
+            ));
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(rest.get(length..length + 2)?.try_into().ok()?);
+    Some((Destination::Address((ip, port).into()), 1 + length + 2))
+}
+
+/// Parse the header of a UDP datagram of the client: reserved, fragment number and the destination.
+/// Returns the destination and the payload. Returns None if it's invalid or a fragment.
+fn parse_udp_header(datagram: &[u8]) -> Option<(Destination, &[u8])> {
+    let (header, address) = (datagram.get(..3)?, &datagram[3..]);
+    if header[2] != 0 {
+        return None;
+    }
+    let (destination, length) = parse_address(address)?;
+    Some((destination, &address[length..]))
+}
+
+/// Append the address type, the address and the port of an address
+fn encode_address(buffer: &mut Vec<u8>, address: SocketAddr) {
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            buffer.push(address_type::IPV4);
+            buffer.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buffer.push(address_type::IPV6);
+            buffer.extend_from_slice(&ip.octets());
+        }
+    }
+    buffer.extend_from_slice(&address.port().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    fn encoded(address: SocketAddr) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        encode_address(&mut buffer, address);
+        buffer
+    }
+
+    /// Negotiate with a client which has sent the bytes. Returns the result and what the client got.
+    async fn negotiate_with(sent: &[u8]) -> (anyhow::Result<(u8, Destination)>, Vec<u8>) {
+        let (mut client, mut server): (DuplexStream, _) = duplex(1024);
+        client.write_all(sent).await.unwrap();
+        let result = negotiate(&mut server).await;
+        drop(server);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        (result, received)
+    }
+
+    #[test]
+    fn addresses_are_parsed_as_they_are_encoded() {
+        for address in [address("192.0.2.1:80"), address("[2001:db8::1]:443")] {
+            let mut buffer = encoded(address);
+            buffer.extend_from_slice(b"payload");
+            assert_eq!(
+                parse_address(&buffer),
+                Some((Destination::Address(address), buffer.len() - 7))
+            );
+        }
+        let domain = [&[address_type::DOMAIN, 11][..], b"example.com", &[0, 53]].concat();
+        assert_eq!(
+            parse_address(&domain),
+            Some((
+                Destination::Domain("example.com".to_owned(), 53),
+                domain.len()
+            ))
+        );
+    }
+
+    #[test]
+    fn truncated_and_unknown_addresses_are_invalid() {
+        let ipv4 = encoded(address("192.0.2.1:80"));
+        for length in 0..ipv4.len() {
+            assert_eq!(parse_address(&ipv4[..length]), None);
+        }
+        let ipv6 = encoded(address("[2001:db8::1]:443"));
+        assert_eq!(parse_address(&ipv6[..ipv6.len() - 1]), None);
+        // The domain is longer than the buffer or not UTF-8
+        assert_eq!(
+            parse_address(&[address_type::DOMAIN, 20, b'a', 0, 80]),
+            None
+        );
+        assert_eq!(parse_address(&[address_type::DOMAIN, 1, 0xff, 0, 80]), None);
+        assert_eq!(parse_address(&[2, 192, 0, 2, 1, 0, 80]), None);
+    }
+
+    #[tokio::test]
+    async fn addresses_are_read_from_streams() {
+        let domain = [&[address_type::DOMAIN, 9][..], b"localhost", &[0x1f, 0x90]].concat();
+        assert_eq!(
+            read_address(&mut &domain[..]).await.unwrap(),
+            Destination::Domain("localhost".to_owned(), 8080)
+        );
+        let ipv6 = encoded(address("[2001:db8::1]:443"));
+        assert_eq!(
+            read_address(&mut &ipv6[..]).await.unwrap(),
+            Destination::Address(address("[2001:db8::1]:443"))
+        );
+        assert!(read_address(&mut &ipv6[..ipv6.len() - 1]).await.is_err());
+        assert!(read_address(&mut &[2, 192, 0, 2, 1, 0, 80][..])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn clients_without_authentication_are_accepted() {
+        let request = [
+            &[VERSION, 2, 2, 0, VERSION, command::CONNECT, 0][..],
+            &encoded(address("192.0.2.1:80")),
+        ]
+        .concat();
+        let (result, received) = negotiate_with(&request).await;
+        assert_eq!(
+            result.unwrap(),
+            (
+                command::CONNECT,
+                Destination::Address(address("192.0.2.1:80"))
+            )
+        );
+        assert_eq!(received, [VERSION, method::NO_AUTHENTICATION]);
+    }
+
+    #[tokio::test]
+    async fn clients_which_need_authentication_are_refused() {
+        let (result, received) = negotiate_with(&[VERSION, 1, 2]).await;
+        assert!(result.is_err());
+        assert_eq!(received, [VERSION, method::NO_ACCEPTABLE_METHODS]);
+        // Other versions get no answer
+        let (result, received) = negotiate_with(&[4, 1, 0]).await;
+        assert!(result.is_err());
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_of_other_versions_are_refused() {
+        let request = [
+            &[VERSION, 1, 0, 4, command::CONNECT, 0][..],
+            &encoded(address("192.0.2.1:80")),
+        ]
+        .concat();
+        let (result, received) = negotiate_with(&request).await;
+        assert!(result.is_err());
+        assert_eq!(
+            received,
+            [
+                &[VERSION, method::NO_AUTHENTICATION][..],
+                &[VERSION, reply::GENERAL_FAILURE, 0],
+                &encoded(unspecified())
+            ]
+            .concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_have_the_bound_address() {
+        let mut reply = Vec::new();
+        send_reply(&mut reply, reply::SUCCEEDED, address("192.0.2.1:1080"))
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            [
+                VERSION,
+                reply::SUCCEEDED,
+                0,
+                address_type::IPV4,
+                192,
+                0,
+                2,
+                1,
+                0x04,
+                0x38
+            ]
+        );
+        let mut reply = Vec::new();
+        send_reply(&mut reply, reply_code(DialError::Refused), unspecified())
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            [
+                VERSION,
+                reply::CONNECTION_REFUSED,
+                0,
+                address_type::IPV4,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_of_the_server_have_their_reply_codes() {
+        assert_eq!(reply_code(DialError::NotAllowed), reply::NOT_ALLOWED);
+        assert_eq!(reply_code(DialError::Unresolved), reply::HOST_UNREACHABLE);
+        assert_eq!(reply_code(DialError::Unreachable), reply::HOST_UNREACHABLE);
+        assert_eq!(reply_code(DialError::Refused), reply::CONNECTION_REFUSED);
+        assert_eq!(reply_code(DialError::Failed), reply::GENERAL_FAILURE);
+    }
+
+    #[test]
+    fn udp_headers_are_parsed() {
+        let datagram = [&[0, 0, 0][..], &encoded(address("192.0.2.1:53")), b"query"].concat();
+        assert_eq!(
+            parse_udp_header(&datagram),
+            Some((Destination::Address(address("192.0.2.1:53")), &b"query"[..]))
+        );
+        let empty = [&[0, 0, 0][..], &encoded(address("192.0.2.1:53"))].concat();
+        assert_eq!(
+            parse_udp_header(&empty),
+            Some((Destination::Address(address("192.0.2.1:53")), &b""[..]))
+        );
+    }
+
+    #[test]
+    fn fragmented_and_truncated_udp_headers_are_invalid() {
+        let datagram = [&[0, 0, 1][..], &encoded(address("192.0.2.1:53")), b"query"].concat();
+        assert_eq!(parse_udp_header(&datagram), None);
+        let datagram = [&[0, 0, 0][..], &encoded(address("192.0.2.1:53"))].concat();
+        for length in 0..datagram.len() {
+            assert_eq!(parse_udp_header(&datagram[..length]), None);
+        }
+        // The domain is longer than the datagram
+        assert_eq!(
+            parse_udp_header(&[0, 0, 0, address_type::DOMAIN, 255, b'a', 0, 53]),
+            None
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Counters of a session. They are logged when the session ends.
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    datagrams_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    datagrams_received: AtomicU64,
+    bytes_received: AtomicU64,
+    /// Frames which are retransmitted in reliable sessions
+    retransmits: AtomicU64,
+    /// Frames which are dropped because the send queue of reliable sessions is full
+    dropped: AtomicU64,
+    /// Smoothed RTT of reliable sessions in microseconds
+    rtt: AtomicU64,
+    /// Congestion window of reliable sessions in frames
+    window: AtomicU64,
+    /// Largest frame which is sent over the path as discovered by path MTU discovery
+    mtu: AtomicU64,
+}
+
+impl SessionStats {
+    /// A datagram of the application is sent to the peer
+    pub fn on_sent(&self, bytes: usize) {
+        self.datagrams_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A datagram of the peer is delivered to the application
+    pub fn on_received(&self, bytes: usize) {
+        self.datagrams_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_retransmit(&self) {
+        self.retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Update the RTT and congestion window of a reliable session
+    pub fn set_congestion(&self, rtt: Option<Duration>, window: usize) {
+        let rtt = rtt.map_or(0, |rtt| rtt.as_micros() as u64);
+        self.rtt.store(rtt, Ordering::Relaxed);
+        self.window.store(window as u64, Ordering::Relaxed);
+    }
+
+    /// Update the path MTU of a datagram session
+    pub fn set_mtu(&self, mtu: usize) {
+        self.mtu.store(mtu as u64, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} datagrams ({} bytes), received {} datagrams ({} bytes)",
+            self.datagrams_sent.load(Ordering::Relaxed),
+            self.bytes_sent.load(Ordering::Relaxed),
+            self.datagrams_received.load(Ordering::Relaxed),
+            self.bytes_received.load(Ordering::Relaxed),
+        )?;
+        let mtu = self.mtu.load(Ordering::Relaxed);
+        if mtu != 0 {
+            write!(f, ", path MTU {} bytes", mtu)?;
+        }
+        let window = self.window.load(Ordering::Relaxed);
+        if window == 0 {
+            return Ok(());
+        }
+        write!(
+            f,
+            ", retransmitted {} frames, dropped {} frames, RTT {:?}, congestion window {} frames",
+            self.retransmits.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            Duration::from_micros(self.rtt.load(Ordering::Relaxed)),
+            window,
+        )
+    }
+}
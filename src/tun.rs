@@ -0,0 +1,135 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    net::IpAddr,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+};
+
+use anyhow::{bail, Context};
+use ipnet::IpNet;
+use tokio::{io::unix::AsyncFd, process::Command};
+
+/// The ioctl which attaches a file of /dev/net/tun to a device
+#[cfg(target_os = "linux")]
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+
+/// The part of struct ifreq which TUNSETIFF uses
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct InterfaceRequest {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _padding: [u8; 22],
+}
+
+/// A Linux TUN device which reads and writes IP packets. The device is removed when it's dropped.
+pub struct Tun {
+    device: AsyncFd<File>,
+    name: String,
+}
+
+impl Tun {
+    /// Create a TUN device and give it its address and the address of its peer if it's point to point.
+    /// The kernel replaces %d in the name with a number. Routes are routed over the device.
+    pub async fn open(
+        name: &str,
+        address: IpAddr,
+        peer: Option<IpAddr>,
+        mtu: u16,
+        routes: &[IpNet],
+    ) -> anyhow::Result<Self> {
+        let tun = Self::create(name)?;
+        let address = address.to_string();
+        match peer {
+            Some(peer) => {
+                let peer = peer.to_string();
+                ip(&["addr", "add", &address, "peer", &peer, "dev", &tun.name]).await?;
+            }
+            None => ip(&["addr", "add", &address, "dev", &tun.name]).await?,
+        }
+        let mtu = mtu.to_string();
+        ip(&["link", "set", "dev", &tun.name, "mtu", &mtu, "up"]).await?;
+        for route in routes {
+            ip(&["route", "add", &route.to_string(), "dev", &tun.name]).await?;
+        }
+        Ok(tun)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create(name: &str) -> anyhow::Result<Self> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")
+            .context("cannot open /dev/net/tun")?;
+        if name.len() >= libc::IFNAMSIZ {
+            bail!("device name {} is too long", name);
+        }
+        let mut request = InterfaceRequest {
+            name: [0; libc::IFNAMSIZ],
+            flags: (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short,
+            _padding: [0; 22],
+        };
+        request.name[..name.len()].copy_from_slice(name.as_bytes());
+        // The request is as large as the struct which the kernel expects
+        if unsafe { libc::ioctl(device.as_raw_fd(), TUNSETIFF as _, &mut request) } < 0 {
+            return Err(io::Error::last_os_error()).context("cannot create the TUN device");
+        }
+        let length = request
+            .name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(libc::IFNAMSIZ);
+        Ok(Self {
+            device: AsyncFd::new(device)?,
+            name: String::from_utf8_lossy(&request.name[..length]).into_owned(),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn create(_: &str) -> anyhow::Result<Self> {
+        bail!("TUN devices are only supported on Linux");
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Read the next IP packet which the kernel routes to the device
+    pub async fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.device.readable().await?;
+            if let Ok(result) = guard.try_io(|device| device.get_ref().read(buffer)) {
+                return result;
+            }
+        }
+    }
+
+    /// Give an IP packet to the kernel as if it's received by the device
+    pub async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.device.writable().await?;
+            if let Ok(result) = guard.try_io(|device| device.get_ref().write(packet)) {
+                return result;
+            }
+        }
+    }
+}
+
+/// Run an ip command of iproute2
+async fn ip(arguments: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new("ip")
+        .args(arguments)
+        .output()
+        .await
+        .context("cannot run ip")?;
+    if !output.status.success() {
+        bail!(
+            "ip {} failed: {}",
+            arguments.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
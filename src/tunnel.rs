@@ -0,0 +1,561 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Size of the header of each frame which peers send to each other after the punch
+pub const FRAME_HEADER_SIZE: usize = 1;
+/// Size of the header of fragment frames: the type byte, datagram id, index and count
+pub const FRAGMENT_HEADER_SIZE: usize = FRAME_HEADER_SIZE + 6;
+/// Size of the header of FEC frames: the type byte, group and index
+pub const FEC_HEADER_SIZE: usize = FRAME_HEADER_SIZE + 5;
+/// Size of the header of FEC parity frames: the type byte, group, count and length
+pub const FEC_PARITY_HEADER_SIZE: usize = FRAME_HEADER_SIZE + 7;
+/// Size of the header of reliable frames: the type byte and sequence number
+pub const RELIABLE_HEADER_SIZE: usize = FRAME_HEADER_SIZE + 4;
+/// Size of buffers which control frames are encoded in
+pub const CONTROL_BUFFER_SIZE: usize = 16;
+
+/// Retransmission timeout is never less than this
+const MIN_RTO: Duration = Duration::from_millis(200);
+/// Retransmission timeout before any RTT sample is taken
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// The path to the peer is considered dead if this many heartbeats in a row are not acknowledged
+/// and the first of them was sent at least one heartbeat interval ago
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// Type bytes of frames. They don't overlap with the first byte of handshake packets
+/// (the variant index of UDPMessage); so stray handshake packets are dropped.
+mod frame_type {
+    pub const DATA: u8 = 0x40;
+    pub const CLOSE: u8 = 0x41;
+    pub const HEARTBEAT: u8 = 0x42;
+    pub const HEARTBEAT_ACK: u8 = 0x43;
+    pub const FRAGMENT: u8 = 0x44;
+    pub const PROBE: u8 = 0x45;
+    pub const PROBE_ACK: u8 = 0x46;
+    pub const FEC: u8 = 0x47;
+    pub const FEC_PARITY: u8 = 0x48;
+    pub const RELIABLE: u8 = 0x49;
+    pub const ACK: u8 = 0x4A;
+}
+
+/// Every packet which peers send to each other over the punched path is a frame.
+/// Each frame starts with a type byte. Only data frames are forwarded to the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame<'a> {
+    /// Data of the application
+    Data(&'a [u8]),
+    /// The peer has closed the session
+    Close,
+    /// Keeps the NAT mappings of the path alive. Must be answered with an ack with the same id
+    Heartbeat(u32),
+    /// Answer of a heartbeat
+    HeartbeatAck(u32),
+    /// A part of a datagram which is larger than the frames of the path
+    Fragment {
+        /// ID of the datagram
+        id: u32,
+        /// Index of this part in the datagram
+        index: u8,
+        /// Number of parts of the datagram
+        count: u8,
+        data: &'a [u8],
+    },
+    /// Probes the path MTU. The frame is padded to the probed size.
+    /// Must be answered with an ack with the same id.
+    Probe { id: u32, size: usize },
+    /// Answer of a probe
+    ProbeAck { id: u32, size: u16 },
+    /// A data, fragment or reliable frame which is protected by forward error correction
+    Fec {
+        /// The group of frames which a parity is sent for
+        group: u32,
+        /// Index of the frame in its group
+        index: u8,
+        frame: &'a [u8],
+    },
+    /// XOR of all frames of a group. Any one lost frame of the group can be rebuilt with it.
+    FecParity {
+        group: u32,
+        /// Number of frames in the group
+        count: u8,
+        /// XOR of the lengths of frames
+        length: u16,
+        data: &'a [u8],
+    },
+    /// A data or fragment frame of a reliable session. Must be acknowledged.
+    Reliable { seq: u32, frame: &'a [u8] },
+    /// Acknowledges the reliable frames
+    Ack {
+        /// Every frame before this sequence number is received
+        next: u32,
+        /// Bit i is set if frame next + 1 + i is received
+        received: u32,
+    },
+}
+
+impl<'a> Frame<'a> {
+    /// Write this frame into the buffer and return the packet.
+    /// Panics if the buffer is too small.
+    pub fn encode<'b>(&self, buffer: &'b mut [u8]) -> &'b [u8] {
+        let length = match *self {
+            Frame::Data(data) => {
+                buffer[0] = frame_type::DATA;
+                buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + data.len()].copy_from_slice(data);
+                FRAME_HEADER_SIZE + data.len()
+            }
+            Frame::Close => {
+                buffer[0] = frame_type::CLOSE;
+                FRAME_HEADER_SIZE
+            }
+            Frame::Heartbeat(id) => encode_id(buffer, frame_type::HEARTBEAT, id),
+            Frame::HeartbeatAck(id) => encode_id(buffer, frame_type::HEARTBEAT_ACK, id),
+            Frame::Fragment {
+                id,
+                index,
+                count,
+                data,
+            } => {
+                encode_id(buffer, frame_type::FRAGMENT, id);
+                buffer[FRAGMENT_HEADER_SIZE - 2] = index;
+                buffer[FRAGMENT_HEADER_SIZE - 1] = count;
+                buffer[FRAGMENT_HEADER_SIZE..FRAGMENT_HEADER_SIZE + data.len()]
+                    .copy_from_slice(data);
+                FRAGMENT_HEADER_SIZE + data.len()
+            }
+            Frame::Probe { id, size } => {
+                let header_size = encode_id(buffer, frame_type::PROBE, id);
+                buffer[header_size..size.max(header_size)].fill(0);
+                size.max(header_size)
+            }
+            Frame::ProbeAck { id, size } => {
+                let header_size = encode_id(buffer, frame_type::PROBE_ACK, id);
+                buffer[header_size..header_size + 2].copy_from_slice(&size.to_be_bytes());
+                header_size + 2
+            }
+            Frame::Fec {
+                group,
+                index,
+                frame,
+            } => {
+                encode_id(buffer, frame_type::FEC, group);
+                buffer[FEC_HEADER_SIZE - 1] = index;
+                buffer[FEC_HEADER_SIZE..FEC_HEADER_SIZE + frame.len()].copy_from_slice(frame);
+                FEC_HEADER_SIZE + frame.len()
+            }
+            Frame::FecParity {
+                group,
+                count,
+                length,
+                data,
+            } => {
+                encode_id(buffer, frame_type::FEC_PARITY, group);
+                buffer[FEC_PARITY_HEADER_SIZE - 3] = count;
+                buffer[FEC_PARITY_HEADER_SIZE - 2..FEC_PARITY_HEADER_SIZE]
+                    .copy_from_slice(&length.to_be_bytes());
+                buffer[FEC_PARITY_HEADER_SIZE..FEC_PARITY_HEADER_SIZE + data.len()]
+                    .copy_from_slice(data);
+                FEC_PARITY_HEADER_SIZE + data.len()
+            }
+            Frame::Reliable { seq, frame } => {
+                encode_id(buffer, frame_type::RELIABLE, seq);
+                buffer[RELIABLE_HEADER_SIZE..RELIABLE_HEADER_SIZE + frame.len()]
+                    .copy_from_slice(frame);
+                RELIABLE_HEADER_SIZE + frame.len()
+            }
+            Frame::Ack { next, received } => {
+                let header_size = encode_id(buffer, frame_type::ACK, next);
+                buffer[header_size..header_size + 4].copy_from_slice(&received.to_be_bytes());
+                header_size + 4
+            }
+        };
+        &buffer[..length]
+    }
+
+    /// Parse a frame. Returns None if the packet is not a valid frame.
+    pub fn decode(packet: &'a [u8]) -> Option<Self> {
+        let (&frame_type, body) = packet.split_first()?;
+        match frame_type {
+            frame_type::DATA => Some(Frame::Data(body)),
+            frame_type::CLOSE if body.is_empty() => Some(Frame::Close),
+            frame_type::HEARTBEAT => Some(Frame::Heartbeat(decode_id(body)?)),
+            frame_type::HEARTBEAT_ACK => Some(Frame::HeartbeatAck(decode_id(body)?)),
+            frame_type::FRAGMENT if packet.len() >= FRAGMENT_HEADER_SIZE => {
+                let (header, data) = body.split_at(FRAGMENT_HEADER_SIZE - FRAME_HEADER_SIZE);
+                Some(Frame::Fragment {
+                    id: decode_id(&header[..4])?,
+                    index: header[4],
+                    count: header[5],
+                    data,
+                })
+            }
+            frame_type::PROBE if body.len() >= 4 => Some(Frame::Probe {
+                id: decode_id(&body[..4])?,
+                size: packet.len(),
+            }),
+            frame_type::PROBE_ACK if body.len() == 6 => Some(Frame::ProbeAck {
+                id: decode_id(&body[..4])?,
+                size: u16::from_be_bytes([body[4], body[5]]),
+            }),
+            frame_type::FEC if packet.len() >= FEC_HEADER_SIZE => {
+                let (header, frame) = body.split_at(FEC_HEADER_SIZE - FRAME_HEADER_SIZE);
+                Some(Frame::Fec {
+                    group: decode_id(&header[..4])?,
+                    index: header[4],
+                    frame,
+                })
+            }
+            frame_type::FEC_PARITY if packet.len() >= FEC_PARITY_HEADER_SIZE => {
+                let (header, data) = body.split_at(FEC_PARITY_HEADER_SIZE - FRAME_HEADER_SIZE);
+                Some(Frame::FecParity {
+                    group: decode_id(&header[..4])?,
+                    count: header[4],
+                    length: u16::from_be_bytes([header[5], header[6]]),
+                    data,
+                })
+            }
+            frame_type::RELIABLE if packet.len() >= RELIABLE_HEADER_SIZE => {
+                let (header, frame) = body.split_at(RELIABLE_HEADER_SIZE - FRAME_HEADER_SIZE);
+                Some(Frame::Reliable {
+                    seq: decode_id(header)?,
+                    frame,
+                })
+            }
+            frame_type::ACK if body.len() == 8 => Some(Frame::Ack {
+                next: decode_id(&body[..4])?,
+                received: decode_id(&body[4..])?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Encode a frame which only has an id in its body. Returns the length of frame.
+fn encode_id(buffer: &mut [u8], frame_type: u8, id: u32) -> usize {
+    buffer[0] = frame_type;
+    buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + 4].copy_from_slice(&id.to_be_bytes());
+    FRAME_HEADER_SIZE + 4
+}
+
+fn decode_id(body: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(body.try_into().ok()?))
+}
+
+/// Turn the data which is read into buffer[FRAME_HEADER_SIZE..] into a data frame in place
+/// and return the packet. This avoids copying the data.
+pub fn data_frame(buffer: &mut [u8], length: usize) -> &[u8] {
+    buffer[0] = frame_type::DATA;
+    &buffer[..FRAME_HEADER_SIZE + length]
+}
+
+/// Estimates the RTT of the path and derives the retransmission timeout from it like TCP (RFC 6298)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RttEstimator {
+    /// Smoothed RTT
+    srtt: Option<Duration>,
+    /// RTT variation
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    /// Smoothed RTT of the path. None if no sample is taken yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// How long to wait for the ack of a packet before retransmitting it
+    pub fn rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + 4 * self.rttvar).max(MIN_RTO),
+            None => INITIAL_RTO,
+        }
+    }
+
+    /// Add the RTT of an acknowledged packet which is not retransmitted
+    pub fn on_sample(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(sample)) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+    }
+}
+
+/// The path to the peer is considered dead
+#[derive(Debug, Clone, Copy)]
+pub struct PathDead;
+
+/// Sends heartbeats over the punched path and checks if the peer answers them.
+/// Unacknowledged heartbeats are sent again with an exponential backoff.
+/// RTT of the path is measured with the heartbeats.
+#[derive(Debug)]
+pub struct Liveness {
+    /// How often heartbeats are sent
+    interval: Duration,
+    /// ID of the next heartbeat
+    next_id: u32,
+    /// The last heartbeat which is waiting for an ack and the time it was sent
+    outstanding: Option<(u32, Instant)>,
+    /// ID of the first heartbeat of the unacknowledged ones. An ack of any of them proves the path.
+    first_outstanding: u32,
+    /// When was the last heartbeat sent
+    last_sent: Instant,
+    /// Number of heartbeats in a row which were not acknowledged
+    missed: u32,
+    /// When was the first heartbeat of the unacknowledged ones sent
+    unacknowledged_since: Instant,
+    rtt: RttEstimator,
+}
+
+impl Liveness {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_id: 0,
+            outstanding: None,
+            first_outstanding: 0,
+            last_sent: Instant::now(),
+            missed: 0,
+            unacknowledged_since: Instant::now(),
+            rtt: RttEstimator::default(),
+        }
+    }
+
+    /// Smoothed RTT of the path. None if no heartbeat is acknowledged yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.rtt()
+    }
+
+    /// When should on_timer be called
+    pub fn deadline(&self) -> Instant {
+        match self.outstanding {
+            // Double the timeout for each missed heartbeat, but never wait longer than an interval
+            Some((_, sent)) => {
+                sent + (self.rtt.rto().saturating_mul(1 << self.missed.min(16)))
+                    .min(self.interval.max(self.rtt.rto()))
+            }
+            None => self.last_sent + self.interval,
+        }
+    }
+
+    /// Must be called when the deadline is reached. Returns the heartbeat which must be sent
+    /// or an error if the path is dead.
+    pub fn on_timer(&mut self) -> Result<Frame<'static>, PathDead> {
+        if self.outstanding.is_some() {
+            self.missed += 1;
+            log::debug!("Heartbeat is not acknowledged ({} in a row)", self.missed);
+            // A short burst of loss must not kill the path; so the peer has at least an interval to answer
+            if self.missed >= MAX_MISSED_HEARTBEATS
+                && self.unacknowledged_since.elapsed() >= self.interval
+            {
+                return Err(PathDead);
+            }
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.last_sent = Instant::now();
+        if self.missed == 0 {
+            self.unacknowledged_since = self.last_sent;
+            self.first_outstanding = id;
+        }
+        self.outstanding = Some((id, self.last_sent));
+        Ok(Frame::Heartbeat(id))
+    }
+
+    /// Must be called when an ack of heartbeat is received. A late ack of a heartbeat which
+    /// was sent again still proves the path, but only the last heartbeat is an RTT sample (Karn's rule).
+    pub fn on_ack(&mut self, id: u32) {
+        let Some((last_id, sent)) = self.outstanding else {
+            return;
+        };
+        if id.wrapping_sub(self.first_outstanding) > last_id.wrapping_sub(self.first_outstanding) {
+            return;
+        }
+        if id == last_id {
+            let sample = sent.elapsed();
+            self.rtt.on_sample(sample);
+            log::trace!("Heartbeat RTT is {:?}, smoothed {:?}", sample, self.rtt());
+        }
+        self.outstanding = None;
+        self.missed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One frame of each type
+    fn frames() -> [Frame<'static>; 11] {
+        [
+            Frame::Data(b"data"),
+            Frame::Close,
+            Frame::Heartbeat(7),
+            Frame::HeartbeatAck(u32::MAX),
+            Frame::Fragment {
+                id: 0x01020304,
+                index: 2,
+                count: 3,
+                data: b"part",
+            },
+            Frame::Probe { id: 9, size: 32 },
+            Frame::ProbeAck { id: 9, size: 1400 },
+            Frame::Fec {
+                group: 5,
+                index: 1,
+                frame: b"\x40protected",
+            },
+            Frame::FecParity {
+                group: 5,
+                count: 4,
+                length: 0x0102,
+                data: b"parity",
+            },
+            Frame::Reliable {
+                seq: 11,
+                frame: b"\x40reliable",
+            },
+            Frame::Ack {
+                next: 12,
+                received: 0b101,
+            },
+        ]
+    }
+
+    #[test]
+    fn frames_are_decoded_as_they_are_encoded() {
+        for frame in frames() {
+            let mut buffer = [0; 64];
+            let packet = frame.encode(&mut buffer);
+            assert_eq!(Frame::decode(packet), Some(frame), "{:02x?}", packet);
+        }
+        // Empty bodies are fine where the frame carries data
+        let mut buffer = [0; 64];
+        let packet = Frame::Data(&[]).encode(&mut buffer);
+        assert_eq!(Frame::decode(packet), Some(Frame::Data(&[])));
+        let packet = data_frame(&mut buffer, 0);
+        assert_eq!(Frame::decode(packet), Some(Frame::Data(&[])));
+    }
+
+    #[test]
+    fn truncated_frames_are_invalid() {
+        for frame in frames() {
+            let mut buffer = [0; 64];
+            let packet = frame.encode(&mut buffer);
+            // Only the frames which end with their data can be cut in the data
+            let header_size = match frame {
+                Frame::Data(_) => FRAME_HEADER_SIZE,
+                Frame::Fragment { .. } => FRAGMENT_HEADER_SIZE,
+                Frame::Probe { .. } => FRAME_HEADER_SIZE + 4,
+                Frame::Fec { .. } => FEC_HEADER_SIZE,
+                Frame::FecParity { .. } => FEC_PARITY_HEADER_SIZE,
+                Frame::Reliable { .. } => RELIABLE_HEADER_SIZE,
+                _ => packet.len(),
+            };
+            for length in 0..header_size {
+                assert_eq!(Frame::decode(&packet[..length]), None, "{:?}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_and_malformed_frames_are_invalid() {
+        assert_eq!(Frame::decode(&[]), None);
+        for frame_type in (0..=u8::MAX).filter(|byte| !(0x40..=0x4A).contains(byte)) {
+            assert_eq!(Frame::decode(&[frame_type, 0, 0, 0, 0, 0, 0, 0, 0]), None);
+        }
+        // Frames with a fixed size must not have anything after them
+        assert_eq!(Frame::decode(&[frame_type::CLOSE, 0]), None);
+        assert_eq!(Frame::decode(&[frame_type::HEARTBEAT, 0, 0, 0, 0, 0]), None);
+        assert_eq!(
+            Frame::decode(&[frame_type::PROBE_ACK, 0, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+        assert_eq!(
+            Frame::decode(&[frame_type::ACK, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            None
+        );
+    }
+
+    /// Wait until the deadline of liveness and run its timer
+    async fn on_deadline(liveness: &mut Liveness) -> Result<Frame<'static>, PathDead> {
+        tokio::time::sleep_until(liveness.deadline()).await;
+        liveness.on_timer()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missed_heartbeats_back_off_and_kill_the_path() {
+        let interval = Duration::from_secs(5);
+        let mut liveness = Liveness::new(interval);
+        assert_eq!(liveness.deadline(), Instant::now() + interval);
+        assert_eq!(
+            on_deadline(&mut liveness).await.unwrap(),
+            Frame::Heartbeat(0)
+        );
+        // The timeout doubles for each missed heartbeat
+        let sent = Instant::now();
+        assert_eq!(liveness.deadline(), sent + INITIAL_RTO);
+        assert_eq!(
+            on_deadline(&mut liveness).await.unwrap(),
+            Frame::Heartbeat(1)
+        );
+        assert_eq!(liveness.deadline(), Instant::now() + 2 * INITIAL_RTO);
+        assert_eq!(
+            on_deadline(&mut liveness).await.unwrap(),
+            Frame::Heartbeat(2)
+        );
+        assert_eq!(liveness.deadline(), Instant::now() + 4 * INITIAL_RTO);
+        assert!(on_deadline(&mut liveness).await.is_err());
+        assert!(sent.elapsed() >= interval);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_peer_has_at_least_an_interval_to_answer() {
+        let interval = Duration::from_secs(10);
+        let mut liveness = Liveness::new(interval);
+        on_deadline(&mut liveness).await.unwrap();
+        let sent = Instant::now();
+        for _ in 0..3 {
+            on_deadline(&mut liveness).await.unwrap();
+        }
+        // Three heartbeats are missed in 7 seconds. The backoff is capped by the interval.
+        assert_eq!(sent.elapsed(), 7 * INITIAL_RTO);
+        assert_eq!(liveness.deadline(), Instant::now() + 8 * INITIAL_RTO);
+        assert!(on_deadline(&mut liveness).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_acks_keep_the_path_alive_without_rtt_samples() {
+        let interval = Duration::from_secs(5);
+        let mut liveness = Liveness::new(interval);
+        on_deadline(&mut liveness).await.unwrap();
+        on_deadline(&mut liveness).await.unwrap();
+        on_deadline(&mut liveness).await.unwrap();
+        // Acks of heartbeats which are not sent yet are ignored
+        liveness.on_ack(3);
+        assert!(liveness.outstanding.is_some());
+        // The ack of the first heartbeat arrives after it is sent again
+        liveness.on_ack(0);
+        assert!(liveness.outstanding.is_none());
+        assert_eq!(liveness.missed, 0);
+        assert_eq!(liveness.rtt(), None);
+        assert_eq!(liveness.deadline(), Instant::now() + interval);
+        // Acks of the heartbeats which were sent again are ignored now
+        liveness.on_ack(2);
+        assert_eq!(liveness.rtt(), None);
+        // Heartbeats which are acknowledged in time are RTT samples
+        assert_eq!(
+            on_deadline(&mut liveness).await.unwrap(),
+            Frame::Heartbeat(3)
+        );
+        tokio::time::advance(Duration::from_millis(100)).await;
+        liveness.on_ack(3);
+        assert_eq!(liveness.rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(liveness.rtt.rto(), MIN_RTO.max(Duration::from_millis(300)));
+    }
+}
@@ -1,103 +1,781 @@
-use postcard;
 use std::{
-    collections::HashMap,
-    net::{SocketAddr, SocketAddrV4, UdpSocket},
+    collections::{hash_map::RandomState, BTreeSet, HashMap},
+    future,
+    hash::BuildHasher,
+    net::{IpAddr, SocketAddr, SocketAddrV4, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use socket2::{Domain, Protocol, Socket, Type};
+
 use crate::{
-    messages::{PunchError, PunchMessage, UDPMessage},
-    util::TURN_BUFFER_SIZE,
+    arguments::{TurnCluster, TurnLimits},
+    identity::Identity,
+    messages::{Cookie, FederationMessage, MeshMessage, PunchError, PunchMessage, UDPMessage},
+    ratelimit::RateLimiter,
+    secure::SecureChannels,
+    util::{die, unix_micros, SEALED_TURN_BUFFER_SIZE, TURN_BUFFER_SIZE},
 };
 
 const SERVERS_CLEAN_UP_INTERVAL: Duration = Duration::from_secs(60 * 10);
 const SLATE_SERVER: Duration = Duration::from_secs(60 * 5);
+/// Nodes of mesh networks are forgotten if they don't join again in this time
+const SLATE_MEMBER: Duration = Duration::from_secs(30);
+/// How often the full buckets of rate limiters are removed
+const RATE_LIMIT_CLEAN_UP_INTERVAL: Duration = Duration::from_secs(30);
+/// Cookies are valid for this epoch and the epoch after it
+const COOKIE_EPOCH: Duration = Duration::from_secs(30);
+/// How many shards of services table each worker gets in multi-threaded mode
+const SHARDS_PER_WORKER: usize = 4;
+/// Messages of peer nodes are dropped if their nonce is this far from our clock
+const MAX_FEDERATION_AGE: Duration = Duration::from_secs(30);
 
-/// Spawn the TURN server which connects all clients and servers together
-pub fn spawn_turn(listen: &str) -> ! {
-    // Bind on address
-    let socket = UdpSocket::bind(listen).expect("cannot bind UDP socket");
-    log::info!("Listening on {}", socket.local_addr().unwrap());
-    // Setup variables
-    let mut buffer = [0; TURN_BUFFER_SIZE];
-    let mut servers: HashMap<String, (SocketAddrV4, Instant)> = HashMap::new();
-    let mut last_server_cleanup = Instant::now();
-    // Wait for clients and servers
-    loop {
-        // Read the first packet
-        let (len, addr) = socket
-            .recv_from(&mut buffer)
-            .expect("cannot receive datagrams");
-        // Before doing stuff, clean up the hashmap if needed
-        if last_server_cleanup.elapsed() > SERVERS_CLEAN_UP_INTERVAL {
-            log::trace!("Cleaning up the servers map");
-            servers.retain(|_, (_, instead_date)| instead_date.elapsed() < SLATE_SERVER);
-            last_server_cleanup = Instant::now();
+/// Packets which must be sent after a packet is processed by TURN server.
+/// At most one packet is sent for each side of the connection and one packet
+/// might be sent to all peer TURN nodes or one of them. Joins of mesh networks
+/// are answered with a packet for each member.
+#[derive(Default)]
+struct Replies<'a> {
+    packets: [Option<(UDPMessage<'a>, SocketAddrV4)>; 2],
+    to_peers: Option<FederationMessage<'a>>,
+    to_node: Option<(FederationMessage<'a>, SocketAddrV4)>,
+    to_members: Vec<(UDPMessage<'a>, SocketAddrV4)>,
+    /// Handshakes of secure channels are never sealed, even if the peer already has a channel
+    handshake: bool,
+}
+
+impl<'a> Replies<'a> {
+    /// Reply with a single packet
+    fn single(msg: UDPMessage<'a>, to: SocketAddrV4) -> Self {
+        Self {
+            packets: [Some((msg, to)), None],
+            ..Self::default()
+        }
+    }
+
+    /// All the packets which must be sent to clients, servers and mesh nodes and their destinations
+    fn iter(&self) -> impl Iterator<Item = (&UDPMessage<'a>, &SocketAddrV4)> {
+        self.packets
+            .iter()
+            .flatten()
+            .chain(self.to_members.iter())
+            .map(|(msg, to)| (msg, to))
+    }
+
+    /// All the messages which must be sent to peer nodes and their destinations
+    fn federation<'b>(
+        &'b self,
+        peers: &'b [SocketAddrV4],
+    ) -> impl Iterator<Item = (&'b FederationMessage<'a>, &'b SocketAddrV4)> {
+        self.to_peers
+            .iter()
+            .flat_map(move |msg| peers.iter().map(move |peer| (msg, peer)))
+            .chain(self.to_node.iter().map(|(msg, to)| (msg, to)))
+    }
+}
+
+/// A server which is registered in the TURN cluster
+#[derive(Debug, Clone, Copy)]
+struct Registration {
+    /// Public address of the server
+    server: SocketAddrV4,
+    /// When was this registration made or refreshed
+    registered: Instant,
+    /// The peer TURN node which the server is registered in. None if it's registered in this node.
+    node: Option<SocketAddrV4>,
+}
+
+/// A shard of services table: Maps service name to its registration
+type ServicesShard = Mutex<HashMap<String, Registration>>;
+
+/// A table of registered servers which is sharded by the service name.
+/// Each shard is locked separately so workers rarely wait on each other.
+struct ServicesTable {
+    shards: Box<[ServicesShard]>,
+    hasher: RandomState,
+}
+
+impl ServicesTable {
+    fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Gets the shard which the service name belongs to
+    fn shard(&self, service_name: &str) -> &ServicesShard {
+        let index = self.hasher.hash_one(service_name) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    /// Remove the servers which have not been used in a long time
+    fn cleanup(&self) {
+        log::trace!("Cleaning up the servers map");
+        for shard in self.shards.iter() {
+            shard
+                .lock()
+                .retain(|_, registration| registration.registered.elapsed() < SLATE_SERVER);
+        }
+    }
+
+    /// Register a server in this node with a service name. A server can refresh its own registration.
+    /// Servers registered in this node are preferred over the ones registered in other nodes.
+    /// Returns false if the name is already taken by another server in this node.
+    fn register(&self, service_name: &str, addr: SocketAddrV4) -> bool {
+        let mut shard = self.shard(service_name).lock();
+        if let Some(registration) = shard.get(service_name) {
+            if registration.node.is_none() && registration.server != addr {
+                return false;
+            }
+        }
+        shard.insert(
+            service_name.to_owned(),
+            Registration {
+                server: addr,
+                registered: Instant::now(),
+                node: None,
+            },
+        );
+        true
+    }
+
+    /// Remove the registration of a server in this node. Returns false if the server is not registered.
+    fn deregister(&self, service_name: &str, addr: SocketAddrV4) -> bool {
+        let mut shard = self.shard(service_name).lock();
+        if shard
+            .get(service_name)
+            .is_some_and(|registration| registration.node.is_none() && registration.server == addr)
+        {
+            shard.remove(service_name);
+            return true;
+        }
+        false
+    }
+
+    /// Register a server which is registered in a peer node. Local registrations are never overwritten.
+    fn register_remote(&self, service_name: &str, server: SocketAddrV4, node: SocketAddrV4) {
+        let mut shard = self.shard(service_name).lock();
+        if shard
+            .get(service_name)
+            .is_some_and(|registration| registration.node.is_none())
+        {
+            return;
+        }
+        shard.insert(
+            service_name.to_owned(),
+            Registration {
+                server,
+                registered: Instant::now(),
+                node: Some(node),
+            },
+        );
+    }
+
+    /// Remove a server which is registered in the given peer node
+    fn remove_remote(&self, service_name: &str, node: SocketAddrV4) {
+        let mut shard = self.shard(service_name).lock();
+        if shard
+            .get(service_name)
+            .is_some_and(|registration| registration.node == Some(node))
+        {
+            shard.remove(service_name);
+        }
+    }
+
+    /// Remove a server from the table and return its registration
+    fn take(&self, service_name: &str) -> Option<Registration> {
+        self.shard(service_name).lock().remove(service_name)
+    }
+
+    /// Remove a server from the table only if it's registered in this node and return its address
+    fn take_local(&self, service_name: &str) -> Option<SocketAddrV4> {
+        let mut shard = self.shard(service_name).lock();
+        if shard
+            .get(service_name)
+            .is_some_and(|registration| registration.node.is_none())
+        {
+            return shard
+                .remove(service_name)
+                .map(|registration| registration.server);
+        }
+        None
+    }
+}
+
+/// A node of a mesh network
+#[derive(Debug, Clone, Copy)]
+struct Member {
+    /// Public address of the node
+    endpoint: SocketAddrV4,
+    /// Virtual address of the node in the network
+    address: IpAddr,
+    /// When did the node join or join again
+    joined: Instant,
+}
+
+/// Mesh networks and their nodes. Nodes join rarely compared to other packets; so one lock is enough.
+#[derive(Default)]
+struct NetworksTable {
+    networks: Mutex<HashMap<String, Vec<Member>>>,
+}
+
+impl NetworksTable {
+    /// Add a node to a network or refresh it. Returns the other nodes of the network and true if
+    /// the node is new to them. Returns None if another node has the virtual address.
+    fn join(
+        &self,
+        network: &str,
+        endpoint: SocketAddrV4,
+        address: IpAddr,
+    ) -> Option<(Vec<Member>, bool)> {
+        let mut networks = self.networks.lock();
+        if !networks.contains_key(network) {
+            networks.insert(network.to_owned(), Vec::new());
+        }
+        let members = networks.get_mut(network).unwrap();
+        members.retain(|member| member.joined.elapsed() < SLATE_MEMBER);
+        if members
+            .iter()
+            .any(|member| member.address == address && member.endpoint != endpoint)
+        {
+            return None;
+        }
+        let new = match members
+            .iter_mut()
+            .find(|member| member.endpoint == endpoint)
+        {
+            Some(member) => {
+                let new = member.address != address;
+                member.address = address;
+                member.joined = Instant::now();
+                new
+            }
+            None => {
+                members.push(Member {
+                    endpoint,
+                    address,
+                    joined: Instant::now(),
+                });
+                true
+            }
+        };
+        let others = members
+            .iter()
+            .filter(|member| member.endpoint != endpoint)
+            .copied()
+            .collect();
+        Some((others, new))
+    }
+
+    /// Remove a node from a network. Returns the other nodes if it was in the network.
+    fn leave(&self, network: &str, endpoint: SocketAddrV4) -> Option<Vec<Member>> {
+        let mut networks = self.networks.lock();
+        let members = networks.get_mut(network)?;
+        let index = members
+            .iter()
+            .position(|member| member.endpoint == endpoint)?;
+        members.swap_remove(index);
+        let others = members.clone();
+        if others.is_empty() {
+            networks.remove(network);
         }
+        Some(others)
+    }
+
+    /// Remove the nodes which have not joined again in a long time and the empty networks
+    fn cleanup(&self) {
+        log::trace!("Cleaning up the mesh networks");
+        self.networks.lock().retain(|_, members| {
+            members.retain(|member| member.joined.elapsed() < SLATE_MEMBER);
+            !members.is_empty()
+        });
+    }
+}
+
+/// Nonces which are received from a peer node. Nonces are timestamps; so messages of a peer
+/// can be reordered by any amount while they are fresh, but a message is never accepted twice.
+#[derive(Debug, Default)]
+struct SeenNonces(BTreeSet<u64>);
+
+impl SeenNonces {
+    fn is_new(&self, nonce: u64) -> bool {
+        !self.0.contains(&nonce)
+    }
+
+    /// Remember a nonce and forget the nonces which are too old to be accepted anyway
+    fn accept(&mut self, nonce: u64, now: u64) {
+        let oldest = now.saturating_sub(MAX_FEDERATION_AGE.as_micros() as u64);
+        self.0 = self.0.split_off(&oldest);
+        self.0.insert(nonce);
+    }
+}
+
+/// Authenticates the messages which TURN nodes of a cluster send to each other with the cluster key
+struct Federation {
+    /// Other TURN nodes of the cluster
+    peers: Vec<SocketAddrV4>,
+    /// HMAC-SHA256 keyed with the cluster key
+    mac: Hmac<Sha256>,
+    /// Nonce of the last message which is sent
+    last_nonce: AtomicU64,
+    /// Nonces which are received from each peer
+    received: Mutex<HashMap<SocketAddrV4, SeenNonces>>,
+    /// Limits the packets which each peer can send
+    limiter: RateLimiter<SocketAddrV4>,
+}
+
+impl Federation {
+    /// Returns None if the node has no peers
+    fn new(cluster: &TurnCluster, shards: usize) -> Option<Self> {
+        let peers = resolve_peers(&cluster.peers);
+        if peers.is_empty() {
+            return None;
+        }
+        let Some(key) = &cluster.cluster_key else {
+            die("peer nodes need a cluster key");
+        };
+        Some(Self {
+            received: Mutex::new(
+                peers
+                    .iter()
+                    .map(|peer| (*peer, SeenNonces::default()))
+                    .collect(),
+            ),
+            peers,
+            mac: Hmac::new_from_slice(key.as_bytes()).expect("HMAC can take key of any size"),
+            last_nonce: AtomicU64::new(0),
+            limiter: RateLimiter::new(cluster.peer_rate, cluster.peer_burst, shards),
+        })
+    }
+
+    /// HMAC of a message with its nonce
+    fn tag(&self, nonce: u64, data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = self.mac.clone();
+        mac.update(&nonce.to_be_bytes());
+        mac.update(data);
+        mac
+    }
+
+    /// Authenticate a message and write its packet into the buffer
+    fn seal<'b>(&self, msg: &FederationMessage<'_>, buffer: &'b mut [u8]) -> Option<&'b [u8]> {
+        let mut data = [0; TURN_BUFFER_SIZE];
+        let data = postcard::to_slice(msg, &mut data).ok()?;
+        // Nonces strictly increase even if the clock goes back or two messages are sent in a microsecond
+        let now = unix_micros();
+        let last = self
+            .last_nonce
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+        let nonce = now.max(last + 1);
+        let tag = self.tag(nonce, data).finalize().into_bytes().into();
+        postcard::to_slice(&UDPMessage::Federation { nonce, data, tag }, buffer)
+            .ok()
+            .map(|packet| &*packet)
+    }
+
+    /// Check the message of a node. Returns None if the node is not a peer or it's limited,
+    /// or the message is forged, stale or replayed.
+    fn open<'a>(
+        &self,
+        node: SocketAddrV4,
+        nonce: u64,
+        data: &'a [u8],
+        tag: &[u8; 32],
+    ) -> Option<FederationMessage<'a>> {
+        if !self.peers.contains(&node) {
+            log::warn!("Got federation packet from unknown node {}", node);
+            return None;
+        }
+        if !self.limiter.check(&node) {
+            log::debug!("Rate limited federation packet from {}", node);
+            return None;
+        }
+        let now = unix_micros();
+        if nonce.abs_diff(now) > MAX_FEDERATION_AGE.as_micros() as u64 {
+            log::warn!(
+                "Dropped stale federation packet from {}; the clocks might be out of sync",
+                node
+            );
+            return None;
+        }
+        if self.tag(nonce, data).verify_slice(tag).is_err() {
+            log::warn!("Dropped forged federation packet from {}", node);
+            return None;
+        }
+        let mut received = self.received.lock();
+        let seen = received.get_mut(&node)?;
+        if !seen.is_new(nonce) {
+            log::debug!("Dropped replayed federation packet from {}", node);
+            return None;
+        }
+        let msg = postcard::from_bytes(data).ok()?;
+        seen.accept(nonce, now);
+        Some(msg)
+    }
+}
+
+/// State of a TURN server which is shared between all workers
+struct TurnServer {
+    services: ServicesTable,
+    networks: NetworksTable,
+    /// Other TURN nodes of the cluster. Only if the server has peers.
+    federation: Option<Federation>,
+    /// Limits the packets which each source IP can send
+    source_limiter: RateLimiter<IpAddr>,
+    /// Limits the lookups of each service name
+    service_limiter: RateLimiter<String>,
+    /// Limits the leaves of each mesh network. Lookups of a service with the same name don't use them up.
+    leave_limiter: RateLimiter<String>,
+    /// Every packet from these networks is dropped
+    banned: Vec<IpNet>,
+    /// The secret key used to create cookies
+    cookie_key: [u8; 32],
+    /// Cookie epochs are counted from this instant
+    started: Instant,
+    /// Secure channels with peers. Only if the server has an identity.
+    secure: Option<SecureChannels>,
+}
+
+impl TurnServer {
+    fn new(
+        shards: usize,
+        cluster: &TurnCluster,
+        limits: &TurnLimits,
+        identity: Option<Identity>,
+    ) -> Self {
+        Self {
+            services: ServicesTable::new(shards),
+            networks: NetworksTable::default(),
+            federation: Federation::new(cluster, shards),
+            source_limiter: RateLimiter::new(limits.source_rate, limits.source_burst, shards),
+            service_limiter: RateLimiter::new(limits.service_rate, limits.service_burst, shards),
+            leave_limiter: RateLimiter::new(limits.service_rate, limits.service_burst, shards),
+            banned: limits.ban.clone(),
+            cookie_key: {
+                let mut key = [0; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            },
+            started: Instant::now(),
+            secure: identity.as_ref().map(SecureChannels::new),
+        }
+    }
+
+    /// Remove the slate entries of services, networks and secure channels
+    fn cleanup(&self) {
+        self.services.cleanup();
+        self.networks.cleanup();
+        if let Some(secure) = &self.secure {
+            secure.cleanup();
+        }
+    }
+
+    /// Remove the full buckets of rate limiters
+    fn cleanup_limiters(&self) {
+        log::trace!("Cleaning up the rate limiters");
+        self.source_limiter.cleanup();
+        self.service_limiter.cleanup();
+        self.leave_limiter.cleanup();
+    }
+
+    /// Creates the cookie of an address in an epoch as HMAC-SHA256(key, address || epoch)
+    /// truncated to the size of cookie
+    fn cookie(&self, addr: SocketAddrV4, epoch: u64) -> Cookie {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.cookie_key)
+            .expect("HMAC can take key of any size");
+        mac.update(&addr.ip().octets());
+        mac.update(&addr.port().to_be_bytes());
+        mac.update(&epoch.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+        Cookie::from_be_bytes(digest[..8].try_into().unwrap())
+    }
+
+    /// Current cookie epoch
+    fn cookie_epoch(&self) -> u64 {
+        self.started.elapsed().as_secs() / COOKIE_EPOCH.as_secs()
+    }
+
+    /// Checks if the cookie is issued for this address in current or the last epoch
+    fn valid_cookie(&self, addr: SocketAddrV4, cookie: Cookie) -> bool {
+        let epoch = self.cookie_epoch();
+        cookie == self.cookie(addr, epoch) || (epoch > 0 && cookie == self.cookie(addr, epoch - 1))
+    }
+
+    /// Process a raw packet which is received from addr and return the packets which must be sent back.
+    /// Messages of secure channels are decrypted into the plain buffer.
+    fn process<'a>(&self, buffer: &'a [u8], addr: SocketAddr, plain: &'a mut [u8]) -> Replies<'a> {
         // Ignore if this is IPv6
         let addr = match addr {
             SocketAddr::V4(v4) => v4,
             SocketAddr::V6(_) => {
                 log::warn!("Got packet from IPv6 address of {}", addr);
-                continue;
+                return Replies::default();
             }
         };
+        let source_ip = IpAddr::V4(*addr.ip());
+        if self.banned.iter().any(|net| net.contains(&source_ip)) {
+            log::trace!("Dropped packet from banned address {}", addr);
+            return Replies::default();
+        }
         // Parse the packet
-        let packet = match postcard::from_bytes::<UDPMessage<'_>>(&buffer[..len]) {
+        let packet = match postcard::from_bytes::<UDPMessage<'_>>(buffer) {
             Err(err) => {
                 log::warn!("Got invalid packet from {}: {}", addr, err);
-                continue;
+                return Replies::default();
             }
             Ok(pkt) => pkt,
         };
-        // Check the request
+        // Peer nodes prove themselves with the cluster key and have their own limits
+        if let UDPMessage::Federation { nonce, data, tag } = packet {
+            let Some(federation) = &self.federation else {
+                log::warn!("Got federation packet from unknown node {}", addr);
+                return Replies::default();
+            };
+            return match federation.open(addr, nonce, data, &tag) {
+                Some(msg) => self.handle_federation(msg, addr),
+                None => Replies::default(),
+            };
+        }
+        let packet = match packet {
+            UDPMessage::SecureHello { cookie, handshake } => {
+                if !self.check_source(&packet, addr) {
+                    log::debug!("Rate limited packet from {}", addr);
+                    return Replies::default();
+                }
+                return self.secure_hello(cookie, handshake, addr, plain);
+            }
+            UDPMessage::Secure { nonce, data } => {
+                let msg = self.secure.as_ref().and_then(|secure| {
+                    let plain = secure.open(addr, nonce, data, plain)?;
+                    postcard::from_bytes::<UDPMessage<'_>>(plain).ok()
+                });
+                match msg {
+                    Some(
+                        UDPMessage::Federation { .. }
+                        | UDPMessage::SecureHello { .. }
+                        | UDPMessage::SecureAccept(_)
+                        | UDPMessage::Secure { .. },
+                    )
+                    | None => {
+                        log::debug!("Dropped forged or replayed packet from {}", addr);
+                        return Replies::default();
+                    }
+                    Some(msg) => msg,
+                }
+            }
+            // Once a peer has a channel, others can't speak for it in plain
+            packet
+                if !matches!(packet, UDPMessage::KeepAlive)
+                    && self
+                        .secure
+                        .as_ref()
+                        .is_some_and(|secure| secure.has_channel(addr)) =>
+            {
+                log::debug!(
+                    "Dropped plain packet from {} which has a secure channel",
+                    addr
+                );
+                return Replies::default();
+            }
+            packet => packet,
+        };
+        // Keep alive packets are never answered, so they don't need to be limited
+        if matches!(packet, UDPMessage::KeepAlive) {
+            return Replies::default();
+        }
+        if !self.check_source(&packet, addr) {
+            log::debug!("Rate limited packet from {}", addr);
+            return Replies::default();
+        }
+        self.handle_packet(packet, addr)
+    }
+
+    /// Returns true if the packet must have a cookie but does not have a valid one.
+    /// Such packets are only answered with a cookie.
+    fn wants_cookie(&self, packet: &UDPMessage<'_>, addr: SocketAddrV4) -> bool {
+        let cookie = match packet {
+            UDPMessage::SecureHello { cookie, .. }
+            | UDPMessage::Client { cookie, .. }
+            | UDPMessage::Join { cookie, .. }
+            | UDPMessage::Leave { cookie, .. } => cookie,
+            _ => return false,
+        };
+        !cookie.is_some_and(|cookie| self.valid_cookie(addr, cookie))
+    }
+
+    /// Rate limit a packet of its source IP. Sources get a bucket only when they prove their address
+    /// with a cookie; so spoofed packets can't fill the limiter.
+    fn check_source(&self, packet: &UDPMessage<'_>, addr: SocketAddrV4) -> bool {
+        let source_ip = IpAddr::V4(*addr.ip());
+        if self.wants_cookie(packet, addr) {
+            self.source_limiter.check_known(&source_ip)
+        } else {
+            self.source_limiter.check(&source_ip)
+        }
+    }
+
+    /// Answer the hello of a peer which opens a secure channel. The answer is written into the buffer.
+    fn secure_hello<'a>(
+        &self,
+        cookie: Option<Cookie>,
+        handshake: &[u8],
+        addr: SocketAddrV4,
+        buffer: &'a mut [u8],
+    ) -> Replies<'a> {
+        let Some(secure) = &self.secure else {
+            log::debug!("{} wants a secure channel but we have no identity", addr);
+            return Replies::default();
+        };
+        // Key exchanges are expensive. Make sure that the peer owns its address first.
+        if !cookie.is_some_and(|cookie| self.valid_cookie(addr, cookie)) {
+            log::trace!("Sending cookie to {}", addr);
+            let cookie = self.cookie(addr, self.cookie_epoch());
+            return Replies {
+                handshake: true,
+                ..Replies::single(UDPMessage::Cookie(cookie), addr)
+            };
+        }
+        let Some(answer) = secure.accept(addr, handshake) else {
+            log::debug!("Invalid secure hello from {}", addr);
+            return Replies::default();
+        };
+        log::debug!("Opened a secure channel with {}", addr);
+        let accept = &mut buffer[..answer.len()];
+        accept.copy_from_slice(&answer);
+        Replies {
+            handshake: true,
+            ..Replies::single(UDPMessage::SecureAccept(accept), addr)
+        }
+    }
+
+    /// Write the packet of a reply into the buffer. Replies to peers which have a secure channel are sealed.
+    fn encode<'b>(
+        &self,
+        msg: &UDPMessage<'_>,
+        to: SocketAddrV4,
+        handshake: bool,
+        buffer: &'b mut [u8],
+    ) -> Option<&'b [u8]> {
+        match &self.secure {
+            Some(secure) if !handshake && secure.has_channel(to) => {
+                let mut plain = [0; TURN_BUFFER_SIZE];
+                let plain = postcard::to_slice(msg, &mut plain).ok()?;
+                secure.seal(to, plain, buffer)
+            }
+            _ => postcard::to_slice(msg, buffer).ok().map(|packet| &*packet),
+        }
+    }
+
+    /// Process a packet which is received from addr and return the packets which must be sent back
+    fn handle_packet<'a>(&self, packet: UDPMessage<'a>, addr: SocketAddrV4) -> Replies<'a> {
         match packet {
             UDPMessage::Server { service_name } => {
                 // Check if same key exists in servers name
-                if servers.contains_key(service_name) {
+                if !self.services.register(service_name, addr) {
                     log::warn!("duplicate key {} from {}", service_name, addr);
-                    // Send the packet
-                    send_udp_packet(
-                        &UDPMessage::Error {
-                            0: PunchError::DuplicateKey,
-                        },
-                        &socket,
-                        &addr,
-                    );
-                    continue;
+                    return Replies::single(UDPMessage::Error(PunchError::DuplicateKey), addr);
                 }
-                // Add it to server list
-                servers.insert(service_name.to_owned(), (addr, Instant::now()));
                 log::debug!("Added {} for {}", service_name, addr);
-                // Send back the success message
-                send_udp_packet(&UDPMessage::Ok, &socket, &addr);
+                // Send back the success message and tell other nodes about the server
+                Replies {
+                    to_peers: Some(FederationMessage::Registered {
+                        service_name,
+                        server: addr,
+                    }),
+                    ..Replies::single(UDPMessage::Ok, addr)
+                }
             }
-            UDPMessage::Client { service_name } => {
+            UDPMessage::Deregister { service_name } => {
+                if !self.services.deregister(service_name, addr) {
+                    return Replies::default();
+                }
+                log::debug!("Removed {} for {}", service_name, addr);
+                // Other nodes must forget this server as well
+                Replies {
+                    to_peers: Some(FederationMessage::Removed { service_name }),
+                    ..Replies::default()
+                }
+            }
+            UDPMessage::Client {
+                service_name,
+                cookie,
+            } => {
+                // Make sure that the client owns its address before sending anything to servers
+                if !cookie.is_some_and(|cookie| self.valid_cookie(addr, cookie)) {
+                    log::trace!("Sending cookie to {}", addr);
+                    let cookie = self.cookie(addr, self.cookie_epoch());
+                    return Replies::single(UDPMessage::Cookie(cookie), addr);
+                }
+                if !self.service_limiter.check(service_name) {
+                    log::warn!("Rate limited lookup of {} from {}", service_name, addr);
+                    return Replies::single(UDPMessage::Error(PunchError::RateLimited), addr);
+                }
                 // Check if the service name exists
-                match servers.remove(service_name) {
-                    Some((server_address, _)) => {
+                match self.services.take(service_name) {
+                    Some(Registration {
+                        server: server_address,
+                        node: None,
+                        ..
+                    }) => {
                         log::debug!(
                             "Matching client {} with server {} via key {}",
                             addr,
                             server_address,
                             service_name
                         );
-                        // Send message to server
-                        send_udp_packet(
-                            &UDPMessage::Punch {
-                                0: PunchMessage::TURN { 0: addr },
-                            },
-                            &socket,
-                            &server_address,
-                        );
-                        // Send message to client
-                        send_udp_packet(
-                            &UDPMessage::Punch {
-                                0: PunchMessage::TURN { 0: server_address },
-                            },
-                            &socket,
-                            &addr,
+                        Replies {
+                            packets: [
+                                // Send message to server
+                                Some((UDPMessage::Punch(PunchMessage::TURN(addr)), server_address)),
+                                // Send message to client
+                                Some((UDPMessage::Punch(PunchMessage::TURN(server_address)), addr)),
+                            ],
+                            // Other nodes must forget this server
+                            to_peers: Some(FederationMessage::Removed { service_name }),
+                            ..Replies::default()
+                        }
+                    }
+                    // The server is registered in another node. Only that node can send packets
+                    // to the server, so we ask it to notify the server.
+                    Some(Registration {
+                        server: server_address,
+                        node: Some(node),
+                        ..
+                    }) => {
+                        log::debug!(
+                            "Matching client {} with server {} of node {} via key {}",
+                            addr,
+                            server_address,
+                            node,
+                            service_name
                         );
+                        Replies {
+                            to_node: Some((
+                                FederationMessage::Match {
+                                    service_name,
+                                    client: addr,
+                                },
+                                node,
+                            )),
+                            ..Replies::single(
+                                UDPMessage::Punch(PunchMessage::TURN(server_address)),
+                                addr,
+                            )
+                        }
                     }
                     // No server was found!
                     None => {
@@ -106,25 +784,786 @@ pub fn spawn_turn(listen: &str) -> ! {
                             addr,
                             service_name
                         );
-                        send_udp_packet(
-                            &UDPMessage::Error {
-                                0: PunchError::NoServer,
-                            },
-                            &socket,
-                            &addr,
-                        );
+                        Replies::single(UDPMessage::Error(PunchError::NoServer), addr)
                     }
+                }
+            }
+            UDPMessage::Join {
+                network,
+                address,
+                cookie,
+            } => {
+                // Joins are answered with a packet for each node; so spoofed joins must not be answered
+                if !cookie.is_some_and(|cookie| self.valid_cookie(addr, cookie)) {
+                    log::trace!("Sending cookie to {}", addr);
+                    let cookie = self.cookie(addr, self.cookie_epoch());
+                    return Replies::single(UDPMessage::Cookie(cookie), addr);
+                }
+                let Some((members, new)) = self.networks.join(network, addr, address) else {
+                    log::warn!(
+                        "{} wants to join {} with taken address {}",
+                        addr,
+                        network,
+                        address
+                    );
+                    return Replies::single(UDPMessage::Error(PunchError::DuplicateKey), addr);
                 };
+                if new {
+                    log::debug!("{} joined {} as {}", addr, network, address);
+                }
+                // Tell the node about every other node and tell them about a new node
+                let mut replies = Replies::single(UDPMessage::Ok, addr);
+                for member in members {
+                    replies.to_members.push((
+                        UDPMessage::Mesh(MeshMessage::Member {
+                            endpoint: member.endpoint,
+                            address: member.address,
+                        }),
+                        addr,
+                    ));
+                    if new {
+                        replies.to_members.push((
+                            UDPMessage::Mesh(MeshMessage::Member {
+                                endpoint: addr,
+                                address,
+                            }),
+                            member.endpoint,
+                        ));
+                    }
+                }
+                replies
+            }
+            UDPMessage::Leave { network, cookie } => {
+                // Leaves are told to every node; so spoofed leaves must not remove anyone.
+                // Nodes which leave without a cookie expire instead.
+                if !cookie.is_some_and(|cookie| self.valid_cookie(addr, cookie)) {
+                    log::trace!("Ignoring leave of {} without a valid cookie", addr);
+                    return Replies::default();
+                }
+                if !self.leave_limiter.check(network) {
+                    log::warn!("Rate limited leave of {} from {}", network, addr);
+                    return Replies::default();
+                }
+                let Some(members) = self.networks.leave(network, addr) else {
+                    return Replies::default();
+                };
+                log::debug!("{} left {}", addr, network);
+                Replies {
+                    to_members: members
+                        .iter()
+                        .map(|member| {
+                            (
+                                UDPMessage::Mesh(MeshMessage::Left { endpoint: addr }),
+                                member.endpoint,
+                            )
+                        })
+                        .collect(),
+                    ..Replies::default()
+                }
+            }
+            _ => Replies::default(),
+        }
+    }
+
+    /// Process a message from a peer node
+    fn handle_federation<'a>(&self, msg: FederationMessage<'a>, node: SocketAddrV4) -> Replies<'a> {
+        match msg {
+            FederationMessage::Registered {
+                service_name,
+                server,
+            } => {
+                log::debug!("Node {} registered {} for {}", node, service_name, server);
+                self.services.register_remote(service_name, server, node);
+                Replies::default()
+            }
+            FederationMessage::Removed { service_name } => {
+                log::trace!("Node {} removed {}", node, service_name);
+                self.services.remove_remote(service_name, node);
+                Replies::default()
+            }
+            FederationMessage::Match {
+                service_name,
+                client,
+            } => match self.services.take_local(service_name) {
+                Some(server_address) => {
+                    log::debug!(
+                        "Matching client {} of node {} with server {} via key {}",
+                        client,
+                        node,
+                        server_address,
+                        service_name
+                    );
+                    Replies {
+                        to_peers: Some(FederationMessage::Removed { service_name }),
+                        ..Replies::single(
+                            UDPMessage::Punch(PunchMessage::TURN(client)),
+                            server_address,
+                        )
+                    }
+                }
+                // Someone else took the server. The client will fail to punch and retry.
+                None => {
+                    log::warn!(
+                        "Node {} asked for {} which is not registered here",
+                        node,
+                        service_name
+                    );
+                    Replies::default()
+                }
+            },
+        }
+    }
+}
+
+/// Spawn the TURN server which connects all clients and servers together.
+/// This server runs on a single thread and uses blocking sockets.
+pub fn spawn_turn(
+    listen: &str,
+    cluster: &TurnCluster,
+    limits: &TurnLimits,
+    identity: Option<Identity>,
+) -> ! {
+    // Bind on address
+    let socket = std::net::UdpSocket::bind(listen).expect("cannot bind UDP socket");
+    log::info!("Listening on {}", socket.local_addr().unwrap());
+    // Setup variables
+    let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut plain = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut write_buffer = [0; SEALED_TURN_BUFFER_SIZE];
+    let server = TurnServer::new(1, cluster, limits, identity);
+    let mut last_server_cleanup = Instant::now();
+    let mut last_limiter_cleanup = Instant::now();
+    // Wait for clients and servers
+    loop {
+        // Read the first packet
+        let (len, addr) = socket
+            .recv_from(&mut buffer)
+            .expect("cannot receive datagrams");
+        // Before doing stuff, clean up the hashmaps if needed
+        if last_server_cleanup.elapsed() > SERVERS_CLEAN_UP_INTERVAL {
+            server.cleanup();
+            last_server_cleanup = Instant::now();
+        }
+        if last_limiter_cleanup.elapsed() > RATE_LIMIT_CLEAN_UP_INTERVAL {
+            server.cleanup_limiters();
+            last_limiter_cleanup = Instant::now();
+        }
+        // Process the packet
+        let replies = server.process(&buffer[..len], addr, &mut plain);
+        for (msg, to) in replies.iter() {
+            if let Some(packet) = server.encode(msg, *to, replies.handshake, &mut write_buffer) {
+                let _ = socket.send_to(packet, to);
+            }
+        }
+        if let Some(federation) = &server.federation {
+            for (msg, to) in replies.federation(&federation.peers) {
+                if let Some(packet) = federation.seal(msg, &mut write_buffer) {
+                    let _ = socket.send_to(packet, to);
+                }
             }
-            _ => {}
+        }
+    }
+}
+
+/// Spawn the TURN server on the tokio runtime with the given number of workers.
+/// Each worker has its own socket bound with SO_REUSEPORT (on unix) so that the
+/// kernel distributes the incoming packets between them.
+pub async fn spawn_turn_multi_threaded(
+    listen: &str,
+    workers: usize,
+    cluster: &TurnCluster,
+    limits: &TurnLimits,
+    identity: Option<Identity>,
+) -> ! {
+    let listen_address = listen
+        .to_socket_addrs()
+        .expect("cannot parse listen address")
+        .next()
+        .expect("cannot parse listen address");
+    let workers = workers.max(1);
+    let server = Arc::new(TurnServer::new(
+        workers * SHARDS_PER_WORKER,
+        cluster,
+        limits,
+        identity,
+    ));
+    // Bind the first socket and use its address for others. This allows binding on port 0.
+    let first_socket = bind_reuse_port(listen_address).unwrap_or_else(|err| die(err));
+    let listen_address = first_socket.local_addr().unwrap();
+    log::info!("Listening on {} with {} workers", listen_address, workers);
+    let mut sockets = vec![Arc::new(first_socket)];
+    for _ in 1..workers {
+        if cfg!(all(
+            unix,
+            not(any(target_os = "solaris", target_os = "illumos"))
+        )) {
+            sockets.push(Arc::new(
+                bind_reuse_port(listen_address).unwrap_or_else(|err| die(err)),
+            ));
+        } else {
+            // No SO_REUSEPORT. All workers share one socket.
+            sockets.push(sockets[0].clone());
+        }
+    }
+    for socket in sockets {
+        tokio::task::spawn(turn_worker(socket, server.clone()));
+    }
+    // Clean up the services table and rate limiters from time to time
+    let limiters_server = server.clone();
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(RATE_LIMIT_CLEAN_UP_INTERVAL);
+        loop {
+            interval.tick().await;
+            limiters_server.cleanup_limiters();
+        }
+    });
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(SERVERS_CLEAN_UP_INTERVAL);
+        loop {
+            interval.tick().await;
+            server.cleanup();
+        }
+    });
+    future::pending().await
+}
+
+/// A worker which reads packets from one socket and answers them
+async fn turn_worker(socket: Arc<tokio::net::UdpSocket>, server: Arc<TurnServer>) {
+    let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut plain = [0; SEALED_TURN_BUFFER_SIZE];
+    let mut write_buffer = [0; SEALED_TURN_BUFFER_SIZE];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buffer).await {
+            Ok(result) => result,
+            Err(err) => die(format!("cannot receive datagrams: {}", err)),
         };
+        let replies = server.process(&buffer[..len], addr, &mut plain);
+        for (msg, to) in replies.iter() {
+            if let Some(packet) = server.encode(msg, *to, replies.handshake, &mut write_buffer) {
+                let _ = socket.send_to(packet, to).await;
+            }
+        }
+        if let Some(federation) = &server.federation {
+            for (msg, to) in replies.federation(&federation.peers) {
+                if let Some(packet) = federation.seal(msg, &mut write_buffer) {
+                    let _ = socket.send_to(packet, to).await;
+                }
+            }
+        }
     }
 }
 
-/// Sends an UDP packet from a socket to address
-fn send_udp_packet(msg: &UDPMessage, socket: &std::net::UdpSocket, addr: &SocketAddrV4) {
-    if let Ok(write_buffer) = postcard::to_vec::<&UDPMessage, TURN_BUFFER_SIZE>(&msg) {
-        // Send it
-        let _ = socket.send_to(&write_buffer, addr);
+/// Resolve the addresses of peer TURN nodes
+fn resolve_peers(peers: &[String]) -> Vec<SocketAddrV4> {
+    let peers: Vec<SocketAddrV4> = peers
+        .iter()
+        .flat_map(|peer| peer.to_socket_addrs().expect("cannot parse peer address"))
+        .filter_map(|peer| match peer {
+            SocketAddr::V4(v4) => Some(v4),
+            SocketAddr::V6(_) => {
+                log::warn!("Ignoring IPv6 peer {}", peer);
+                None
+            }
+        })
+        .collect();
+    if !peers.is_empty() {
+        log::info!("Peer nodes: {:?}", peers);
+    }
+    peers
+}
+
+/// Bind a non-blocking UDP socket with SO_REUSEPORT set on it (if supported)
+fn bind_reuse_port(address: SocketAddr) -> std::io::Result<tokio::net::UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new([192, 0, 2, 1].into(), port)
+    }
+
+    fn address(last: u8) -> IpAddr {
+        [10, 202, 0, last].into()
+    }
+
+    fn endpoints(members: &[Member]) -> Vec<SocketAddrV4> {
+        let mut endpoints: Vec<_> = members.iter().map(|member| member.endpoint).collect();
+        endpoints.sort();
+        endpoints
+    }
+
+    #[test]
+    fn nodes_join_and_learn_the_other_nodes() {
+        let networks = NetworksTable::default();
+        let (others, new) = networks.join("net", endpoint(1), address(1)).unwrap();
+        assert!(others.is_empty() && new);
+        let (others, new) = networks.join("net", endpoint(2), address(2)).unwrap();
+        assert_eq!(endpoints(&others), [endpoint(1)]);
+        assert!(new);
+        // Joining again only refreshes the node
+        let (others, new) = networks.join("net", endpoint(1), address(1)).unwrap();
+        assert_eq!(endpoints(&others), [endpoint(2)]);
+        assert!(!new);
+        // A new virtual address is news to the others
+        let (_, new) = networks.join("net", endpoint(1), address(3)).unwrap();
+        assert!(new);
+        // Other networks are separate
+        let (others, _) = networks.join("other", endpoint(3), address(2)).unwrap();
+        assert!(others.is_empty());
+    }
+
+    #[test]
+    fn taken_addresses_are_refused() {
+        let networks = NetworksTable::default();
+        networks.join("net", endpoint(1), address(1)).unwrap();
+        assert!(networks.join("net", endpoint(2), address(1)).is_none());
+    }
+
+    #[test]
+    fn nodes_leave_and_empty_networks_are_removed() {
+        let networks = NetworksTable::default();
+        networks.join("net", endpoint(1), address(1)).unwrap();
+        networks.join("net", endpoint(2), address(2)).unwrap();
+        assert!(networks.leave("net", endpoint(3)).is_none());
+        assert!(networks.leave("other", endpoint(1)).is_none());
+        assert_eq!(
+            endpoints(&networks.leave("net", endpoint(1)).unwrap()),
+            [endpoint(2)]
+        );
+        // The address is free after its node has left
+        networks.join("net", endpoint(3), address(1)).unwrap();
+        networks.leave("net", endpoint(2)).unwrap();
+        networks.leave("net", endpoint(3)).unwrap();
+        assert!(networks.networks.lock().is_empty());
+    }
+
+    #[test]
+    fn nodes_which_do_not_join_again_expire() {
+        let networks = NetworksTable::default();
+        networks.join("net", endpoint(1), address(1)).unwrap();
+        networks.join("net", endpoint(2), address(2)).unwrap();
+        networks.join("lonely", endpoint(3), address(3)).unwrap();
+        let expired = Instant::now().checked_sub(SLATE_MEMBER).unwrap();
+        for members in networks.networks.lock().values_mut() {
+            for member in members
+                .iter_mut()
+                .filter(|member| member.endpoint != endpoint(2))
+            {
+                member.joined = expired;
+            }
+        }
+        // An expired node is not one of the others and its address is free
+        let (others, _) = networks.join("net", endpoint(4), address(1)).unwrap();
+        assert_eq!(endpoints(&others), [endpoint(2)]);
+        networks.cleanup();
+        let tables = networks.networks.lock();
+        assert!(!tables.contains_key("lonely"));
+        assert_eq!(endpoints(&tables["net"]), [endpoint(2), endpoint(4)]);
+    }
+
+    fn limits() -> TurnLimits {
+        TurnLimits {
+            source_rate: 10.0,
+            source_burst: 20.0,
+            service_rate: 5.0,
+            service_burst: 10.0,
+            ban: vec![],
+        }
+    }
+
+    /// A TURN server without peers and identity
+    fn turn_server(shards: usize, limits: &TurnLimits) -> TurnServer {
+        TurnServer::new(
+            shards,
+            &TurnCluster {
+                peers: vec![],
+                cluster_key: None,
+                peer_rate: 1000.0,
+                peer_burst: 2000.0,
+            },
+            limits,
+            None,
+        )
+    }
+
+    /// Index of the shard which the service name belongs to
+    fn shard_index(services: &ServicesTable, service_name: &str) -> usize {
+        let shard = services.shard(service_name);
+        services
+            .shards
+            .iter()
+            .position(|other| std::ptr::eq(other, shard))
+            .unwrap()
+    }
+
+    #[test]
+    fn service_names_are_spread_over_the_shards() {
+        let services = ServicesTable::new(8);
+        let mut used = [false; 8];
+        for i in 0..100 {
+            let name = format!("service {}", i);
+            let index = shard_index(&services, &name);
+            assert_eq!(shard_index(&services, &name), index);
+            used[index] = true;
+        }
+        assert!(used.iter().filter(|used| **used).count() > 1);
+        // There is always at least one shard
+        assert_eq!(ServicesTable::new(0).shards.len(), 1);
+    }
+
+    #[test]
+    fn services_are_registered_and_removed_in_their_shards() {
+        let services = ServicesTable::new(8);
+        let names: Vec<_> = (0..32).map(|i| format!("service {}", i)).collect();
+        for (port, name) in (1..).zip(&names) {
+            assert!(services.register(name, endpoint(port)));
+            // Others can't take the name but the server can refresh it
+            assert!(!services.register(name, endpoint(1000)));
+            assert!(services.register(name, endpoint(port)));
+            assert!(services.shard(name).lock().contains_key(name));
+        }
+        let total: usize = services.shards.iter().map(|shard| shard.lock().len()).sum();
+        assert_eq!(total, names.len());
+        for (port, name) in (1..).zip(&names) {
+            match port % 3 {
+                0 => {
+                    assert!(!services.deregister(name, endpoint(1000)));
+                    assert!(services.deregister(name, endpoint(port)));
+                    assert!(services.take(name).is_none());
+                }
+                1 => assert_eq!(services.take(name).unwrap().server, endpoint(port)),
+                _ => assert_eq!(services.take_local(name), Some(endpoint(port))),
+            }
+        }
+        assert!(services.shards.iter().all(|shard| shard.lock().is_empty()));
+    }
+
+    #[test]
+    fn remote_services_do_not_replace_local_ones() {
+        let services = ServicesTable::new(4);
+        let node = endpoint(2000);
+        services.register("local", endpoint(1));
+        services.register_remote("local", endpoint(2), node);
+        services.register_remote("remote", endpoint(3), node);
+        assert!(services.take_local("remote").is_none());
+        // Only the node of a remote service can remove it
+        services.remove_remote("remote", endpoint(2001));
+        services.remove_remote("local", node);
+        assert_eq!(services.take("local").unwrap().server, endpoint(1));
+        assert_eq!(services.take("remote").unwrap().node, Some(node));
+    }
+
+    #[test]
+    fn clients_find_the_servers_of_every_shard() {
+        let server = turn_server(8, &limits());
+        let names: Vec<_> = (0..16).map(|i| format!("service {}", i)).collect();
+        for (port, name) in (1..).zip(&names) {
+            let replies =
+                server.handle_packet(UDPMessage::Server { service_name: name }, endpoint(port));
+            assert!(matches!(replies.packets[0], Some((UDPMessage::Ok, _))));
+        }
+        let client = endpoint(1000);
+        let cookie = server.cookie(client, server.cookie_epoch());
+        for (port, name) in (1..).zip(&names) {
+            let lookup = UDPMessage::Client {
+                service_name: name,
+                cookie: Some(cookie),
+            };
+            let replies = server.handle_packet(lookup, client);
+            let destinations: Vec<_> = replies.iter().map(|(_, to)| *to).collect();
+            assert_eq!(destinations, [endpoint(port), client]);
+        }
+        // Each server is matched once
+        let lookup = UDPMessage::Client {
+            service_name: &names[0],
+            cookie: Some(cookie),
+        };
+        assert!(matches!(
+            server.handle_packet(lookup, client).packets[0],
+            Some((UDPMessage::Error(PunchError::NoServer), _))
+        ));
+    }
+
+    #[test]
+    fn leaves_need_the_cookie_of_the_node() {
+        let server = turn_server(1, &limits());
+        server
+            .networks
+            .join("net", endpoint(1), address(1))
+            .unwrap();
+        server
+            .networks
+            .join("net", endpoint(2), address(2))
+            .unwrap();
+        let leave = |cookie| UDPMessage::Leave {
+            network: "net",
+            cookie,
+        };
+        let forged = server.cookie(endpoint(2), server.cookie_epoch());
+        for cookie in [None, Some(forged)] {
+            let replies = server.handle_packet(leave(cookie), endpoint(1));
+            assert!(replies.to_members.is_empty());
+        }
+        let cookie = server.cookie(endpoint(1), server.cookie_epoch());
+        let replies = server.handle_packet(leave(Some(cookie)), endpoint(1));
+        assert_eq!(replies.to_members.len(), 1);
+        assert_eq!(replies.to_members[0].1, endpoint(2));
+        assert!(server.networks.leave("net", endpoint(1)).is_none());
+    }
+
+    /// Move the clock of the cookies some epochs forward
+    fn rotate_cookies(server: &mut TurnServer, epochs: u32) {
+        server.started = server.started.checked_sub(COOKIE_EPOCH * epochs).unwrap();
+    }
+
+    #[test]
+    fn cookies_are_valid_only_for_their_address() {
+        let server = turn_server(1, &limits());
+        let cookie = server.cookie(endpoint(1), server.cookie_epoch());
+        assert!(server.valid_cookie(endpoint(1), cookie));
+        assert!(!server.valid_cookie(endpoint(2), cookie));
+        assert!(!server.valid_cookie(SocketAddrV4::new([192, 0, 2, 2].into(), 1), cookie));
+        // Cookies of another server are not valid
+        let other = turn_server(1, &limits());
+        assert!(!server.valid_cookie(endpoint(1), other.cookie(endpoint(1), 0)));
+    }
+
+    #[test]
+    fn cookies_expire_after_the_next_epoch() {
+        let mut server = turn_server(1, &limits());
+        let cookie = server.cookie(endpoint(1), server.cookie_epoch());
+        rotate_cookies(&mut server, 1);
+        assert_eq!(server.cookie_epoch(), 1);
+        assert!(server.valid_cookie(endpoint(1), cookie));
+        assert_ne!(server.cookie(endpoint(1), server.cookie_epoch()), cookie);
+        rotate_cookies(&mut server, 1);
+        assert!(!server.valid_cookie(endpoint(1), cookie));
+        // Expired cookies are answered with a new cookie instead of a lookup
+        server.handle_packet(
+            UDPMessage::Server {
+                service_name: "service",
+            },
+            endpoint(2),
+        );
+        let lookup = UDPMessage::Client {
+            service_name: "service",
+            cookie: Some(cookie),
+        };
+        let replies = server.handle_packet(lookup, endpoint(1));
+        let Some((UDPMessage::Cookie(new), _)) = replies.packets[0] else {
+            panic!("expected a cookie");
+        };
+        assert!(server.valid_cookie(endpoint(1), new));
+        assert!(replies.packets[1].is_none());
+    }
+
+    #[test]
+    fn banned_addresses_are_dropped() {
+        let server = turn_server(
+            1,
+            &TurnLimits {
+                ban: vec![
+                    "192.0.2.0/28".parse().unwrap(),
+                    "198.51.100.7/32".parse().unwrap(),
+                ],
+                ..limits()
+            },
+        );
+        let mut packet = [0; TURN_BUFFER_SIZE];
+        let packet = postcard::to_slice(
+            &UDPMessage::Server {
+                service_name: "service",
+            },
+            &mut packet,
+        )
+        .unwrap();
+        let mut plain = [0; TURN_BUFFER_SIZE];
+        for banned in ["192.0.2.1:1", "192.0.2.15:2", "198.51.100.7:3"] {
+            let replies = server.process(packet, banned.parse().unwrap(), &mut plain);
+            assert_eq!(replies.iter().count(), 0);
+        }
+        assert!(server.services.take("service").is_none());
+        for allowed in ["192.0.2.16:1", "198.51.100.8:2"] {
+            let replies = server.process(packet, allowed.parse().unwrap(), &mut plain);
+            assert!(matches!(replies.packets[0], Some((UDPMessage::Ok, _))));
+            server.services.take("service").unwrap();
+        }
+    }
+
+    #[test]
+    fn sources_are_limited_once_they_have_a_cookie() {
+        let server = turn_server(1, &limits());
+        let source = endpoint(1);
+        let lookup = |cookie| {
+            let mut packet = [0; TURN_BUFFER_SIZE];
+            let length = postcard::to_slice(
+                &UDPMessage::Client {
+                    service_name: "service",
+                    cookie,
+                },
+                &mut packet,
+            )
+            .unwrap()
+            .len();
+            packet[..length].to_vec()
+        };
+        let mut plain = [0; TURN_BUFFER_SIZE];
+        // Packets without a cookie are always answered with a cookie and don't get a bucket
+        let packet = lookup(None);
+        for _ in 0..limits().source_burst as usize * 2 {
+            let replies = server.process(&packet, source.into(), &mut plain);
+            assert!(matches!(
+                replies.packets[0],
+                Some((UDPMessage::Cookie(_), _))
+            ));
+        }
+        let packet = lookup(Some(server.cookie(source, server.cookie_epoch())));
+        for _ in 0..limits().source_burst as usize {
+            let replies = server.process(&packet, source.into(), &mut plain);
+            assert!(replies.packets[0].is_some());
+        }
+        let replies = server.process(&packet, source.into(), &mut plain);
+        assert!(replies.packets[0].is_none());
+        // The bucket of the source limits its packets without a cookie too
+        let packet = lookup(None);
+        let replies = server.process(&packet, source.into(), &mut plain);
+        assert!(replies.packets[0].is_none());
+    }
+
+    #[test]
+    fn leaves_and_lookups_of_the_same_name_are_limited_separately() {
+        let limits = limits();
+        let server = turn_server(1, &limits);
+        let cookie = |addr| Some(server.cookie(addr, server.cookie_epoch()));
+        // Use up the lookups of the name
+        let limited = (0..100).any(|_| {
+            let lookup = UDPMessage::Client {
+                service_name: "net",
+                cookie: cookie(endpoint(1)),
+            };
+            matches!(
+                server.handle_packet(lookup, endpoint(1)).packets[0],
+                Some((UDPMessage::Error(PunchError::RateLimited), _))
+            )
+        });
+        assert!(limited);
+        server
+            .networks
+            .join("net", endpoint(1), address(1))
+            .unwrap();
+        let leave = |addr| UDPMessage::Leave {
+            network: "net",
+            cookie: cookie(addr),
+        };
+        // Leaves have their own burst
+        for port in 2..2 + limits.service_burst as u16 {
+            server
+                .networks
+                .join("net", endpoint(port), address(2))
+                .unwrap();
+            let replies = server.handle_packet(leave(endpoint(port)), endpoint(port));
+            assert_eq!(replies.to_members.len(), 1);
+        }
+        server
+            .networks
+            .join("net", endpoint(100), address(2))
+            .unwrap();
+        let replies = server.handle_packet(leave(endpoint(100)), endpoint(100));
+        assert!(replies.to_members.is_empty());
+    }
+
+    fn federation() -> Federation {
+        Federation::new(
+            &TurnCluster {
+                peers: vec![endpoint(1).to_string()],
+                cluster_key: Some("cluster key".to_owned()),
+                peer_rate: 1000.0,
+                peer_burst: 2000.0,
+            },
+            1,
+        )
+        .unwrap()
+    }
+
+    /// Seal a message and read back its nonce, data and tag
+    fn seal(federation: &Federation, service_name: &str) -> (u64, Vec<u8>, [u8; 32]) {
+        let mut buffer = [0; SEALED_TURN_BUFFER_SIZE];
+        let packet = federation
+            .seal(&FederationMessage::Removed { service_name }, &mut buffer)
+            .unwrap();
+        match postcard::from_bytes(packet).unwrap() {
+            UDPMessage::Federation { nonce, data, tag } => (nonce, data.to_vec(), tag),
+            msg => panic!("sealed {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn reordered_federation_messages_are_accepted() {
+        let federation = federation();
+        let first = seal(&federation, "first");
+        std::thread::sleep(Duration::from_millis(2));
+        let second = seal(&federation, "second");
+        // The first message is much more than 64 nonces behind the second one
+        assert!(second.0 - first.0 > u64::BITS as u64);
+        for (nonce, data, tag) in [&second, &first] {
+            assert!(federation.open(endpoint(1), *nonce, data, tag).is_some());
+        }
+    }
+
+    #[test]
+    fn duplicated_federation_messages_are_dropped() {
+        let federation = federation();
+        let first = seal(&federation, "first");
+        let second = seal(&federation, "second");
+        for (nonce, data, tag) in [&first, &second] {
+            assert!(federation.open(endpoint(1), *nonce, data, tag).is_some());
+        }
+        for (nonce, data, tag) in [&first, &second] {
+            assert!(federation.open(endpoint(1), *nonce, data, tag).is_none());
+        }
+    }
+
+    #[test]
+    fn forged_and_stale_federation_messages_are_dropped() {
+        let federation = federation();
+        let (nonce, data, tag) = seal(&federation, "service");
+        // Unknown nodes and tampered messages
+        assert!(federation.open(endpoint(2), nonce, &data, &tag).is_none());
+        assert!(federation
+            .open(endpoint(1), nonce + 1, &data, &tag)
+            .is_none());
+        let mut forged = tag;
+        forged[0] ^= 1;
+        assert!(federation
+            .open(endpoint(1), nonce, &data, &forged)
+            .is_none());
+        // Messages which are too old are dropped even if they are authentic
+        let stale = nonce - MAX_FEDERATION_AGE.as_micros() as u64 - 1_000_000;
+        let tag = federation.tag(stale, &data).finalize().into_bytes().into();
+        assert!(federation.open(endpoint(1), stale, &data, &tag).is_none());
+    }
+
+    #[test]
+    fn old_nonces_are_forgotten() {
+        let mut seen = SeenNonces::default();
+        let now = 100 * MAX_FEDERATION_AGE.as_micros() as u64;
+        seen.accept(now - MAX_FEDERATION_AGE.as_micros() as u64 - 1, now);
+        seen.accept(now - 1, now);
+        seen.accept(now, now);
+        assert!(!seen.is_new(now) && !seen.is_new(now - 1));
+        seen.accept(now + 1, now);
+        assert_eq!(seen.0.len(), 3);
     }
 }
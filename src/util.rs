@@ -1,14 +1,27 @@
-use std::{fmt, net::SocketAddrV4, time::Duration};
+use std::{
+    fmt,
+    net::{SocketAddr, SocketAddrV4, ToSocketAddrs},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::secure::Turn;
 
 /// Size of buffer of network sockets for connecting to TURN server
 pub const TURN_BUFFER_SIZE: usize = 128;
 
+/// Size of buffer of packets of TURN server which might be encrypted over a secure channel
+/// or authenticated with the cluster key
+pub const SEALED_TURN_BUFFER_SIZE: usize = TURN_BUFFER_SIZE + 48;
+
+/// Size of buffer of the handshake packets between peers. VPN sessions push their routes in them.
+pub const PUNCH_BUFFER_SIZE: usize = 512;
+
 /// Local address on which we should bind in order to open the UDP socket as client
 pub const LOCAL_UDP_BIND_ADDRESS: SocketAddrV4 =
     SocketAddrV4::new(std::net::Ipv4Addr::new(0, 0, 0, 0), 0);
 
-/// The buffer size which is used to copy two UDP sockets
-pub const FORWARD_BUFFER_SIZE: usize = 4 * 1024;
+/// The buffer size which is used to copy two UDP sockets. Large enough for any UDP datagram.
+pub const FORWARD_BUFFER_SIZE: usize = 64 * 1024;
 
 /// How long to wait before a socket times out
 pub const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
@@ -18,3 +31,41 @@ pub fn die<E: fmt::Debug>(error: E) -> ! {
     log::error!("{:?}", error);
     std::process::exit(1);
 }
+
+/// Parse a comma separated list of TURN server addresses. Each address might end with @ and
+/// the public key of the server. Every address which a hostname resolves to is used.
+pub fn parse_turn_addresses(turn: &str) -> Vec<Turn> {
+    let mut addresses: Vec<Turn> = Vec::new();
+    for turn in turn.split(',') {
+        let (address, key) = match turn.trim().rsplit_once('@') {
+            Some((address, key)) => (address, Some(key.parse().unwrap_or_else(|err| die(err)))),
+            None => (turn.trim(), None),
+        };
+        for turn_address in address
+            .to_socket_addrs()
+            .expect("cannot parse TURN address")
+        {
+            match turn_address {
+                SocketAddr::V4(v4) if !addresses.iter().any(|turn| turn.address == v4) => {
+                    addresses.push(Turn { address: v4, key })
+                }
+                SocketAddr::V4(_) => {}
+                // TURN protocol only works with IPv4 addresses
+                SocketAddr::V6(v6) => log::warn!("Ignoring IPv6 TURN address {}", v6),
+            }
+        }
+    }
+    if addresses.is_empty() {
+        die("no IPv4 TURN address found");
+    }
+    log::debug!("TURN servers: {:?}", addresses);
+    addresses
+}
+
+/// Microseconds since UNIX epoch
+pub fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
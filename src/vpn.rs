@@ -0,0 +1,193 @@
+use std::{collections::HashSet, net::IpAddr, sync::Arc};
+
+use anyhow::{anyhow, bail};
+use ipnet::IpNet;
+use parking_lot::Mutex;
+
+use crate::{
+    arguments::{TunOptions, VpnServerOptions},
+    messages::VpnConfig,
+    ratelimit::TokenBucket,
+    tun::Tun,
+    util::die,
+};
+
+/// Routes which are pushed to clients must fit in the handshake
+const MAX_ROUTES: usize = 16;
+/// Addresses which are leased to new clients per second. Each lease creates a TUN device.
+const LEASE_RATE: f64 = 5.0;
+/// Addresses which can be leased at once before the rate limits new clients
+const LEASE_BURST: f64 = 20.0;
+
+/// Addresses of a VPN network which the server leases to its clients
+struct AddressPool {
+    network: IpNet,
+    /// The address of the server itself. It's never leased.
+    server: IpAddr,
+    leased: Mutex<HashSet<IpAddr>>,
+}
+
+/// An address which is leased to a client. It's released when the lease is dropped.
+pub struct Lease {
+    pool: Arc<AddressPool>,
+    address: IpAddr,
+}
+
+impl Lease {
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// Returns true if the source of the IP packet is the leased address.
+    /// Clients can't send packets on behalf of other addresses.
+    pub fn owns(&self, packet: &[u8]) -> bool {
+        source(packet) == Some(self.address)
+    }
+}
+
+/// The source address of an IP packet
+fn source(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => Some(<[u8; 4]>::try_from(packet.get(12..16)?).ok()?.into()),
+        6 => Some(<[u8; 16]>::try_from(packet.get(8..24)?).ok()?.into()),
+        _ => None,
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool.leased.lock().remove(&self.address);
+    }
+}
+
+/// The VPN of a server. Each session has its own point to point TUN device.
+pub struct Vpn {
+    pool: Arc<AddressPool>,
+    routes: Vec<IpNet>,
+    tun: TunOptions,
+    leases: Mutex<TokenBucket>,
+}
+
+impl Vpn {
+    pub fn new(options: VpnServerOptions, tun: TunOptions) -> Self {
+        if options.routes.len() > MAX_ROUTES {
+            die(format!("at most {} routes can be pushed", MAX_ROUTES));
+        }
+        Self {
+            pool: Arc::new(AddressPool {
+                network: options.address.trunc(),
+                server: options.address.addr(),
+                leased: Mutex::new(HashSet::new()),
+            }),
+            routes: options.routes,
+            tun,
+            leases: Mutex::new(TokenBucket::new(LEASE_BURST)),
+        }
+    }
+
+    /// Lease an address to a new client. Returns the lease and the config which is pushed to the client.
+    pub fn lease(&self) -> anyhow::Result<(Lease, VpnConfig)> {
+        if !self.leases.lock().take(1.0, LEASE_RATE, LEASE_BURST) {
+            bail!("too many new VPN clients");
+        }
+        let mut leased = self.pool.leased.lock();
+        let address = self
+            .pool
+            .network
+            .hosts()
+            .find(|address| *address != self.pool.server && !leased.contains(address))
+            .ok_or_else(|| anyhow!("no VPN address is left for new clients"))?;
+        leased.insert(address);
+        let config = VpnConfig {
+            address,
+            peer: self.pool.server,
+            routes: self.routes.clone(),
+        };
+        let lease = Lease {
+            pool: self.pool.clone(),
+            address,
+        };
+        Ok((lease, config))
+    }
+
+    /// Create the TUN device of the session of a client
+    pub async fn open(&self, lease: &Lease) -> anyhow::Result<Tun> {
+        Tun::open(
+            &self.tun.tun_name,
+            self.pool.server,
+            Some(lease.address),
+            self.tun.mtu,
+            &[],
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vpn(address: &str) -> Vpn {
+        Vpn::new(
+            VpnServerOptions {
+                address: address.parse().unwrap(),
+                routes: vec![],
+            },
+            TunOptions {
+                tun_name: "punch%d".to_owned(),
+                mtu: 1400,
+            },
+        )
+    }
+
+    #[test]
+    fn leases_skip_the_server_and_are_released_when_dropped() {
+        let vpn = vpn("10.200.0.2/29");
+        let leases: Vec<_> = (0..5).map(|_| vpn.lease().unwrap().0).collect();
+        let addresses: Vec<_> = leases.iter().map(|lease| lease.address()).collect();
+        let expected: Vec<IpAddr> = [
+            "10.200.0.1",
+            "10.200.0.3",
+            "10.200.0.4",
+            "10.200.0.5",
+            "10.200.0.6",
+        ]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect();
+        assert_eq!(addresses, expected);
+        assert!(vpn.lease().is_err());
+        drop(leases);
+        let (lease, config) = vpn.lease().unwrap();
+        assert_eq!(lease.address(), expected[0]);
+        assert_eq!(config.address, expected[0]);
+        assert_eq!(config.peer, "10.200.0.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn new_leases_are_rate_limited() {
+        let vpn = vpn("10.200.0.1/16");
+        for _ in 0..LEASE_BURST as usize {
+            // Dropped leases are released but still count against the rate
+            vpn.lease().unwrap();
+        }
+        assert!(vpn.lease().is_err());
+    }
+
+    #[test]
+    fn leases_own_only_packets_from_their_address() {
+        let vpn = vpn("10.200.0.1/24");
+        let (lease, _) = vpn.lease().unwrap();
+        let mut packet = [0; 20];
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&[10, 200, 0, 2]);
+        assert!(lease.owns(&packet));
+        packet[15] = 3;
+        assert!(!lease.owns(&packet));
+        assert!(!lease.owns(&packet[..14]));
+        assert!(!lease.owns(&[]));
+        let mut packet = [0; 40];
+        packet[0] = 0x60;
+        assert!(!lease.owns(&packet));
+    }
+}
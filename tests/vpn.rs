@@ -0,0 +1,151 @@
+//! A VPN client and server in their own network namespaces, connected by a veth pair.
+//! Needs root and iproute2; run with `cargo test -- --ignored`.
+
+use std::{
+    fs::File,
+    net::{IpAddr, UdpSocket},
+    os::fd::AsRawFd,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const PROGRAM: &str = env!("CARGO_BIN_EXE_p2p_udp_puncher");
+
+/// Network namespaces and programs which are removed when the test is over
+struct Namespaces {
+    names: Vec<String>,
+    children: Vec<Child>,
+}
+
+impl Namespaces {
+    /// Create two namespaces which are connected with 10.231.0.1/24 and 10.231.0.2/24
+    fn connected(prefix: &str) -> Self {
+        let names = vec![format!("{}a", prefix), format!("{}b", prefix)];
+        let namespaces = Self {
+            names,
+            children: Vec::new(),
+        };
+        let (a, b) = (&namespaces.names[0], &namespaces.names[1]);
+        let veth = format!("{}va", prefix);
+        let peer = format!("{}vb", prefix);
+        ip(&["netns", "add", a]);
+        ip(&["netns", "add", b]);
+        ip(&["link", "add", &veth, "type", "veth", "peer", "name", &peer]);
+        ip(&["link", "set", &veth, "netns", a]);
+        ip(&["link", "set", &peer, "netns", b]);
+        ip(&["-n", a, "addr", "add", "10.231.0.1/24", "dev", &veth]);
+        ip(&["-n", b, "addr", "add", "10.231.0.2/24", "dev", &peer]);
+        for (namespace, device) in [(a, &veth), (b, &peer)] {
+            ip(&["-n", namespace, "link", "set", device, "up"]);
+            ip(&["-n", namespace, "link", "set", "lo", "up"]);
+        }
+        namespaces
+    }
+
+    /// Run the program in a namespace
+    fn spawn(&mut self, namespace: usize, arguments: &[&str]) {
+        let child = Command::new("ip")
+            .args(["netns", "exec", &self.names[namespace], PROGRAM])
+            .args(arguments)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("cannot run the program");
+        self.children.push(child);
+    }
+
+    /// Run the function on a thread in a namespace. Sockets stay in the namespace which they
+    /// are created in.
+    fn enter<T: Send>(&self, namespace: usize, function: impl FnOnce() -> T + Send) -> T {
+        let path = format!("/var/run/netns/{}", self.names[namespace]);
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let file = File::open(path).expect("cannot open the namespace");
+                    let entered = unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) };
+                    assert_eq!(entered, 0, "cannot enter the namespace");
+                    function()
+                })
+                .join()
+                .unwrap()
+        })
+    }
+
+    /// The routes of a namespace
+    fn routes(&self, namespace: usize) -> String {
+        let output = Command::new("ip")
+            .args(["-n", &self.names[namespace], "route"])
+            .output()
+            .expect("cannot run ip");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+}
+
+impl Drop for Namespaces {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        for name in &self.names {
+            let _ = Command::new("ip").args(["netns", "del", name]).status();
+        }
+    }
+}
+
+fn ip(arguments: &[&str]) {
+    let status = Command::new("ip")
+        .args(arguments)
+        .status()
+        .expect("cannot run ip");
+    assert!(status.success(), "ip {} failed", arguments.join(" "));
+}
+
+#[test]
+#[ignore = "needs root to create network namespaces"]
+fn vpn_client_reaches_the_server_and_refuses_routes_to_it() {
+    let mut namespaces = Namespaces::connected("pvpn");
+    namespaces.spawn(0, &["turn", "10.231.0.1:12345"]);
+    thread::sleep(Duration::from_millis(300));
+    namespaces.spawn(
+        0,
+        &[
+            "vpn",
+            "server",
+            "10.231.0.1:12345",
+            "test",
+            "--address",
+            "10.232.0.1/24",
+            "--route",
+            "192.168.231.0/24",
+            "--route",
+            "10.231.0.0/24",
+        ],
+    );
+    thread::sleep(Duration::from_millis(300));
+    namespaces.spawn(1, &["vpn", "client", "10.231.0.1:12345", "test"]);
+    let server = namespaces.enter(0, || UdpSocket::bind("0.0.0.0:0").unwrap());
+    let client = namespaces.enter(1, || UdpSocket::bind("0.0.0.0:0").unwrap());
+    let port = server.local_addr().unwrap().port();
+    server
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    // Send until the VPN is up
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let from = loop {
+        assert!(Instant::now() < deadline, "VPN is not up");
+        let _ = client.send_to(b"hello", ("10.232.0.1", port));
+        let mut buffer = [0; 16];
+        if let Ok((length, from)) = server.recv_from(&mut buffer) {
+            assert_eq!(&buffer[..length], b"hello");
+            break from;
+        }
+    };
+    // The client got the first address of the network
+    assert_eq!(from.ip(), "10.232.0.2".parse::<IpAddr>().unwrap());
+    let routes = namespaces.routes(1);
+    assert!(routes.contains("192.168.231.0/24 dev punch"), "{}", routes);
+    // The route which covers the server would send the tunnel into itself
+    assert!(!routes.contains("10.231.0.0/24 dev punch"), "{}", routes);
+}